@@ -1,11 +1,14 @@
 use futures::prelude::*;
 
-use crate::data::query::{CacheStatus, Query, QueryTarget};
-use crate::data::subscription::{Subscription, SubscriptionError, SubscriptionResult};
+use crate::data::query::{CacheStatus, Query, QueryExecutionError, QueryTarget};
+use crate::data::subscription::{
+    QueryResultStream, Subscription, SubscriptionError, SubscriptionResult,
+};
 use crate::data::{graphql::effort::LoadManager, query::QueryResults};
-use crate::prelude::DeploymentHash;
+use crate::prelude::{q, DeploymentHash};
 
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -13,6 +16,32 @@ use std::time::Duration;
 pub type SubscriptionResultFuture =
     Box<dyn Future<Item = SubscriptionResult, Error = SubscriptionError> + Send>;
 
+/// The cost of a query as estimated by `GraphQlRunner::estimate_cost`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryCost {
+    /// The complexity of the query
+    pub complexity: u64,
+    /// The hash of the shape of the query, which the `LoadManager` uses to
+    /// track the effort spent on similar queries
+    pub shape_hash: u64,
+    /// Whether running the query would be rejected, either because it is
+    /// too complex or because the `LoadManager` would not let it proceed
+    pub rejected: bool,
+    /// How much the fields with a selection set contribute to the
+    /// complexity, the most expensive first
+    pub breakdown: Vec<FieldCost>,
+}
+
+/// The contribution of one field, including everything nested below it, to
+/// the complexity of a query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldCost {
+    /// The response keys from the root of the query to the field, separated
+    /// by `.`, e.g., `musicians.bands`
+    pub path: String,
+    pub cost: u64,
+}
+
 pub enum GraphQlTarget {
     SubgraphName(String),
     Deployment(DeploymentHash),
@@ -35,6 +64,77 @@ pub trait GraphQlRunner: Send + Sync + 'static {
         max_skip: Option<u32>,
     ) -> QueryResults;
 
+    /// Runs a batch of GraphQL queries and returns their results in the
+    /// order of `queries`. Queries for the same target are executed against
+    /// the same `QueryStore` and snapshot of the deployment state; each
+    /// query is checked by the `LoadManager` on its own, and a query that
+    /// fails does not affect the other queries in the batch
+    async fn run_query_batch(
+        self: Arc<Self>,
+        queries: Vec<(Query, QueryTarget)>,
+    ) -> Vec<QueryResults>;
+
+    /// Runs a batch of GraphQL queries like `run_query_batch`, with the
+    /// same limits for each query that `run_query_with_complexity` uses
+    async fn run_query_batch_with_complexity(
+        self: Arc<Self>,
+        queries: Vec<(Query, QueryTarget)>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+    ) -> Vec<QueryResults>;
+
+    /// Runs the same GraphQL query against each of `targets`, e.g., several
+    /// versions of a subgraph, and returns the results by target. Each
+    /// execution uses its own `QueryStore` and therefore has a consistent
+    /// view of its deployment; a query that fails for one target does not
+    /// affect the results for the other targets
+    async fn run_query_multi(
+        self: Arc<Self>,
+        query: Query,
+        targets: Vec<QueryTarget>,
+    ) -> HashMap<QueryTarget, QueryResults>;
+
+    /// Runs a GraphQL query whose only toplevel field is a collection and
+    /// returns the entities of the collection one page at a time, so that
+    /// very large results never have to be held in memory all at once.
+    /// Queries that fail before the first page, or that can not be
+    /// streamed, produce a stream with just the error
+    async fn run_query_stream(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> QueryResultStream;
+
+    /// Prepares a GraphQL query without executing it and returns the plan
+    /// for running it, i.e., the block constraints with their resolved block
+    /// pointers, the fields selected for each of them, the query's
+    /// complexity and the database replica the query would be sent to
+    async fn run_query_explain(self: Arc<Self>, query: Query, target: QueryTarget) -> QueryResults;
+
+    /// Estimates the cost of a GraphQL query without executing it. Only the
+    /// query's complexity is computed and the `LoadManager` asked whether
+    /// it would run the query; no data is fetched from the store
+    async fn estimate_cost(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Result<QueryCost, QueryResults>;
+
+    /// Checks a GraphQL query against the schema of its deployment without
+    /// executing it, and returns the errors that running it would produce
+    /// before any data is read: validation errors, violations of the
+    /// complexity and depth limits, and variables that can not be coerced
+    /// to their declared types. A valid query produces no errors. Only the
+    /// API schema is loaded from the store, and the `LoadManager` is not
+    /// consulted
+    async fn validate(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Vec<QueryExecutionError>;
+
     /// Runs a GraphQL subscription and returns a stream of results.
     async fn run_subscription(
         self: Arc<Self>,
@@ -42,6 +142,21 @@ pub trait GraphQlRunner: Send + Sync + 'static {
         target: QueryTarget,
     ) -> Result<SubscriptionResult, SubscriptionError>;
 
+    /// Registers the subscription `document` under `name` so that clients
+    /// can run it by setting `Subscription::name` instead of sending the
+    /// whole document. Registering a name again replaces its document.
+    /// Stored subscriptions are subject to the same checks as any other
+    /// subscription when they are run
+    fn register_subscription(
+        &self,
+        name: String,
+        document: q::Document,
+    ) -> Result<(), QueryExecutionError>;
+
+    /// The names of the registered subscriptions together with the text of
+    /// their documents, ordered by name
+    fn stored_subscriptions(&self) -> Vec<(String, String)>;
+
     fn load_manager(&self) -> Arc<LoadManager>;
 }
 
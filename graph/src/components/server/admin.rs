@@ -1,6 +1,7 @@
 use std::io;
 use std::sync::Arc;
 
+use crate::data::graphql::effort::LoadManager;
 use crate::prelude::Logger;
 use crate::prelude::NodeId;
 
@@ -13,6 +14,7 @@ pub trait JsonRpcServer<P> {
         http_port: u16,
         ws_port: u16,
         provider: Arc<P>,
+        load_manager: Arc<LoadManager>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error>;
@@ -281,6 +281,12 @@ pub enum EntityOrder {
     Ascending(String, ValueType),
     /// Order descending by the given attribute. Use `id` as a tie-breaker
     Descending(String, ValueType),
+    /// Order ascending by an attribute of an entity that the entities
+    /// reference. Use `id` as a tie-breaker
+    ChildAscending(EntityOrderByChild),
+    /// Order descending by an attribute of an entity that the entities
+    /// reference. Use `id` as a tie-breaker
+    ChildDescending(EntityOrderByChild),
     /// Order by the `id` of the entities
     Default,
     /// Do not order at all. This speeds up queries where we know that
@@ -288,6 +294,32 @@ pub enum EntityOrder {
     Unordered,
 }
 
+/// An attribute of an entity that is reached from the entities that should
+/// be ordered by following one or more references
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityOrderByChild {
+    /// The references to follow, starting with the one on the entities
+    /// that should be ordered. Never empty
+    pub joins: Vec<EntityOrderByJoin>,
+    /// The attribute of the last referenced entity to order by
+    pub attribute: String,
+    pub value_type: ValueType,
+}
+
+/// One reference that needs to be followed to get to the attribute by
+/// which entities should be ordered
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityOrderByJoin {
+    /// The type of the referenced entity
+    pub entity_type: EntityType,
+    /// The attribute that holds the reference. For a derived field, this
+    /// is the attribute of the referenced entity that holds the `id` of the
+    /// referencing entity; otherwise, it is the attribute of the
+    /// referencing entity that holds the `id` of the referenced entity
+    pub attribute: String,
+    pub derived: bool,
+}
+
 /// How many entities to return, how many to skip etc.
 #[derive(Clone, Debug, PartialEq)]
 pub struct EntityRange {
@@ -834,7 +866,7 @@ pub struct StoredDynamicDataSource {
 /// identifier only has meaning in the context of a specific instance of
 /// graph-node. Only store code should ever construct or consume it; all
 /// other code passes it around as an opaque token.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DeploymentId(pub i32);
 
 impl Display for DeploymentId {
@@ -888,6 +920,64 @@ impl Display for DeploymentLocator {
 // connection checkouts
 pub type PoolWaitStats = Arc<RwLock<MovingStats>>;
 
+/// When connected to read replicas, this allows choosing which DB server to use for an operation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplicaId {
+    /// The main server has write and read access.
+    Main,
+
+    /// A read replica identified by its index.
+    ReadOnly(usize),
+}
+
+impl fmt::Display for ReplicaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaId::Main => write!(f, "main"),
+            ReplicaId::ReadOnly(idx) => write!(f, "read_only_{}", idx),
+        }
+    }
+}
+
+/// A replica that can serve a query, together with the information a
+/// `ReplicaSelector` needs to decide whether it should
+#[derive(Clone)]
+pub struct ReplicaCandidate {
+    pub id: ReplicaId,
+    /// The weight the replica was configured with
+    pub weight: usize,
+    /// How long queries have had to wait for a connection from the
+    /// replica's pool recently
+    pub wait_stats: PoolWaitStats,
+}
+
+/// Decides which replica a query that can be served by any replica is sent
+/// to. Queries that need the main server, like subscriptions, do not go
+/// through the selector
+pub trait ReplicaSelector: Send + Sync + 'static {
+    /// Choose one of `candidates` for the query with `shape_hash`.
+    /// `round_robin` is the replica that weighted round-robin would pick
+    /// next. Returning a replica that is not one of `candidates` makes the
+    /// query fail
+    fn select(
+        &self,
+        shape_hash: u64,
+        candidates: &[ReplicaCandidate],
+        round_robin: ReplicaId,
+    ) -> ReplicaId;
+}
+
+/// Distribute queries across replicas by weighted round-robin, without
+/// looking at queries or the load on replicas
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RoundRobinReplicaSelector;
+
+impl ReplicaSelector for RoundRobinReplicaSelector {
+    fn select(&self, _: u64, _: &[ReplicaCandidate], round_robin: ReplicaId) -> ReplicaId {
+        round_robin
+    }
+}
+
 /// An entity operation that can be transacted into the store; as opposed to
 /// `EntityOperation`, we already know whether a `Set` should be an `Insert`
 /// or `Update`
@@ -257,6 +257,16 @@ pub trait QueryStoreManager: Send + Sync + 'static {
         target: QueryTarget,
         for_subscription: bool,
     ) -> Result<Arc<dyn QueryStore + Send + Sync>, QueryExecutionError>;
+
+    /// Get a new `QueryStore` for a query that is not part of a
+    /// subscription, and let `selector` choose the replica it is tied to.
+    /// `shape_hash` is the shape hash of the query
+    async fn select_query_store(
+        &self,
+        target: QueryTarget,
+        shape_hash: u64,
+        selector: Arc<dyn ReplicaSelector>,
+    ) -> Result<Arc<dyn QueryStore + Send + Sync>, QueryExecutionError>;
 }
 
 pub trait BlockStore: Send + Sync + 'static {
@@ -399,8 +409,20 @@ pub trait QueryStore: Send + Sync {
 
     async fn block_ptr(&self) -> Result<Option<BlockPtr>, StoreError>;
 
+    /// The head of the chain that the deployment is indexing, if the chain
+    /// store knows about it
+    async fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, StoreError>;
+
     fn block_number(&self, block_hash: &BlockHash) -> Result<Option<BlockNumber>, StoreError>;
 
+    /// The block at which the snapshot `id` of the deployment was taken, or
+    /// `None` if the store does not have such a snapshot. Queries that ask
+    /// for a snapshot read the deployment as of that block. Stores that do
+    /// not keep snapshots never have one
+    fn snapshot_block(&self, _id: &str) -> Result<Option<BlockPtr>, StoreError> {
+        Ok(None)
+    }
+
     fn wait_stats(&self) -> Result<PoolWaitStats, StoreError>;
 
     /// If `block` is `None`, assumes the latest block.
@@ -412,8 +434,18 @@ pub trait QueryStore: Send + Sync {
 
     fn api_schema(&self) -> Result<Arc<ApiSchema>, QueryExecutionError>;
 
+    /// Identifies the API schema that `api_schema` returns: the deployment
+    /// together with the id of the copy of it that this store reads. The
+    /// id changes when the deployment is redeployed or when queries switch
+    /// to another copy of it, so that a schema cached under an older
+    /// version is no longer used
+    fn api_schema_version(&self) -> (DeploymentHash, DeploymentId);
+
     fn network_name(&self) -> &str;
 
+    /// The database replica that this store sends its queries to
+    fn replica_id(&self) -> ReplicaId;
+
     /// A permit should be acquired before starting query execution.
     async fn query_permit(&self) -> Result<tokio::sync::OwnedSemaphorePermit, StoreError>;
 }
@@ -2,10 +2,10 @@
 
 use prometheus::core::GenericCounter;
 use rand::{prelude::Rng, thread_rng};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::components::metrics::{Counter, Gauge, MetricsRegistry};
 use crate::components::store::PoolWaitStats;
@@ -60,6 +60,20 @@ impl QueryEffort {
         let query_effort = inner.effort.get(&shape_hash).map(|stats| stats.duration());
         (query_effort, total_effort)
     }
+
+    /// The effort for every query shape we have measurements for, and the
+    /// total effort. Shapes whose measurements have all left the window
+    /// are omitted
+    pub fn all_efforts(&self) -> (Vec<(u64, Duration)>, Duration) {
+        let inner = self.inner.read().unwrap();
+        let efforts = inner
+            .effort
+            .iter()
+            .map(|(shape_hash, stats)| (*shape_hash, stats.duration()))
+            .filter(|(_, effort)| !effort.is_zero())
+            .collect();
+        (efforts, inner.total.duration())
+    }
 }
 
 impl QueryEffortInner {
@@ -84,6 +98,93 @@ impl QueryEffortInner {
     }
 }
 
+/// The longest query text that `LoadManager` keeps as the sample for a
+/// query shape; longer texts are cut off
+const MAX_QUERY_SAMPLE_LEN: usize = 2048;
+
+/// The first `MAX_QUERY_SAMPLE_LEN` bytes of `query`, cut at a character
+/// boundary
+fn query_sample(query: &str) -> String {
+    let mut end = query.len().min(MAX_QUERY_SAMPLE_LEN);
+    while !query.is_char_boundary(end) {
+        end -= 1;
+    }
+    query[..end].to_string()
+}
+
+/// Whether a query that was jailed manually until `until` is still in
+/// jail at `now`
+fn still_jailed(until: &Option<Instant>, now: Instant) -> bool {
+    until.map_or(true, |until| until > now)
+}
+
+/// How long clients should wait before retrying a query that was declined
+/// because of an overload where connections had to wait `wait` on average.
+/// That is `GRAPH_LOAD_BIN_SIZE` when the wait is at most
+/// `load_threshold`, and grows in proportion to how far the wait is
+/// above the threshold, up to `GRAPH_LOAD_WINDOW_SIZE`. The result is
+/// rounded up to whole seconds since that is what `Retry-After` uses
+fn retry_after(wait: Duration, load_threshold: Duration) -> Duration {
+    let overload = wait.as_secs_f64() / load_threshold.as_secs_f64();
+    let retry_after = ENV_VARS
+        .load_bin_size
+        .mul_f64(overload.max(1.0))
+        .min(ENV_VARS.load_window_size);
+    Duration::from_secs(retry_after.as_secs_f64().ceil() as u64)
+}
+
+/// The largest `load_threshold` that can be set at runtime. Connections
+/// that wait longer than this on average mean that queries are already
+/// failing for most clients
+pub const MAX_LOAD_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// The thresholds with which `LoadManager::decide` detects an overload and
+/// jails queries. They start out as `GRAPH_LOAD_THRESHOLD` and
+/// `GRAPH_LOAD_JAIL_THRESHOLD` and can be changed while the process is
+/// running with `LoadManager::set_thresholds`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadThresholds {
+    /// The average wait for a connection above which we consider the
+    /// system overloaded; zero turns load management off
+    pub load_threshold: Duration,
+    /// The fraction of the total effort above which a query gets jailed
+    /// during an overload; `None` means that queries are never jailed
+    pub jail_threshold: Option<f64>,
+}
+
+impl LoadThresholds {
+    fn from_env() -> Self {
+        LoadThresholds {
+            load_threshold: ENV_VARS.load_threshold,
+            jail_threshold: ENV_VARS.load_jail_threshold,
+        }
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.load_threshold.is_zero()
+    }
+
+    /// Check that the thresholds are sensible values for updating them at
+    /// runtime. Load management can only be turned off with the
+    /// environment variable
+    fn validate(&self) -> Result<(), String> {
+        if self.load_threshold.is_zero() || self.load_threshold > MAX_LOAD_THRESHOLD {
+            return Err(format!(
+                "the load threshold must be more than 0ms and at most {}ms, but is {}ms",
+                MAX_LOAD_THRESHOLD.as_millis(),
+                self.load_threshold.as_millis()
+            ));
+        }
+        match self.jail_threshold {
+            Some(jail) if !(jail > 0.0 && jail <= 1.0) => Err(format!(
+                "the jail threshold must be more than 0 and at most 1, but is {}",
+                jail
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// What to log about the state we are currently in
 enum KillStateLogEvent {
     /// Overload is starting right now
@@ -163,29 +264,159 @@ impl KillState {
     }
 }
 
+/// Why the load manager declined to run a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedReason {
+    /// An operator jailed the query with `LoadManager::jail`
+    ManuallyJailed,
+    /// The query is one of the statically configured blocked queries
+    Blocked,
+    /// The query caused more than `GRAPH_LOAD_JAIL_THRESHOLD` of the effort,
+    /// scaled by the budget of its lane, while the system was overloaded,
+    /// either just now or earlier
+    Jailed,
+    /// The system as a whole is overloaded, and the query was picked at
+    /// random to be shed, with a probability proportional to its effort
+    Overloaded,
+}
+
+impl ShedReason {
+    /// A short name for the reason, suitable for use in metrics
+    pub fn as_str(&self) -> &'static str {
+        use ShedReason::*;
+        match self {
+            ManuallyJailed => "manually_jailed",
+            Blocked => "blocked",
+            Jailed => "jailed",
+            Overloaded => "overloaded",
+        }
+    }
+
+    /// Whether the reason is specific to the shape of the query, and it
+    /// will therefore be declined again. Queries that are shed because
+    /// the system is overloaded might succeed if they are retried
+    pub fn is_per_shape(&self) -> bool {
+        !matches!(self, ShedReason::Overloaded)
+    }
+}
+
 /// Indicate what the load manager wants query execution to do with a query
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Decision {
     /// Proceed with executing the query
     Proceed,
-    /// The query is too expensive and should not be executed
-    TooExpensive,
-    /// The service is overloaded, and we should not execute the query
-    /// right now
-    Throttle,
+    /// Do not execute the query
+    Shed {
+        reason: ShedReason,
+        /// The fraction of the total effort in the current window that
+        /// went into queries of this shape, if we know it
+        effort: Option<f64>,
+        /// What `effort` was compared to in order to decline the query:
+        /// `GRAPH_LOAD_JAIL_THRESHOLD` times the budget of the query's lane
+        /// when the query was jailed just now, and the current kill rate
+        /// divided by that budget when the system is overloaded
+        threshold: Option<f64>,
+        /// How long clients should wait before they try the query again.
+        /// Only set when the query was declined because of an overload,
+        /// since queries declined for their shape will be declined again
+        retry_after: Option<Duration>,
+    },
 }
 
 impl Decision {
+    /// A short name for the decision, suitable for use in metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Proceed => "proceed",
+            Decision::Shed { reason, .. } => reason.as_str(),
+        }
+    }
+
     pub fn to_result(self) -> Result<(), QueryExecutionError> {
-        use Decision::*;
         match self {
-            Proceed => Ok(()),
-            TooExpensive => Err(QueryExecutionError::TooExpensive),
-            Throttle => Err(QueryExecutionError::Throttled),
+            Decision::Proceed => Ok(()),
+            Decision::Shed { reason, .. } if reason.is_per_shape() => {
+                Err(QueryExecutionError::TooExpensive)
+            }
+            Decision::Shed { retry_after, .. } => Err(QueryExecutionError::Throttled {
+                retry_after: retry_after.unwrap_or(ENV_VARS.load_bin_size),
+            }),
         }
     }
 }
 
+/// A query that the `LoadManager` declined to run, as reported by
+/// `LoadManager::recent_sheds`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShedQuery {
+    pub time: SystemTime,
+    pub shape_hash: u64,
+    /// The lane of the query as it is reported in metrics
+    pub lane: String,
+    pub reason: ShedReason,
+    pub effort: Option<f64>,
+    pub threshold: Option<f64>,
+}
+
+/// A query shape that the `LoadManager` currently refuses to run, as
+/// reported by `LoadManager::jailed`
+#[derive(Clone, Debug, PartialEq)]
+pub struct JailedQuery {
+    pub shape_hash: u64,
+    /// Whether an operator jailed the query with `LoadManager::jail`, as
+    /// opposed to the `LoadManager` jailing it during an overload
+    pub manual: bool,
+    /// How much longer the query stays in jail, or `None` if it stays
+    /// there until it is unjailed
+    pub remaining: Option<Duration>,
+    /// The effort spent on the query in the current window, if we have
+    /// seen it recently
+    pub effort: Option<Duration>,
+    /// The effort spent on all queries in the current window
+    pub total_effort: Duration,
+}
+
+/// The effort spent on a query shape, as reported by
+/// `LoadManager::effort_snapshot`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeEffort {
+    pub shape_hash: u64,
+    /// The effort spent on the query in the current window, or `None` if
+    /// we have not seen it recently
+    pub effort: Option<Duration>,
+    /// The effort spent on all queries in the current window
+    pub total_effort: Duration,
+    /// Whether the query is in jail, either because an operator put it
+    /// there or because of the effort it caused during an overload
+    pub jailed: bool,
+    /// The text of one of the queries with this shape, cut off after
+    /// `MAX_QUERY_SAMPLE_LEN` bytes
+    pub query: Option<String>,
+}
+
+/// The health of the node as far as the `LoadManager` can tell, as
+/// reported by `LoadManager::health`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadHealth {
+    /// The fraction of queries that were shed in the current window
+    pub shed_rate: f64,
+    /// The average time queries waited for a database connection in the
+    /// current window
+    pub wait_avg: Option<Duration>,
+    /// Whether `shed_rate` exceeds `ENV_VARS.load_degraded_shed_rate`
+    pub degraded: bool,
+}
+
+/// The decisions of `LoadManager::decide` over the current window
+#[derive(Default)]
+struct RecentLoad {
+    /// One second for every query that was shed, zero for every query
+    /// that was run
+    sheds: MovingStats,
+    /// The average wait for database connections at every decision
+    waits: MovingStats,
+}
+
 pub struct LoadManager {
     logger: Logger,
     effort: QueryEffort,
@@ -193,11 +424,30 @@ pub struct LoadManager {
     /// configuration
     blocked_queries: HashSet<u64>,
     /// List of query shapes that have caused more than `JAIL_THRESHOLD`
-    /// proportion of the work while the system was overloaded. Currently,
-    /// there is no way for a query to get out of jail other than
-    /// restarting the process
-    jailed_queries: RwLock<HashSet<u64>>,
+    /// proportion of the work while the system was overloaded, together
+    /// with the largest lane budget that the query exceeded. Queries in
+    /// lanes with a larger budget are not affected by the jail. Queries
+    /// only get out of jail when they are unjailed with `unjail` or when
+    /// the process is restarted
+    jailed_queries: RwLock<HashMap<u64, f64>>,
+    /// Query shapes that an operator jailed with `jail`, and when they
+    /// get out of jail again; `None` if they stay in jail until they are
+    /// unjailed
+    manually_jailed: RwLock<HashMap<u64, Option<Instant>>>,
+    /// The text of the first query we saw for each query shape, so that
+    /// `effort_snapshot` can show what a shape looks like. Samples for
+    /// shapes that are neither jailed nor have effort in the current
+    /// window are pruned once every `GRAPH_LOAD_BIN_SIZE`
+    query_samples: RwLock<HashMap<u64, String>>,
+    /// When we last pruned `query_samples`
+    samples_pruned: Mutex<Instant>,
+    /// The most recent queries we declined to run, oldest first. At most
+    /// `ENV_VARS.load_shed_log_size` are kept
+    recent_sheds: Mutex<VecDeque<ShedQuery>>,
     kill_state: RwLock<KillState>,
+    thresholds: RwLock<LoadThresholds>,
+    /// What `decide` decided recently, for `health`
+    recent_load: RwLock<RecentLoad>,
     effort_gauge: Box<Gauge>,
     query_counters: HashMap<CacheStatus, Counter>,
     kill_rate_gauge: Box<Gauge>,
@@ -256,8 +506,14 @@ impl LoadManager {
             logger,
             effort: QueryEffort::default(),
             blocked_queries,
-            jailed_queries: RwLock::new(HashSet::new()),
+            jailed_queries: RwLock::new(HashMap::new()),
+            manually_jailed: RwLock::new(HashMap::new()),
+            query_samples: RwLock::new(HashMap::new()),
+            samples_pruned: Mutex::new(Instant::now()),
+            recent_sheds: Mutex::new(VecDeque::new()),
             kill_state: RwLock::new(KillState::new()),
+            thresholds: RwLock::new(LoadThresholds::from_env()),
+            recent_load: RwLock::new(RecentLoad::default()),
             effort_gauge,
             query_counters,
             kill_rate_gauge,
@@ -271,11 +527,269 @@ impl LoadManager {
         self.query_counters
             .get(&cache_status)
             .map(GenericCounter::inc);
-        if !ENV_VARS.load_management_is_disabled() {
+        if !self.thresholds().is_disabled() {
             self.effort.add(shape_hash, duration, &self.effort_gauge);
         }
     }
 
+    /// The thresholds that `decide` currently uses
+    pub fn thresholds(&self) -> LoadThresholds {
+        *self.thresholds.read().unwrap()
+    }
+
+    /// Replace the thresholds that `decide` uses, starting with the next
+    /// call. Setting a load threshold turns load management on if it was
+    /// turned off with `GRAPH_LOAD_THRESHOLD`
+    pub fn set_thresholds(&self, thresholds: LoadThresholds) -> Result<(), String> {
+        thresholds.validate()?;
+        let old = std::mem::replace(&mut *self.thresholds.write().unwrap(), thresholds);
+        warn!(self.logger, "Changing load thresholds";
+            "old_load_threshold_ms" => old.load_threshold.as_millis(),
+            "load_threshold_ms" => thresholds.load_threshold.as_millis(),
+            "old_jail_threshold" => format!("{:?}", old.jail_threshold),
+            "jail_threshold" => format!("{:?}", thresholds.jail_threshold));
+        Ok(())
+    }
+
+    /// Refuse to run queries with `shape_hash` for the next `ttl`,
+    /// regardless of how busy the system is. Jailing a query that is
+    /// already jailed replaces its `ttl`. A `ttl` that is too long to
+    /// ever run out keeps the query in jail until it is unjailed
+    pub fn jail(&self, shape_hash: u64, ttl: Duration) {
+        warn!(self.logger, "Jailing query manually";
+            "shape_hash" => shape_hash,
+            "ttl_s" => ttl.as_secs());
+        self.manually_jailed
+            .write()
+            .unwrap()
+            .insert(shape_hash, Instant::now().checked_add(ttl));
+    }
+
+    /// Let queries with `shape_hash` run again, no matter whether they
+    /// were jailed manually or because of the effort they caused. Return
+    /// whether the query was jailed
+    pub fn unjail(&self, shape_hash: u64) -> bool {
+        let manual = self
+            .manually_jailed
+            .write()
+            .unwrap()
+            .remove(&shape_hash)
+            .is_some();
+        let auto = self
+            .jailed_queries
+            .write()
+            .unwrap()
+            .remove(&shape_hash)
+            .is_some();
+        if manual || auto {
+            info!(self.logger, "Unjailing query"; "shape_hash" => shape_hash);
+        }
+        manual || auto
+    }
+
+    /// List the queries that are currently in jail together with the
+    /// effort they caused, most expensive ones first. Queries that are in
+    /// jail both manually and automatically are only listed as manually
+    /// jailed
+    pub fn jailed(&self) -> Vec<JailedQuery> {
+        let now = Instant::now();
+        self.manually_jailed
+            .write()
+            .unwrap()
+            .retain(|_, until| still_jailed(until, now));
+
+        let manual = self
+            .manually_jailed
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(shape_hash, until)| {
+                let remaining = until.map(|until| until.duration_since(now));
+                (*shape_hash, true, remaining)
+            })
+            .collect::<Vec<_>>();
+        let manual_hashes = manual
+            .iter()
+            .map(|(shape_hash, _, _)| *shape_hash)
+            .collect::<HashSet<_>>();
+        let auto = self
+            .jailed_queries
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|shape_hash| !manual_hashes.contains(shape_hash))
+            .map(|shape_hash| (*shape_hash, false, None))
+            .collect::<Vec<_>>();
+
+        let mut jailed = manual
+            .into_iter()
+            .chain(auto)
+            .map(|(shape_hash, manual, remaining)| {
+                let (effort, total_effort) = self.effort.current_effort(shape_hash);
+                JailedQuery {
+                    shape_hash,
+                    manual,
+                    remaining,
+                    effort,
+                    total_effort,
+                }
+            })
+            .collect::<Vec<_>>();
+        jailed.sort_by(|a, b| b.effort.cmp(&a.effort));
+        jailed
+    }
+
+    /// Remember that the query `shape_hash` in `lane` was not run because
+    /// of `decision` so that it shows up in `recent_sheds`. Only the last
+    /// `ENV_VARS.load_shed_log_size` such queries are remembered
+    pub fn record_shed(&self, shape_hash: u64, lane: Option<&str>, decision: Decision) {
+        let (reason, effort, threshold) = match decision {
+            Decision::Proceed => return,
+            Decision::Shed {
+                reason,
+                effort,
+                threshold,
+                ..
+            } => (reason, effort, threshold),
+        };
+        let size = ENV_VARS.load_shed_log_size;
+        if size == 0 {
+            return;
+        }
+        let mut recent = self.recent_sheds.lock().unwrap();
+        while recent.len() >= size {
+            recent.pop_front();
+        }
+        recent.push_back(ShedQuery {
+            time: SystemTime::now(),
+            shape_hash,
+            lane: ENV_VARS.load_lanes.label(lane).to_string(),
+            reason,
+            effort,
+            threshold,
+        });
+    }
+
+    /// The most recent queries that were not run, oldest first
+    pub fn recent_sheds(&self) -> Vec<ShedQuery> {
+        self.recent_sheds.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// List the query shapes that we spent effort on in the current
+    /// window or that are in jail, most expensive ones first, together
+    /// with a sample of their query text. At most `limit` shapes are
+    /// listed. Effort is only tracked when load management is enabled
+    pub fn effort_snapshot(&self, limit: usize) -> Vec<ShapeEffort> {
+        let now = Instant::now();
+        let (efforts, total_effort) = self.effort.all_efforts();
+        let mut efforts = efforts
+            .into_iter()
+            .map(|(shape_hash, effort)| (shape_hash, Some(effort)))
+            .collect::<HashMap<_, _>>();
+
+        let manually_jailed = self.manually_jailed.read().unwrap();
+        let jailed_queries = self.jailed_queries.read().unwrap();
+        let jailed = manually_jailed
+            .iter()
+            .filter(|(_, until)| still_jailed(until, now))
+            .map(|(shape_hash, _)| *shape_hash)
+            .chain(jailed_queries.keys().cloned())
+            .collect::<HashSet<_>>();
+        for shape_hash in &jailed {
+            efforts.entry(*shape_hash).or_insert(None);
+        }
+
+        let mut efforts = efforts.into_iter().collect::<Vec<_>>();
+        efforts.sort_by(|(hash_a, a), (hash_b, b)| b.cmp(a).then(hash_a.cmp(hash_b)));
+        efforts.truncate(limit);
+
+        let samples = self.query_samples.read().unwrap();
+        efforts
+            .into_iter()
+            .map(|(shape_hash, effort)| ShapeEffort {
+                shape_hash,
+                effort,
+                total_effort,
+                jailed: jailed.contains(&shape_hash),
+                query: samples.get(&shape_hash).cloned(),
+            })
+            .collect()
+    }
+
+    /// Remember `query` as the sample text for `shape_hash` unless we
+    /// already have one
+    fn remember_query(&self, shape_hash: u64, query: &str) {
+        if self.query_samples.read().unwrap().contains_key(&shape_hash) {
+            return;
+        }
+        self.prune_query_samples();
+        self.query_samples
+            .write()
+            .unwrap()
+            .entry(shape_hash)
+            .or_insert_with(|| query_sample(query));
+    }
+
+    /// Forget the samples for shapes that have left the effort window and
+    /// are not jailed, unless we already did that less than
+    /// `GRAPH_LOAD_BIN_SIZE` ago. A shape that shows up again gets a new
+    /// sample
+    fn prune_query_samples(&self) {
+        let now = Instant::now();
+        {
+            let mut pruned = self.samples_pruned.lock().unwrap();
+            if now.saturating_duration_since(*pruned) < ENV_VARS.load_bin_size {
+                return;
+            }
+            *pruned = now;
+        }
+
+        let (efforts, _) = self.effort.all_efforts();
+        let mut keep = efforts
+            .into_iter()
+            .map(|(shape_hash, _)| shape_hash)
+            .collect::<HashSet<_>>();
+        keep.extend(self.jailed_queries.read().unwrap().keys());
+        keep.extend(
+            self.manually_jailed
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, until)| still_jailed(until, now))
+                .map(|(shape_hash, _)| *shape_hash),
+        );
+        self.query_samples
+            .write()
+            .unwrap()
+            .retain(|shape_hash, _| keep.contains(shape_hash));
+    }
+
+    /// The fraction of the total effort in the current window that went
+    /// into queries with `shape_hash`, if we have seen such queries
+    fn effort_share(&self, shape_hash: u64) -> Option<f64> {
+        let (query_effort, total_effort) = self.effort.current_effort(shape_hash);
+        match query_effort {
+            Some(query_effort) if !total_effort.is_zero() => {
+                Some(query_effort.as_millis() as f64 / total_effort.as_millis() as f64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether an operator jailed `shape_hash` and the jail time has not
+    /// run out yet
+    fn manually_jailed(&self, shape_hash: u64) -> bool {
+        let until = match self.manually_jailed.read().unwrap().get(&shape_hash) {
+            Some(until) => *until,
+            None => return false,
+        };
+        if still_jailed(&until, Instant::now()) {
+            return true;
+        }
+        self.manually_jailed.write().unwrap().remove(&shape_hash);
+        false
+    }
+
     /// Decide whether we should decline to run the query with this
     /// `ShapeHash`. This is the heart of reacting to overload situations.
     ///
@@ -301,46 +815,181 @@ impl LoadManager {
     ///
     /// We detect whether we are in an overloaded situation by looking at
     /// the average wait time for connection checkouts. If that exceeds
-    /// [`ENV_VARS.load_threshold`], we consider ourselves to be in an overload
-    /// situation.
+    /// the load threshold from [`LoadThresholds`], we consider ourselves to
+    /// be in an overload situation.
     ///
     /// There are several criteria that will lead to us declining to run
     /// a query with a certain `ShapeHash`:
-    /// 1) If the query is one of the configured `blocked_queries`, we will
+    /// 1) If an operator jailed the query with `jail`, we will decline
+    ///    until its jail time runs out or it is unjailed
+    /// 2) If the query is one of the configured `blocked_queries`, we will
     ///    always decline
-    /// 2) If a query, during an overload situation, causes more than
+    /// 3) If a query, during an overload situation, causes more than
     ///    `JAIL_THRESHOLD` fraction of the total query effort, we will
-    ///    refuse to run this query again for the lifetime of the process
-    /// 3) During an overload situation, we step a `kill_rate` from 0 to 1,
+    ///    refuse to run this query again until it is unjailed or the
+    ///    process is restarted
+    /// 4) During an overload situation, we step a `kill_rate` from 0 to 1,
     ///    roughly in steps of `KILL_RATE_STEP`, though with an eye towards
     ///    not hitting a `kill_rate` of 1 too soon. We will decline to run
     ///    queries randomly with a probability of
     ///    kill_rate * query_effort / total_effort
     ///
-    /// If [`ENV_VARS.load_threshold`] is set to 0, we bypass all this logic,
-    /// and only ever decline to run statically configured queries (1). In that
-    /// case, we also do not take any locks when asked to update statistics,
-    /// or to check whether we are overloaded; these operations amount to
-    /// noops.
-    pub fn decide(&self, wait_stats: &PoolWaitStats, shape_hash: u64, query: &str) -> Decision {
+    /// Queries in a priority `lane` that has a budget `b` configured in
+    /// [`ENV_VARS.load_lanes`] are treated more leniently in (3) and (4):
+    /// they are only jailed when they cause more than `b * JAIL_THRESHOLD`
+    /// of the effort, and are declined with `1/b` times the probability
+    /// from (4). A query that was jailed in one lane can therefore still
+    /// run in a lane with a larger budget. Queries without a lane have a
+    /// budget of 1.
+    ///
+    /// If the load threshold is 0, we bypass all this logic,
+    /// and only ever decline to run manually jailed (1) and statically
+    /// configured queries (2). In that case, the only lock we take is the
+    /// one for looking up manually jailed queries; updating statistics and
+    /// checking whether we are overloaded amount to noops.
+    pub fn decide(
+        &self,
+        wait_stats: &PoolWaitStats,
+        shape_hash: u64,
+        query: &str,
+        lane: Option<&str>,
+    ) -> Decision {
         use Decision::*;
 
+        let shed = |reason, effort, threshold| Shed {
+            reason,
+            effort,
+            threshold,
+            retry_after: None,
+        };
+
+        if self.manually_jailed(shape_hash) {
+            self.remember_query(shape_hash, query);
+            return shed(
+                ShedReason::ManuallyJailed,
+                self.effort_share(shape_hash),
+                None,
+            );
+        }
+        if self.blocked_queries.contains(&shape_hash) {
+            self.remember_query(shape_hash, query);
+            return shed(ShedReason::Blocked, self.effort_share(shape_hash), None);
+        }
+        let thresholds = self.thresholds();
+        if thresholds.is_disabled() {
+            return Proceed;
+        }
+        self.remember_query(shape_hash, query);
+
+        let decision = self.decide_under_load(wait_stats, shape_hash, query, lane, thresholds);
+        self.record_decision(wait_stats, &decision);
+        decision
+    }
+
+    /// What `decide` would decide for the query `shape_hash` in `lane`
+    /// right now, without any of its side effects: the query is neither
+    /// remembered nor jailed, the `kill_rate` stays the same, and the
+    /// decision is not recorded for `health`. Since `decide` declines
+    /// queries at random when the system is overloaded, only the queries
+    /// that it would decline for certain are reported as `Shed`
+    pub fn would_decide(
+        &self,
+        wait_stats: &PoolWaitStats,
+        shape_hash: u64,
+        lane: Option<&str>,
+    ) -> Decision {
+        use Decision::*;
+
+        let shed = |reason, effort, threshold| Shed {
+            reason,
+            effort,
+            threshold,
+            retry_after: None,
+        };
+
+        let manually_jailed = self
+            .manually_jailed
+            .read()
+            .unwrap()
+            .get(&shape_hash)
+            .map_or(false, |until| still_jailed(until, Instant::now()));
+        if manually_jailed {
+            return shed(
+                ShedReason::ManuallyJailed,
+                self.effort_share(shape_hash),
+                None,
+            );
+        }
         if self.blocked_queries.contains(&shape_hash) {
-            return TooExpensive;
+            return shed(ShedReason::Blocked, self.effort_share(shape_hash), None);
         }
-        if ENV_VARS.load_management_is_disabled() {
+        let thresholds = self.thresholds();
+        if thresholds.is_disabled() || ENV_VARS.load_simulate {
             return Proceed;
         }
 
-        if self.jailed_queries.read().unwrap().contains(&shape_hash) {
+        let budget = ENV_VARS.load_lanes.budget(lane);
+        let jailed = self
+            .jailed_queries
+            .read()
+            .unwrap()
+            .get(&shape_hash)
+            .map_or(false, |jailed_budget| budget <= *jailed_budget);
+        if jailed {
+            return shed(ShedReason::Jailed, self.effort_share(shape_hash), None);
+        }
+
+        // `decide_under_load` would jail the query now
+        let (overloaded, _) = self.overloaded(wait_stats, thresholds.load_threshold);
+        let (kill_rate, _) = self.kill_state();
+        if !overloaded && kill_rate == 0.0 {
+            return Proceed;
+        }
+        match (thresholds.jail_threshold, self.effort_share(shape_hash)) {
+            (Some(jail_threshold), Some(share)) if share > jail_threshold * budget => shed(
+                ShedReason::Jailed,
+                Some(share),
+                Some(jail_threshold * budget),
+            ),
+            _ => Proceed,
+        }
+    }
+
+    /// The part of `decide` that only applies when load management is
+    /// enabled, i.e., cases (3) and (4)
+    fn decide_under_load(
+        &self,
+        wait_stats: &PoolWaitStats,
+        shape_hash: u64,
+        query: &str,
+        lane: Option<&str>,
+        thresholds: LoadThresholds,
+    ) -> Decision {
+        use Decision::*;
+
+        let shed = |reason, effort, threshold| Shed {
+            reason,
+            effort,
+            threshold,
+            retry_after: None,
+        };
+
+        let budget = ENV_VARS.load_lanes.budget(lane);
+        let jailed = self
+            .jailed_queries
+            .read()
+            .unwrap()
+            .get(&shape_hash)
+            .map_or(false, |jailed_budget| budget <= *jailed_budget);
+        if jailed {
             return if ENV_VARS.load_simulate {
                 Proceed
             } else {
-                TooExpensive
+                shed(ShedReason::Jailed, self.effort_share(shape_hash), None)
             };
         }
 
-        let (overloaded, wait_ms) = self.overloaded(wait_stats);
+        let (overloaded, wait_ms) = self.overloaded(wait_stats, thresholds.load_threshold);
         let (kill_rate, last_update) = self.kill_state();
         if !overloaded && kill_rate == 0.0 {
             return Proceed;
@@ -362,7 +1011,8 @@ impl LoadManager {
         let total_effort = total_effort.as_millis() as f64;
 
         // When this variable is not set, we never jail any queries.
-        if let Some(jail_threshold) = ENV_VARS.load_jail_threshold {
+        if let Some(jail_threshold) = thresholds.jail_threshold {
+            let jail_threshold = jail_threshold * budget;
             if known_query && query_effort / total_effort > jail_threshold {
                 // Any single query that causes at least JAIL_THRESHOLD of the
                 // effort in an overload situation gets killed
@@ -371,12 +1021,22 @@ impl LoadManager {
                 "wait_ms" => wait_ms.as_millis(),
                 "query_effort_ms" => query_effort,
                 "total_effort_ms" => total_effort,
-                "ratio" => format!("{:.4}", query_effort/total_effort));
-                self.jailed_queries.write().unwrap().insert(shape_hash);
+                "ratio" => format!("{:.4}", query_effort/total_effort),
+                "lane" => ENV_VARS.load_lanes.label(lane));
+                self.jailed_queries
+                    .write()
+                    .unwrap()
+                    .entry(shape_hash)
+                    .and_modify(|jailed_budget| *jailed_budget = jailed_budget.max(budget))
+                    .or_insert(budget);
                 return if ENV_VARS.load_simulate {
                     Proceed
                 } else {
-                    TooExpensive
+                    shed(
+                        ShedReason::Jailed,
+                        Some(query_effort / total_effort),
+                        Some(jail_threshold),
+                    )
                 };
             }
         }
@@ -384,8 +1044,12 @@ impl LoadManager {
         // Kill random queries in case we have no queries, or not enough queries
         // that cause at least 20% of the effort
         let kill_rate = self.update_kill_rate(kill_rate, last_update, overloaded, wait_ms);
-        let decline =
-            thread_rng().gen_bool((kill_rate * query_effort / total_effort).min(1.0).max(0.0));
+        let lane_kill_rate = kill_rate / budget;
+        let decline = thread_rng().gen_bool(
+            (lane_kill_rate * query_effort / total_effort)
+                .min(1.0)
+                .max(0.0),
+        );
         if decline {
             if ENV_VARS.load_simulate {
                 debug!(self.logger, "Declining query";
@@ -396,16 +1060,66 @@ impl LoadManager {
                 );
                 return Proceed;
             } else {
-                return Throttle;
+                return Shed {
+                    reason: ShedReason::Overloaded,
+                    effort: known_query.then(|| query_effort / total_effort),
+                    threshold: Some(lane_kill_rate),
+                    retry_after: Some(retry_after(wait_ms, thresholds.load_threshold)),
+                };
             }
         }
         Proceed
     }
 
-    fn overloaded(&self, wait_stats: &PoolWaitStats) -> (bool, Duration) {
+    /// Remember `decision` and the current wait for database connections
+    /// for `health`. Since `health` only needs a sample of the decisions,
+    /// a decision is dropped rather than waiting for the lock when another
+    /// query is recording its decision or `health` is reading them at the
+    /// same time
+    fn record_decision(&self, wait_stats: &PoolWaitStats, decision: &Decision) {
+        let wait = wait_stats.read().unwrap().average();
+        // A query that we shed counts as one second and one that we run
+        // as zero so that the average is the fraction of queries we shed
+        let shed = match decision {
+            Decision::Proceed => Duration::ZERO,
+            Decision::Shed { .. } => Duration::from_secs(1),
+        };
+        let mut recent = match self.recent_load.try_write() {
+            Ok(recent) => recent,
+            Err(TryLockError::WouldBlock) => return,
+            Err(TryLockError::Poisoned(e)) => panic!("recent load lock is poisoned: {}", e),
+        };
+        recent.sheds.add(shed);
+        if let Some(wait) = wait {
+            recent.waits.add(wait);
+        }
+    }
+
+    /// Report how healthy the node is based on the decisions `decide`
+    /// made in the current window. The node is degraded when it shed
+    /// more than `ENV_VARS.load_degraded_shed_rate` of the queries.
+    /// Decisions are only tracked when load management is enabled, and
+    /// queries that were declined because they were blocked or jailed by
+    /// an operator do not count
+    pub fn health(&self) -> LoadHealth {
+        let mut recent = self.recent_load.write().unwrap();
+        recent.sheds.expire();
+        recent.waits.expire();
+        let shed_rate = recent
+            .sheds
+            .average()
+            .map_or(0.0, |average| average.as_secs_f64());
+        LoadHealth {
+            shed_rate,
+            wait_avg: recent.waits.average(),
+            degraded: shed_rate > ENV_VARS.load_degraded_shed_rate,
+        }
+    }
+
+    fn overloaded(&self, wait_stats: &PoolWaitStats, load_threshold: Duration) -> (bool, Duration) {
         let store_avg = wait_stats.read().unwrap().average();
         let overloaded = store_avg
-            .map(|average| average > ENV_VARS.load_threshold)
+            .map(|average| average > load_threshold)
             .unwrap_or(false);
         (overloaded, store_avg.unwrap_or(Duration::ZERO))
     }
@@ -497,8 +1211,244 @@ impl QueryLoadManager for LoadManager {
         self.query_counters
             .get(&cache_status)
             .map(|counter| counter.inc());
-        if !ENV_VARS.load_management_is_disabled() {
+        if !self.thresholds().is_disabled() {
             self.effort.add(shape_hash, duration, &self.effort_gauge);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::metrics::{
+        Collector, CounterVec, GaugeVec, HistogramOpts, HistogramVec, Opts, PrometheusError,
+    };
+
+    /// A registry that hands out metrics without registering them anywhere
+    struct TestRegistry;
+
+    impl MetricsRegistry for TestRegistry {
+        fn register(&self, _name: &str, _c: Box<dyn Collector>) {}
+
+        fn unregister(&self, _metric: Box<dyn Collector>) {}
+
+        fn global_counter(
+            &self,
+            name: &str,
+            help: &str,
+            const_labels: HashMap<String, String>,
+        ) -> Result<Counter, PrometheusError> {
+            Counter::with_opts(Opts::new(name, help).const_labels(const_labels))
+        }
+
+        fn global_counter_vec(
+            &self,
+            name: &str,
+            help: &str,
+            variable_labels: &[&str],
+        ) -> Result<CounterVec, PrometheusError> {
+            CounterVec::new(Opts::new(name, help), variable_labels)
+        }
+
+        fn global_deployment_counter_vec(
+            &self,
+            name: &str,
+            help: &str,
+            subgraph: &str,
+            variable_labels: &[&str],
+        ) -> Result<CounterVec, PrometheusError> {
+            let opts = Opts::new(name, help).const_label("deployment", subgraph);
+            CounterVec::new(opts, variable_labels)
+        }
+
+        fn global_gauge(
+            &self,
+            name: &str,
+            help: &str,
+            const_labels: HashMap<String, String>,
+        ) -> Result<Gauge, PrometheusError> {
+            Gauge::with_opts(Opts::new(name, help).const_labels(const_labels))
+        }
+
+        fn global_gauge_vec(
+            &self,
+            name: &str,
+            help: &str,
+            variable_labels: &[&str],
+        ) -> Result<GaugeVec, PrometheusError> {
+            GaugeVec::new(Opts::new(name, help), variable_labels)
+        }
+
+        fn global_histogram_vec(
+            &self,
+            name: &str,
+            help: &str,
+            variable_labels: &[&str],
+        ) -> Result<HistogramVec, PrometheusError> {
+            HistogramVec::new(HistogramOpts::new(name, help), variable_labels)
+        }
+    }
+
+    fn load_manager(blocked_queries: Vec<Arc<q::Document>>) -> LoadManager {
+        let logger = Logger::root(slog::Discard, o!());
+        LoadManager::new(&logger, blocked_queries, Arc::new(TestRegistry))
+    }
+
+    #[test]
+    fn thresholds_can_be_changed() {
+        let load_manager = load_manager(vec![]);
+        let thresholds = LoadThresholds {
+            load_threshold: Duration::from_millis(250),
+            jail_threshold: Some(0.2),
+        };
+        load_manager.set_thresholds(thresholds).unwrap();
+        assert_eq!(thresholds, load_manager.thresholds());
+
+        // Invalid thresholds are rejected and leave the current ones in place
+        for invalid in [
+            LoadThresholds {
+                load_threshold: Duration::ZERO,
+                jail_threshold: None,
+            },
+            LoadThresholds {
+                load_threshold: Duration::from_secs(3600),
+                jail_threshold: None,
+            },
+            LoadThresholds {
+                load_threshold: Duration::from_millis(250),
+                jail_threshold: Some(1.5),
+            },
+        ] {
+            assert!(load_manager.set_thresholds(invalid).is_err());
+        }
+        assert_eq!(thresholds, load_manager.thresholds());
+    }
+
+    #[test]
+    fn reports_degraded_health() {
+        let load_manager = load_manager(vec![]);
+        load_manager
+            .set_thresholds(LoadThresholds {
+                load_threshold: Duration::from_millis(10),
+                jail_threshold: Some(0.5),
+            })
+            .unwrap();
+        let health = load_manager.health();
+        assert_eq!(0.0, health.shed_rate);
+        assert_eq!(None, health.wait_avg);
+        assert!(!health.degraded);
+
+        // The only query we have seen caused all the effort while connections
+        // are scarce, so it gets jailed and the node sheds all of its queries
+        let wait_stats = Arc::new(RwLock::new(MovingStats::default()));
+        wait_stats.write().unwrap().add(Duration::from_secs(1));
+        load_manager.record_work(1, Duration::from_secs(1), CacheStatus::Insert);
+        let decision = load_manager.decide(&wait_stats, 1, "query { musicians { id } }", None);
+        assert_eq!("jailed", decision.as_str());
+
+        let health = load_manager.health();
+        assert_eq!(1.0, health.shed_rate);
+        assert_eq!(Some(Duration::from_secs(1)), health.wait_avg);
+        assert!(health.degraded);
+    }
+
+    #[test]
+    fn jails_queries_manually() {
+        const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+        let load_manager = load_manager(vec![]);
+        let wait_stats = PoolWaitStats::default();
+        let shape_hash = shape_hash(&graphql_parser::parse_query(QUERY).unwrap().into_static());
+        let decide = || load_manager.decide(&wait_stats, shape_hash, QUERY, None);
+
+        load_manager.jail(shape_hash, Duration::from_secs(300));
+        assert!(matches!(
+            decide(),
+            Decision::Shed {
+                reason: ShedReason::ManuallyJailed,
+                ..
+            }
+        ));
+        let jailed = load_manager.jailed();
+        assert_eq!(1, jailed.len());
+        assert_eq!(shape_hash, jailed[0].shape_hash);
+        assert!(jailed[0].manual);
+        assert!(jailed[0].remaining.unwrap() <= Duration::from_secs(300));
+
+        assert!(load_manager.unjail(shape_hash));
+        assert!(!load_manager.unjail(shape_hash));
+        assert!(load_manager.jailed().is_empty());
+        assert_eq!(Decision::Proceed, decide());
+
+        // Queries leave jail once their time is up
+        load_manager.jail(shape_hash, Duration::ZERO);
+        assert!(load_manager.jailed().is_empty());
+        assert_eq!(Decision::Proceed, decide());
+
+        // A query that is also jailed automatically is only listed once
+        load_manager
+            .set_thresholds(LoadThresholds {
+                load_threshold: Duration::from_millis(10),
+                jail_threshold: Some(0.5),
+            })
+            .unwrap();
+        wait_stats.write().unwrap().add(Duration::from_secs(1));
+        load_manager.record_work(shape_hash, Duration::from_secs(1), CacheStatus::Insert);
+        assert_eq!("jailed", decide().as_str());
+        assert!(!load_manager.jailed()[0].manual);
+        load_manager.jail(shape_hash, Duration::from_secs(300));
+        let jailed = load_manager.jailed();
+        assert_eq!(1, jailed.len());
+        assert_eq!(shape_hash, jailed[0].shape_hash);
+        assert!(jailed[0].manual);
+    }
+
+    #[test]
+    fn remembers_shed_queries() {
+        const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+        let query = graphql_parser::parse_query(QUERY).unwrap().into_static();
+        let shape_hash = shape_hash(&query);
+        let load_manager = load_manager(vec![Arc::new(query)]);
+        let wait_stats = PoolWaitStats::default();
+
+        assert!(load_manager.recent_sheds().is_empty());
+        for _ in 0..2 {
+            let decision = load_manager.decide(&wait_stats, shape_hash, QUERY, None);
+            assert_eq!("blocked", decision.as_str());
+            load_manager.record_shed(shape_hash, None, decision);
+        }
+        load_manager.record_shed(shape_hash, None, Decision::Proceed);
+
+        let sheds = load_manager.recent_sheds();
+        assert_eq!(2, sheds.len());
+        for shed in &sheds {
+            assert_eq!(shape_hash, shed.shape_hash);
+            assert_eq!(ShedReason::Blocked, shed.reason);
+            assert_eq!(None, shed.threshold);
+        }
+        assert!(sheds[0].time <= sheds[1].time);
+    }
+
+    #[test]
+    fn effort_snapshot() {
+        const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+        let load_manager = load_manager(vec![]);
+        let wait_stats = PoolWaitStats::default();
+        let shape_hash = shape_hash(&graphql_parser::parse_query(QUERY).unwrap().into_static());
+
+        load_manager.jail(shape_hash, Duration::from_secs(300));
+        let decision = load_manager.decide(&wait_stats, shape_hash, QUERY, None);
+        assert_eq!("manually_jailed", decision.as_str());
+
+        let snapshot = load_manager.effort_snapshot(10);
+        let effort = snapshot
+            .iter()
+            .find(|effort| effort.shape_hash == shape_hash)
+            .expect("the jailed query is in the snapshot");
+        assert!(effort.jailed);
+        assert!(effort.query.as_ref().unwrap().contains("musicians"));
+        assert!(load_manager.effort_snapshot(0).is_empty());
+    }
+}
@@ -2,20 +2,71 @@
 //! the query. The shape hash will be the same for two instances of a query
 //! that are deemed identical except for unimportant details. Those details
 //! are any values used with filters, and any differences in the query
-//! name or response keys
+//! name or response keys. Unless `GRAPH_GRAPHQL_DISABLE_SHAPE_NORMALIZATION`
+//! is set, the order of definitions, of the selections in a selection set
+//! and of the arguments of a field does not matter either
 
+use crate::env::ENV_VARS;
 use crate::prelude::{q, s};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
-type ShapeHasher = DefaultHasher;
+pub struct ShapeHasher {
+    inner: DefaultHasher,
+    /// Whether to ignore the order of definitions, selections and
+    /// arguments
+    normalize: bool,
+}
+
+impl ShapeHasher {
+    fn new(normalize: bool) -> Self {
+        ShapeHasher {
+            inner: DefaultHasher::new(),
+            normalize,
+        }
+    }
+
+    /// Hash `items` in a way that does not depend on their order if we
+    /// normalize, and in the order in which they appear otherwise
+    fn hash_items<'a, T: ShapeHash + 'a>(&mut self, items: impl Iterator<Item = &'a T>) {
+        if !self.normalize {
+            for item in items {
+                item.shape_hash(self);
+            }
+            return;
+        }
+        let mut hashes = items
+            .map(|item| {
+                let mut hasher = ShapeHasher::new(true);
+                item.shape_hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.hash(self);
+    }
+}
+
+impl Hasher for ShapeHasher {
+    fn finish(&self) -> u64 {
+        self.inner.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.inner.write(bytes)
+    }
+}
 
 pub trait ShapeHash {
     fn shape_hash(&self, hasher: &mut ShapeHasher);
 }
 
 pub fn shape_hash(query: &q::Document) -> u64 {
-    let mut hasher = DefaultHasher::new();
+    shape_hash_with(query, !ENV_VARS.graphql.disable_shape_normalization)
+}
+
+fn shape_hash_with(query: &q::Document, normalize: bool) -> u64 {
+    let mut hasher = ShapeHasher::new(normalize);
     query.shape_hash(&mut hasher);
     hasher.finish()
 }
@@ -26,12 +77,16 @@ pub fn shape_hash(query: &q::Document) -> u64 {
 
 impl ShapeHash for q::Document {
     fn shape_hash(&self, hasher: &mut ShapeHasher) {
-        for defn in &self.definitions {
-            use q::Definition::*;
-            match defn {
-                Operation(op) => op.shape_hash(hasher),
-                Fragment(frag) => frag.shape_hash(hasher),
-            }
+        hasher.hash_items(self.definitions.iter())
+    }
+}
+
+impl ShapeHash for q::Definition<'static, String> {
+    fn shape_hash(&self, hasher: &mut ShapeHasher) {
+        use q::Definition::*;
+        match self {
+            Operation(op) => op.shape_hash(hasher),
+            Fragment(frag) => frag.shape_hash(hasher),
         }
     }
 }
@@ -61,9 +116,7 @@ impl ShapeHash for q::FragmentDefinition {
 
 impl ShapeHash for q::SelectionSet {
     fn shape_hash(&self, hasher: &mut ShapeHasher) {
-        for item in &self.items {
-            item.shape_hash(hasher);
-        }
+        hasher.hash_items(self.items.iter())
     }
 }
 
@@ -83,10 +136,15 @@ impl ShapeHash for q::Field {
         // Omit alias, directives
         self.name.hash(hasher);
         self.selection_set.shape_hash(hasher);
-        for (name, value) in &self.arguments {
-            name.hash(hasher);
-            value.shape_hash(hasher);
-        }
+        hasher.hash_items(self.arguments.iter())
+    }
+}
+
+impl ShapeHash for (String, s::Value) {
+    fn shape_hash(&self, hasher: &mut ShapeHasher) {
+        let (name, value) = self;
+        name.hash(hasher);
+        value.shape_hash(hasher);
     }
 }
 
@@ -176,4 +234,27 @@ mod tests {
         assert_ne!(shape_hash(&q1), shape_hash(&q3));
         assert_ne!(shape_hash(&q2), shape_hash(&q4));
     }
+
+    #[test]
+    fn order_does_not_matter() {
+        const Q1: &str = "{ things(first: 10, where: { stuff_gt: 42 }) { id name } other { id } }";
+        const Q2: &str = "{
+            other { id }
+            renamed: things(where: { stuff_gt: 1 }, first: 5) { name id }
+        }";
+        const Q3: &str = "{ things(first: 10, where: { stuff_gt: 42 }) { id name } }";
+        let q1 = parse_query(Q1)
+            .expect("q1 is syntactically valid")
+            .into_static();
+        let q2 = parse_query(Q2)
+            .expect("q2 is syntactically valid")
+            .into_static();
+        let q3 = parse_query(Q3)
+            .expect("q3 is syntactically valid")
+            .into_static();
+
+        assert_eq!(shape_hash_with(&q1, true), shape_hash_with(&q2, true));
+        assert_ne!(shape_hash_with(&q1, true), shape_hash_with(&q3, true));
+        assert_ne!(shape_hash_with(&q1, false), shape_hash_with(&q2, false));
+    }
 }
@@ -7,9 +7,11 @@ use std::error::Error;
 use std::fmt;
 use std::string::FromUtf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::data::subgraph::*;
-use crate::prelude::q;
+use crate::data::subscription::SubscriptionError;
+use crate::prelude::{q, r, BlockNumber};
 use crate::{components::store::StoreError, prelude::CacheWeight};
 
 #[derive(Debug)]
@@ -60,17 +62,24 @@ pub enum QueryExecutionError {
     AttributeTypeError(String, String),
     EntityParseError(String),
     StoreError(CloneableAnyhowError),
-    Timeout,
+    Timeout {
+        elapsed_ms: u64,
+        limit_ms: u64,
+    },
     EmptySelectionSet(String),
     AmbiguousDerivedFromResult(Pos, String, String, String),
     Unimplemented(String),
     EnumCoercionError(Pos, String, q::Value, String, Vec<String>),
     ScalarCoercionError(Pos, String, q::Value, String),
-    TooComplex(u64, u64), // (complexity, max_complexity)
-    TooDeep(u8),          // max_depth
+    TooComplex(u64, u64),           // (complexity, max_complexity)
+    TooDeep(u8),                    // max_depth
+    TooManyBlockConstraints(usize), // max_block_constraints
+    TooManyAliases(usize, usize),   // (aliases, max_aliases)
     CyclicalFragment(String),
     TooExpensive,
-    Throttled,
+    Throttled {
+        retry_after: Duration,
+    },
     UndefinedFragment(String),
     Panic(String),
     EventStreamError,
@@ -79,14 +88,59 @@ pub enum QueryExecutionError {
     DeploymentReverted,
     SubgraphManifestResolveError(Arc<SubgraphManifestResolveError>),
     InvalidSubgraphManifest,
-    ResultTooBig(usize, usize),
+    ResultTooBig {
+        measured: usize,
+        limit: usize,
+    },
+    MemoryLimitExceeded {
+        measured: usize,
+        limit: usize,
+    },
     DeploymentNotFound(String),
+    PersistedQueryNotFound(String),
+    PersistedQueryHashMismatch(String),
+    SchemaVersionMismatch {
+        expected: String,
+        actual: String,
+    },
+    NetworkMismatch {
+        expected: String,
+        actual: String,
+    },
+    StoredSubscriptionNotFound(String),
+    InvalidCursor(String),
+    PaginationBlockReverted(BlockNumber),
+    DeadlineExceeded,
+    BlockNotFound {
+        hash: String,
+    },
+    InvalidVariable {
+        name: String,
+        reason: String,
+    },
+    ScalarCoercion {
+        pos: Pos,
+        /// The argument or variable that was passed the value
+        argument: String,
+        scalar_type: String,
+        value: String,
+        reason: String,
+    },
+    QueryNotAllowed(u64),
+    FieldForbidden {
+        path: Vec<String>,
+    },
+    DeploymentBusy(String),
+    SnapshotNotFound(String),
+    Internal(String),
+    AtPath(Vec<r::Value>, Box<QueryExecutionError>), // (path in the response, error)
 }
 
 impl QueryExecutionError {
     pub fn is_attestable(&self) -> bool {
         use self::QueryExecutionError::*;
         match self {
+            AtPath(_, e) => e.is_attestable(),
             OperationNameRequired
             | OperationNotFound(_)
             | NotSupported(_)
@@ -115,29 +169,89 @@ impl QueryExecutionError {
             | CyclicalFragment(_)
             | UndefinedFragment(_)
             | FulltextQueryInvalidSyntax(_)
-            | FulltextQueryRequiresFilter => true,
+            | FulltextQueryRequiresFilter
+            | InvalidCursor(_)
+            | InvalidVariable { .. }
+            | ScalarCoercion { .. } => true,
             ListValueError(_, _)
             | ResolveEntitiesError(_)
             | RangeArgumentsError(_, _, _)
             | ValueParseError(_, _)
             | EntityParseError(_)
             | StoreError(_)
-            | Timeout
+            | Timeout { .. }
             | EnumCoercionError(_, _, _, _, _)
             | ScalarCoercionError(_, _, _, _)
             | AmbiguousDerivedFromResult(_, _, _, _)
             | TooComplex(_, _)
             | TooDeep(_)
+            | TooManyBlockConstraints(_)
+            | TooManyAliases(_, _)
             | Panic(_)
             | EventStreamError
             | TooExpensive
-            | Throttled
+            | Throttled { .. }
             | DeploymentReverted
             | SubgraphManifestResolveError(_)
             | InvalidSubgraphManifest
             | ValidationError(_, _)
-            | ResultTooBig(_, _)
-            | DeploymentNotFound(_) => false,
+            | ResultTooBig { .. }
+            | MemoryLimitExceeded { .. }
+            | DeploymentNotFound(_)
+            | PersistedQueryNotFound(_)
+            | PersistedQueryHashMismatch(_)
+            | SchemaVersionMismatch { .. }
+            | NetworkMismatch { .. }
+            | StoredSubscriptionNotFound(_)
+            | PaginationBlockReverted(_)
+            | DeadlineExceeded
+            | BlockNotFound { .. }
+            | QueryNotAllowed(_)
+            | FieldForbidden { .. }
+            | DeploymentBusy(_)
+            | SnapshotNotFound(_)
+            | Internal(_) => false,
+        }
+    }
+
+    /// Whether the error happened because the database that the query was
+    /// sent to could not be reached
+    pub fn is_database_unavailable(&self) -> bool {
+        match self {
+            QueryExecutionError::AtPath(_, e) => e.is_database_unavailable(),
+            QueryExecutionError::StoreError(e) => matches!(
+                e.0.downcast_ref::<StoreError>(),
+                Some(StoreError::DatabaseUnavailable)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Record that this error happened inside the field or list item
+    /// `segment` of the response. Errors bubble up from the innermost
+    /// field, and each enclosing field adds itself to the front of the
+    /// path. Errors that concern the query as a whole do not get a path
+    pub fn in_path(self, segment: r::Value) -> Self {
+        use self::QueryExecutionError::*;
+        match self {
+            Timeout { .. }
+            | DeadlineExceeded
+            | TooExpensive
+            | Throttled { .. }
+            | DeploymentReverted
+            | SchemaVersionMismatch { .. }
+            | NetworkMismatch { .. }
+            | QueryNotAllowed(_)
+            | FieldForbidden { .. }
+            | DeploymentBusy(_)
+            | ResultTooBig { .. }
+            | MemoryLimitExceeded { .. }
+            | Internal(_) => self,
+            AtPath(mut path, e) => {
+                path.insert(0, segment);
+                AtPath(path, e)
+            }
+            e => AtPath(vec![segment], Box::new(e)),
         }
     }
 }
@@ -244,7 +358,7 @@ impl fmt::Display for QueryExecutionError {
             StoreError(e) => {
                 write!(f, "Store error: {}", e.0)
             }
-            Timeout => write!(f, "Query timed out"),
+            Timeout { elapsed_ms, limit_ms } => write!(f, "Query timed out after {}ms, the limit is {}ms", elapsed_ms, limit_ms),
             EmptySelectionSet(entity_type) => {
                 write!(f, "Selection set for type `{}` is empty", entity_type)
             }
@@ -269,6 +383,8 @@ impl fmt::Display for QueryExecutionError {
                            return smaller collections", complexity, max_complexity)
             }
             TooDeep(max_depth) => write!(f, "query has a depth that exceeds the limit of `{}`", max_depth),
+            TooManyBlockConstraints(max) => write!(f, "query uses more than `{}` different block constraints", max),
+            TooManyAliases(aliases, max) => write!(f, "query has `{}` aliased fields, which is more than the limit of `{}`", aliases, max),
             CyclicalFragment(name) =>write!(f, "query has fragment cycle including `{}`", name),
             UndefinedFragment(frag_name) => write!(f, "fragment `{}` is not defined", frag_name),
             Panic(msg) => write!(f, "panic processing query: {}", msg),
@@ -276,12 +392,32 @@ impl fmt::Display for QueryExecutionError {
             FulltextQueryRequiresFilter => write!(f, "fulltext search queries can only use EntityFilter::Equal"),
             FulltextQueryInvalidSyntax(msg) => write!(f, "Invalid fulltext search query syntax. Error: {}. Hint: Search terms with spaces need to be enclosed in single quotes", msg),
             TooExpensive => write!(f, "query is too expensive"),
-            Throttled => write!(f, "service is overloaded and can not run the query right now. Please try again in a few minutes"),
+            Throttled { retry_after } => write!(f, "service is overloaded and can not run the query right now. Please try again in {}s", retry_after.as_secs()),
             DeploymentReverted => write!(f, "the chain was reorganized while executing the query"),
             SubgraphManifestResolveError(e) => write!(f, "failed to resolve subgraph manifest: {}", e),
             InvalidSubgraphManifest => write!(f, "invalid subgraph manifest file"),
-            ResultTooBig(actual, limit) => write!(f, "the result size of {} is larger than the allowed limit of {}", actual, limit),
-            DeploymentNotFound(id_or_name) => write!(f, "deployment `{}` does not exist", id_or_name)
+            ResultTooBig { measured, limit } => write!(f, "the result size of {} is larger than the allowed limit of {}", measured, limit),
+            MemoryLimitExceeded { measured, limit } => write!(f, "the query needs at least {} bytes of memory for the entities it loads, which is more than the allowed limit of {}", measured, limit),
+            DeploymentNotFound(id_or_name) => write!(f, "deployment `{}` does not exist", id_or_name),
+            // Clients recognize this error by its message and respond by
+            // resending the query with its full text
+            PersistedQueryNotFound(_) => write!(f, "PersistedQueryNotFound"),
+            PersistedQueryHashMismatch(hash) => write!(f, "provided sha256Hash `{}` does not match the query", hash),
+            SchemaVersionMismatch { expected, actual } => write!(f, "the query was written for schema `{}` but the deployment now has schema `{}`, fetch the schema again", expected, actual),
+            NetworkMismatch { expected, actual } => write!(f, "the query expects the deployment to index network `{}` but it indexes `{}`", expected, actual),
+            StoredSubscriptionNotFound(name) => write!(f, "no subscription is registered under the name `{}`", name),
+            InvalidCursor(msg) => write!(f, "invalid cursor: {}", msg),
+            PaginationBlockReverted(block) => write!(f, "block {} that pagination is pinned to has been reverted; start again from the first page", block),
+            DeadlineExceeded => write!(f, "the query ran out of time, and the result only contains the data that was fetched before the deadline"),
+            BlockNotFound { hash } => write!(f, "the block `{}` is not part of the chain of this deployment", hash),
+            InvalidVariable { name, reason } => write!(f, "Invalid value for variable `{}`: {}", name, reason),
+            ScalarCoercion { argument, scalar_type, value, reason, .. } => write!(f, "Invalid `{}` value `{}` for `{}`: {}", scalar_type, value, argument, reason),
+            QueryNotAllowed(shape_hash) => write!(f, "the query with shape hash {} is not on the allowlist of this endpoint", shape_hash),
+            FieldForbidden { path } => write!(f, "the caller is not allowed to query the field `{}`", path.join(".")),
+            DeploymentBusy(deployment) => write!(f, "too many queries are running for deployment `{}`, please try again later", deployment),
+            SnapshotNotFound(id) => write!(f, "there is no snapshot `{}` of this deployment", id),
+            Internal(msg) => write!(f, "internal error: {}", msg),
+            AtPath(_, e) => write!(f, "{}", e),
         }
     }
 }
@@ -334,6 +470,9 @@ pub enum QueryError {
     ParseError(Arc<anyhow::Error>),
     ExecutionError(QueryExecutionError),
     IndexingError,
+    /// An error that a running subscription reported. If the error is not
+    /// recoverable, it is the last result of the subscription
+    SubscriptionError(Arc<SubscriptionError>),
 }
 
 impl QueryError {
@@ -341,7 +480,7 @@ impl QueryError {
         match self {
             QueryError::EncodingError(_) | QueryError::ParseError(_) => true,
             QueryError::ExecutionError(err) => err.is_attestable(),
-            QueryError::IndexingError => false,
+            QueryError::IndexingError | QueryError::SubscriptionError(_) => false,
         }
     }
 }
@@ -358,6 +497,12 @@ impl From<QueryExecutionError> for QueryError {
     }
 }
 
+impl From<SubscriptionError> for QueryError {
+    fn from(e: SubscriptionError) -> Self {
+        QueryError::SubscriptionError(Arc::new(e))
+    }
+}
+
 impl Error for QueryError {
     fn description(&self) -> &str {
         "Query error"
@@ -367,6 +512,7 @@ impl Error for QueryError {
         match *self {
             QueryError::EncodingError(ref e) => Some(e),
             QueryError::ExecutionError(ref e) => Some(e),
+            QueryError::SubscriptionError(ref e) => Some(e.as_ref()),
             _ => None,
         }
     }
@@ -378,6 +524,7 @@ impl fmt::Display for QueryError {
             QueryError::EncodingError(ref e) => write!(f, "{}", e),
             QueryError::ExecutionError(ref e) => write!(f, "{}", e),
             QueryError::ParseError(ref e) => write!(f, "{}", e),
+            QueryError::SubscriptionError(ref e) => write!(f, "{}", e),
 
             // This error message is part of attestable responses.
             QueryError::IndexingError => write!(f, "indexing_error"),
@@ -392,15 +539,42 @@ impl Serialize for QueryError {
     {
         use self::QueryExecutionError::*;
 
-        let mut map = serializer.serialize_map(Some(1))?;
+        // Subscription errors say whether the subscription keeps running,
+        // and otherwise look like the query error they wrap, if any
+        let (recoverable, inner) = match self {
+            QueryError::SubscriptionError(e) => match e.as_ref() {
+                SubscriptionError::GraphQLError(errors) if errors.len() == 1 => (
+                    Some(e.is_recoverable()),
+                    Some(QueryError::ExecutionError(errors[0].clone())),
+                ),
+                SubscriptionError::Fatal(error) => (
+                    Some(e.is_recoverable()),
+                    Some(QueryError::ExecutionError(error.clone())),
+                ),
+                _ => (Some(e.is_recoverable()), None),
+            },
+            _ => (None, None),
+        };
+        let error = inner.as_ref().unwrap_or(self);
 
-        let msg = match self {
+        // Errors for a field report its path next to what they would
+        // report without it
+        let unwrapped;
+        let (path, this) = match error {
+            QueryError::ExecutionError(AtPath(path, e)) => {
+                unwrapped = QueryError::ExecutionError(e.as_ref().clone());
+                (Some(path), &unwrapped)
+            }
+            _ => (None, error),
+        };
+
+        let (locations, msg) = match this {
             // Serialize parse errors with their location (line, column) to make it easier
             // for users to find where the errors are; this is likely to change as the
             // graphql_parser team makes improvements to their error reporting
             QueryError::ParseError(_) => {
                 // Split the inner message into (first line, rest)
-                let msg = format!("{}", self);
+                let msg = format!("{}", this);
                 let inner_msg = msg.replace("query parse error:", "");
                 let inner_msg = inner_msg.trim();
                 let parts: Vec<&str> = inner_msg.splitn(2, '\n').collect();
@@ -425,10 +599,9 @@ impl Serialize for QueryError {
                 let mut location = HashMap::new();
                 location.insert("line", line);
                 location.insert("column", column);
-                map.serialize_entry("locations", &vec![location])?;
 
                 // Only use the remainder after the location as the error message
-                parts[1].to_string()
+                (Some(vec![location]), parts[1].to_string())
             }
 
             // Serialize entity resolution errors using their position
@@ -441,17 +614,34 @@ impl Serialize for QueryError {
             | QueryError::ExecutionError(AmbiguousDerivedFromResult(pos, _, _, _))
             | QueryError::ExecutionError(EnumCoercionError(pos, _, _, _, _))
             | QueryError::ExecutionError(ScalarCoercionError(pos, _, _, _))
+            | QueryError::ExecutionError(ScalarCoercion { pos, .. })
             | QueryError::ExecutionError(UnknownField(pos, _, _)) => {
                 let mut location = HashMap::new();
-                location.insert("line", pos.line);
-                location.insert("column", pos.column);
-                map.serialize_entry("locations", &vec![location])?;
-                format!("{}", self)
+                location.insert("line", pos.line as u32);
+                location.insert("column", pos.column as u32);
+                (Some(vec![location]), format!("{}", this))
             }
-            _ => format!("{}", self),
+            _ => (None, format!("{}", this)),
         };
 
+        // Binary encodings need to know how many entries the map has
+        let len = 1
+            + path.is_some() as usize
+            + locations.is_some() as usize
+            + recoverable.is_some() as usize;
+        let mut map = serializer.serialize_map(Some(len))?;
+        if let Some(path) = path {
+            map.serialize_entry("path", path)?;
+        }
+        if let Some(locations) = &locations {
+            map.serialize_entry("locations", locations)?;
+        }
         map.serialize_entry("message", msg.as_str())?;
+        if let Some(recoverable) = recoverable {
+            let mut extensions = HashMap::new();
+            extensions.insert("recoverable", recoverable);
+            map.serialize_entry("extensions", &extensions)?;
+        }
         map.end()
     }
 }
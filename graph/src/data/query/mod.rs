@@ -5,5 +5,7 @@ mod result;
 
 pub use self::cache_status::CacheStatus;
 pub use self::error::{QueryError, QueryExecutionError};
-pub use self::query::{Query, QueryTarget, QueryVariables};
-pub use self::result::{QueryResult, QueryResults};
+pub use self::query::{PersistedQuery, Query, QueryTarget, QueryVariables, ReorgTolerance};
+pub use self::result::{
+    etag_suffix, ContentEncoding, QueryResult, QueryResults, ResponseEncoding, VARY_HEADERS,
+};
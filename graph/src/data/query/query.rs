@@ -3,7 +3,9 @@ use serde::Deserialize;
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{
     data::graphql::shape_hash::shape_hash,
@@ -110,7 +112,7 @@ impl serde::ser::Serialize for QueryVariables {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum QueryTarget {
     Name(SubgraphName),
     Deployment(DeploymentHash),
@@ -128,6 +130,53 @@ impl From<SubgraphName> for QueryTarget {
     }
 }
 
+/// A reference to an automatic persisted query (APQ): clients identify a
+/// query by the SHA-256 hash of its text, and only send the text itself
+/// when the server does not know the hash yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersistedQuery {
+    /// The hex-encoded SHA-256 hash of the query text
+    pub sha256_hash: String,
+    /// The query text exactly as the client sent it, or `None` if the
+    /// client only sent the hash
+    pub text: Option<String>,
+}
+
+/// How a query reacts to the deployment being reverted while the query is
+/// executing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReorgTolerance {
+    /// Fail the query if any revert happened while it was executing
+    Strict,
+    /// Fail the query if a revert might have affected the blocks that the
+    /// query read from
+    Default,
+    /// Never fail the query because of a revert, but warn about it
+    Tolerant,
+}
+
+impl Default for ReorgTolerance {
+    fn default() -> Self {
+        ReorgTolerance::Default
+    }
+}
+
+impl FromStr for ReorgTolerance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ReorgTolerance::Strict),
+            "default" => Ok(ReorgTolerance::Default),
+            "tolerant" => Ok(ReorgTolerance::Tolerant),
+            _ => Err(format!(
+                "invalid reorg tolerance `{}`, must be one of `strict`, `default`, or `tolerant`",
+                s
+            )),
+        }
+    }
+}
+
 /// A GraphQL query as submitted by a client, either directly or through a subscription.
 #[derive(Clone, Debug)]
 pub struct Query {
@@ -136,6 +185,75 @@ pub struct Query {
     pub shape_hash: u64,
     pub query_text: Arc<String>,
     pub variables_text: Arc<String>,
+    pub persisted_query: Option<PersistedQuery>,
+    /// Whether to collect timings while executing the query and report
+    /// them in the Apollo Tracing format under `extensions.tracing`
+    pub trace: bool,
+    /// Whether the query should fail if the deployment is reverted while
+    /// the query is executing
+    pub reorg_tolerance: ReorgTolerance,
+    /// Whether to report how far the deployment has synced under
+    /// `extensions._meta.block`
+    pub sync_status: bool,
+    /// Whether to return the data fetched so far instead of failing when
+    /// the query runs out of time
+    pub partial_results: bool,
+    /// Whether to read from the primary instead of a replica so that the
+    /// query sees all writes that have been committed so far. Reading from
+    /// the primary puts more load on it and can be slower than reading
+    /// from a replica
+    pub force_primary: bool,
+    /// Whether to report the `CacheWeight` of the result under
+    /// `extensions._meta.resultSize`
+    pub result_size: bool,
+    /// Whether to fetch fewer entities than `first` asks for when the
+    /// query gets close to its deadline, rather than risk running out of
+    /// time. Results of such queries depend on how fast they execute
+    pub adaptive_first: bool,
+    /// Whether to report a `null` in a non-null field as an error at that
+    /// field, and set the field to `null`, instead of nulling out its
+    /// parent as the GraphQL spec requires
+    pub localize_non_null_errors: bool,
+    /// How long the client is willing to wait for the query. This can
+    /// only shorten the timeout set with `GRAPH_GRAPHQL_QUERY_TIMEOUT` or
+    /// for the deployment in the configuration file
+    pub timeout: Option<Duration>,
+    /// The priority lane that load management puts the query in, usually
+    /// derived from the API key or tenant of the client further upstream.
+    /// Lanes and their effort budgets are configured with
+    /// `GRAPH_LOAD_LANES`
+    pub lane: Option<String>,
+    /// Who sent the query, as established by a proxy in front of the
+    /// server, for example from an API key. The runner asks its
+    /// `FieldAuthorizer` which fields the caller may query
+    pub caller: Option<String>,
+    /// How long the query may wait for the deployment to reach the blocks
+    /// that its block constraints ask for, instead of failing right away
+    /// because the blocks have not been indexed yet. The wait is capped by
+    /// `GRAPH_GRAPHQL_MAX_BLOCK_WAIT`
+    pub wait_for_block: Option<Duration>,
+    /// The id of the request that carried the query, which is attached to
+    /// the log lines for the query and reported under
+    /// `extensions.requestId`. Only set when `GRAPH_GRAPHQL_REQUEST_IDS`
+    /// is on
+    pub request_id: Option<String>,
+    /// The hash of the deployment whose schema the client built the query
+    /// against. If the deployment has changed since, the query fails with
+    /// `SchemaVersionMismatch` instead of running into validation errors
+    pub schema_hash: Option<String>,
+    /// The name of the network the client expects the deployment to
+    /// index. If the deployment indexes a different network, the query
+    /// fails with `NetworkMismatch`
+    pub network: Option<String>,
+    /// The ETags of responses that the client already has, from the
+    /// `If-None-Match` header. If the response to the query would have one
+    /// of them, the query is not executed and the response only says
+    /// that the result has not changed
+    pub if_none_match: Vec<String>,
+    /// Whether to work out how much each field contributes to the
+    /// complexity of the query. Only explaining a query and estimating its
+    /// cost need that
+    pub complexity_breakdown: bool,
     _force_use_of_new: (),
 }
 
@@ -145,6 +263,8 @@ impl Query {
 
         let (query_text, variables_text) = if ENV_VARS.log_gql_timing()
             || (ENV_VARS.graphql.enable_validations && ENV_VARS.graphql.silent_graphql_validations)
+            || (ENV_VARS.graphql.field_audit_variables
+                && ENV_VARS.graphql.field_audit_sample_rate > 0.0)
         {
             (
                 document
@@ -162,7 +282,68 @@ impl Query {
             shape_hash,
             query_text: Arc::new(query_text),
             variables_text: Arc::new(variables_text),
+            persisted_query: None,
+            trace: false,
+            reorg_tolerance: ReorgTolerance::default(),
+            sync_status: false,
+            partial_results: false,
+            force_primary: false,
+            result_size: false,
+            adaptive_first: false,
+            localize_non_null_errors: false,
+            timeout: None,
+            lane: None,
+            caller: None,
+            wait_for_block: None,
+            request_id: None,
+            schema_hash: None,
+            network: None,
+            if_none_match: Vec::new(),
+            complexity_breakdown: false,
             _force_use_of_new: (),
         }
     }
+
+    /// Replace the document of this query, keeping the variables and all
+    /// flags the client set for it
+    pub fn with_document(self, document: q::Document) -> Self {
+        Query {
+            persisted_query: self.persisted_query,
+            trace: self.trace,
+            reorg_tolerance: self.reorg_tolerance,
+            sync_status: self.sync_status,
+            partial_results: self.partial_results,
+            force_primary: self.force_primary,
+            result_size: self.result_size,
+            adaptive_first: self.adaptive_first,
+            localize_non_null_errors: self.localize_non_null_errors,
+            timeout: self.timeout,
+            lane: self.lane,
+            caller: self.caller,
+            wait_for_block: self.wait_for_block,
+            request_id: self.request_id,
+            schema_hash: self.schema_hash,
+            network: self.network,
+            if_none_match: self.if_none_match,
+            complexity_breakdown: self.complexity_breakdown,
+            ..Query::new(document, self.variables)
+        }
+    }
+
+    /// A query that the client only identified by the SHA-256 hash of its
+    /// text. The document is empty and needs to be looked up by the hash
+    /// before the query can be executed
+    pub fn persisted(sha256_hash: String, variables: Option<QueryVariables>) -> Self {
+        let mut query = Query::new(
+            q::Document {
+                definitions: vec![],
+            },
+            variables,
+        );
+        query.persisted_query = Some(PersistedQuery {
+            sha256_hash,
+            text: None,
+        });
+        query
+    }
 }
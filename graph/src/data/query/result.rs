@@ -1,23 +1,27 @@
 use super::error::{QueryError, QueryExecutionError};
 use crate::data::value::Object;
+use crate::env::ENV_VARS;
 use crate::prelude::{r, CacheWeight, DeploymentHash};
 use http::header::{
     ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_TYPE,
+    CONTENT_ENCODING, CONTENT_TYPE, ETAG, RETRY_AFTER, VARY,
 };
 use serde::ser::*;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::io::{self, Write};
 use std::sync::Arc;
+use std::time::Duration;
 
 fn serialize_data<S>(data: &Option<Data>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let mut ser = serializer.serialize_map(None)?;
-
     // Unwrap: data is only serialized if it is `Some`.
-    for (k, v) in data.as_ref().unwrap() {
+    let data = data.as_ref().unwrap();
+    let mut ser = serializer.serialize_map(Some(data.iter().count()))?;
+    for (k, v) in data {
         ser.serialize_entry(k, v)?;
     }
     ser.end()
@@ -30,7 +34,10 @@ fn serialize_value_map<'a, S>(
 where
     S: Serializer,
 {
-    let mut ser = serializer.serialize_map(None)?;
+    // Binary encodings need to know the number of entries up front
+    let data = data.collect::<Vec<_>>();
+    let len = data.iter().map(|map| map.iter().count()).sum();
+    let mut ser = serializer.serialize_map(Some(len))?;
     for map in data {
         for (k, v) in map {
             ser.serialize_entry(k, v)?;
@@ -41,19 +48,72 @@ where
 
 pub type Data = Object;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 /// A collection of query results that is serialized as a single result.
 pub struct QueryResults {
     results: Vec<Arc<QueryResult>>,
+    /// Additional information about the query that is serialized under the
+    /// `extensions` key of the response
+    extensions: BTreeMap<String, r::Value>,
+    /// A strong validator for the response that HTTP caches can use
+    etag: Option<String>,
+    /// Whether the client already has the response with `etag`, and this
+    /// response therefore carries no results
+    not_modified: bool,
 }
 
 impl QueryResults {
     pub fn empty() -> Self {
+        QueryResults::new(Vec::new())
+    }
+
+    fn new(results: Vec<Arc<QueryResult>>) -> Self {
+        QueryResults {
+            results,
+            extensions: BTreeMap::new(),
+            etag: None,
+            not_modified: false,
+        }
+    }
+
+    /// The response to a conditional request for which the client already
+    /// has the response with `etag`
+    pub fn not_modified(etag: String) -> Self {
         QueryResults {
-            results: Vec::new(),
+            etag: Some(etag),
+            not_modified: true,
+            ..QueryResults::empty()
         }
     }
 
+    pub fn is_not_modified(&self) -> bool {
+        self.not_modified
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    pub fn set_etag(&mut self, etag: String) {
+        self.etag = Some(etag);
+    }
+
+    pub fn clear_etag(&mut self) {
+        self.etag = None;
+    }
+
+    /// The ETag of the response when it is sent in `encoding` and
+    /// compressed with `compression`, see `etag_suffix`
+    pub fn representation_etag(
+        &self,
+        encoding: ResponseEncoding,
+        compression: ContentEncoding,
+    ) -> Option<String> {
+        self.etag
+            .as_ref()
+            .map(|etag| format!("{}{}", etag, etag_suffix(encoding, compression)))
+    }
+
     pub fn first(&self) -> Option<&Arc<QueryResult>> {
         self.results.first()
     }
@@ -66,12 +126,70 @@ impl QueryResults {
         self.results.iter().any(|result| result.not_found())
     }
 
+    /// Whether any of the results failed because the database could not
+    /// be reached
+    pub fn database_unavailable(&self) -> bool {
+        self.results
+            .iter()
+            .any(|result| result.database_unavailable())
+    }
+
+    /// How long the client should wait before sending the query again,
+    /// if the query was declined because the service is overloaded
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.results
+            .iter()
+            .filter_map(|result| result.retry_after())
+            .max()
+    }
+
     pub fn deployment_hash(&self) -> Option<&DeploymentHash> {
         self.results
             .iter()
             .filter_map(|result| result.deployment.as_ref())
             .next()
     }
+
+    pub fn extension(&self, key: &str) -> Option<&r::Value> {
+        self.extensions.get(key)
+    }
+
+    /// Set the value of `key` in the `extensions` of the response
+    pub fn set_extension(&mut self, key: impl Into<String>, value: r::Value) {
+        self.extensions.insert(key.into(), value);
+    }
+
+    /// Add a warning to the list in `extensions.warnings`. Warnings are
+    /// for conditions that clients should know about, but that did not
+    /// prevent us from producing a result
+    pub fn add_warning(&mut self, message: impl Into<String>) {
+        let warning = r::Value::Object(Object::from_iter([(
+            "message".to_string(),
+            r::Value::String(message.into()),
+        )]));
+        match self.extensions.get_mut("warnings") {
+            Some(r::Value::List(warnings)) => warnings.push(warning),
+            _ => {
+                self.extensions
+                    .insert("warnings".to_string(), r::Value::List(vec![warning]));
+            }
+        }
+    }
+
+    /// Set `key` to `value` in the `_meta` extension of the response
+    pub fn set_meta(&mut self, key: &str, value: r::Value) {
+        match self.extensions.get_mut("_meta") {
+            Some(r::Value::Object(meta)) => match meta.get_mut(key) {
+                Some(current) => *current = value,
+                None => meta.extend(Object::from_iter([(key.to_string(), value)])),
+            },
+            _ => {
+                let meta = Object::from_iter([(key.to_string(), value)]);
+                self.extensions
+                    .insert("_meta".to_string(), r::Value::Object(meta));
+            }
+        }
+    }
 }
 
 impl Serialize for QueryResults {
@@ -85,6 +203,10 @@ impl Serialize for QueryResults {
         if has_errors {
             len += 1;
         }
+        let has_extensions = !self.extensions.is_empty();
+        if has_extensions {
+            len += 1;
+        }
 
         let mut state = serializer.serialize_struct("QueryResults", len)?;
 
@@ -110,7 +232,8 @@ impl Serialize for QueryResults {
 
             impl Serialize for SerError<'_> {
                 fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-                    let mut seq = serializer.serialize_seq(None)?;
+                    let len = self.0.results.iter().map(|r| r.errors.len()).sum();
+                    let mut seq = serializer.serialize_seq(Some(len))?;
                     for err in self.0.results.iter().map(|r| &r.errors).flatten() {
                         seq.serialize_element(err)?;
                     }
@@ -121,45 +244,42 @@ impl Serialize for QueryResults {
             state.serialize_field("errors", &SerError(self))?;
         }
 
+        // Serialize extensions.
+        if has_extensions {
+            state.serialize_field("extensions", &self.extensions)?;
+        }
+
         state.end()
     }
 }
 
 impl From<Data> for QueryResults {
     fn from(x: Data) -> Self {
-        QueryResults {
-            results: vec![Arc::new(x.into())],
-        }
+        QueryResults::new(vec![Arc::new(x.into())])
     }
 }
 
 impl From<QueryResult> for QueryResults {
     fn from(x: QueryResult) -> Self {
-        QueryResults {
-            results: vec![Arc::new(x)],
-        }
+        QueryResults::new(vec![Arc::new(x)])
     }
 }
 
 impl From<Arc<QueryResult>> for QueryResults {
     fn from(x: Arc<QueryResult>) -> Self {
-        QueryResults { results: vec![x] }
+        QueryResults::new(vec![x])
     }
 }
 
 impl From<QueryExecutionError> for QueryResults {
     fn from(x: QueryExecutionError) -> Self {
-        QueryResults {
-            results: vec![Arc::new(x.into())],
-        }
+        QueryResults::new(vec![Arc::new(x.into())])
     }
 }
 
 impl From<Vec<QueryExecutionError>> for QueryResults {
     fn from(x: Vec<QueryExecutionError>) -> Self {
-        QueryResults {
-            results: vec![Arc::new(x.into())],
-        }
+        QueryResults::new(vec![Arc::new(x.into())])
     }
 }
 
@@ -168,27 +288,296 @@ impl QueryResults {
         self.results.push(other);
     }
 
+    /// Combine the results into one `QueryResult` for transports that send
+    /// a single result at a time, like subscriptions. A `QueryResult` has
+    /// no `extensions`, and they are therefore dropped
+    pub fn into_single(self) -> QueryResult {
+        let mut single = QueryResult {
+            data: None,
+            errors: Vec::new(),
+            deployment: self.deployment_hash().cloned(),
+            cursors: BTreeMap::new(),
+            result_size: 0,
+            warnings: Vec::new(),
+        };
+        for result in self.results {
+            if let Some(data) = &result.data {
+                single
+                    .data
+                    .get_or_insert_with(Object::default)
+                    .extend(data.clone());
+            }
+            single.errors.extend(result.errors.iter().cloned());
+            single.cursors.extend(result.cursors.clone());
+            single.result_size += result.result_size;
+            single.warnings.extend(result.warnings.iter().cloned());
+        }
+        single
+    }
+
     pub fn as_http_response<T: From<String>>(&self) -> http::Response<T> {
-        let status_code = http::StatusCode::OK;
         let json =
             serde_json::to_string(self).expect("Failed to serialize GraphQL response to JSON");
-        http::Response::builder()
-            .status(status_code)
+        self.http_response_builder(ResponseEncoding::Json, ContentEncoding::Identity)
+            .body(T::from(json))
+            .unwrap()
+    }
+
+    /// Like `as_http_response`, but encode the response with `encoding`
+    /// and, if it is at least `GRAPH_GRAPHQL_COMPRESSION_THRESHOLD` bytes,
+    /// compress it with `compression`
+    pub fn as_encoded_http_response<T: From<Vec<u8>>>(
+        &self,
+        encoding: ResponseEncoding,
+        compression: ContentEncoding,
+    ) -> http::Response<T> {
+        let body = encoding.encode(self);
+        let builder = self.http_response_builder(encoding, compression);
+        if compression == ContentEncoding::Identity
+            || body.len() < ENV_VARS.graphql.compression_threshold
+        {
+            return builder.body(T::from(body)).unwrap();
+        }
+        match compression.compress(&body) {
+            Ok(compressed) => builder
+                .header(CONTENT_ENCODING, compression.as_str())
+                .body(T::from(compressed))
+                .unwrap(),
+            // Sending the response uncompressed is always an option
+            Err(_) => builder.body(T::from(body)).unwrap(),
+        }
+    }
+
+    fn http_response_builder(
+        &self,
+        encoding: ResponseEncoding,
+        compression: ContentEncoding,
+    ) -> http::response::Builder {
+        let builder = http::Response::builder()
+            .status(http::StatusCode::OK)
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
             .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
             .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
-            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_TYPE, encoding.content_type())
+            .header(VARY, VARY_HEADERS)
             .header(
                 "Graph-Attestable",
                 self.results.iter().all(|r| r.is_attestable()).to_string(),
-            )
-            .body(T::from(json))
-            .unwrap()
+            );
+        let builder = match self.representation_etag(encoding, compression) {
+            Some(etag) => builder.header(ETAG, format!("\"{}\"", etag)),
+            None => builder,
+        };
+        match self.retry_after() {
+            Some(retry_after) => builder.header(RETRY_AFTER, retry_after.as_secs().to_string()),
+            None => builder,
+        }
+    }
+}
+
+/// The request headers that decide how a response is encoded. Caches in
+/// front of the server must keep the responses for different values of
+/// these headers apart
+pub const VARY_HEADERS: &str = "Accept, Accept-Encoding";
+
+/// What the ETag of a response gets appended when it is sent in `encoding`
+/// and compressed with `compression`. A strong ETag may only match
+/// responses with the same bytes, and each representation of a response
+/// therefore has its own ETag. The compression only counts if it is turned
+/// on with `GRAPH_GRAPHQL_COMPRESSION_THRESHOLD`
+pub fn etag_suffix(encoding: ResponseEncoding, compression: ContentEncoding) -> String {
+    let mut suffix = String::new();
+    if encoding != ResponseEncoding::Json {
+        suffix.push('-');
+        suffix.push_str(encoding.as_str());
+    }
+    if compression != ContentEncoding::Identity
+        && ENV_VARS.graphql.compression_threshold != usize::MAX
+    {
+        suffix.push('-');
+        suffix.push_str(compression.as_str());
+    }
+    suffix
+}
+
+/// The formats in which responses to queries can be sent. Clients choose
+/// one with the `Accept` header of their request. All formats encode the
+/// same structure; `BigInt` and `BigDecimal` values are strings in all of
+/// them so that they keep their full precision
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseEncoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Default for ResponseEncoding {
+    fn default() -> Self {
+        ResponseEncoding::Json
     }
 }
 
-/// The result of running a query, if successful.
-#[derive(Debug, Default, Serialize)]
+impl ResponseEncoding {
+    /// The encoding that the value of an `Accept` header asks for. Of the
+    /// media types we support, the one with the highest quality wins, and
+    /// the first one of those if several have the same quality. We fall
+    /// back to JSON if there is no header or it only lists media types
+    /// that we do not support
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return ResponseEncoding::Json,
+        };
+        let mut best: Option<(ResponseEncoding, f32)> = None;
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';').map(str::trim);
+            let encoding = match parts.next().unwrap_or("").to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => ResponseEncoding::Json,
+                "application/cbor" => ResponseEncoding::Cbor,
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                    ResponseEncoding::MessagePack
+                }
+                _ => continue,
+            };
+            let quality = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.map_or(true, |(_, best)| quality > best) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding).unwrap_or_default()
+    }
+
+    /// A short name for the encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseEncoding::Json => "json",
+            ResponseEncoding::Cbor => "cbor",
+            ResponseEncoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResponseEncoding::Json => "application/json",
+            ResponseEncoding::Cbor => "application/cbor",
+            ResponseEncoding::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn encode(&self, results: &QueryResults) -> Vec<u8> {
+        match self {
+            ResponseEncoding::Json => {
+                serde_json::to_vec(results).expect("Failed to serialize GraphQL response to JSON")
+            }
+            ResponseEncoding::Cbor => {
+                serde_cbor::to_vec(results).expect("Failed to serialize GraphQL response to CBOR")
+            }
+            ResponseEncoding::MessagePack => rmp_serde::to_vec_named(results)
+                .expect("Failed to serialize GraphQL response to MessagePack"),
+        }
+    }
+}
+
+/// The compressions with which responses to queries can be sent. Clients
+/// choose one with the `Accept-Encoding` header of their request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Default for ContentEncoding {
+    fn default() -> Self {
+        ContentEncoding::Identity
+    }
+}
+
+impl ContentEncoding {
+    /// The compression that the value of an `Accept-Encoding` header asks
+    /// for, chosen by quality like in `ResponseEncoding::from_accept`. A
+    /// wildcard stands for the compressions that the header does not name
+    /// itself, gzip before zstd since more clients can decode it, so that
+    /// `*, zstd;q=0` still refuses zstd
+    pub fn from_accept_encoding(accept_encoding: Option<&str>) -> Self {
+        let accept_encoding = match accept_encoding {
+            Some(accept_encoding) => accept_encoding,
+            None => return ContentEncoding::Identity,
+        };
+        let codings: Vec<_> = accept_encoding
+            .split(',')
+            .map(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                let name = parts.next().unwrap_or("").to_ascii_lowercase();
+                let encoding = match name.as_str() {
+                    "zstd" => Some(ContentEncoding::Zstd),
+                    "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+                    _ => None,
+                };
+                let quality = parts
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (name, encoding, quality)
+            })
+            .collect();
+        let named = |encoding| codings.iter().any(|(_, named, _)| *named == Some(encoding));
+
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for (name, encoding, quality) in &codings {
+            let encoding = match encoding {
+                Some(encoding) => *encoding,
+                None if name == "*" => {
+                    match [ContentEncoding::Gzip, ContentEncoding::Zstd]
+                        .into_iter()
+                        .find(|encoding| !named(*encoding))
+                    {
+                        Some(encoding) => encoding,
+                        None => continue,
+                    }
+                }
+                None => continue,
+            };
+            if *quality > 0.0 && best.map_or(true, |(_, best)| *quality > best) {
+                best = Some((encoding, *quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding).unwrap_or_default()
+    }
+
+    /// The value of the `Content-Encoding` header for this compression
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    /// Compress `bytes` at the level configured for this compression
+    pub fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Identity => Ok(bytes.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::new(ENV_VARS.graphql.gzip_level),
+                );
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            ContentEncoding::Zstd => zstd::encode_all(bytes, ENV_VARS.graphql.zstd_level),
+        }
+    }
+}
+
+/// The result of running a query, if successful. Results can be very
+/// large, and they should be shared through an `Arc` rather than cloned
+/// wherever possible
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct QueryResult {
     #[serde(
         skip_serializing_if = "Option::is_none",
@@ -199,6 +588,18 @@ pub struct QueryResult {
     errors: Vec<QueryError>,
     #[serde(skip_serializing)]
     pub deployment: Option<DeploymentHash>,
+    /// Cursors pointing after the last entity of each toplevel collection,
+    /// keyed by the response key of the collection
+    #[serde(skip_serializing)]
+    pub cursors: BTreeMap<String, String>,
+    /// The `CacheWeight` of the data that was fetched from the store for
+    /// this result
+    #[serde(skip_serializing)]
+    pub result_size: usize,
+    /// Conditions that clients should know about and that came up while
+    /// producing this result. They are sent as `extensions.warnings`
+    #[serde(skip_serializing)]
+    pub warnings: Vec<String>,
 }
 
 impl QueryResult {
@@ -207,19 +608,18 @@ impl QueryResult {
             data: Some(data),
             errors: Vec::new(),
             deployment: None,
+            cursors: BTreeMap::new(),
+            result_size: 0,
+            warnings: Vec::new(),
         }
     }
 
-    /// This is really `clone`, but we do not want to implement `Clone`;
-    /// this is only meant for test purposes and should not be used in production
-    /// code since cloning query results can be very expensive
+    /// This is really `clone`; it is only meant for test purposes and
+    /// should not be used in production code since cloning query results
+    /// can be very expensive
     #[cfg(debug_assertions)]
     pub fn duplicate(&self) -> Self {
-        Self {
-            data: self.data.clone(),
-            errors: self.errors.clone(),
-            deployment: self.deployment.clone(),
-        }
+        self.clone()
     }
 
     pub fn has_errors(&self) -> bool {
@@ -235,14 +635,42 @@ impl QueryResult {
         })
     }
 
+    pub fn database_unavailable(&self) -> bool {
+        self.errors.iter().any(|e| match e {
+            QueryError::ExecutionError(e) => e.is_database_unavailable(),
+            _ => false,
+        })
+    }
+
     pub fn has_data(&self) -> bool {
         self.data.is_some()
     }
 
+    /// How long the client should wait before retrying the query if the
+    /// load manager declined to run it because of an overload
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.errors.iter().find_map(|e| match e {
+            QueryError::ExecutionError(QueryExecutionError::Throttled { retry_after }) => {
+                Some(*retry_after)
+            }
+            _ => None,
+        })
+    }
+
     pub fn is_attestable(&self) -> bool {
         self.errors.iter().all(|err| err.is_attestable())
     }
 
+    /// Whether the data is incomplete because the query ran out of time
+    pub fn is_partial(&self) -> bool {
+        self.errors.iter().any(|e| {
+            matches!(
+                e,
+                QueryError::ExecutionError(QueryExecutionError::DeadlineExceeded)
+            )
+        })
+    }
+
     pub fn to_result(self) -> Result<Option<r::Value>, Vec<QueryError>> {
         if self.has_errors() {
             Err(self.errors)
@@ -263,9 +691,72 @@ impl QueryResult {
         &mut self.errors
     }
 
+    pub fn errors(&self) -> &[QueryError] {
+        &self.errors
+    }
+
     pub fn data(&self) -> Option<&Data> {
         self.data.as_ref()
     }
+
+    /// Split `result` into one result for each entry of `keys`, where each
+    /// entry lists the response keys of the toplevel fields that belong to
+    /// that part. A key that is listed for several parts goes to the first
+    /// of them. Errors go to the part of the field they happened in, and
+    /// errors that concern the whole query, the size of the result and its
+    /// warnings go to the first part
+    pub fn partition(result: Arc<QueryResult>, keys: &[Vec<String>]) -> Vec<QueryResult> {
+        let result = Arc::try_unwrap(result).unwrap_or_else(|result| QueryResult {
+            data: result.data.clone(),
+            errors: result.errors.clone(),
+            deployment: result.deployment.clone(),
+            cursors: result.cursors.clone(),
+            result_size: result.result_size,
+            warnings: result.warnings.clone(),
+        });
+        let part_of = |key: &str| {
+            keys.iter()
+                .position(|keys| keys.iter().any(|k| k == key))
+                .unwrap_or(0)
+        };
+
+        let mut data: Vec<Vec<(String, r::Value)>> = keys.iter().map(|_| Vec::new()).collect();
+        let has_data = result.data.is_some();
+        for (key, value) in result.data.into_iter().flatten() {
+            data[part_of(key.as_str())].push((key.to_string(), value));
+        }
+        let mut parts: Vec<QueryResult> = data
+            .into_iter()
+            .map(|data| QueryResult {
+                data: has_data.then(|| Object::from_iter(data)),
+                errors: Vec::new(),
+                deployment: result.deployment.clone(),
+                cursors: BTreeMap::new(),
+                result_size: 0,
+                warnings: Vec::new(),
+            })
+            .collect();
+        for error in result.errors {
+            let part = match &error {
+                QueryError::ExecutionError(QueryExecutionError::AtPath(path, _)) => {
+                    match path.first() {
+                        Some(r::Value::String(key)) => part_of(key),
+                        _ => 0,
+                    }
+                }
+                _ => 0,
+            };
+            parts[part].errors.push(error);
+        }
+        for (key, cursor) in result.cursors {
+            parts[part_of(&key)].cursors.insert(key, cursor);
+        }
+        if let Some(first) = parts.first_mut() {
+            first.result_size = result.result_size;
+            first.warnings = result.warnings;
+        }
+        parts
+    }
 }
 
 impl From<QueryExecutionError> for QueryResult {
@@ -274,6 +765,9 @@ impl From<QueryExecutionError> for QueryResult {
             data: None,
             errors: vec![e.into()],
             deployment: None,
+            cursors: BTreeMap::new(),
+            result_size: 0,
+            warnings: Vec::new(),
         }
     }
 }
@@ -284,6 +778,9 @@ impl From<QueryError> for QueryResult {
             data: None,
             errors: vec![e],
             deployment: None,
+            cursors: BTreeMap::new(),
+            result_size: 0,
+            warnings: Vec::new(),
         }
     }
 }
@@ -294,6 +791,9 @@ impl From<Vec<QueryExecutionError>> for QueryResult {
             data: None,
             errors: e.into_iter().map(QueryError::from).collect(),
             deployment: None,
+            cursors: BTreeMap::new(),
+            result_size: 0,
+            warnings: Vec::new(),
         }
     }
 }
@@ -330,6 +830,16 @@ impl CacheWeight for QueryResult {
     }
 }
 
+impl CacheWeight for QueryResults {
+    fn indirect_weight(&self) -> usize {
+        self.results
+            .iter()
+            .map(|result| result.weight())
+            .sum::<usize>()
+            + self.extensions.indirect_weight()
+    }
+}
+
 // Check that when we serialize a `QueryResult` with multiple entries
 // in `data` it appears as if we serialized one big map
 #[test]
@@ -353,3 +863,282 @@ fn multiple_data_items() {
     let actual = serde_json::to_string(&res).unwrap();
     assert_eq!(expected, actual)
 }
+
+#[test]
+fn set_meta() {
+    let mut res = QueryResults::empty();
+    res.set_meta("cached", r::Value::Boolean(false));
+    res.set_meta("cacheAge", r::Value::Int(10));
+    res.set_meta("cached", r::Value::Boolean(true));
+    let meta = match res.extension("_meta") {
+        Some(r::Value::Object(meta)) => meta,
+        meta => panic!("expected an object, got {:?}", meta),
+    };
+    assert_eq!(Some(&r::Value::Boolean(true)), meta.get("cached"));
+    assert_eq!(Some(&r::Value::Int(10)), meta.get("cacheAge"));
+    assert_eq!(2, meta.iter().count());
+}
+
+// Check that extensions are serialized after data, and only if there are any
+#[test]
+fn extensions() {
+    use serde_json::json;
+
+    let obj = Object::from_iter([("key".to_owned(), r::Value::String("value".to_owned()))]);
+    let mut res = QueryResults::from(obj);
+
+    let expected = serde_json::to_string(&json!({"data":{"key": "value"}})).unwrap();
+    assert_eq!(expected, serde_json::to_string(&res).unwrap());
+
+    res.set_extension("ext", r::Value::Int(1));
+    let expected =
+        serde_json::to_string(&json!({"data":{"key": "value"}, "extensions": {"ext": 1}})).unwrap();
+    assert_eq!(expected, serde_json::to_string(&res).unwrap());
+}
+
+#[test]
+fn error_paths() {
+    use serde_json::json;
+
+    let error = QueryExecutionError::NonNullError(
+        graphql_parser::Pos { line: 1, column: 5 },
+        "name".to_string(),
+    )
+    .in_path(r::Value::String("name".to_string()))
+    .in_path(r::Value::Int(1))
+    .in_path(r::Value::String("musicians".to_string()));
+    let res = QueryResult::from(error);
+    let expected = json!({"errors": [{
+        "path": ["musicians", 1, "name"],
+        "locations": [{"line": 1, "column": 5}],
+        "message": "Null value resolved for non-null field `name`",
+    }]});
+    assert_eq!(expected, serde_json::to_value(&res).unwrap());
+
+    // Errors that concern the whole query have no path
+    let res = QueryResult::from(
+        QueryExecutionError::TooExpensive.in_path(r::Value::String("musicians".to_string())),
+    );
+    let expected = json!({"errors": [{"message": QueryExecutionError::TooExpensive.to_string()}]});
+    assert_eq!(expected, serde_json::to_value(&res).unwrap());
+}
+
+#[test]
+fn subscription_errors() {
+    use crate::data::subscription::SubscriptionError;
+    use serde_json::json;
+
+    // Errors from running the query for one change keep their path, and
+    // the subscription keeps running
+    let error = QueryExecutionError::Timeout {
+        elapsed_ms: 10,
+        limit_ms: 5,
+    };
+    let path_error = QueryExecutionError::EntityParseError("bad entity".to_string())
+        .in_path(r::Value::String("musicians".to_string()));
+    let mut res = QueryResult::default();
+    res.errors_mut().extend(vec![
+        QueryError::from(SubscriptionError::from(error.clone())),
+        QueryError::from(SubscriptionError::from(path_error)),
+    ]);
+    let expected = json!({"errors": [{
+        "message": error.to_string(),
+        "extensions": {"recoverable": true},
+    }, {
+        "path": ["musicians"],
+        "message": "Broken entity found in store: bad entity",
+        "extensions": {"recoverable": true},
+    }]});
+    assert_eq!(expected, serde_json::to_value(&res).unwrap());
+
+    // Errors that end the subscription are marked as such
+    let error = QueryExecutionError::ResultTooBig {
+        measured: 10,
+        limit: 5,
+    };
+    let res = QueryResult::from(QueryError::from(SubscriptionError::Fatal(error.clone())));
+    let expected = json!({"errors": [{
+        "message": error.to_string(),
+        "extensions": {"recoverable": false},
+    }]});
+    assert_eq!(expected, serde_json::to_value(&res).unwrap());
+}
+
+#[test]
+fn response_encodings() {
+    use serde_json::json;
+
+    assert_eq!(ResponseEncoding::Json, ResponseEncoding::from_accept(None));
+    assert_eq!(
+        ResponseEncoding::Json,
+        ResponseEncoding::from_accept(Some("text/html, */*;q=0.8"))
+    );
+    assert_eq!(
+        ResponseEncoding::Cbor,
+        ResponseEncoding::from_accept(Some("application/cbor"))
+    );
+    assert_eq!(
+        ResponseEncoding::MessagePack,
+        ResponseEncoding::from_accept(Some("application/json;q=0.5, application/x-msgpack"))
+    );
+    assert_eq!(
+        ResponseEncoding::Json,
+        ResponseEncoding::from_accept(Some("application/cbor;q=0, image/png"))
+    );
+
+    // Big numbers are strings and survive the round trip through the
+    // binary encodings unchanged
+    let obj = Object::from_iter([(
+        "token".to_owned(),
+        r::Value::object(BTreeMap::from_iter([
+            (
+                "supply".into(),
+                r::Value::String("115792089237316195423570985008687907853269984665640564039457584007913129639935".to_owned()),
+            ),
+            (
+                "price".into(),
+                r::Value::String("0.000000000000000000000000000001234567890123456789".to_owned()),
+            ),
+            ("holders".into(), r::Value::Int(17)),
+        ])),
+    )]);
+    let mut res = QueryResults::from(obj);
+    let error = QueryExecutionError::NonNullError(
+        graphql_parser::Pos { line: 1, column: 5 },
+        "name".to_string(),
+    )
+    .in_path(r::Value::String("name".to_string()))
+    .in_path(r::Value::String("token".to_string()));
+    res.append(Arc::new(QueryResult::from(error)));
+    res.set_extension("ext", r::Value::Boolean(true));
+
+    let expected = serde_json::to_value(&res).unwrap();
+    assert_eq!(
+        json!("115792089237316195423570985008687907853269984665640564039457584007913129639935"),
+        expected["data"]["token"]["supply"]
+    );
+    let cbor: serde_json::Value =
+        serde_cbor::from_slice(&ResponseEncoding::Cbor.encode(&res)).unwrap();
+    assert_eq!(expected, cbor);
+    let msgpack: serde_json::Value =
+        rmp_serde::from_slice(&ResponseEncoding::MessagePack.encode(&res)).unwrap();
+    assert_eq!(expected, msgpack);
+}
+
+#[test]
+fn content_encodings() {
+    use std::io::Read;
+
+    assert_eq!(
+        ContentEncoding::Identity,
+        ContentEncoding::from_accept_encoding(None)
+    );
+    assert_eq!(
+        ContentEncoding::Identity,
+        ContentEncoding::from_accept_encoding(Some("br, deflate"))
+    );
+    assert_eq!(
+        ContentEncoding::Gzip,
+        ContentEncoding::from_accept_encoding(Some("deflate, gzip;q=1.0, *;q=0.5"))
+    );
+    assert_eq!(
+        ContentEncoding::Zstd,
+        ContentEncoding::from_accept_encoding(Some("gzip;q=0.8, zstd"))
+    );
+    assert_eq!(
+        ContentEncoding::Identity,
+        ContentEncoding::from_accept_encoding(Some("gzip;q=0"))
+    );
+    assert_eq!(
+        ContentEncoding::Gzip,
+        ContentEncoding::from_accept_encoding(Some("*"))
+    );
+    assert_eq!(
+        ContentEncoding::Gzip,
+        ContentEncoding::from_accept_encoding(Some("*, zstd;q=0"))
+    );
+    assert_eq!(
+        ContentEncoding::Zstd,
+        ContentEncoding::from_accept_encoding(Some("gzip;q=0, *"))
+    );
+    assert_eq!(
+        ContentEncoding::Identity,
+        ContentEncoding::from_accept_encoding(Some("*, gzip;q=0, zstd;q=0"))
+    );
+
+    let json = br#"{"data":{"musicians":[{"id":"m1"},{"id":"m2"}]}}"#;
+    let mut gunzipped = Vec::new();
+    flate2::read::GzDecoder::new(&ContentEncoding::Gzip.compress(json).unwrap()[..])
+        .read_to_end(&mut gunzipped)
+        .unwrap();
+    assert_eq!(&json[..], &gunzipped[..]);
+    let unzstd = zstd::decode_all(&ContentEncoding::Zstd.compress(json).unwrap()[..]).unwrap();
+    assert_eq!(&json[..], &unzstd[..]);
+
+    // Responses are not compressed unless the threshold is set
+    let res = QueryResults::from(QueryResult::from(QueryExecutionError::TooExpensive));
+    let response: http::Response<Vec<u8>> =
+        res.as_encoded_http_response(ResponseEncoding::Json, ContentEncoding::Gzip);
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(
+        "Accept, Accept-Encoding",
+        response.headers().get(VARY).unwrap()
+    );
+}
+
+#[test]
+fn etags_differ_by_representation() {
+    let mut res = QueryResults::from(QueryResult::from(QueryExecutionError::TooExpensive));
+    res.set_etag("0123abcd".to_string());
+    let etag = |encoding| {
+        let response: http::Response<Vec<u8>> =
+            res.as_encoded_http_response(encoding, ContentEncoding::Identity);
+        response
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!("\"0123abcd\"", etag(ResponseEncoding::Json));
+    assert_eq!("\"0123abcd-cbor\"", etag(ResponseEncoding::Cbor));
+    assert_eq!("\"0123abcd-msgpack\"", etag(ResponseEncoding::MessagePack));
+
+    // Compression is turned off unless the threshold is set, and the
+    // response is the same whether or not the client accepts it
+    assert_eq!(
+        "",
+        etag_suffix(ResponseEncoding::Json, ContentEncoding::Gzip)
+    );
+}
+
+#[test]
+fn retry_after_header() {
+    let res = QueryResults::from(QueryResult::from(QueryExecutionError::TooExpensive));
+    let response: http::Response<String> = res.as_http_response();
+    assert!(response.headers().get(RETRY_AFTER).is_none());
+
+    let res = QueryResults::from(QueryResult::from(QueryExecutionError::Throttled {
+        retry_after: Duration::from_secs(7),
+    }));
+    assert_eq!(Some(Duration::from_secs(7)), res.retry_after());
+    let response: http::Response<String> = res.as_http_response();
+    assert_eq!("7", response.headers().get(RETRY_AFTER).unwrap());
+}
+
+#[test]
+fn database_unavailable() {
+    use crate::components::store::StoreError;
+
+    let unavailable = QueryExecutionError::from(StoreError::DatabaseUnavailable)
+        .in_path(r::Value::String("musicians".to_string()));
+    assert!(QueryResults::from(QueryResult::from(unavailable)).database_unavailable());
+
+    let other = QueryExecutionError::from(StoreError::Unknown(anyhow::anyhow!("boom")));
+    assert!(!QueryResults::from(QueryResult::from(other)).database_unavailable());
+    assert!(
+        !QueryResults::from(QueryResult::from(QueryExecutionError::TooExpensive))
+            .database_unavailable()
+    );
+}
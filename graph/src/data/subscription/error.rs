@@ -1,6 +1,6 @@
 use serde::ser::*;
 
-use crate::prelude::QueryExecutionError;
+use crate::prelude::{DeploymentHash, QueryExecutionError};
 use thiserror::Error;
 
 /// Error caused while processing a [Subscription](struct.Subscription.html) request.
@@ -8,6 +8,27 @@ use thiserror::Error;
 pub enum SubscriptionError {
     #[error("GraphQL error: {0:?}")]
     GraphQLError(Vec<QueryExecutionError>),
+    #[error("Too many subscriptions: {0}")]
+    TooManySubscriptions(String),
+    #[error("Deployment {0} is no longer available; it was removed or reassigned")]
+    DeploymentUnavailable(DeploymentHash),
+    /// An error after which a running subscription was ended
+    #[error("{0}")]
+    Fatal(QueryExecutionError),
+}
+
+impl SubscriptionError {
+    /// Whether a running subscription that reported this error keeps
+    /// running; it then reports the result for the next change as usual.
+    /// The subscription ends after reporting any other error
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            SubscriptionError::GraphQLError(_) => true,
+            SubscriptionError::TooManySubscriptions(_)
+            | SubscriptionError::DeploymentUnavailable(_)
+            | SubscriptionError::Fatal(_) => false,
+        }
+    }
 }
 
 impl From<QueryExecutionError> for SubscriptionError {
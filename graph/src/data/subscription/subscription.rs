@@ -1,11 +1,20 @@
 use crate::prelude::Query;
 
 /// A GraphQL subscription made by a client.
-///
-/// At the moment, this only contains the GraphQL query submitted as the
-/// subscription payload.
 #[derive(Clone, Debug)]
 pub struct Subscription {
     /// The GraphQL subscription query.
     pub query: Query,
+    /// The name of a subscription that was registered with
+    /// `GraphQlRunner::register_subscription`. If it is set, the document
+    /// registered under that name is run instead of the document of
+    /// `query`, with the variables of `query`
+    pub name: Option<String>,
+    /// The connection over which the client made the subscription, if the
+    /// transport has connections.
+    pub connection_id: Option<String>,
+    /// Whether to only send results when the entities the subscription
+    /// reads change, and not the result for their current state right
+    /// away
+    pub skip_initial: bool,
 }
@@ -76,6 +76,13 @@ impl Object {
             .map(|entry| &entry.value)
     }
 
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.0
+            .iter_mut()
+            .find(|entry| entry.has_key(key))
+            .map(|entry| &mut entry.value)
+    }
+
     pub fn remove(&mut self, key: &str) -> Option<Value> {
         self.0
             .iter_mut()
@@ -324,7 +331,8 @@ impl Serialize for Value {
             Value::Null => serializer.serialize_none(),
             Value::String(s) => serializer.serialize_str(s),
             Value::Object(o) => {
-                let mut map = serializer.serialize_map(Some(o.len()))?;
+                // Removed entries are not serialized and must not be counted
+                let mut map = serializer.serialize_map(Some(o.iter().count()))?;
                 for (k, v) in o {
                     map.serialize_entry(k, v)?;
                 }
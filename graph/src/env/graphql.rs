@@ -8,6 +8,10 @@ pub struct EnvVarsGraphQl {
     pub enable_validations: bool,
     /// Set by the flag `SILENT_GRAPHQL_VALIDATIONS`. On by default.
     pub silent_graphql_validations: bool,
+    /// Whether arguments that a field or directive does not have are
+    /// ignored with a warning instead of failing validation. Set by the
+    /// flag `GRAPH_GRAPHQL_IGNORE_UNKNOWN_ARGUMENTS`. Off by default.
+    pub ignore_unknown_arguments: bool,
     pub subscription_throttle_interval: Duration,
     /// This is the timeout duration for SQL queries.
     ///
@@ -63,12 +67,54 @@ pub struct EnvVarsGraphQl {
     /// Set by the environment variable `GRAPH_GRAPHQL_MAX_DEPTH`. The default
     /// value is 255.
     pub max_depth: u8,
+    /// The maximum number of different block constraints in a query. Set
+    /// by the environment variable `GRAPH_GRAPHQL_MAX_BLOCK_CONSTRAINTS`.
+    /// The default value is 100.
+    pub max_block_constraints: usize,
+    /// The maximum number of aliased fields in a query, counting fields in
+    /// fragments once for every time the fragment is used. Set by the
+    /// environment variable `GRAPH_GRAPHQL_MAX_ALIASES`. The default value
+    /// is [`usize::MAX`].
+    pub max_aliases: usize,
+    /// The maximum complexity of a subscription. Set by the environment
+    /// variable `GRAPH_GRAPHQL_SUBSCRIPTION_MAX_COMPLEXITY`. The default
+    /// value is `max_complexity`.
+    pub subscription_max_complexity: Option<u64>,
+    /// The maximum depth of a subscription. Set by the environment variable
+    /// `GRAPH_GRAPHQL_SUBSCRIPTION_MAX_DEPTH`. The default value is
+    /// `max_depth`.
+    pub subscription_max_depth: u8,
     /// Set by the environment variable `GRAPH_GRAPHQL_MAX_FIRST`. The default
     /// value is 1000.
     pub max_first: u32,
     /// Set by the environment variable `GRAPH_GRAPHQL_MAX_SKIP`. The default
     /// value is 4294967295 ([`u32::MAX`]).
     pub max_skip: u32,
+    /// The largest value that a per-deployment override in the `[query]`
+    /// section of the configuration file can set for `max_first`. Set by
+    /// the environment variable `GRAPH_GRAPHQL_MAX_FIRST_CEILING`. The
+    /// default value is 10000.
+    pub max_first_ceiling: u32,
+    /// The largest value that a per-deployment override can set for
+    /// `max_skip`. Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_SKIP_CEILING`. The default value is 4294967295
+    /// ([`u32::MAX`]).
+    pub max_skip_ceiling: u32,
+    /// The longest timeout that a per-deployment override can set. Set by
+    /// the environment variable `GRAPH_GRAPHQL_QUERY_TIMEOUT_CEILING`
+    /// (expressed in seconds). No default value is provided.
+    pub query_timeout_ceiling: Option<Duration>,
+    /// The absolute maximum for `first` that applies after the default,
+    /// the per-deployment override and the limit the caller passed for a
+    /// query have been resolved, so that no combination of settings can
+    /// remove the protection for the store. Set by the environment
+    /// variable `GRAPH_GRAPHQL_ABSOLUTE_MAX_FIRST`. The default value is
+    /// 100000.
+    pub absolute_max_first: u32,
+    /// The absolute maximum for `skip`, like `absolute_max_first`. Set by
+    /// the environment variable `GRAPH_GRAPHQL_ABSOLUTE_MAX_SKIP`. The
+    /// default value is 4294967295 ([`u32::MAX`]).
+    pub absolute_max_skip: u32,
     /// Allow skipping the check whether a deployment has changed while
     /// we were running a query. Once we are sure that the check mechanism
     /// is reliable, this variable should be removed.
@@ -81,9 +127,194 @@ pub struct EnvVarsGraphQl {
     /// Set by the environment variable `GRAPH_GRAPHQL_ERROR_RESULT_SIZE`. The
     /// default value is [`usize::MAX`].
     pub error_result_size: usize,
+    /// The most memory, in bytes, that the entities a query loads from the
+    /// store may take up, including entities that do not end up in the
+    /// result. Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_QUERY_MEMORY`. The default value is
+    /// [`usize::MAX`].
+    pub max_query_memory: usize,
+    /// The largest result that a single update of a subscription may have.
+    /// Subscriptions with a larger update are ended with an error. Set by
+    /// the environment variable `GRAPH_GRAPHQL_SUBSCRIPTION_MAX_RESULT_SIZE`.
+    /// The default value is [`usize::MAX`].
+    pub subscription_max_result_size: usize,
+    /// How long a subscription waits after a change before it re-runs its
+    /// query, so that all changes within that window cause a single
+    /// update. Set by the environment variable
+    /// `GRAPH_GRAPHQL_SUBSCRIPTION_DEBOUNCE_MS`. Defaults to 0, i.e.,
+    /// subscriptions re-run right away.
+    pub subscription_debounce: Duration,
     /// Set by the flag `GRAPH_GRAPHQL_MAX_OPERATIONS_PER_CONNECTION`.
     /// Defaults to 1000.
     pub max_operations_per_connection: usize,
+    /// How many automatic persisted queries to remember. When the limit is
+    /// reached, the least recently used query is evicted.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE`. The default value is 1000.
+    pub persisted_query_cache_size: usize,
+    /// How many validated query documents to remember, so that queries
+    /// with the same text are only validated once. A value of 0 turns the
+    /// cache off.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_PREPARED_QUERY_CACHE_SIZE`. The default value is 1000.
+    pub prepared_query_cache_size: usize,
+    /// The fraction of queries for which to log which fields they select,
+    /// between 0 and 1. Set by the environment variable
+    /// `GRAPH_GRAPHQL_FIELD_AUDIT_SAMPLE_RATE`. The default value is 0,
+    /// i.e., no queries are audited.
+    pub field_audit_sample_rate: f64,
+    /// The fraction of queries, between 0 and 1, for which OpenTelemetry
+    /// spans are recorded. Set by the environment variable
+    /// `GRAPH_GRAPHQL_SPAN_SAMPLE_RATE`. The default value is 0, i.e., no
+    /// spans are recorded.
+    pub span_sample_rate: f64,
+    /// Whether to give every query request an id that is reported under
+    /// `extensions.requestId` and attached to the log lines for the query.
+    /// Set by the flag `GRAPH_GRAPHQL_REQUEST_IDS`. Off by default.
+    pub request_ids: bool,
+    /// Whether the field audit log includes the variables of the query.
+    /// Set by the flag `GRAPH_GRAPHQL_FIELD_AUDIT_VARIABLES`. Off by
+    /// default.
+    pub field_audit_variables: bool,
+    /// How often a query that failed because the database replica it was
+    /// sent to is unavailable is run again against the primary. Set by the
+    /// environment variable `GRAPH_GRAPHQL_REPLICA_FAILOVER_RETRIES`. The
+    /// default value is 1; 0 turns the failover off.
+    pub replica_failover_retries: usize,
+    /// The longest time a query may wait for the deployment to reach the
+    /// block it asks for when the client opts into waiting. Set by the
+    /// environment variable `GRAPH_GRAPHQL_MAX_BLOCK_WAIT` (expressed in
+    /// seconds). The default value is 0, i.e., queries never wait.
+    pub max_block_wait: Duration,
+    /// The `query_result_max` gauge reports the largest query result seen
+    /// within this window. Set by the environment variable
+    /// `GRAPH_QUERY_RESULT_MAX_WINDOW` (expressed in seconds). The default
+    /// value is 300; 0 makes the gauge report the largest result since
+    /// startup.
+    pub query_result_max_window: Duration,
+    /// The bucket boundaries of the `query_result_size` histogram as a
+    /// comma separated list of sizes. When this is not set, or can not be
+    /// parsed into a strictly increasing list of numbers, exponentially
+    /// sized buckets between 1k and 4G are used.
+    ///
+    /// Set by the environment variable `GRAPH_QUERY_RESULT_SIZE_BUCKETS`. No
+    /// default value is provided.
+    pub query_result_size_buckets: Option<String>,
+    /// How long a subscription can go without sending a result before we
+    /// send a keepalive message to the client.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_WS_KEEPALIVE_INTERVAL` (expressed in seconds). The
+    /// default value is 30s. Zero turns keepalives off.
+    pub ws_keepalive_interval: Duration,
+    /// The maximum number of subscriptions a single connection can have
+    /// active at the same time.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_CONNECTION`. No default value is
+    /// provided.
+    pub max_subscriptions_per_connection: Option<usize>,
+    /// The maximum number of subscriptions that can be active for a single
+    /// deployment at the same time.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_DEPLOYMENT`. No default value is
+    /// provided.
+    pub max_subscriptions_per_deployment: Option<usize>,
+    /// The maximum number of queries that can be executed for a single
+    /// deployment at the same time.
+    ///
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_CONCURRENT_QUERIES_PER_DEPLOYMENT`. No default
+    /// value is provided.
+    pub max_concurrent_queries_per_deployment: Option<usize>,
+    /// How long a query waits for one of the other queries for its
+    /// deployment to finish when `max_concurrent_queries_per_deployment`
+    /// of them are already running. Set by the environment variable
+    /// `GRAPH_GRAPHQL_DEPLOYMENT_QUEUE_TIMEOUT` (expressed in
+    /// milliseconds). The default value is 0, i.e., such queries are
+    /// rejected right away.
+    pub deployment_queue_timeout: Duration,
+    /// Multipliers for the complexity of fetching entities of individual
+    /// types, used when checking queries against `max_complexity`.
+    ///
+    /// Set by the environment variable `GRAPH_GRAPHQL_COMPLEXITY_WEIGHTS`
+    /// as a comma separated list of `Type=weight` pairs. By default, all
+    /// types have a weight of 1.
+    pub complexity_weights: ComplexityWeights,
+    /// Collections that a query does not order with `orderBy` are ordered
+    /// by `id` so that their results are the same on every replica. Turning
+    /// that off saves sorting them in the database, but the order of the
+    /// entities, and which entities a query with `first` or `skip` returns,
+    /// can then differ between executions of the same query.
+    ///
+    /// Set by the flag `GRAPH_GRAPHQL_DISABLE_DEFAULT_ORDER`. Off by default.
+    pub disable_default_order: bool,
+    /// Make the shape hash of a query depend on the order of its
+    /// definitions, selections and arguments, as it did in the past. This
+    /// is meant for debugging effort tracking.
+    ///
+    /// Set by the flag `GRAPH_GRAPHQL_DISABLE_SHAPE_NORMALIZATION`. Off by
+    /// default.
+    pub disable_shape_normalization: bool,
+    /// How many references `orderBy` can follow to get to the attribute
+    /// by which a collection is ordered, e.g., ordering tokens by
+    /// `owner__createdAt` follows one reference. Each additional level
+    /// adds an enum value to `<Type>_orderBy` for every attribute that
+    /// can be reached that way.
+    ///
+    /// Set by the environment variable `GRAPH_GRAPHQL_MAX_ORDER_BY_DEPTH`.
+    /// The default value is 1; 0 only allows ordering by the attributes of
+    /// the entities themselves.
+    pub max_order_by_depth: usize,
+    /// How long the complete results of successful queries are cached.
+    /// Cached results are also dropped as soon as their deployment
+    /// advances to a new block.
+    ///
+    /// Set by the environment variable `GRAPH_QUERY_RESULT_CACHE_TTL` as a
+    /// comma separated list; a number of seconds by itself sets the TTL for
+    /// all deployments, and `Qm...=seconds` sets it for one deployment. By
+    /// default, query results are not cached.
+    pub query_result_cache_ttl: ResultCacheTtl,
+    /// The error policy of queries that do not set `subgraphError` on
+    /// their toplevel fields. Set by the environment variable
+    /// `GRAPH_GRAPHQL_DEFAULT_ERROR_POLICY`, which must be `allow` or
+    /// `deny`. The default value is `deny`.
+    pub default_error_policy: DefaultErrorPolicy,
+    /// Maximum total memory to be used by the query result cache.
+    ///
+    /// Set by the environment variable `GRAPH_QUERY_RESULT_CACHE_MAX_MEM`
+    /// (expressed in MB). The default value is 100MB.
+    pub query_result_cache_max_mem: usize,
+    /// Queries that take at least this long are always logged in a
+    /// `Query execution` record. Set by the environment variable
+    /// `GRAPH_GRAPHQL_SLOW_QUERY_THRESHOLD_MS`. The default value is 0,
+    /// i.e., queries are not logged because they are slow.
+    pub slow_query_threshold: Option<Duration>,
+    /// The fraction of queries, between 0 and 1, that are logged in a
+    /// `Query execution` record even though they are not slow. Set by the
+    /// environment variable `GRAPH_GRAPHQL_QUERY_LOG_SAMPLE_RATE`. The
+    /// default value is 0.
+    pub query_log_sample_rate: f64,
+    /// Responses that are at least this many bytes are compressed if the
+    /// client accepts a compressed response. Set by the environment
+    /// variable `GRAPH_GRAPHQL_COMPRESSION_THRESHOLD`. The default value is
+    /// [`usize::MAX`], i.e., responses are never compressed.
+    pub compression_threshold: usize,
+    /// The level, between 0 and 9, at which responses are compressed with
+    /// gzip. Set by the environment variable `GRAPH_GRAPHQL_GZIP_LEVEL`. The
+    /// default value is 6.
+    pub gzip_level: u32,
+    /// The level, between 1 and 22, at which responses are compressed with
+    /// zstd. Set by the environment variable `GRAPH_GRAPHQL_ZSTD_LEVEL`. The
+    /// default value is 3.
+    pub zstd_level: i32,
+    /// Whether responses list the fields marked `@deprecated` in the
+    /// schema that the query selected under `extensions.deprecations`. Set
+    /// by the flag `GRAPH_GRAPHQL_REPORT_DEPRECATIONS`. Off by default.
+    pub report_deprecations: bool,
 }
 
 // This does not print any values avoid accidentally leaking any sensitive env vars
@@ -98,6 +329,7 @@ impl From<InnerGraphQl> for EnvVarsGraphQl {
         Self {
             enable_validations: x.enable_validations.0,
             silent_graphql_validations: x.silent_graphql_validations.0,
+            ignore_unknown_arguments: x.ignore_unknown_arguments.0,
             subscription_throttle_interval: Duration::from_millis(
                 x.subscription_throttle_interval_in_ms,
             ),
@@ -122,22 +354,79 @@ impl From<InnerGraphQl> for EnvVarsGraphQl {
             query_timeout: x.query_timeout_in_secs.map(Duration::from_secs),
             max_complexity: x.max_complexity.map(|x| x.0),
             max_depth: x.max_depth.0,
+            max_block_constraints: x.max_block_constraints,
+            max_aliases: x.max_aliases.0 .0,
+            subscription_max_complexity: x
+                .subscription_max_complexity
+                .map(|x| x.0)
+                .or(x.max_complexity.map(|x| x.0)),
+            subscription_max_depth: x.subscription_max_depth.unwrap_or(x.max_depth.0),
             max_first: x.max_first,
             max_skip: x.max_skip.0,
+            max_first_ceiling: x.max_first_ceiling,
+            max_skip_ceiling: x.max_skip_ceiling.0,
+            query_timeout_ceiling: x.query_timeout_ceiling_in_secs.map(Duration::from_secs),
+            absolute_max_first: x.absolute_max_first,
+            absolute_max_skip: x.absolute_max_skip.0,
             allow_deployment_change: x.allow_deployment_change.0,
             warn_result_size: x.warn_result_size.0 .0,
             error_result_size: x.error_result_size.0 .0,
+            max_query_memory: x.max_query_memory.0 .0,
+            subscription_max_result_size: x.subscription_max_result_size.0 .0,
+            subscription_debounce: Duration::from_millis(x.subscription_debounce_in_ms),
             max_operations_per_connection: x.max_operations_per_connection,
+            persisted_query_cache_size: x.persisted_query_cache_size,
+            prepared_query_cache_size: x.prepared_query_cache_size,
+            field_audit_sample_rate: sample_rate(x.field_audit_sample_rate),
+            span_sample_rate: sample_rate(x.span_sample_rate),
+            request_ids: x.request_ids.0,
+            field_audit_variables: x.field_audit_variables.0,
+            replica_failover_retries: x.replica_failover_retries,
+            max_block_wait: Duration::from_secs(x.max_block_wait_in_secs),
+            query_result_max_window: Duration::from_secs(x.query_result_max_window_in_secs),
+            query_result_size_buckets: x.query_result_size_buckets,
+            ws_keepalive_interval: Duration::from_secs(x.ws_keepalive_interval_in_secs),
+            max_subscriptions_per_connection: x.max_subscriptions_per_connection,
+            max_subscriptions_per_deployment: x.max_subscriptions_per_deployment,
+            max_concurrent_queries_per_deployment: x.max_concurrent_queries_per_deployment,
+            deployment_queue_timeout: Duration::from_millis(x.deployment_queue_timeout_in_ms),
+            complexity_weights: x.complexity_weights,
+            disable_default_order: x.disable_default_order.0,
+            disable_shape_normalization: x.disable_shape_normalization.0,
+            max_order_by_depth: x.max_order_by_depth,
+            query_result_cache_ttl: x.query_result_cache_ttl,
+            default_error_policy: x.default_error_policy,
+            query_result_cache_max_mem: x.query_result_cache_max_mem_in_mb.0 * 1000 * 1000,
+            slow_query_threshold: Some(x.slow_query_threshold_in_ms)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            query_log_sample_rate: sample_rate(x.query_log_sample_rate),
+            compression_threshold: x.compression_threshold.0 .0,
+            gzip_level: x.gzip_level.min(9),
+            zstd_level: x.zstd_level.clamp(1, 22),
+            report_deprecations: x.report_deprecations.0,
         }
     }
 }
 
+/// Turn the sample `rate` from the environment into a probability. Rates
+/// that are not finite, like `NaN`, turn sampling off
+fn sample_rate(rate: f64) -> f64 {
+    if rate.is_finite() {
+        rate.clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
 #[derive(Clone, Debug, Envconfig)]
 pub struct InnerGraphQl {
     #[envconfig(from = "ENABLE_GRAPHQL_VALIDATIONS", default = "true")]
     enable_validations: EnvVarBoolean,
     #[envconfig(from = "SILENT_GRAPHQL_VALIDATIONS", default = "true")]
     silent_graphql_validations: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_IGNORE_UNKNOWN_ARGUMENTS", default = "false")]
+    ignore_unknown_arguments: EnvVarBoolean,
     #[envconfig(from = "SUBSCRIPTION_THROTTLE_INTERVAL", default = "1000")]
     subscription_throttle_interval_in_ms: u64,
     #[envconfig(from = "GRAPH_SQL_STATEMENT_TIMEOUT")]
@@ -161,16 +450,106 @@ pub struct InnerGraphQl {
     max_complexity: Option<NoUnderscores<u64>>,
     #[envconfig(from = "GRAPH_GRAPHQL_MAX_DEPTH", default = "")]
     max_depth: WithDefaultUsize<u8, { u8::MAX as usize }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_BLOCK_CONSTRAINTS", default = "100")]
+    max_block_constraints: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_ALIASES", default = "")]
+    max_aliases: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_MAX_COMPLEXITY")]
+    subscription_max_complexity: Option<NoUnderscores<u64>>,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_MAX_DEPTH")]
+    subscription_max_depth: Option<u8>,
     #[envconfig(from = "GRAPH_GRAPHQL_MAX_FIRST", default = "1000")]
     max_first: u32,
     #[envconfig(from = "GRAPH_GRAPHQL_MAX_SKIP", default = "")]
     max_skip: WithDefaultUsize<u32, { u32::MAX as usize }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_FIRST_CEILING", default = "10000")]
+    max_first_ceiling: u32,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_SKIP_CEILING", default = "")]
+    max_skip_ceiling: WithDefaultUsize<u32, { u32::MAX as usize }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_QUERY_TIMEOUT_CEILING")]
+    query_timeout_ceiling_in_secs: Option<u64>,
+    #[envconfig(from = "GRAPH_GRAPHQL_ABSOLUTE_MAX_FIRST", default = "100000")]
+    absolute_max_first: u32,
+    #[envconfig(from = "GRAPH_GRAPHQL_ABSOLUTE_MAX_SKIP", default = "")]
+    absolute_max_skip: WithDefaultUsize<u32, { u32::MAX as usize }>,
     #[envconfig(from = "GRAPHQL_ALLOW_DEPLOYMENT_CHANGE", default = "false")]
     allow_deployment_change: EnvVarBoolean,
     #[envconfig(from = "GRAPH_GRAPHQL_WARN_RESULT_SIZE", default = "")]
     warn_result_size: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
     #[envconfig(from = "GRAPH_GRAPHQL_ERROR_RESULT_SIZE", default = "")]
     error_result_size: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_QUERY_MEMORY", default = "")]
+    max_query_memory: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_MAX_RESULT_SIZE", default = "")]
+    subscription_max_result_size: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_DEBOUNCE_MS", default = "0")]
+    subscription_debounce_in_ms: u64,
     #[envconfig(from = "GRAPH_GRAPHQL_MAX_OPERATIONS_PER_CONNECTION", default = "1000")]
     max_operations_per_connection: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE", default = "1000")]
+    persisted_query_cache_size: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_PREPARED_QUERY_CACHE_SIZE", default = "1000")]
+    prepared_query_cache_size: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_FIELD_AUDIT_SAMPLE_RATE", default = "0")]
+    field_audit_sample_rate: f64,
+    #[envconfig(from = "GRAPH_GRAPHQL_SPAN_SAMPLE_RATE", default = "0")]
+    span_sample_rate: f64,
+    #[envconfig(from = "GRAPH_GRAPHQL_REQUEST_IDS", default = "false")]
+    request_ids: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_FIELD_AUDIT_VARIABLES", default = "false")]
+    field_audit_variables: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_REPLICA_FAILOVER_RETRIES", default = "1")]
+    replica_failover_retries: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_BLOCK_WAIT", default = "0")]
+    max_block_wait_in_secs: u64,
+    #[envconfig(from = "GRAPH_QUERY_RESULT_MAX_WINDOW", default = "300")]
+    query_result_max_window_in_secs: u64,
+    #[envconfig(from = "GRAPH_QUERY_RESULT_SIZE_BUCKETS")]
+    query_result_size_buckets: Option<String>,
+    #[envconfig(from = "GRAPH_GRAPHQL_WS_KEEPALIVE_INTERVAL", default = "30")]
+    ws_keepalive_interval_in_secs: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_CONNECTION")]
+    max_subscriptions_per_connection: Option<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_DEPLOYMENT")]
+    max_subscriptions_per_deployment: Option<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_CONCURRENT_QUERIES_PER_DEPLOYMENT")]
+    max_concurrent_queries_per_deployment: Option<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_DEPLOYMENT_QUEUE_TIMEOUT", default = "0")]
+    deployment_queue_timeout_in_ms: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_COMPLEXITY_WEIGHTS", default = "")]
+    complexity_weights: ComplexityWeights,
+    #[envconfig(from = "GRAPH_GRAPHQL_DISABLE_DEFAULT_ORDER", default = "false")]
+    disable_default_order: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_DISABLE_SHAPE_NORMALIZATION", default = "false")]
+    disable_shape_normalization: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_ORDER_BY_DEPTH", default = "1")]
+    max_order_by_depth: usize,
+    #[envconfig(from = "GRAPH_QUERY_RESULT_CACHE_TTL", default = "")]
+    query_result_cache_ttl: ResultCacheTtl,
+    #[envconfig(from = "GRAPH_GRAPHQL_DEFAULT_ERROR_POLICY", default = "deny")]
+    default_error_policy: DefaultErrorPolicy,
+    #[envconfig(from = "GRAPH_QUERY_RESULT_CACHE_MAX_MEM", default = "100")]
+    query_result_cache_max_mem_in_mb: NoUnderscores<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_SLOW_QUERY_THRESHOLD_MS", default = "0")]
+    slow_query_threshold_in_ms: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_QUERY_LOG_SAMPLE_RATE", default = "0")]
+    query_log_sample_rate: f64,
+    #[envconfig(from = "GRAPH_GRAPHQL_COMPRESSION_THRESHOLD", default = "")]
+    compression_threshold: WithDefaultUsize<NoUnderscores<usize>, { usize::MAX }>,
+    #[envconfig(from = "GRAPH_GRAPHQL_GZIP_LEVEL", default = "6")]
+    gzip_level: u32,
+    #[envconfig(from = "GRAPH_GRAPHQL_ZSTD_LEVEL", default = "3")]
+    zstd_level: i32,
+    #[envconfig(from = "GRAPH_GRAPHQL_REPORT_DEPRECATIONS", default = "false")]
+    report_deprecations: EnvVarBoolean,
+}
+
+#[test]
+fn sample_rates_are_probabilities() {
+    assert_eq!(0.25, sample_rate(0.25));
+    assert_eq!(1.0, sample_rate(2.0));
+    assert_eq!(0.0, sample_rate(-1.0));
+    assert_eq!(0.0, sample_rate(f64::NAN));
+    assert_eq!(0.0, sample_rate(f64::INFINITY));
+    assert_eq!(0.0, sample_rate(f64::NEG_INFINITY));
 }
@@ -6,7 +6,7 @@ use envconfig::Envconfig;
 use lazy_static::lazy_static;
 use semver::Version;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env::VarError,
     fmt,
     str::FromStr,
@@ -104,6 +104,27 @@ pub struct EnvVars {
     ///
     /// Set by the flag `GRAPH_LOAD_SIMULATE`.
     pub load_simulate: bool,
+    /// How many of the queries that the load manager declined to run most
+    /// recently it remembers for `LoadManager::recent_sheds`. Setting this
+    /// to 0 turns remembering them off.
+    ///
+    /// Set by the environment variable `GRAPH_LOAD_SHED_LOG_SIZE`. The
+    /// default value is 100.
+    pub load_shed_log_size: usize,
+    /// The priority lanes that queries can be put into, and how much more
+    /// effort queries in each lane may cause before they are shed, relative
+    /// to queries without a lane.
+    ///
+    /// Set by the environment variable `GRAPH_LOAD_LANES`, for example as
+    /// `paid=4,partner=2`. The default is to have no lanes.
+    pub load_lanes: LoadLanes,
+    /// When the load manager sheds more than this fraction of queries
+    /// because the node is overloaded, `LoadManager::health` reports the
+    /// node as degraded.
+    ///
+    /// Set by the environment variable `GRAPH_LOAD_DEGRADED_SHED_RATE`. The
+    /// default value is 0.5.
+    pub load_degraded_shed_rate: f64,
     /// Set by the flag `GRAPH_ALLOW_NON_DETERMINISTIC_FULLTEXT_SEARCH`, but
     /// enabled anyway (overridden) if [debug
     /// assertions](https://doc.rust-lang.org/reference/conditional-compilation.html#debug_assertions)
@@ -220,6 +241,9 @@ impl EnvVars {
             load_threshold: Duration::from_millis(inner.load_threshold_in_ms),
             load_jail_threshold: inner.load_jail_threshold,
             load_simulate: inner.load_simulate.0,
+            load_shed_log_size: inner.load_shed_log_size,
+            load_lanes: inner.load_lanes,
+            load_degraded_shed_rate: inner.load_degraded_shed_rate.clamp(0.0, 1.0),
             allow_non_deterministic_fulltext_search: inner
                 .allow_non_deterministic_fulltext_search
                 .0
@@ -305,6 +329,12 @@ struct Inner {
     load_jail_threshold: Option<f64>,
     #[envconfig(from = "GRAPH_LOAD_SIMULATE", default = "false")]
     load_simulate: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_LOAD_SHED_LOG_SIZE", default = "100")]
+    load_shed_log_size: usize,
+    #[envconfig(from = "GRAPH_LOAD_LANES", default = "")]
+    load_lanes: LoadLanes,
+    #[envconfig(from = "GRAPH_LOAD_DEGRADED_SHED_RATE", default = "0.5")]
+    load_degraded_shed_rate: f64,
     #[envconfig(
         from = "GRAPH_ALLOW_NON_DETERMINISTIC_FULLTEXT_SEARCH",
         default = "false"
@@ -379,6 +409,174 @@ pub enum CachedSubgraphIds {
     Only(Vec<String>),
 }
 
+/// Multipliers for the cost of fetching entities of a given type when
+/// computing the complexity of a query. Parsed from a comma separated list
+/// of `Type=weight` pairs; types that are not listed have a weight of 1
+#[derive(Clone, Debug, Default)]
+pub struct ComplexityWeights(HashMap<String, f64>);
+
+impl ComplexityWeights {
+    pub fn weight(&self, type_name: &str) -> f64 {
+        self.0.get(type_name).copied().unwrap_or(1.0)
+    }
+}
+
+impl FromStr for ComplexityWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (type_name, weight) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected `Type=weight` but got `{}`", pair))?;
+                let weight = weight
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|weight| weight.is_finite() && *weight >= 0.0)
+                    .ok_or_else(|| format!("invalid weight for `{}`", type_name.trim()))?;
+                Ok((type_name.trim().to_string(), weight))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self(weights))
+    }
+}
+
+/// The effort budget of each priority lane, as a multiple of the budget of
+/// queries that are not in any lane
+#[derive(Clone, Debug, Default)]
+pub struct LoadLanes(HashMap<String, f64>);
+
+impl LoadLanes {
+    /// The label under which queries in `lane` are reported in metrics.
+    /// Queries without a lane or in a lane that is not configured are
+    /// reported as `default`
+    pub fn label<'a>(&'a self, lane: Option<&'a str>) -> &'a str {
+        match lane {
+            Some(lane) if self.0.contains_key(lane) => lane,
+            _ => "default",
+        }
+    }
+
+    /// How many times more effort than queries without a lane queries in
+    /// `lane` may cause before they are shed
+    pub fn budget(&self, lane: Option<&str>) -> f64 {
+        lane.and_then(|lane| self.0.get(lane))
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
+impl FromStr for LoadLanes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lanes = s
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (lane, budget) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected `lane=budget` but got `{}`", pair))?;
+                let lane = lane.trim();
+                if lane == "default" {
+                    return Err("the lane `default` can not be configured".to_string());
+                }
+                let budget = budget
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|budget| budget.is_finite() && *budget > 0.0)
+                    .ok_or_else(|| format!("invalid budget for lane `{}`", lane))?;
+                Ok((lane.to_string(), budget))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self(lanes))
+    }
+}
+
+/// How long query results are cached, by default and for individual
+/// deployments
+#[derive(Clone, Debug, Default)]
+pub struct ResultCacheTtl {
+    default: Option<Duration>,
+    by_deployment: HashMap<String, Duration>,
+}
+
+impl ResultCacheTtl {
+    /// How long results for `deployment` should be cached, or `None` if
+    /// they should not be cached at all
+    pub fn ttl(&self, deployment: &str) -> Option<Duration> {
+        self.by_deployment
+            .get(deployment)
+            .copied()
+            .or(self.default)
+            .filter(|ttl| !ttl.is_zero())
+    }
+}
+
+impl FromStr for ResultCacheTtl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secs = |text: &str| {
+            text.trim()
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("invalid number of seconds `{}`", text.trim()))
+        };
+        let mut ttl = ResultCacheTtl::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            match entry.split_once('=') {
+                Some((deployment, text)) => {
+                    ttl.by_deployment
+                        .insert(deployment.trim().to_string(), secs(text)?);
+                }
+                None if ttl.default.is_some() => {
+                    return Err(format!(
+                        "the default TTL is given more than once in `{}`",
+                        s
+                    ))
+                }
+                None => ttl.default = Some(secs(entry)?),
+            }
+        }
+        Ok(ttl)
+    }
+}
+
+/// The error policy of queries that do not choose one with their
+/// `subgraphError` argument
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DefaultErrorPolicy {
+    Allow,
+    Deny,
+}
+
+impl FromStr for DefaultErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "allow" => Ok(DefaultErrorPolicy::Allow),
+            "deny" => Ok(DefaultErrorPolicy::Deny),
+            _ => Err(format!(
+                "invalid error policy `{}`, it must be `allow` or `deny`",
+                s
+            )),
+        }
+    }
+}
+
 /// When reading [`bool`] values from environment variables, we must be able to
 /// parse many different ways to specify booleans:
 ///
@@ -443,3 +641,69 @@ where
         }
     }
 }
+
+#[test]
+fn parse_complexity_weights() {
+    let weights = ComplexityWeights::from_str("Token=1.5, Metadata=10,Cheap=0.25").unwrap();
+    assert_eq!(1.5, weights.weight("Token"));
+    assert_eq!(10.0, weights.weight("Metadata"));
+    assert_eq!(0.25, weights.weight("Cheap"));
+    assert_eq!(1.0, weights.weight("Other"));
+    assert_eq!(
+        1.0,
+        ComplexityWeights::from_str("").unwrap().weight("Token")
+    );
+
+    assert!(ComplexityWeights::from_str("Token").is_err());
+    assert!(ComplexityWeights::from_str("Token=many").is_err());
+    assert!(ComplexityWeights::from_str("Token=-1").is_err());
+}
+
+#[test]
+fn parse_load_lanes() {
+    let lanes = LoadLanes::from_str("paid=4, partner=1.5").unwrap();
+    assert_eq!(4.0, lanes.budget(Some("paid")));
+    assert_eq!(1.5, lanes.budget(Some("partner")));
+    assert_eq!(1.0, lanes.budget(Some("free")));
+    assert_eq!(1.0, lanes.budget(None));
+    assert_eq!("paid", lanes.label(Some("paid")));
+    assert_eq!("default", lanes.label(Some("free")));
+    assert_eq!("default", lanes.label(None));
+    assert_eq!(1.0, LoadLanes::from_str("").unwrap().budget(Some("paid")));
+
+    assert!(LoadLanes::from_str("paid").is_err());
+    assert!(LoadLanes::from_str("paid=lots").is_err());
+    assert!(LoadLanes::from_str("paid=0").is_err());
+    assert!(LoadLanes::from_str("default=2").is_err());
+}
+
+#[test]
+fn parse_result_cache_ttl() {
+    let ttl = ResultCacheTtl::from_str("30, QmHot=5,QmOff=0").unwrap();
+    assert_eq!(Some(Duration::from_secs(30)), ttl.ttl("QmOther"));
+    assert_eq!(Some(Duration::from_secs(5)), ttl.ttl("QmHot"));
+    assert_eq!(None, ttl.ttl("QmOff"));
+
+    let ttl = ResultCacheTtl::from_str("QmHot=5").unwrap();
+    assert_eq!(Some(Duration::from_secs(5)), ttl.ttl("QmHot"));
+    assert_eq!(None, ttl.ttl("QmOther"));
+    assert_eq!(None, ResultCacheTtl::from_str("").unwrap().ttl("QmHot"));
+
+    assert!(ResultCacheTtl::from_str("soon").is_err());
+    assert!(ResultCacheTtl::from_str("QmHot=-1").is_err());
+    assert!(ResultCacheTtl::from_str("10,20").is_err());
+}
+
+#[test]
+fn parse_default_error_policy() {
+    assert_eq!(
+        Ok(DefaultErrorPolicy::Allow),
+        DefaultErrorPolicy::from_str("allow")
+    );
+    assert_eq!(
+        Ok(DefaultErrorPolicy::Deny),
+        DefaultErrorPolicy::from_str(" deny ")
+    );
+    assert!(DefaultErrorPolicy::from_str("Allow").is_err());
+    assert!(DefaultErrorPolicy::from_str("").is_err());
+}
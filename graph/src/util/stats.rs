@@ -151,6 +151,14 @@ impl MovingStats {
         }
     }
 
+    /// Drop the measurements that have fallen out of the window. Since
+    /// that otherwise only happens when measurements are added, this
+    /// keeps `average` from reporting old measurements when no new ones
+    /// arrive
+    pub fn expire(&mut self) {
+        self.expire_bins(Instant::now());
+    }
+
     pub fn duration(&self) -> Duration {
         self.total.duration
     }
@@ -127,6 +127,20 @@ impl SelectionSet {
         Ok(())
     }
 
+    /// Set the argument `name` to `value` for the fields with
+    /// `response_key` for all the sets' types. Fields further down in the
+    /// selection set are not changed
+    pub fn set_argument(&mut self, response_key: &str, name: &str, value: r::Value) {
+        for (_, fields) in &mut self.items {
+            for field in fields
+                .iter_mut()
+                .filter(|field| field.response_key() == response_key)
+            {
+                field.set_argument(name, value.clone());
+            }
+        }
+    }
+
     /// Append the fields for all the sets' types
     pub fn push_fields(&mut self, fields: Vec<&Field>) -> Result<(), QueryExecutionError> {
         for field in fields {
@@ -251,6 +265,14 @@ impl Field {
             .map(|(_, v)| v)
     }
 
+    /// Set the argument `name` to `value`, replacing any previous value
+    pub fn set_argument(&mut self, name: &str, value: r::Value) {
+        match self.arguments.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.arguments.push((name.to_string(), value)),
+        }
+    }
+
     fn prepend_directives(&mut self, mut directives: Vec<Directive>) {
         // TODO: check that the new directives don't conflict with existing
         // directives
@@ -258,7 +280,7 @@ impl Field {
         self.directives.extend(directives);
     }
 
-    fn is_leaf(&self) -> bool {
+    pub fn is_leaf(&self) -> bool {
         self.selection_set.is_empty()
     }
 }
@@ -1,7 +1,11 @@
 use futures03::future::FutureExt;
 use futures03::future::Shared;
 use graph::{
-    prelude::{debug, futures03, BlockPtr, CheapClone, Logger, QueryResult},
+    data::query::QueryResults,
+    prelude::{
+        debug, futures03, BlockNumber, BlockPtr, CacheWeight, CheapClone, DeploymentHash,
+        DeploymentState, Logger, QueryResult,
+    },
     util::timed_rw_lock::TimedMutex,
 };
 use stable_hash_legacy::crypto::SetHasher;
@@ -9,7 +13,7 @@ use stable_hash_legacy::prelude::*;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, time::Duration};
 use std::{
     collections::{hash_map::Entry, VecDeque},
@@ -249,3 +253,354 @@ impl QueryBlockCache {
         None
     }
 }
+
+/// The cached results for one deployment, all computed at `block` after
+/// the deployment had been reverted `reorg_count` times
+struct ResultsAtBlock {
+    block: BlockPtr,
+    reorg_count: u32,
+    results: HashMap<QueryHash, CachedResult>,
+}
+
+struct CachedResult {
+    result: QueryResults,
+    weight: usize,
+    inserted: Instant,
+    /// When the result expires; `None` if the TTL is so long that the
+    /// result never does
+    expires: Option<Instant>,
+    /// The position of the entry in `ResultCacheInner.order`
+    seq: u64,
+}
+
+#[derive(Default)]
+struct ResultCacheInner {
+    weight: usize,
+    by_deployment: HashMap<DeploymentHash, ResultsAtBlock>,
+    /// The entries in the order in which they were inserted, so that the
+    /// oldest ones can be evicted when the cache is full. Entries that
+    /// were removed or replaced in the meantime are skipped on eviction
+    order: VecDeque<(DeploymentHash, QueryHash, u64)>,
+    next_seq: u64,
+}
+
+impl ResultCacheInner {
+    /// Drop the results for the deployment of `state` if they are for a
+    /// block other than the latest block of `state`, and return whether
+    /// results for that block can be stored. Results for a block that is
+    /// older than the cached one, or from before a reorg that the cache
+    /// already knows about, cannot be stored, since they come from a
+    /// replica that is behind or from a query that started before the reorg
+    fn advance(&mut self, state: &DeploymentState) -> bool {
+        let cached = match self.by_deployment.get_mut(&state.id) {
+            None => return true,
+            Some(cached) => cached,
+        };
+        if state.reorg_count < cached.reorg_count {
+            return false;
+        }
+        if state.reorg_count == cached.reorg_count {
+            if cached.block == state.latest_block {
+                return true;
+            }
+            if cached.block.number > state.latest_block.number {
+                return false;
+            }
+        }
+        cached.block = state.latest_block.clone();
+        cached.reorg_count = state.reorg_count;
+        self.clear(&state.id);
+        true
+    }
+
+    /// Drop all results for `deployment`
+    fn clear(&mut self, deployment: &DeploymentHash) {
+        if let Some(cached) = self.by_deployment.get_mut(deployment) {
+            let dropped = std::mem::take(&mut cached.results);
+            self.weight -= dropped.values().map(|entry| entry.weight).sum::<usize>();
+        }
+    }
+
+    fn remove(&mut self, deployment: &DeploymentHash, key: &QueryHash) {
+        if let Some(cached) = self.by_deployment.get_mut(deployment) {
+            if let Some(entry) = cached.results.remove(key) {
+                self.weight -= entry.weight;
+            }
+        }
+    }
+
+    /// Forget the entries in `order` that were removed or replaced once
+    /// there are many more of them than entries in the cache
+    fn compact(&mut self) {
+        let live = self
+            .by_deployment
+            .values()
+            .map(|cached| cached.results.len())
+            .sum::<usize>();
+        if self.order.len() <= 2 * live {
+            return;
+        }
+        let by_deployment = &self.by_deployment;
+        self.order.retain(|(deployment, key, seq)| {
+            by_deployment
+                .get(deployment)
+                .and_then(|cached| cached.results.get(key))
+                .map_or(false, |entry| entry.seq == *seq)
+        });
+    }
+
+    /// Evict the oldest entry, and return `false` if there was none
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((deployment, key, seq)) = self.order.pop_front() {
+            let current = self
+                .by_deployment
+                .get(&deployment)
+                .and_then(|cached| cached.results.get(&key))
+                .map(|entry| entry.seq);
+            if current == Some(seq) {
+                self.remove(&deployment, &key);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A cache for the complete results of successful queries. Results are
+/// kept until their TTL runs out, but only for as long as the deployment
+/// is at the block at which they were computed; as soon as a query sees
+/// that the deployment has advanced, all results for it are dropped
+pub struct ResultCache {
+    max_weight: usize,
+    inner: Mutex<ResultCacheInner>,
+}
+
+impl ResultCache {
+    pub fn new(max_weight: usize) -> Self {
+        ResultCache {
+            max_weight,
+            inner: Mutex::new(ResultCacheInner::default()),
+        }
+    }
+
+    /// The result for the query with `key` against a deployment that is in
+    /// `state` together with how long ago it was cached, if we have one and
+    /// it has not expired yet
+    pub fn get(
+        &self,
+        state: &DeploymentState,
+        key: &QueryHash,
+    ) -> Option<(QueryResults, Duration)> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.advance(state) {
+            return None;
+        }
+        let entry = inner
+            .by_deployment
+            .get(&state.id)
+            .and_then(|cached| cached.results.get(key))?;
+        let now = Instant::now();
+        if entry.expires.map_or(true, |expires| expires > now) {
+            return Some((
+                entry.result.clone(),
+                now.saturating_duration_since(entry.inserted),
+            ));
+        }
+        inner.remove(&state.id, key);
+        None
+    }
+
+    /// Remember `result` for `ttl` as the result of the query with `key`
+    /// against a deployment that was in `state` when the query started.
+    /// Results with errors and results that are bigger than the whole
+    /// cache are not cached. To make room for others, the oldest results
+    /// are evicted
+    pub fn insert(
+        &self,
+        state: &DeploymentState,
+        key: QueryHash,
+        result: QueryResults,
+        ttl: Duration,
+    ) {
+        if result.has_errors() {
+            return;
+        }
+        let weight = result.weight();
+        if weight > self.max_weight {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.advance(state) {
+            return;
+        }
+        let deployment = &state.id;
+        inner.remove(deployment, &key);
+        while inner.weight + weight > self.max_weight {
+            if !inner.evict_oldest() {
+                break;
+            }
+        }
+
+        let now = Instant::now();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.weight += weight;
+        inner.order.push_back((deployment.clone(), key, seq));
+        inner
+            .by_deployment
+            .entry(deployment.clone())
+            .or_insert_with(|| ResultsAtBlock {
+                block: state.latest_block.clone(),
+                reorg_count: state.reorg_count,
+                results: HashMap::new(),
+            })
+            .results
+            .insert(
+                key,
+                CachedResult {
+                    result,
+                    weight,
+                    inserted: now,
+                    expires: now.checked_add(ttl),
+                    seq,
+                },
+            );
+        inner.compact();
+    }
+
+    /// The deployment of `new_state` was reverted, and blocks after
+    /// `reverted_after` might have been reverted. Drop all results for
+    /// those blocks, and make sure that results of queries that started
+    /// before the revert are not cached anymore
+    pub fn reverted(&self, new_state: &DeploymentState, reverted_after: BlockNumber) {
+        let mut inner = self.inner.lock().unwrap();
+        let cached = match inner.by_deployment.get_mut(&new_state.id) {
+            None => return,
+            Some(cached) => cached,
+        };
+        if new_state.reorg_count <= cached.reorg_count {
+            return;
+        }
+        cached.reorg_count = new_state.reorg_count;
+        if cached.block.number > reverted_after {
+            cached.block = new_state.latest_block.clone();
+            inner.clear(&new_state.id);
+        }
+    }
+}
+
+#[test]
+fn result_cache() {
+    use graph::data::value::Object;
+    use graph::prelude::{r, web3::types::H256, QueryExecutionError};
+
+    let result = |value: &str| {
+        QueryResults::from(Object::from_iter([(
+            "value".to_string(),
+            r::Value::String(value.to_string()),
+        )]))
+    };
+    let value = |result: Option<(QueryResults, Duration)>| {
+        result.and_then(|(result, _)| match result.first()?.data()?.get("value")? {
+            r::Value::String(value) => Some(value.clone()),
+            _ => None,
+        })
+    };
+    let state = |number: u8, reorg_count: u32| DeploymentState {
+        id: DeploymentHash::new("QmResultCache").unwrap(),
+        reorg_count,
+        max_reorg_depth: 1,
+        latest_block: BlockPtr::from((H256::repeat_byte(number), number as i32)),
+        earliest_block_number: 0,
+    };
+    let ttl = Duration::from_secs(60);
+    let (key1, key2) = ([1u8; 32], [2u8; 32]);
+    let (block1, block2) = (state(1, 0), state(2, 0));
+
+    let cache = ResultCache::new(10_000);
+    assert_eq!(None, value(cache.get(&block1, &key1)));
+    cache.insert(&block1, key1, result("one"), ttl);
+    assert_eq!(Some("one".to_string()), value(cache.get(&block1, &key1)));
+    let (_, age) = cache.get(&block1, &key1).unwrap();
+    assert!(age < ttl);
+
+    // Once the deployment advances, older results are gone, and results
+    // for the older block are not stored anymore
+    assert_eq!(None, value(cache.get(&block2, &key1)));
+    cache.insert(&block1, key1, result("one"), ttl);
+    assert_eq!(None, value(cache.get(&block1, &key1)));
+    assert_eq!(0, cache.inner.lock().unwrap().weight);
+
+    // Results expire
+    cache.insert(&block2, key1, result("two"), Duration::ZERO);
+    assert_eq!(None, value(cache.get(&block2, &key1)));
+
+    // unless their TTL is too long to ever run out
+    cache.insert(&block2, key1, result("two"), Duration::MAX);
+    assert_eq!(Some("two".to_string()), value(cache.get(&block2, &key1)));
+
+    // Results with errors are not cached
+    let mut failed = result("three");
+    failed.append(Arc::new(QueryExecutionError::DeadlineExceeded.into()));
+    cache.insert(&block2, key2, failed, ttl);
+    assert_eq!(None, value(cache.get(&block2, &key2)));
+
+    // The oldest results are evicted to make room for new ones
+    let weight = result("one").weight();
+    let cache = ResultCache::new(weight * 3 / 2);
+    cache.insert(&block1, key1, result("one"), ttl);
+    cache.insert(&block1, key2, result("two"), ttl);
+    assert_eq!(None, value(cache.get(&block1, &key1)));
+    assert_eq!(Some("two".to_string()), value(cache.get(&block1, &key2)));
+    assert_eq!(weight, cache.inner.lock().unwrap().weight);
+}
+
+#[test]
+fn result_cache_revert() {
+    use graph::data::value::Object;
+    use graph::prelude::{r, web3::types::H256};
+
+    let result = || {
+        QueryResults::from(Object::from_iter([(
+            "value".to_string(),
+            r::Value::String("cached".to_string()),
+        )]))
+    };
+    let state = |number: u8, reorg_count: u32| DeploymentState {
+        id: DeploymentHash::new("QmResultCache").unwrap(),
+        reorg_count,
+        max_reorg_depth: 1,
+        latest_block: BlockPtr::from((H256::repeat_byte(number), number as i32)),
+        earliest_block_number: 0,
+    };
+    let ttl = Duration::from_secs(60);
+    let key = [1u8; 32];
+
+    // The deployment is reverted from block 2 to block 1, and another
+    // block 2 with a different hash is processed
+    let cache = ResultCache::new(10_000);
+    cache.insert(&state(2, 0), key, result(), ttl);
+    assert!(cache.get(&state(2, 0), &key).is_some());
+    cache.reverted(&state(1, 1), 1);
+    assert!(cache.get(&state(2, 0), &key).is_none());
+    assert_eq!(0, cache.inner.lock().unwrap().weight);
+
+    // Queries that started before the revert are not cached
+    cache.insert(&state(2, 0), key, result(), ttl);
+    assert!(cache.get(&state(2, 0), &key).is_none());
+
+    // Queries after the revert are cached again, even at a lower block
+    cache.insert(&state(1, 1), key, result(), ttl);
+    assert!(cache.get(&state(1, 1), &key).is_some());
+
+    // A deeper revert drops them again
+    cache.reverted(&state(0, 2), 0);
+    assert!(cache.get(&state(1, 1), &key).is_none());
+
+    // Results for blocks that a revert did not touch are kept
+    let cache = ResultCache::new(10_000);
+    cache.insert(&state(1, 0), key, result(), ttl);
+    cache.reverted(&state(1, 1), 1);
+    assert!(cache.get(&state(1, 1), &key).is_some());
+}
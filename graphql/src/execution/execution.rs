@@ -6,9 +6,12 @@ use graph::{
     util::{lfu_cache::EvictStats, timed_rw_lock::TimedMutex},
 };
 use lazy_static::lazy_static;
-use parking_lot::MutexGuard;
+use parking_lot::{Mutex, MutexGuard};
 use std::time::Instant;
-use std::{borrow::ToOwned, collections::HashSet};
+use std::{
+    borrow::ToOwned,
+    collections::{BTreeMap, HashSet},
+};
 
 use graph::data::graphql::*;
 use graph::data::query::CacheStatus;
@@ -18,9 +21,11 @@ use graph::util::{lfu_cache::LfuCache, stable_hash_glue::impl_stable_hash};
 
 use super::QueryHash;
 use crate::execution::ast as a;
+use crate::execution::Tracer;
 use crate::introspection::{is_introspection_field, INTROSPECTION_QUERY_TYPE};
 use crate::prelude::*;
 use crate::schema::ast as sast;
+use crate::store::{CURSORS_KEY, RESULT_SIZE_KEY};
 
 lazy_static! {
     // Sharded query results cache for recent blocks by network.
@@ -178,6 +183,141 @@ fn log_lfu_evict_stats(
         }
     }
 }
+/// Shrinks the pages of collections that a query fetches as the query
+/// approaches its deadline, for queries that opted into that
+pub(crate) struct AdaptiveFirst {
+    /// The collections whose pages we shrunk, keyed by their response key,
+    /// with the `first` the query asked for and the smallest page that we
+    /// fetched instead
+    shrunk: Mutex<BTreeMap<String, (u32, u32)>>,
+}
+
+impl AdaptiveFirst {
+    pub fn new() -> Self {
+        AdaptiveFirst {
+            shrunk: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The number of entities to fetch for the collection `response_key`
+    /// when the query asked for `first` of them. Once less than half of
+    /// the time between `start` and `deadline` remains, the page shrinks
+    /// in proportion to the time that is left, but we always fetch at
+    /// least one entity
+    fn page_size(&self, start: Instant, deadline: Instant, response_key: &str, first: u32) -> u32 {
+        let total = deadline.saturating_duration_since(start).as_secs_f64();
+        if total == 0.0 {
+            return first;
+        }
+        let left = deadline
+            .saturating_duration_since(Instant::now())
+            .as_secs_f64();
+        let scale = (2.0 * left / total).min(1.0);
+        let size = ((first as f64 * scale).ceil() as u32).clamp(1, first.max(1));
+        if size < first {
+            let mut shrunk = self.shrunk.lock();
+            let (_, smallest) = shrunk
+                .entry(response_key.to_string())
+                .or_insert((first, size));
+            *smallest = (*smallest).min(size);
+        }
+        size
+    }
+
+    /// Tell the client about the pages that we shrunk
+    pub fn warnings(&self) -> Vec<String> {
+        self.shrunk
+            .lock()
+            .iter()
+            .map(|(response_key, (first, size))| {
+                format!(
+                    "only {} of the {} entities requested for `{}` were fetched because \
+                     the query was close to its deadline",
+                    size, first, response_key
+                )
+            })
+            .collect()
+    }
+}
+
+/// Collects the non-null violations of a query that asked for them to be
+/// reported at the field that caused them, rather than nulling out the
+/// parent of the field as the GraphQL spec requires. The violating field is
+/// set to `null`, and the error for it carries its path in the response
+#[derive(Default)]
+pub(crate) struct LocalizedErrors {
+    /// The path to the field that is currently being completed
+    path: Mutex<Vec<r::Value>>,
+    errors: Mutex<Vec<QueryExecutionError>>,
+}
+
+impl LocalizedErrors {
+    fn enter(&self, segment: r::Value) {
+        self.path.lock().push(segment);
+    }
+
+    fn leave(&self) {
+        self.path.lock().pop();
+    }
+
+    /// Record `errors` for the field `response_key` of the object that is
+    /// currently being completed
+    fn report(&self, response_key: &str, errors: Vec<QueryExecutionError>) {
+        let path = self.path.lock();
+        self.errors.lock().extend(errors.into_iter().map(|e| {
+            path.iter().rev().fold(
+                e.in_path(r::Value::String(response_key.to_string())),
+                |e, segment| e.in_path(segment.clone()),
+            )
+        }));
+    }
+
+    pub fn take(&self) -> Vec<QueryExecutionError> {
+        std::mem::take(&mut *self.errors.lock())
+    }
+}
+
+/// Keeps track of how much memory the entities that a query loads from the
+/// store take up. That includes entities that are only needed to get to
+/// other entities or that do not end up in the result, and therefore
+/// differs from the size of the result
+#[derive(Default)]
+pub(crate) struct MemoryUsage {
+    used: AtomicCell<usize>,
+}
+
+impl MemoryUsage {
+    /// Record that we loaded entities with a total weight of `weight`, and
+    /// fail if the query now uses more than `GRAPH_GRAPHQL_MAX_QUERY_MEMORY`
+    pub fn add(&self, weight: usize) -> Result<(), QueryExecutionError> {
+        let used = self.used.fetch_add(weight).saturating_add(weight);
+        let limit = ENV_VARS.graphql.max_query_memory;
+        if used > limit {
+            return Err(QueryExecutionError::MemoryLimitExceeded {
+                measured: used,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// The memory that the query has used so far. Since entities are held
+    /// until the query finishes, this is also the most memory it used
+    pub fn peak(&self) -> usize {
+        self.used.load()
+    }
+}
+
+/// Whether `error` is a non-null violation, possibly somewhere below the
+/// field that reported it
+fn is_non_null_error(error: &QueryExecutionError) -> bool {
+    match error {
+        QueryExecutionError::NonNullError(_, _) => true,
+        QueryExecutionError::AtPath(_, e) => is_non_null_error(e),
+        _ => false,
+    }
+}
+
 /// Contextual information passed around during query execution.
 pub struct ExecutionContext<R>
 where
@@ -192,6 +332,9 @@ where
     /// The resolver to use.
     pub resolver: R,
 
+    /// The instant from which `deadline` was computed
+    pub start: Instant,
+
     /// Time at which the query times out.
     pub deadline: Option<Instant>,
 
@@ -203,6 +346,29 @@ where
 
     /// Records whether this was a cache hit, used for logging.
     pub(crate) cache_status: AtomicCell<CacheStatus>,
+
+    /// Collects timings if the client asked for the query to be traced
+    pub tracer: Option<Arc<Tracer>>,
+
+    /// Whether to return the data resolved so far instead of failing the
+    /// query when the deadline passes
+    pub partial_results: bool,
+
+    /// Records whether we stopped fetching data because the deadline
+    /// passed while producing partial results
+    pub(crate) deadline_exceeded: AtomicCell<bool>,
+
+    /// Set if the query fetches smaller pages as its deadline approaches.
+    /// Such queries are never cached since their results depend on how
+    /// fast they execute
+    pub(crate) adaptive_first: Option<AdaptiveFirst>,
+
+    /// Set if the query wants non-null violations reported at the field
+    /// that caused them instead of propagating them to the parent
+    pub(crate) localized_errors: Option<LocalizedErrors>,
+
+    /// The memory used by the entities the query loaded
+    pub(crate) memory: Arc<MemoryUsage>,
 }
 
 pub(crate) fn get_field<'a>(
@@ -221,6 +387,45 @@ impl<R> ExecutionContext<R>
 where
     R: Resolver,
 {
+    /// Check whether the deadline for the query has passed. If the query
+    /// accepts partial results, remember that we ran out of time so that
+    /// execution returns what it has resolved so far
+    pub(crate) fn deadline_passed(&self) -> bool {
+        match self.deadline {
+            Some(deadline) if deadline < Instant::now() => {
+                if self.partial_results {
+                    self.deadline_exceeded.store(true);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The error for a query that ran past its deadline, with how long it
+    /// ran and how long it was allowed to run
+    pub(crate) fn timeout(&self) -> QueryExecutionError {
+        let limit = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(self.start))
+            .unwrap_or_default();
+        QueryExecutionError::Timeout {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            limit_ms: limit.as_millis() as u64,
+        }
+    }
+
+    /// The number of entities to fetch for the collection `response_key`
+    /// when the query asked for `first` of them
+    pub(crate) fn page_size(&self, response_key: &str, first: u32) -> u32 {
+        match (&self.adaptive_first, self.deadline) {
+            (Some(adaptive), Some(deadline)) => {
+                adaptive.page_size(self.start, deadline, response_key, first)
+            }
+            _ => first,
+        }
+    }
+
     pub fn as_introspection_context(&self) -> ExecutionContext<IntrospectionResolver> {
         let introspection_resolver =
             IntrospectionResolver::new(&self.logger, self.query.schema.schema());
@@ -229,26 +434,46 @@ where
             logger: self.logger.cheap_clone(),
             resolver: introspection_resolver,
             query: self.query.cheap_clone(),
+            start: self.start,
             deadline: self.deadline,
             max_first: std::u32::MAX,
             max_skip: std::u32::MAX,
 
             // `cache_status` is a dead value for the introspection context.
             cache_status: AtomicCell::new(CacheStatus::Miss),
+            tracer: self.tracer.cheap_clone(),
+            partial_results: self.partial_results,
+            deadline_exceeded: AtomicCell::new(false),
+            adaptive_first: None,
+            localized_errors: None,
+            memory: self.memory.cheap_clone(),
         }
     }
 }
 
+/// Whether all toplevel fields of `selection_set` can be resolved from the
+/// schema alone because they are `__typename` or introspection fields.
+/// Executing such a selection set never needs the store
+pub(crate) fn resolves_from_schema(selection_set: &a::SelectionSet) -> bool {
+    selection_set.fields().all(|(_, mut fields)| {
+        fields.all(|field| field.name == "__typename" || is_introspection_field(&field.name))
+    })
+}
+
+/// Execute the root selection set without consulting the query cache.
+/// Besides the data, return the cursors for the toplevel collections and
+/// the `CacheWeight` of the data that was fetched from the store
 pub(crate) fn execute_root_selection_set_uncached(
     ctx: &ExecutionContext<impl Resolver>,
     selection_set: &a::SelectionSet,
     root_type: &sast::ObjectType,
-) -> Result<Object, Vec<QueryExecutionError>> {
+) -> Result<(Object, BTreeMap<String, String>, usize), Vec<QueryExecutionError>> {
     // Split the top-level fields into introspection fields and
     // regular data fields
     let mut data_set = a::SelectionSet::empty_from(selection_set);
     let mut intro_set = a::SelectionSet::empty_from(selection_set);
     let mut meta_items = Vec::new();
+    let mut needs_store = false;
 
     for field in selection_set.fields_for(root_type)? {
         // See if this is an introspection or data field. We don't worry about
@@ -256,18 +481,45 @@ pub(crate) fn execute_root_selection_set_uncached(
         // the data_set SelectionSet
         if is_introspection_field(&field.name) {
             intro_set.push(field)?
-        } else if field.name == META_FIELD_NAME || field.name == "__typename" {
+        } else if field.name == "__typename" {
+            meta_items.push(field)
+        } else if field.name == META_FIELD_NAME {
+            needs_store = true;
             meta_items.push(field)
         } else {
+            needs_store = true;
             data_set.push(field)?
         }
     }
 
-    // If we are getting regular data, prefetch it from the database
-    let mut values = if data_set.is_empty() && meta_items.is_empty() {
-        Object::default()
+    // If we are getting regular data, prefetch it from the database. A
+    // `__typename` on its own is answered from the schema
+    let mut cursors = BTreeMap::new();
+    let mut result_size = 0;
+    let mut values = if !needs_store {
+        let mut typename_set = a::SelectionSet::empty_from(selection_set);
+        typename_set.push_fields(meta_items)?;
+        execute_selection_set_to_map(ctx, &typename_set, root_type, None)?
     } else {
-        let initial_data = ctx.resolver.prefetch(ctx, &data_set)?;
+        let start = Instant::now();
+        let mut initial_data = ctx.resolver.prefetch(ctx, &data_set)?;
+        if let Some(r::Value::Object(data)) = &mut initial_data {
+            if let Some(r::Value::Object(prefetched)) = data.remove(CURSORS_KEY) {
+                cursors = prefetched
+                    .iter()
+                    .filter_map(|(key, cursor)| match cursor {
+                        r::Value::String(cursor) => Some((key.to_string(), cursor.to_string())),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            if let Some(r::Value::Int(size)) = data.remove(RESULT_SIZE_KEY) {
+                result_size = size as usize;
+            }
+        }
+        if let Some(tracer) = &ctx.tracer {
+            tracer.store_query(start);
+        }
         data_set.push_fields(meta_items)?;
         execute_selection_set_to_map(ctx, &data_set, root_type, initial_data)?
     };
@@ -284,7 +536,7 @@ pub(crate) fn execute_root_selection_set_uncached(
         )?);
     }
 
-    Ok(values)
+    Ok((values, cursors, result_size))
 }
 
 /// Executes the root selection set of a query.
@@ -298,7 +550,16 @@ pub(crate) async fn execute_root_selection_set<R: Resolver>(
     // and once for insert.
     let mut key: Option<QueryHash> = None;
 
+    // Queries that are answered from the schema are cheaper to run than to
+    // look up in the cache, and traced queries need to actually run. The
+    // results of queries that accept partial results depend on how fast
+    // they ran, and can not be shared with other queries
     let should_check_cache = R::CACHEABLE
+        && !resolves_from_schema(&selection_set)
+        && ctx.tracer.is_none()
+        && !ctx.partial_results
+        && ctx.adaptive_first.is_none()
+        && ctx.localized_errors.is_none()
         && match ENV_VARS.graphql.cached_subgraph_ids {
             CachedSubgraphIds::All => true,
             CachedSubgraphIds::Only(ref subgraph_ids) => {
@@ -337,51 +598,11 @@ pub(crate) async fn execute_root_selection_set<R: Resolver>(
         }
     }
 
-    let execute_ctx = ctx.cheap_clone();
-    let execute_selection_set = selection_set.cheap_clone();
-    let execute_root_type = root_type.cheap_clone();
-    let run_query = async move {
-        let _permit = execute_ctx.resolver.query_permit().await;
-
-        let logger = execute_ctx.logger.clone();
-        let query_text = execute_ctx.query.query_text.cheap_clone();
-        let variables_text = execute_ctx.query.variables_text.cheap_clone();
-        match graph::spawn_blocking_allow_panic(move || {
-            let mut query_res = QueryResult::from(execute_root_selection_set_uncached(
-                &execute_ctx,
-                &execute_selection_set,
-                &execute_root_type,
-            ));
-
-            // Unwrap: In practice should never fail, but if it does we will catch the panic.
-            execute_ctx.resolver.post_process(&mut query_res).unwrap();
-            query_res.deployment = Some(execute_ctx.query.schema.id().clone());
-            Arc::new(query_res)
-        })
-        .await
-        {
-            Ok(result) => result,
-            Err(e) => {
-                let e = e.into_panic();
-                let e = match e
-                    .downcast_ref::<String>()
-                    .map(String::as_str)
-                    .or(e.downcast_ref::<&'static str>().copied())
-                {
-                    Some(e) => e.to_string(),
-                    None => "panic is not a string".to_string(),
-                };
-                error!(
-                    logger,
-                    "panic when processing graphql query";
-                    "panic" => e.to_string(),
-                    "query" => query_text,
-                    "variables" => variables_text,
-                );
-                Arc::new(QueryResult::from(QueryExecutionError::Panic(e)))
-            }
-        }
-    };
+    let run_query = run_root_selection_set(
+        ctx.cheap_clone(),
+        selection_set.cheap_clone(),
+        root_type.cheap_clone(),
+    );
 
     let (result, herd_hit) = if let Some(key) = key {
         QUERY_HERD_CACHE
@@ -441,6 +662,80 @@ pub(crate) async fn execute_root_selection_set<R: Resolver>(
     result
 }
 
+/// Execute the root selection set on a blocking thread once the resolver
+/// gives us a permit to run the query, without consulting any of the query
+/// caches. Selection sets that are resolved from the schema do not need a
+/// permit
+pub(crate) async fn run_root_selection_set<R: Resolver>(
+    ctx: Arc<ExecutionContext<R>>,
+    selection_set: Arc<a::SelectionSet>,
+    root_type: sast::ObjectType,
+) -> Arc<QueryResult> {
+    let _permit = if resolves_from_schema(&selection_set) {
+        None
+    } else {
+        Some(ctx.resolver.query_permit().await)
+    };
+
+    let logger = ctx.logger.clone();
+    let query_text = ctx.query.query_text.cheap_clone();
+    let variables_text = ctx.query.variables_text.cheap_clone();
+    match graph::spawn_blocking_allow_panic(move || {
+        let (data, cursors, result_size) =
+            match execute_root_selection_set_uncached(&ctx, &selection_set, &root_type) {
+                Ok((data, cursors, result_size)) => (Ok(data), cursors, result_size),
+                Err(e) => (Err(e), BTreeMap::new(), 0),
+            };
+        let mut query_res = QueryResult::from(data);
+        query_res.cursors = cursors;
+        query_res.result_size = result_size;
+
+        // Unwrap: In practice should never fail, but if it does we will catch the panic.
+        ctx.resolver
+            .post_process(&mut query_res, &selection_set)
+            .unwrap();
+        if ctx.deadline_exceeded.load() {
+            query_res
+                .errors_mut()
+                .push(QueryExecutionError::DeadlineExceeded.into());
+        }
+        if let Some(adaptive) = &ctx.adaptive_first {
+            query_res.warnings = adaptive.warnings();
+        }
+        if let Some(localized) = &ctx.localized_errors {
+            query_res
+                .errors_mut()
+                .extend(localized.take().into_iter().map(QueryError::from));
+        }
+        query_res.deployment = Some(ctx.query.schema.id().clone());
+        ctx.resolver.record_result(&query_res);
+        Arc::new(query_res)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            let e = e.into_panic();
+            let e = match e
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or(e.downcast_ref::<&'static str>().copied())
+            {
+                Some(e) => e.to_string(),
+                None => "panic is not a string".to_string(),
+            };
+            error!(
+                logger,
+                "panic when processing graphql query";
+                "panic" => e.to_string(),
+                "query" => query_text,
+                "variables" => variables_text,
+            );
+            Arc::new(QueryResult::from(QueryExecutionError::Panic(e)))
+        }
+    }
+}
+
 /// Executes a selection set, requiring the result to be of the given object type.
 ///
 /// Allows passing in a parent value during recursive processing of objects and their fields.
@@ -484,14 +779,13 @@ fn execute_selection_set_to_map<'a>(
         multiple_response_keys
     };
 
-    // Process all field groups in order
+    // Process all field groups in order. When the query accepts partial
+    // results, we keep resolving whatever data we already have in memory
+    // after the deadline and only skip fields we never fetched
     for field in selection_set.fields_for(object_type)? {
-        match ctx.deadline {
-            Some(deadline) if deadline < Instant::now() => {
-                errors.push(QueryExecutionError::Timeout);
-                break;
-            }
-            _ => (),
+        if !ctx.partial_results && ctx.deadline_passed() {
+            errors.push(ctx.timeout());
+            break;
         }
 
         let response_key = field.response_key();
@@ -517,15 +811,43 @@ fn execute_selection_set_to_map<'a>(
             })
             .flatten();
 
+        if field_value.is_none() && !field.is_leaf() && ctx.deadline_exceeded.load() {
+            continue;
+        }
+
         if field.name.as_str() == "__typename" && field_value.is_none() {
             results.push((response_key, r::Value::String(object_type.name.clone())));
         } else {
-            match execute_field(ctx, object_type, field_value, field, field_type) {
+            let trace_start = ctx.tracer.as_ref().map(|tracer| tracer.enter(response_key));
+            if let Some(localized) = &ctx.localized_errors {
+                localized.enter(r::Value::String(response_key.to_string()));
+            }
+            let value = execute_field(ctx, object_type, field_value, field, field_type);
+            if let Some(localized) = &ctx.localized_errors {
+                localized.leave();
+            }
+            if let (Some(tracer), Some(start)) = (&ctx.tracer, trace_start) {
+                tracer.leave(
+                    start,
+                    &object_type.name,
+                    &field.name,
+                    &field_type.field_type,
+                );
+            }
+            match value {
                 Ok(v) => {
                     results.push((response_key, v));
                 }
-                Err(mut e) => {
-                    errors.append(&mut e);
+                Err(e) if ctx.localized_errors.is_some() && e.iter().all(is_non_null_error) => {
+                    ctx.localized_errors
+                        .as_ref()
+                        .unwrap()
+                        .report(response_key, e);
+                    results.push((response_key, r::Value::Null));
+                }
+                Err(e) => {
+                    let key = r::Value::String(response_key.to_string());
+                    errors.extend(e.into_iter().map(|e| e.in_path(key.clone())));
                 }
             }
         }
@@ -741,14 +1063,30 @@ fn complete_value(
                     let mut errors = Vec::new();
 
                     // To avoid allocating a new vector this completes the values in place.
-                    for value_place in &mut values {
+                    for (index, value_place) in values.iter_mut().enumerate() {
                         // Put in a placeholder, complete the value, put the completed value back.
                         let value = std::mem::replace(value_place, r::Value::Null);
-                        match complete_value(ctx, field, inner_type, value) {
+                        if let Some(tracer) = &ctx.tracer {
+                            tracer.enter_index(index);
+                        }
+                        if let Some(localized) = &ctx.localized_errors {
+                            localized.enter(r::Value::Int(index as i64));
+                        }
+                        let completed = complete_value(ctx, field, inner_type, value);
+                        if let Some(tracer) = &ctx.tracer {
+                            tracer.leave_index();
+                        }
+                        if let Some(localized) = &ctx.localized_errors {
+                            localized.leave();
+                        }
+                        match completed {
                             Ok(value) => {
                                 *value_place = value;
                             }
-                            Err(errs) => errors.extend(errs),
+                            Err(errs) => errors.extend(
+                                errs.into_iter()
+                                    .map(|e| e.in_path(r::Value::Int(index as i64))),
+                            ),
                         }
                     }
                     match errors.is_empty() {
@@ -859,3 +1197,28 @@ fn resolve_abstract_type<'a>(
         })?;
     Ok(ctx.query.schema.object_type(obj_type).into())
 }
+
+#[test]
+fn adaptive_first_shrinks_pages_near_the_deadline() {
+    use std::time::Duration;
+
+    let now = Instant::now();
+    let start = now - Duration::from_secs(9);
+    let adaptive = AdaptiveFirst::new();
+
+    // Plenty of time left
+    assert_eq!(
+        100,
+        adaptive.page_size(start, now + Duration::from_secs(90), "musicians", 100)
+    );
+    assert!(adaptive.warnings().is_empty());
+
+    // A tenth of the time left
+    let size = adaptive.page_size(start, now + Duration::from_secs(1), "musicians", 100);
+    assert!(size > 0 && size <= 20);
+    assert_eq!(1, adaptive.warnings().len());
+
+    // We always fetch something
+    assert_eq!(1, adaptive.page_size(start, now, "bands", 100));
+    assert_eq!(2, adaptive.warnings().len());
+}
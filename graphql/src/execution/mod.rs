@@ -4,14 +4,22 @@ mod execution;
 mod query;
 /// Common trait for field resolvers used in the execution.
 mod resolver;
+/// OpenTelemetry spans for a sample of queries
+mod span;
+/// Timing of query execution for Apollo Tracing
+mod trace;
 
 /// Our representation of a query AST
 pub mod ast;
 
 use stable_hash_legacy::{crypto::SetHasher, StableHasher};
 
+pub(crate) use self::cache::{QueryCache, ResultCache};
 pub use self::execution::*;
 pub use self::query::Query;
+pub(crate) use self::query::{deprecated_fields, PreparedQueries, SelectedFields};
 pub use self::resolver::Resolver;
+pub(crate) use self::span::QuerySpan;
+pub use self::trace::Tracer;
 
-type QueryHash = <SetHasher as StableHasher>::Out;
+pub(crate) type QueryHash = <SetHasher as StableHasher>::Out;
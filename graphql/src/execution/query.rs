@@ -4,61 +4,93 @@ use graphql_parser::Pos;
 use graphql_tools::validation::rules::*;
 use graphql_tools::validation::validate::{validate, ValidationPlan};
 use lazy_static::lazy_static;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use lru_time_cache::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use std::{collections::hash_map::DefaultHasher, convert::TryFrom};
 
-use graph::data::graphql::{ext::TypeExt, ObjectOrInterface};
+use graph::data::graphql::{
+    ext::{DirectiveFinder, TypeExt},
+    object, ObjectOrInterface,
+};
 use graph::data::query::QueryExecutionError;
 use graph::data::query::{Query as GraphDataQuery, QueryVariables};
 use graph::data::schema::ApiSchema;
+use graph::prelude::rand::{thread_rng, Rng};
 use graph::prelude::{
-    info, o, q, r, s, warn, BlockNumber, CheapClone, Logger, TryFromValue, ENV_VARS,
+    info, o, q, r, s, warn, BlockNumber, CheapClone, DeploymentHash, FieldCost, Logger,
+    TryFromValue, ENV_VARS,
 };
 
-use crate::execution::ast as a;
+use crate::execution::{ast as a, MemoryUsage};
 use crate::query::{ast as qast, ext::BlockConstraint};
 use crate::schema::ast::{self as sast};
 use crate::values::coercion;
 use crate::{execution::get_field, schema::api::ErrorPolicy};
 
+/// The directive that turns a query into a live query
+const LIVE_DIRECTIVE: &str = "live";
+
 lazy_static! {
     static ref GRAPHQL_VALIDATION_PLAN: ValidationPlan =
+        ValidationPlan::from(validation_rules(true));
+    /// The rules of `GRAPHQL_VALIDATION_PLAN` without `KnownArgumentNames`,
+    /// for queries whose unknown arguments are ignored
+    static ref LENIENT_VALIDATION_PLAN: ValidationPlan =
+        ValidationPlan::from(validation_rules(false));
+    /// Only the `KnownArgumentNames` rule, to find the unknown arguments of
+    /// queries that were validated with `LENIENT_VALIDATION_PLAN`
+    static ref KNOWN_ARGUMENT_NAMES_PLAN: ValidationPlan =
         ValidationPlan::from(if !ENV_VARS.graphql.enable_validations {
             vec![]
         } else {
-            vec![
-                Box::new(UniqueOperationNames::new()),
-                Box::new(LoneAnonymousOperation::new()),
-                Box::new(SingleFieldSubscriptions::new()),
-                Box::new(KnownTypeNames::new()),
-                Box::new(FragmentsOnCompositeTypes::new()),
-                Box::new(VariablesAreInputTypes::new()),
-                Box::new(LeafFieldSelections::new()),
-                Box::new(FieldsOnCorrectType::new()),
-                Box::new(UniqueFragmentNames::new()),
-                Box::new(KnownFragmentNames::new()),
-                Box::new(NoUnusedFragments::new()),
-                Box::new(OverlappingFieldsCanBeMerged::new()),
-                Box::new(NoFragmentsCycle::new()),
-                Box::new(PossibleFragmentSpreads::new()),
-                Box::new(NoUnusedVariables::new()),
-                Box::new(NoUndefinedVariables::new()),
-                Box::new(KnownArgumentNames::new()),
-                Box::new(UniqueArgumentNames::new()),
-                Box::new(UniqueVariableNames::new()),
-                Box::new(ProvidedRequiredArguments::new()),
-                Box::new(KnownDirectives::new()),
-                Box::new(VariablesInAllowedPosition::new()),
-                Box::new(ValuesOfCorrectType::new()),
-                Box::new(UniqueDirectivesPerLocation::new()),
-            ]
+            vec![Box::new(KnownArgumentNames::new())]
         });
 }
 
+/// The rules that queries are validated with, with or without the
+/// `KnownArgumentNames` rule
+fn validation_rules(known_argument_names: bool) -> Vec<Box<dyn ValidationRule>> {
+    if !ENV_VARS.graphql.enable_validations {
+        return vec![];
+    }
+    let mut rules: Vec<Box<dyn ValidationRule>> = vec![
+        Box::new(UniqueOperationNames::new()),
+        Box::new(LoneAnonymousOperation::new()),
+        Box::new(SingleFieldSubscriptions::new()),
+        Box::new(KnownTypeNames::new()),
+        Box::new(FragmentsOnCompositeTypes::new()),
+        Box::new(VariablesAreInputTypes::new()),
+        Box::new(LeafFieldSelections::new()),
+        Box::new(FieldsOnCorrectType::new()),
+        Box::new(UniqueFragmentNames::new()),
+        Box::new(KnownFragmentNames::new()),
+        Box::new(NoUnusedFragments::new()),
+        Box::new(OverlappingFieldsCanBeMerged::new()),
+        Box::new(NoFragmentsCycle::new()),
+        Box::new(PossibleFragmentSpreads::new()),
+        Box::new(NoUnusedVariables::new()),
+        Box::new(NoUndefinedVariables::new()),
+    ];
+    if known_argument_names {
+        rules.push(Box::new(KnownArgumentNames::new()));
+    }
+    rules.extend(vec![
+        Box::new(UniqueArgumentNames::new()) as Box<dyn ValidationRule>,
+        Box::new(UniqueVariableNames::new()),
+        Box::new(ProvidedRequiredArguments::new()),
+        Box::new(KnownDirectives::new()),
+        Box::new(VariablesInAllowedPosition::new()),
+        Box::new(ValuesOfCorrectType::new()),
+        Box::new(UniqueDirectivesPerLocation::new()),
+    ]);
+    rules
+}
+
 #[derive(Clone, Debug)]
 pub enum ComplexityError {
     TooDeep,
@@ -79,7 +111,7 @@ enum Kind {
 /// uses ',' to separate key/value pairs.
 /// If `SelectionSet` is `None`, log `*` to indicate that the query was
 /// for the entire selection set of the query
-struct SelectedFields<'a>(&'a a::SelectionSet);
+pub(crate) struct SelectedFields<'a>(pub &'a a::SelectionSet);
 
 impl<'a> std::fmt::Display for SelectedFields<'a> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
@@ -105,6 +137,89 @@ impl<'a> std::fmt::Display for SelectedFields<'a> {
     }
 }
 
+/// The paths of all fields in `selection_set`, like `musicians.bands.name`.
+/// Paths use the names of fields, not their aliases, and every path
+/// appears only once, even if it is selected for several types
+pub(crate) fn field_paths(selection_set: &a::SelectionSet) -> BTreeSet<String> {
+    fn add_paths(prefix: &str, selection_set: &a::SelectionSet, paths: &mut BTreeSet<String>) {
+        for (_, fields) in selection_set.fields() {
+            for field in fields {
+                let path = if prefix.is_empty() {
+                    field.name.clone()
+                } else {
+                    format!("{}.{}", prefix, field.name)
+                };
+                add_paths(&path, &field.selection_set, paths);
+                paths.insert(path);
+            }
+        }
+    }
+
+    let mut paths = BTreeSet::new();
+    add_paths("", selection_set, &mut paths);
+    paths
+}
+
+/// The fields marked `@deprecated` in the schema that `selection_set`
+/// selects, with the path to them, their type and the reason they are
+/// deprecated. Every field appears only once per path and type
+pub(crate) fn deprecated_fields(selection_set: &a::SelectionSet) -> Vec<r::Value> {
+    /// The reason the GraphQL spec gives for `@deprecated` without one
+    const DEFAULT_REASON: &str = "No longer supported";
+
+    fn add_fields(
+        prefix: &str,
+        selection_set: &a::SelectionSet,
+        deprecated: &mut BTreeMap<(String, String), String>,
+    ) {
+        for (obj_type, fields) in selection_set.fields() {
+            for field in fields {
+                let path = if prefix.is_empty() {
+                    field.name.clone()
+                } else {
+                    format!("{}.{}", prefix, field.name)
+                };
+                add_fields(&path, &field.selection_set, deprecated);
+                let directive = sast::get_field(obj_type, &field.name)
+                    .and_then(|field_def| field_def.find_directive("deprecated"));
+                if let Some(directive) = directive {
+                    let reason = match qast::get_argument_value(&directive.arguments, "reason") {
+                        Some(q::Value::String(reason)) => reason.clone(),
+                        _ => DEFAULT_REASON.to_string(),
+                    };
+                    deprecated.insert((path, obj_type.name.clone()), reason);
+                }
+            }
+        }
+    }
+
+    let mut deprecated = BTreeMap::new();
+    add_fields("", selection_set, &mut deprecated);
+    deprecated
+        .into_iter()
+        .map(|((path, type_name), reason)| {
+            object! {
+                path: path,
+                parentType: type_name,
+                reason: reason,
+            }
+        })
+        .collect()
+}
+
+/// The number of nested selection sets in `selection_set`, counting
+/// `selection_set` itself. This is the depth that `max_depth` limits, not
+/// counting fragments since they have been inlined into `selection_set`
+fn selection_depth(selection_set: &a::SelectionSet) -> u8 {
+    selection_set
+        .interior_fields()
+        .flat_map(|(_, fields)| fields)
+        .map(|field| selection_depth(&field.selection_set))
+        .max()
+        .unwrap_or(0)
+        .saturating_add(1)
+}
+
 /// A GraphQL query that has been preprocessed and checked and is ready
 /// for execution. Checking includes validating all query fields and, if
 /// desired, checking the query's complexity
@@ -121,6 +236,16 @@ pub struct Query {
     pub selection_set: Arc<a::SelectionSet>,
     /// The ShapeHash of the original query
     pub shape_hash: u64,
+    /// The complexity of the query as computed by `check_complexity`
+    pub complexity: u64,
+    /// What each field contributes to `complexity`, the most expensive
+    /// first. Only filled in if the query asked for it with
+    /// `complexity_breakdown`
+    pub complexity_breakdown: Vec<FieldCost>,
+    /// How deeply the selection sets of the query are nested
+    pub depth: u8,
+    /// The name of the operation, if the query gave it one
+    pub operation_name: Option<String>,
 
     pub network: Option<String>,
 
@@ -130,11 +255,101 @@ pub struct Query {
 
     kind: Kind,
 
+    /// Whether the query has a `@live` directive and should be rerun
+    /// whenever the entities it reads change
+    live: bool,
+
     /// Used only for logging; if logging is configured off, these will
     /// have dummy values
     pub query_text: Arc<String>,
     pub variables_text: Arc<String>,
     pub query_id: String,
+
+    /// The priority lane the client put the query in
+    pub lane: Option<String>,
+
+    /// Who sent the query, if a proxy in front of the server told us
+    pub caller: Option<String>,
+
+    /// Whether the client wants pages to shrink as the query approaches
+    /// its deadline
+    pub adaptive_first: bool,
+
+    /// Whether non-null violations are reported at the field that caused
+    /// them instead of nulling out its parent
+    pub localize_non_null_errors: bool,
+
+    /// Whether this query was sampled for the field audit log, see
+    /// `GRAPH_GRAPHQL_FIELD_AUDIT_SAMPLE_RATE`
+    audited: bool,
+
+    /// Whether this query was sampled for the query log, see
+    /// `GRAPH_GRAPHQL_QUERY_LOG_SAMPLE_RATE`
+    sampled: bool,
+
+    /// Problems with the query that did not keep us from executing it and
+    /// that are reported to the client in `extensions.warnings`
+    pub warnings: Vec<String>,
+
+    /// The memory used by the entities that executing this query loaded,
+    /// shared by the parts of the query for all block constraints
+    pub(crate) memory: Arc<MemoryUsage>,
+
+    /// Whether validating the query was skipped because a query with the
+    /// same text had been prepared before
+    pub(crate) prepared: bool,
+}
+
+/// The part of preparing a query that only depends on its text and the
+/// schema: validating the document and splitting it into its operation
+/// and fragments
+struct PreparedDocument {
+    /// The SHA-256 hash of the document. `query_text` can not tell
+    /// documents apart since it is only filled in when queries are logged
+    document_hash: [u8; 32],
+    operation: q::OperationDefinition,
+    fragments: HashMap<String, q::FragmentDefinition>,
+    warnings: Vec<String>,
+}
+
+/// Query documents that passed validation, keyed by the deployment whose
+/// schema they were validated against and by their shape hash. A new
+/// version of a subgraph, and therefore of its schema, is a new
+/// deployment, so that documents that were validated against an earlier
+/// version are never used for a later one
+pub(crate) struct PreparedQueries {
+    capacity: usize,
+    documents: Mutex<LruCache<(DeploymentHash, u64), Arc<PreparedDocument>>>,
+}
+
+impl PreparedQueries {
+    pub fn new(capacity: usize) -> Self {
+        PreparedQueries {
+            capacity,
+            documents: Mutex::new(LruCache::with_capacity(capacity.max(1))),
+        }
+    }
+
+    fn get(
+        &self,
+        key: &(DeploymentHash, u64),
+        document_hash: &[u8; 32],
+    ) -> Option<Arc<PreparedDocument>> {
+        // Queries that only differ in the values of their arguments have
+        // the same shape hash, but they need to be validated separately
+        self.documents
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|document| &document.document_hash == document_hash)
+            .cloned()
+    }
+
+    fn insert(&self, key: (DeploymentHash, u64), document: Arc<PreparedDocument>) {
+        if self.capacity > 0 {
+            self.documents.lock().unwrap().insert(key, document);
+        }
+    }
 }
 
 impl Query {
@@ -150,56 +365,83 @@ impl Query {
         max_complexity: Option<u64>,
         max_depth: u8,
     ) -> Result<Arc<Self>, Vec<QueryExecutionError>> {
-        let validation_errors =
-            validate(schema.document(), &query.document, &GRAPHQL_VALIDATION_PLAN);
-
-        if !validation_errors.is_empty() {
-            if !ENV_VARS.graphql.silent_graphql_validations {
-                return Err(validation_errors
-                    .into_iter()
-                    .map(|e| {
-                        QueryExecutionError::ValidationError(
-                            e.locations.first().cloned(),
-                            e.message,
-                        )
-                    })
-                    .collect());
-            } else {
-                warn!(
-                  &logger,
-                  "GraphQL Validation failure";
-                  "query" => &query.query_text,
-                  "variables" => &query.variables_text,
-                  "errors" => format!("[{:?}]", validation_errors.iter().map(|e| e.message.clone()).collect::<Vec<_>>().join(", "))
-                );
-            }
-        }
+        Self::prepare(
+            logger,
+            schema,
+            network,
+            query,
+            max_complexity,
+            max_depth,
+            None,
+            ENV_VARS.graphql.ignore_unknown_arguments,
+        )
+    }
 
-        let mut operation = None;
-        let mut fragments = HashMap::new();
-        for defn in query.document.definitions.into_iter() {
-            match defn {
-                q::Definition::Operation(op) => match operation {
-                    None => operation = Some(op),
-                    Some(_) => return Err(vec![QueryExecutionError::OperationNameRequired]),
-                },
-                q::Definition::Fragment(frag) => {
-                    fragments.insert(frag.name.clone(), frag);
+    /// Like `new`, but skip validating the query if `prepared` has a
+    /// document with the same text for the same schema, and add the
+    /// document to `prepared` otherwise. Variables, complexity and block
+    /// constraints depend on more than the text of the query and are
+    /// checked every time. All documents in `prepared` must have been
+    /// validated with the same `ignore_unknown_arguments`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn prepare(
+        logger: &Logger,
+        schema: Arc<ApiSchema>,
+        network: Option<String>,
+        query: GraphDataQuery,
+        max_complexity: Option<u64>,
+        max_depth: u8,
+        prepared: Option<&PreparedQueries>,
+        ignore_unknown_arguments: bool,
+    ) -> Result<Arc<Self>, Vec<QueryExecutionError>> {
+        let key = (schema.id().clone(), query.shape_hash);
+        let document_hash: [u8; 32] = Sha256::digest(query.document.to_string()).into();
+        let (document, cache_hit) =
+            match prepared.and_then(|prepared| prepared.get(&key, &document_hash)) {
+                Some(document) => (document, true),
+                None => {
+                    let document = Arc::new(Self::validate_document(
+                        logger,
+                        &schema,
+                        &query,
+                        document_hash,
+                        ignore_unknown_arguments,
+                    )?);
+                    if let Some(prepared) = prepared {
+                        prepared.insert(key, document.cheap_clone());
+                    }
+                    (document, false)
                 }
-            }
-        }
-        let operation = operation.ok_or(QueryExecutionError::OperationNameRequired)?;
+            };
+        let (operation, fragments, warnings) = match Arc::try_unwrap(document) {
+            Ok(document) => (document.operation, document.fragments, document.warnings),
+            Err(document) => (
+                document.operation.clone(),
+                document.fragments.clone(),
+                document.warnings.clone(),
+            ),
+        };
 
         let variables = coerce_variables(schema.as_ref(), &operation, query.variables)?;
-        let (kind, selection_set) = match operation {
-            q::OperationDefinition::Query(q::Query { selection_set, .. }) => {
-                (Kind::Query, selection_set)
+        let (kind, operation_name, selection_set, live) = match operation {
+            q::OperationDefinition::Query(q::Query {
+                name,
+                selection_set,
+                directives,
+                ..
+            }) => {
+                let live = directives.iter().any(|dir| dir.name == LIVE_DIRECTIVE);
+                (Kind::Query, name, selection_set, live)
             }
             // Queries can be run by just sending a selection set
-            q::OperationDefinition::SelectionSet(selection_set) => (Kind::Query, selection_set),
-            q::OperationDefinition::Subscription(q::Subscription { selection_set, .. }) => {
-                (Kind::Subscription, selection_set)
+            q::OperationDefinition::SelectionSet(selection_set) => {
+                (Kind::Query, None, selection_set, false)
             }
+            q::OperationDefinition::Subscription(q::Subscription {
+                name,
+                selection_set,
+                ..
+            }) => (Kind::Subscription, name, selection_set, false),
             q::OperationDefinition::Mutation(_) => {
                 return Err(vec![QueryExecutionError::NotSupported(
                     "Mutations are not supported".to_owned(),
@@ -218,6 +460,10 @@ impl Query {
             "subgraph_id" => schema.id().clone(),
             "query_id" => query_id.clone()
         ));
+        let logger = match &query.request_id {
+            Some(request_id) => logger.new(o!("request_id" => request_id.clone())),
+            None => logger,
+        };
 
         let start = Instant::now();
         let root_type = match kind {
@@ -235,36 +481,143 @@ impl Query {
         };
 
         // It's important to check complexity first, so `validate_fields`
-        // doesn't risk a stack overflow from invalid queries. We don't
-        // really care about the resulting complexity, only that all the
-        // checks that `check_complexity` performs pass successfully
-        let _ = raw_query.check_complexity(max_complexity, max_depth)?;
+        // doesn't risk a stack overflow from invalid queries. The resulting
+        // complexity is only kept around for reporting
+        let mut complexity_breakdown = Vec::new();
+        let complexity = raw_query.check_complexity(
+            max_complexity,
+            max_depth,
+            if query.complexity_breakdown {
+                Some(&mut complexity_breakdown)
+            } else {
+                None
+            },
+        )?;
+        raw_query.check_aliases(ENV_VARS.graphql.max_aliases)?;
+        complexity_breakdown.sort_by(|a, b| b.cost.cmp(&a.cost));
         raw_query.validate_fields()?;
         let selection_set = raw_query.convert()?;
+        let depth = selection_depth(&selection_set);
 
         let query = Self {
             schema,
             selection_set: Arc::new(selection_set),
             shape_hash: query.shape_hash,
+            complexity,
+            complexity_breakdown,
+            depth,
+            operation_name,
             kind,
+            live,
             network,
             logger,
             start,
             query_text: query.query_text.cheap_clone(),
             variables_text: query.variables_text.cheap_clone(),
             query_id,
+            lane: query.lane,
+            caller: query.caller,
+            adaptive_first: query.adaptive_first,
+            localize_non_null_errors: query.localize_non_null_errors,
+            audited: ENV_VARS.graphql.field_audit_sample_rate > 0.0
+                && thread_rng().gen_bool(ENV_VARS.graphql.field_audit_sample_rate),
+            sampled: ENV_VARS.graphql.query_log_sample_rate > 0.0
+                && thread_rng().gen_bool(ENV_VARS.graphql.query_log_sample_rate),
+            memory: Default::default(),
+            warnings,
+            prepared: cache_hit,
         };
 
         Ok(Arc::new(query))
     }
 
+    /// Validate the document of `query` against `schema` and split it into
+    /// its operation and fragments. With `ignore_unknown_arguments`,
+    /// arguments that a field or directive does not have only cause a
+    /// warning
+    fn validate_document(
+        logger: &Logger,
+        schema: &ApiSchema,
+        query: &GraphDataQuery,
+        document_hash: [u8; 32],
+        ignore_unknown_arguments: bool,
+    ) -> Result<PreparedDocument, Vec<QueryExecutionError>> {
+        // Unknown arguments are not used for anything, and clients that
+        // still send them can be told about them with a warning
+        let (validation_errors, unknown_arguments) = if ignore_unknown_arguments {
+            (
+                validate(schema.document(), &query.document, &LENIENT_VALIDATION_PLAN),
+                validate(
+                    schema.document(),
+                    &query.document,
+                    &KNOWN_ARGUMENT_NAMES_PLAN,
+                ),
+            )
+        } else {
+            (
+                validate(schema.document(), &query.document, &GRAPHQL_VALIDATION_PLAN),
+                vec![],
+            )
+        };
+        let warnings = unknown_arguments
+            .into_iter()
+            .map(|e| format!("{} (ignored)", e.message.trim_end_matches('.')))
+            .collect();
+
+        if !validation_errors.is_empty() {
+            if !ENV_VARS.graphql.silent_graphql_validations {
+                return Err(validation_errors
+                    .into_iter()
+                    .map(|e| {
+                        QueryExecutionError::ValidationError(
+                            e.locations.first().cloned(),
+                            e.message,
+                        )
+                    })
+                    .collect());
+            } else {
+                warn!(
+                  logger,
+                  "GraphQL Validation failure";
+                  "query" => &query.query_text,
+                  "variables" => &query.variables_text,
+                  "errors" => format!("[{:?}]", validation_errors.iter().map(|e| e.message.clone()).collect::<Vec<_>>().join(", "))
+                );
+            }
+        }
+
+        let mut operation = None;
+        let mut fragments = HashMap::new();
+        for defn in query.document.definitions.iter().cloned() {
+            match defn {
+                q::Definition::Operation(op) => match operation {
+                    None => operation = Some(op),
+                    Some(_) => return Err(vec![QueryExecutionError::OperationNameRequired]),
+                },
+                q::Definition::Fragment(frag) => {
+                    fragments.insert(frag.name.clone(), frag);
+                }
+            }
+        }
+        let operation = operation.ok_or(QueryExecutionError::OperationNameRequired)?;
+
+        Ok(PreparedDocument {
+            document_hash,
+            operation,
+            fragments,
+            warnings,
+        })
+    }
+
     /// Return the block constraint for the toplevel query field(s), merging
     /// consecutive fields that have the same block constraint, while making
     /// sure that the fields appear in the same order as they did in the
     /// query
     ///
     /// Also returns the combined error policy for those fields, which is
-    /// `Deny` if any field is `Deny` and `Allow` otherwise.
+    /// `Deny` if any field is `Deny` and `Allow` otherwise. The policy of a
+    /// field is set by its `@errorPolicy` directive if it has one, and by
+    /// its `subgraphError` argument otherwise
     pub fn block_constraint(
         &self,
     ) -> Result<Vec<(BlockConstraint, (a::SelectionSet, ErrorPolicy))>, Vec<QueryExecutionError>>
@@ -285,6 +638,8 @@ impl Query {
                 None => BlockConstraint::Latest,
             };
 
+            // An `@errorPolicy` directive on the field takes precedence
+            // over its `subgraphError` argument
             let field_error_policy = match field.argument_value("subgraphError") {
                 Some(value) => ErrorPolicy::try_from(value).map_err(|_| {
                     vec![QueryExecutionError::InvalidArgumentError(
@@ -293,8 +648,12 @@ impl Query {
                         value.clone().into(),
                     )]
                 })?,
-                None => ErrorPolicy::Deny,
+                None => ErrorPolicy::default_policy(),
             };
+            let field_error_policy = ErrorPolicy::for_field(field)
+                .map_err(|e| vec![e])?
+                .unwrap_or(field_error_policy);
+            ErrorPolicy::used_in(&field.selection_set).map_err(|e| vec![e])?;
 
             let next_bc = Some(bc.clone());
             if prev_bc == next_bc {
@@ -330,6 +689,11 @@ impl Query {
         }
     }
 
+    /// Return `true` if this is a query with a `@live` directive
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
     /// Log details about the overall execution of the query
     pub fn log_execution(&self, block: BlockNumber) {
         if ENV_VARS.log_gql_timing() {
@@ -344,6 +708,57 @@ impl Query {
         }
     }
 
+    /// Log a summary of the execution of the query at `block` if it was
+    /// slower than `GRAPH_GRAPHQL_SLOW_QUERY_THRESHOLD_MS` or if it was
+    /// sampled for the query log. `result_size` is the size of the result
+    /// in bytes
+    pub fn log_summary(&self, block: BlockNumber, result_size: usize) {
+        let elapsed = self.start.elapsed();
+        let slow = ENV_VARS
+            .graphql
+            .slow_query_threshold
+            .map_or(false, |threshold| elapsed >= threshold);
+        if !slow && !self.sampled {
+            return;
+        }
+        info!(
+            &self.logger,
+            "Query execution";
+            "deployment" => self.schema.id().as_str(),
+            "shape_hash" => self.shape_hash,
+            "query_time_ms" => elapsed.as_millis(),
+            "result_size" => result_size,
+            "block" => block,
+            "slow" => slow,
+        );
+    }
+
+    /// Log which fields the part of the query corresponding to
+    /// `selection_set` accesses at `block`, if the query was sampled for
+    /// the field audit log
+    pub fn log_field_access(&self, selection_set: &a::SelectionSet, block: BlockNumber) {
+        if !self.audited {
+            return;
+        }
+        let fields = field_paths(selection_set)
+            .into_iter()
+            .collect::<Vec<_>>()
+            .join(",");
+        let variables = ENV_VARS
+            .graphql
+            .field_audit_variables
+            .then(|| self.variables_text.as_str());
+        info!(
+            &self.logger,
+            "Query field audit";
+            "deployment" => self.schema.id().as_str(),
+            "shape_hash" => self.shape_hash,
+            "block" => block,
+            "fields" => fields,
+            "variables" => variables,
+        );
+    }
+
     /// Log details about how the part of the query corresponding to
     /// `selection_set` was cached
     pub fn log_cache_status(
@@ -366,7 +781,10 @@ impl Query {
     }
 }
 
-/// Coerces variable values for an operation.
+/// Coerces variable values for an operation. Every variable that the
+/// operation declares is checked against its declared type before the
+/// query is executed, and all variables that are missing or can not be
+/// coerced are reported together
 pub fn coerce_variables(
     schema: &ApiSchema,
     operation: &q::OperationDefinition,
@@ -403,10 +821,13 @@ pub fn coerce_variables(
             // No variable value provided and no default for non-null type, fail
             None => {
                 if sast::is_non_null_type(&variable_def.var_type) {
-                    errors.push(QueryExecutionError::MissingVariableError(
-                        variable_def.position,
-                        variable_def.name.to_owned(),
-                    ));
+                    errors.push(QueryExecutionError::InvalidVariable {
+                        name: variable_def.name.to_owned(),
+                        reason: format!(
+                            "a value of type `{}` is required but none was provided",
+                            variable_def.var_type
+                        ),
+                    });
                 };
                 continue;
             }
@@ -415,10 +836,12 @@ pub fn coerce_variables(
 
         // We have a variable value, attempt to coerce it to the value type
         // of the variable definition
-        coerced_values.insert(
-            variable_def.name.to_owned(),
-            coerce_variable(schema, variable_def, value)?,
-        );
+        match coerce_variable(schema, variable_def, value) {
+            Ok(value) => {
+                coerced_values.insert(variable_def.name.to_owned(), value);
+            }
+            Err(e) => errors.push(e),
+        }
     }
 
     if errors.is_empty() {
@@ -432,17 +855,21 @@ fn coerce_variable(
     schema: &ApiSchema,
     variable_def: &q::VariableDefinition,
     value: r::Value,
-) -> Result<r::Value, Vec<QueryExecutionError>> {
+) -> Result<r::Value, QueryExecutionError> {
     use crate::values::coercion::coerce_value;
 
     let resolver = |name: &str| schema.get_named_type(name);
 
-    coerce_value(value, &variable_def.var_type, &resolver).map_err(|value| {
-        vec![QueryExecutionError::InvalidArgumentError(
-            variable_def.position,
-            variable_def.name.to_owned(),
-            value.into(),
-        )]
+    coerce_value(value, &variable_def.var_type, &resolver).map_err(|e| {
+        e.into_error(variable_def.position, &variable_def.name, |value| {
+            QueryExecutionError::InvalidVariable {
+                name: variable_def.name.to_owned(),
+                reason: format!(
+                    "`{}` is not a valid value of type `{}`",
+                    value, variable_def.var_type
+                ),
+            }
+        })
     })
 }
 
@@ -458,13 +885,29 @@ struct RawQuery<'s> {
     root_type: &'s s::ObjectType,
 }
 
+/// The contribution of fetching `count` entities of type `type_name` to
+/// the complexity of a query, taking the weights from
+/// `GRAPH_GRAPHQL_COMPLEXITY_WEIGHTS` into account
+fn entities_cost(type_name: &str, count: u64) -> Result<u64, ComplexityError> {
+    let weight = ENV_VARS.graphql.complexity_weights.weight(type_name);
+    if weight == 1.0 {
+        return Ok(count);
+    }
+    let cost = (count as f64 * weight).ceil();
+    if cost >= u64::MAX as f64 {
+        return Err(ComplexityError::Overflow);
+    }
+    Ok(cost as u64)
+}
+
 impl<'s> RawQuery<'s> {
     fn check_complexity(
         &self,
         max_complexity: Option<u64>,
         max_depth: u8,
+        breakdown: Option<&mut Vec<FieldCost>>,
     ) -> Result<u64, Vec<QueryExecutionError>> {
-        let complexity = self.complexity(max_depth).map_err(|e| vec![e])?;
+        let complexity = self.complexity(max_depth, breakdown).map_err(|e| vec![e])?;
         if let Some(max_complexity) = max_complexity {
             if complexity > max_complexity {
                 return Err(vec![QueryExecutionError::TooComplex(
@@ -476,6 +919,9 @@ impl<'s> RawQuery<'s> {
         Ok(complexity)
     }
 
+    /// The complexity of `selection_set`. If `breakdown` is given, the
+    /// cost of every field with a selection set is added to it, with the
+    /// field's path below `path`
     fn complexity_inner<'a>(
         &'a self,
         ty: &s::TypeDefinition,
@@ -483,6 +929,8 @@ impl<'s> RawQuery<'s> {
         max_depth: u8,
         depth: u8,
         visited_fragments: &'a HashSet<&'a str>,
+        path: &str,
+        mut breakdown: Option<&mut Vec<FieldCost>>,
     ) -> Result<u64, ComplexityError> {
         use ComplexityError::*;
 
@@ -493,7 +941,18 @@ impl<'s> RawQuery<'s> {
         selection_set
             .items
             .iter()
-            .try_fold(0, |total_complexity, selection| {
+            .try_fold(0u64, |total_complexity, selection| {
+                let directives = match selection {
+                    q::Selection::Field(field) => &field.directives,
+                    q::Selection::FragmentSpread(fragment) => &fragment.directives,
+                    q::Selection::InlineFragment(fragment) => &fragment.directives,
+                };
+                // Selections that will not be executed do not add to the
+                // cost of the query
+                if self.skipped(directives) {
+                    return Ok(total_complexity);
+                }
+
                 match selection {
                     q::Selection::Field(field) => {
                         // Empty selection sets are the base case.
@@ -516,6 +975,17 @@ impl<'s> RawQuery<'s> {
                         }
                         .ok_or(Invalid)?;
 
+                        let field_path = match breakdown {
+                            Some(_) => {
+                                let key = field.alias.as_ref().unwrap_or(&field.name);
+                                if path.is_empty() {
+                                    key.clone()
+                                } else {
+                                    format!("{}.{}", path, key)
+                                }
+                            }
+                            None => String::new(),
+                        };
                         let field_complexity = self.complexity_inner(
                             self.schema
                                 .get_named_type(s_field.field_type.get_base_type())
@@ -524,25 +994,34 @@ impl<'s> RawQuery<'s> {
                             max_depth,
                             depth + 1,
                             visited_fragments,
+                            &field_path,
+                            breakdown.as_deref_mut(),
                         )?;
 
                         // Non-collection queries pass through.
-                        if !sast::is_list_or_non_null_list_field(&s_field) {
-                            return Ok(total_complexity + field_complexity);
+                        let cost = if !sast::is_list_or_non_null_list_field(&s_field) {
+                            field_complexity
+                        } else {
+                            // For collection queries, check the `first` argument.
+                            let max_entities = qast::get_argument_value(&field.arguments, "first")
+                                .and_then(|arg| match arg {
+                                    q::Value::Int(n) => Some(n.as_i64()? as u64),
+                                    _ => None,
+                                })
+                                .unwrap_or(100);
+                            entities_cost(s_field.field_type.get_base_type(), max_entities)?
+                                .checked_add(
+                                    max_entities.checked_mul(field_complexity).ok_or(Overflow)?,
+                                )
+                                .ok_or(Overflow)?
+                        };
+                        if let Some(breakdown) = breakdown.as_deref_mut() {
+                            breakdown.push(FieldCost {
+                                path: field_path,
+                                cost,
+                            });
                         }
-
-                        // For collection queries, check the `first` argument.
-                        let max_entities = qast::get_argument_value(&field.arguments, "first")
-                            .and_then(|arg| match arg {
-                                q::Value::Int(n) => Some(n.as_i64()? as u64),
-                                _ => None,
-                            })
-                            .unwrap_or(100);
-                        max_entities
-                            .checked_add(
-                                max_entities.checked_mul(field_complexity).ok_or(Overflow)?,
-                            )
-                            .ok_or(Overflow)
+                        Ok(cost)
                     }
                     q::Selection::FragmentSpread(fragment) => {
                         let def = self.fragments.get(&fragment.fragment_name).unwrap();
@@ -560,6 +1039,8 @@ impl<'s> RawQuery<'s> {
                             max_depth,
                             depth + 1,
                             &visited_fragments,
+                            path,
+                            breakdown.as_deref_mut(),
                         )
                     }
                     q::Selection::InlineFragment(fragment) => {
@@ -575,6 +1056,8 @@ impl<'s> RawQuery<'s> {
                             max_depth,
                             depth + 1,
                             visited_fragments,
+                            path,
+                            breakdown.as_deref_mut(),
                         )
                     }
                 }
@@ -582,11 +1065,82 @@ impl<'s> RawQuery<'s> {
             })
     }
 
+    /// Fail if the query has more than `max_aliases` aliased fields. This
+    /// must only be called after `check_complexity` has made sure that
+    /// there are no fragment cycles
+    fn check_aliases(&self, max_aliases: usize) -> Result<(), Vec<QueryExecutionError>> {
+        if max_aliases == usize::MAX {
+            return Ok(());
+        }
+        let aliases = self.alias_count(&self.selection_set);
+        if aliases > max_aliases {
+            return Err(vec![QueryExecutionError::TooManyAliases(
+                aliases,
+                max_aliases,
+            )]);
+        }
+        Ok(())
+    }
+
+    /// The number of fields in `selection_set` that have an alias that is
+    /// different from their name. Fragments count every time they are
+    /// used since each use can multiply the work for the query, while
+    /// skipped selections do not count at all
+    fn alias_count(&self, selection_set: &q::SelectionSet) -> usize {
+        selection_set
+            .items
+            .iter()
+            .map(|selection| match selection {
+                q::Selection::Field(field) if !self.skipped(&field.directives) => {
+                    let aliased = field
+                        .alias
+                        .as_ref()
+                        .map_or(false, |alias| alias != &field.name);
+                    aliased as usize + self.alias_count(&field.selection_set)
+                }
+                q::Selection::FragmentSpread(spread) if !self.skipped(&spread.directives) => self
+                    .fragments
+                    .get(&spread.fragment_name)
+                    .map_or(0, |def| self.alias_count(&def.selection_set)),
+                q::Selection::InlineFragment(fragment) if !self.skipped(&fragment.directives) => {
+                    self.alias_count(&fragment.selection_set)
+                }
+                _ => 0,
+            })
+            .fold(0, usize::saturating_add)
+    }
+
+    /// Whether `directives` exclude the selection they are attached to
+    /// from execution, i.e., whether they contain `@skip(if: true)` or
+    /// `@include(if: false)`. The condition can be a literal or a variable;
+    /// a condition we can not evaluate here counts as including the
+    /// selection, so that we never undercount
+    fn skipped(&self, directives: &[q::Directive]) -> bool {
+        directives.iter().any(|dir| {
+            let condition = match qast::get_argument_value(&dir.arguments, "if") {
+                Some(q::Value::Boolean(condition)) => Some(*condition),
+                Some(q::Value::Variable(name)) => match self.variables.get(name) {
+                    Some(r::Value::Boolean(condition)) => Some(*condition),
+                    _ => None,
+                },
+                _ => None,
+            };
+            matches!(
+                (dir.name.as_str(), condition),
+                ("skip", Some(true)) | ("include", Some(false))
+            )
+        })
+    }
+
     /// See https://developer.github.com/v4/guides/resource-limitations/.
     ///
     /// If the query is invalid, returns `Ok(0)` so that execution proceeds and
     /// gives a proper error.
-    fn complexity(&self, max_depth: u8) -> Result<u64, QueryExecutionError> {
+    fn complexity(
+        &self,
+        max_depth: u8,
+        breakdown: Option<&mut Vec<FieldCost>>,
+    ) -> Result<u64, QueryExecutionError> {
         let root_type = self.schema.get_root_query_type_def().unwrap();
 
         match self.complexity_inner(
@@ -595,6 +1149,8 @@ impl<'s> RawQuery<'s> {
             max_depth,
             0,
             &HashSet::new(),
+            "",
+            breakdown,
         ) {
             Ok(complexity) => Ok(complexity),
             Err(ComplexityError::Invalid) => Ok(0),
@@ -117,7 +117,16 @@ pub trait Resolver: Sized + Send + Sync + 'static {
         )))
     }
 
-    fn post_process(&self, _result: &mut QueryResult) -> Result<(), Error> {
+    fn post_process(
+        &self,
+        _result: &mut QueryResult,
+        _selection_set: &a::SelectionSet,
+    ) -> Result<(), Error> {
         Ok(())
     }
+
+    /// Record `result` in metrics once executing the query has produced
+    /// it, together with all its errors. Results from a cache are not
+    /// recorded again
+    fn record_result(&self, _result: &QueryResult) {}
 }
@@ -0,0 +1,70 @@
+use graph::prelude::opentelemetry::{
+    global,
+    trace::{TraceContextExt, Tracer as _},
+    Context, KeyValue,
+};
+use graph::prelude::rand::{thread_rng, Rng};
+use graph::prelude::ENV_VARS;
+
+/// The name under which our spans are reported
+const TRACER_NAME: &str = "graph-graphql";
+
+/// An OpenTelemetry span for one step in executing a query. Only a sample
+/// of queries, as set by `GRAPH_GRAPHQL_SPAN_SAMPLE_RATE`, gets spans; for
+/// all others, a `QuerySpan` is empty and using it does nothing. Spans are
+/// sent to whatever tracer provider is installed globally
+#[derive(Clone, Default)]
+pub(crate) struct QuerySpan {
+    cx: Option<Context>,
+}
+
+impl QuerySpan {
+    /// Start the span `name` for a new query if the query is sampled
+    pub fn sample(name: &'static str) -> Self {
+        let rate = ENV_VARS.graphql.span_sample_rate;
+        if rate <= 0.0 || !thread_rng().gen_bool(rate) {
+            return QuerySpan::default();
+        }
+        let span = global::tracer(TRACER_NAME).start(name);
+        QuerySpan {
+            cx: Some(Context::current_with_span(span)),
+        }
+    }
+
+    /// Start the span `name` inside this span. The new span is empty if
+    /// this one is
+    pub fn child(&self, name: &'static str) -> Self {
+        let cx = self.cx.as_ref().map(|cx| {
+            let span = global::tracer(TRACER_NAME).start_with_context(name, cx);
+            cx.with_span(span)
+        });
+        QuerySpan { cx }
+    }
+
+    /// Add the attributes that `attributes` produces to the span. The
+    /// attributes are only computed if this span is recorded
+    pub fn set_attributes(&self, attributes: impl FnOnce() -> Vec<KeyValue>) {
+        if let Some(cx) = &self.cx {
+            let span = cx.span();
+            for attribute in attributes() {
+                span.set_attribute(attribute);
+            }
+        }
+    }
+
+    /// Record the event `name` with the attributes that `attributes`
+    /// produces
+    pub fn add_event(&self, name: &'static str, attributes: impl FnOnce() -> Vec<KeyValue>) {
+        if let Some(cx) = &self.cx {
+            cx.span().add_event(name, attributes());
+        }
+    }
+
+    /// Finish the span. A span whose last copy is dropped without calling
+    /// `end` is finished at that point
+    pub fn end(&self) {
+        if let Some(cx) = &self.cx {
+            cx.span().end();
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use parking_lot::Mutex;
+use std::time::Instant;
+
+use graph::components::store::ReplicaId;
+use graph::data::graphql::object;
+use graph::prelude::{chrono, r, s, BlockNumber};
+
+#[derive(Default)]
+struct TraceState {
+    /// The path to the field that is currently being resolved
+    path: Vec<r::Value>,
+    resolvers: Vec<r::Value>,
+    store_queries: Vec<r::Value>,
+    block_constraints: Vec<r::Value>,
+    /// The database replica the query was sent to
+    replica: Option<String>,
+    /// The most memory the entities the query loaded took up, in bytes
+    peak_memory: usize,
+}
+
+/// Collects timings while a query is executed and reports them in the
+/// Apollo Tracing format. Execution only consults the tracer if the client
+/// asked for tracing, so that normal queries do not pay for it
+pub struct Tracer {
+    start: Instant,
+    start_time: chrono::DateTime<chrono::Utc>,
+    state: Mutex<TraceState>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Tracer {
+            start: Instant::now(),
+            start_time: chrono::Utc::now(),
+            state: Mutex::new(TraceState::default()),
+        }
+    }
+
+    /// Nanoseconds between the start of the trace and `instant`
+    fn offset(&self, instant: Instant) -> r::Value {
+        r::Value::Int(instant.saturating_duration_since(self.start).as_nanos() as i64)
+    }
+
+    /// Nanoseconds that have passed since `start`
+    fn duration(start: Instant) -> r::Value {
+        r::Value::Int(start.elapsed().as_nanos() as i64)
+    }
+
+    /// Start resolving the field with `response_key`. The returned instant
+    /// must be passed to the matching call to `leave`
+    pub(crate) fn enter(&self, response_key: &str) -> Instant {
+        self.state
+            .lock()
+            .path
+            .push(r::Value::String(response_key.to_string()));
+        Instant::now()
+    }
+
+    /// Finish resolving the field that was entered at `start`
+    pub(crate) fn leave(
+        &self,
+        start: Instant,
+        parent_type: &str,
+        field_name: &str,
+        return_type: &s::Type,
+    ) {
+        let mut state = self.state.lock();
+        let path = state.path.clone();
+        state.path.pop();
+        state.resolvers.push(object! {
+            path: r::Value::List(path),
+            parentType: parent_type,
+            fieldName: field_name,
+            returnType: return_type.to_string(),
+            startOffset: self.offset(start),
+            duration: Self::duration(start),
+        });
+    }
+
+    /// Start completing the item at `index` of a list
+    pub(crate) fn enter_index(&self, index: usize) {
+        self.state.lock().path.push(r::Value::Int(index as i64));
+    }
+
+    pub(crate) fn leave_index(&self) {
+        self.state.lock().path.pop();
+    }
+
+    /// Record how long it took to fetch the data for the toplevel fields
+    /// of a query from the store
+    pub(crate) fn store_query(&self, start: Instant) {
+        let entry = object! {
+            startOffset: self.offset(start),
+            duration: Self::duration(start),
+        };
+        self.state.lock().store_queries.push(entry);
+    }
+
+    /// Record how long it took to execute the part of the query for one
+    /// block constraint, and at which block it was executed
+    pub fn block_constraint(&self, block: BlockNumber, start: Instant) {
+        let entry = object! {
+            block: block,
+            startOffset: self.offset(start),
+            duration: Self::duration(start),
+        };
+        self.state.lock().block_constraints.push(entry);
+    }
+
+    /// Record which database replica the query is executed against
+    pub fn replica(&self, replica: ReplicaId) {
+        self.state.lock().replica = Some(replica.to_string());
+    }
+
+    /// Record that the entities the query loaded took up `bytes` of memory
+    pub(crate) fn memory(&self, bytes: usize) {
+        let mut state = self.state.lock();
+        state.peak_memory = state.peak_memory.max(bytes);
+    }
+
+    /// Produce the trace in the Apollo Tracing format
+    pub fn finish(&self) -> r::Value {
+        let end_time = chrono::Utc::now();
+        let state = std::mem::take(&mut *self.state.lock());
+        object! {
+            version: 1,
+            startTime: self.start_time.to_rfc3339(),
+            endTime: end_time.to_rfc3339(),
+            duration: Self::duration(self.start),
+            execution: object! {
+                resolvers: state.resolvers,
+                storeQueries: state.store_queries,
+            },
+            blockConstraints: state.block_constraints,
+            replica: state.replica,
+            peakMemory: r::Value::Int(state.peak_memory as i64),
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
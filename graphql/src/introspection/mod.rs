@@ -2,4 +2,7 @@ mod resolver;
 mod schema;
 
 pub use self::resolver::IntrospectionResolver;
-pub use self::schema::{is_introspection_field, INTROSPECTION_DOCUMENT, INTROSPECTION_QUERY_TYPE};
+pub use self::schema::{
+    is_introspection_field, is_introspection_query, INTROSPECTION_DOCUMENT,
+    INTROSPECTION_QUERY_TYPE,
+};
@@ -4,7 +4,7 @@ use graphql_parser;
 
 use graph::data::graphql::ext::DocumentExt;
 use graph::data::graphql::ext::ObjectTypeExt;
-use graph::prelude::s::Document;
+use graph::prelude::{q, s::Document};
 
 use lazy_static::lazy_static;
 
@@ -130,3 +130,33 @@ lazy_static! {
 pub fn is_introspection_field(name: &str) -> bool {
     INTROSPECTION_QUERY_TYPE.field(name).is_some()
 }
+
+/// Return `true` if the toplevel selections of all operations in `document`
+/// are introspection fields like `__schema` and `__type`. Such queries can
+/// be answered from the schema alone. Fragment spreads at the top level
+/// are not looked into, and queries that use them do not count
+pub fn is_introspection_query(document: &q::Document) -> bool {
+    let mut operations = document
+        .definitions
+        .iter()
+        .filter_map(|def| match def {
+            q::Definition::Operation(op) => Some(op),
+            q::Definition::Fragment(_) => None,
+        })
+        .peekable();
+    operations.peek().is_some()
+        && operations.all(|op| {
+            let selection_set = match op {
+                q::OperationDefinition::Query(query) => &query.selection_set,
+                q::OperationDefinition::SelectionSet(selection_set) => selection_set,
+                q::OperationDefinition::Mutation(_) | q::OperationDefinition::Subscription(_) => {
+                    return false
+                }
+            };
+            !selection_set.items.is_empty()
+                && selection_set.items.iter().all(|selection| match selection {
+                    q::Selection::Field(field) => is_introspection_field(&field.name),
+                    q::Selection::FragmentSpread(_) | q::Selection::InlineFragment(_) => false,
+                })
+        })
+}
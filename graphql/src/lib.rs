@@ -26,15 +26,20 @@ mod runner;
 
 /// Prelude that exports the most important traits and types.
 pub mod prelude {
-    pub use super::execution::{ast as a, ExecutionContext, Query, Resolver};
+    pub use super::execution::{ast as a, ExecutionContext, Query, Resolver, Tracer};
     pub use super::introspection::IntrospectionResolver;
-    pub use super::query::{execute_query, ext::BlockConstraint, QueryExecutionOptions};
+    pub use super::query::{
+        execute_query, execute_query_stream, ext::BlockConstraint, QueryExecutionOptions,
+    };
     pub use super::schema::{api_schema, APISchemaError};
     pub use super::store::StoreResolver;
     pub use super::subscription::SubscriptionExecutionOptions;
     pub use super::values::MaybeCoercible;
 
-    pub use super::runner::GraphQlRunner;
+    pub use super::execution::ast::SelectionSet;
+    pub use super::runner::{
+        AllowAllFields, FieldAuthorizer, GraphQlRunner, QueryAllowlist, QueryLimits,
+    };
     pub use graph::prelude::s::ObjectType;
 }
 
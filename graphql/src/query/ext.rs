@@ -1,6 +1,7 @@
 //! Extension traits for graphql_parser::query structs
 
 use graph::blockchain::BlockHash;
+use graph::data::graphql::{object, IntoValue};
 use graph::prelude::TryFromValue;
 use graphql_parser::Pos;
 
@@ -62,6 +63,13 @@ pub enum BlockConstraint {
     /// Execute the query on the latest block only if the the subgraph has progressed to or past the
     /// given block number.
     Min(BlockNumber),
+    /// Execute the query at the block that is the given number of blocks
+    /// before the latest block of the subgraph. Clients ask for this with
+    /// a negative `number`
+    BlocksAgo(BlockNumber),
+    /// Execute the query at the block of the snapshot with the given id,
+    /// if the store keeps snapshots
+    Snapshot(String),
     Latest,
 }
 
@@ -83,15 +91,35 @@ impl TryFromValue for BlockConstraint {
         if let Some(hash) = map.get("hash") {
             Ok(BlockConstraint::Hash(TryFromValue::try_from_value(hash)?))
         } else if let Some(number_value) = map.get("number") {
-            Ok(BlockConstraint::Number(BlockNumber::try_from_value(
-                number_value,
-            )?))
+            let number = BlockNumber::try_from_value(number_value)?;
+            if number < 0 {
+                Ok(BlockConstraint::BlocksAgo(number.saturating_neg()))
+            } else {
+                Ok(BlockConstraint::Number(number))
+            }
         } else if let Some(number_value) = map.get("number_gte") {
             Ok(BlockConstraint::Min(BlockNumber::try_from_value(
                 number_value,
             )?))
+        } else if let Some(snapshot) = map.get("snapshot") {
+            Ok(BlockConstraint::Snapshot(String::try_from_value(snapshot)?))
         } else {
             Err(anyhow!("invalid `BlockConstraint`"))
         }
     }
 }
+
+impl IntoValue for BlockConstraint {
+    /// Turn the constraint back into the `block` argument that would
+    /// produce it; `Latest` corresponds to not passing a `block` argument
+    fn into_value(self) -> r::Value {
+        match self {
+            BlockConstraint::Hash(hash) => object! { hash: hash.to_string() },
+            BlockConstraint::Number(number) => object! { number: number },
+            BlockConstraint::Min(number) => object! { number_gte: number },
+            BlockConstraint::BlocksAgo(ago) => object! { number: -ago },
+            BlockConstraint::Snapshot(id) => object! { snapshot: id },
+            BlockConstraint::Latest => r::Value::Null,
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use graph::prelude::{BlockPtr, CheapClone, QueryExecutionError, QueryResult};
+use graph::prelude::{
+    futures03, r, BlockPtr, CheapClone, QueryError, QueryExecutionError, QueryResult,
+    QueryResultStream,
+};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use graph::data::graphql::effort::LoadManager;
 
 use crate::execution::{ast as a, *};
+use crate::introspection::IntrospectionResolver;
+use crate::schema::ast as sast;
 
 /// Utilities for working with GraphQL query ASTs.
 pub mod ast;
@@ -17,6 +22,10 @@ pub struct QueryExecutionOptions<R> {
     /// The resolver to use.
     pub resolver: R,
 
+    /// The instant from which `deadline` was computed. Timeouts report
+    /// how long the query ran since then
+    pub start: Instant,
+
     /// Time at which the query times out.
     pub deadline: Option<Instant>,
 
@@ -27,6 +36,13 @@ pub struct QueryExecutionOptions<R> {
     pub max_skip: u32,
 
     pub load_manager: Arc<LoadManager>,
+
+    /// Collects timings for the query if it should be traced
+    pub tracer: Option<Arc<Tracer>>,
+
+    /// Whether to return the data resolved so far instead of failing the
+    /// query when the deadline passes
+    pub partial_results: bool,
 }
 
 /// Executes a query and returns a result.
@@ -45,10 +61,19 @@ where
         logger: query.logger.clone(),
         resolver: options.resolver,
         query: query.clone(),
+        start: options.start,
         deadline: options.deadline,
         max_first: options.max_first,
         max_skip: options.max_skip,
         cache_status: Default::default(),
+        tracer: options.tracer,
+        partial_results: options.partial_results,
+        deadline_exceeded: Default::default(),
+        adaptive_first: query.adaptive_first.then(AdaptiveFirst::new),
+        localized_errors: query
+            .localize_non_null_errors
+            .then(LocalizedErrors::default),
+        memory: query.memory.cheap_clone(),
     });
 
     if !query.is_query() {
@@ -71,6 +96,9 @@ where
     )
     .await;
     let elapsed = start.elapsed();
+    if let Some(tracer) = &ctx.tracer {
+        tracer.memory(ctx.memory.peak());
+    }
     let cache_status = ctx.cache_status.load();
     options
         .load_manager
@@ -83,3 +111,204 @@ where
     );
     result
 }
+
+/// The collection that a streamed query pages through, and what we need to
+/// execute its pages
+struct PageStream<R> {
+    query: Arc<Query>,
+    resolver: R,
+    timeout: Option<Duration>,
+    max_first: u32,
+    max_skip: u32,
+    load_manager: Arc<LoadManager>,
+    /// The response key of the toplevel collection
+    response_key: String,
+    /// The number of entities in a full page
+    page_size: usize,
+}
+
+impl<R: Resolver + CheapClone> PageStream<R> {
+    async fn execute(&self, selection_set: Arc<a::SelectionSet>) -> Arc<QueryResult> {
+        let start = Instant::now();
+        let ctx = Arc::new(ExecutionContext {
+            logger: self.query.logger.clone(),
+            resolver: self.resolver.cheap_clone(),
+            query: self.query.clone(),
+            start,
+            deadline: self.timeout.map(|timeout| start + timeout),
+            max_first: self.max_first,
+            max_skip: self.max_skip,
+            cache_status: Default::default(),
+            tracer: None,
+            partial_results: false,
+            deadline_exceeded: Default::default(),
+            // Pages that are not full would end the stream early
+            adaptive_first: None,
+            localized_errors: self
+                .query
+                .localize_non_null_errors
+                .then(LocalizedErrors::default),
+            // Pages are sent to the client once they are complete, and each
+            // page only accounts for its own entities
+            memory: Default::default(),
+        });
+        let query_type = self.query.schema.query_type.cheap_clone().into();
+        let result = run_root_selection_set(ctx.cheap_clone(), selection_set, query_type).await;
+        self.load_manager.record_work(
+            self.query.shape_hash,
+            start.elapsed(),
+            ctx.cache_status.load(),
+        );
+        result
+    }
+
+    /// The selection set for the page after `result`, or `None` if
+    /// `result` is the last page
+    fn next_page(
+        &self,
+        selection_set: &a::SelectionSet,
+        result: &QueryResult,
+    ) -> Option<a::SelectionSet> {
+        if result.has_errors() {
+            return None;
+        }
+        let len = match result.data()?.get(&self.response_key) {
+            Some(r::Value::List(items)) => items.len(),
+            _ => 0,
+        };
+        if len < self.page_size {
+            return None;
+        }
+        let cursor = result.cursors.get(&self.response_key)?;
+        let mut selection_set = selection_set.clone();
+        selection_set.set_argument(
+            &self.response_key,
+            "after",
+            r::Value::String(cursor.clone()),
+        );
+        selection_set.set_argument(&self.response_key, "skip", r::Value::Int(0));
+        Some(selection_set)
+    }
+}
+
+/// The response key and page size of the toplevel collection of a query
+/// that should be streamed. Only queries with a single toplevel field that
+/// is a list of entities can be streamed
+fn stream_field(
+    query: &Query,
+    selection_set: &a::SelectionSet,
+) -> Result<(String, usize), QueryExecutionError> {
+    let not_supported = || {
+        QueryExecutionError::NotSupported(
+            "only queries with a single toplevel collection can be streamed".to_string(),
+        )
+    };
+    let field = selection_set.single_field().ok_or_else(not_supported)?;
+    let is_collection = sast::get_field(query.schema.query_type.as_ref(), &field.name)
+        .map(sast::is_list_or_non_null_list_field)
+        .unwrap_or(false);
+    if !is_collection || field.argument_value("text").is_some() {
+        return Err(not_supported());
+    }
+    let page_size = match field.argument_value("first") {
+        Some(r::Value::Int(first)) => *first as usize,
+        // The default for `first`
+        _ => 100,
+    };
+    Ok((field.response_key().to_string(), page_size))
+}
+
+/// Executes a query whose only toplevel field is a collection one page at
+/// a time. Every page is a separate result with the next `first` entities
+/// of the collection after the last entity of the previous page, and the
+/// stream ends after the first page that is not full or has errors. Only
+/// one page is held in memory at a time. The `first` argument is checked
+/// against `max_first` as for any other query and sets the size of the
+/// pages, and `skip` only applies to the first page. The deadline from
+/// `options` applies to each page separately, and the query is never
+/// cached or traced
+pub fn execute_query_stream<R>(
+    query: Arc<Query>,
+    selection_set: Option<a::SelectionSet>,
+    options: QueryExecutionOptions<R>,
+) -> QueryResultStream
+where
+    R: Resolver + CheapClone,
+{
+    use futures03::stream::{self, StreamExt};
+
+    let selection_set = selection_set.unwrap_or_else(|| query.selection_set.as_ref().clone());
+    let field = if query.is_query() {
+        stream_field(&query, &selection_set)
+    } else {
+        Err(QueryExecutionError::NotSupported(
+            "Only queries are supported".to_string(),
+        ))
+    };
+    let (response_key, page_size) = match field {
+        Ok(field) => field,
+        Err(e) => {
+            let result = Arc::new(QueryResult::from(e));
+            return stream::once(async move { result }).boxed();
+        }
+    };
+
+    let pages = PageStream {
+        query,
+        resolver: options.resolver,
+        timeout: options
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(options.start)),
+        max_first: options.max_first,
+        max_skip: options.max_skip,
+        load_manager: options.load_manager,
+        response_key,
+        page_size,
+    };
+    stream::unfold(Some((pages, selection_set)), |state| async move {
+        let (pages, selection_set) = state?;
+        let result = pages.execute(Arc::new(selection_set.clone())).await;
+        let next = pages
+            .next_page(&selection_set, &result)
+            .map(|selection_set| (pages, selection_set));
+        Some((result, next))
+    })
+    .boxed()
+}
+
+/// Execute a query that only asks for introspection fields. Such queries
+/// are answered from the schema alone; they do not go through the
+/// `LoadManager` and do not read anything from the store
+pub fn execute_introspection_query(query: Arc<Query>, timeout: Option<Duration>) -> QueryResult {
+    let start = Instant::now();
+    let ctx = ExecutionContext {
+        logger: query.logger.clone(),
+        resolver: IntrospectionResolver::new(&query.logger, query.schema.schema()),
+        query: query.clone(),
+        start,
+        deadline: timeout.map(|timeout| start + timeout),
+        max_first: std::u32::MAX,
+        max_skip: std::u32::MAX,
+        cache_status: Default::default(),
+        tracer: None,
+        partial_results: false,
+        deadline_exceeded: Default::default(),
+        adaptive_first: None,
+        localized_errors: query
+            .localize_non_null_errors
+            .then(LocalizedErrors::default),
+        memory: Default::default(),
+    };
+
+    let query_type = ctx.query.schema.query_type.cheap_clone().into();
+    let data = execute_root_selection_set_uncached(&ctx, &query.selection_set, &query_type)
+        .map(|(data, _, _)| data);
+    let mut result = QueryResult::from(data);
+    if let Some(localized) = &ctx.localized_errors {
+        result
+            .errors_mut()
+            .extend(localized.take().into_iter().map(QueryError::from));
+    }
+    result.deployment = Some(query.schema.id().clone());
+    result
+}
@@ -1,38 +1,143 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use lru_time_cache::LruCache;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::execution::{
+    ast as a, deprecated_fields, PreparedQueries, QueryCache, QueryHash, QuerySpan, ResultCache,
+    SelectedFields, Tracer,
+};
+use crate::introspection::is_introspection_query;
 use crate::prelude::{QueryExecutionOptions, StoreResolver, SubscriptionExecutionOptions};
-use crate::query::execute_query;
-use crate::subscription::execute_prepared_subscription;
+use crate::query::ext::BlockConstraint;
+use crate::query::{execute_introspection_query, execute_query, execute_query_stream};
+use crate::schema::{api::ErrorPolicy, ast as sast};
+use crate::store::{collect_entities_from_query_field, Cursor};
+use crate::subscription::{
+    changes_only, end_when_unavailable, execute_prepared_subscription, limit_result_size,
+    recoverable_errors,
+};
+use graph::data::graphql::{object, IntoValue, TypeExt};
+use graph::data::value::{Object, Word};
+use graph::prelude::futures03::stream::StreamExt;
 use graph::prelude::MetricsRegistry;
-use graph::prometheus::{Gauge, Histogram};
+use graph::prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramVec};
 use graph::{
-    components::store::SubscriptionManager,
+    components::store::{DeploymentId, ReplicaId, ReplicaSelector, SubscriptionManager},
     prelude::{
-        async_trait, o, CheapClone, DeploymentState, GraphQlRunner as GraphQlRunnerTrait, Logger,
-        Query, QueryExecutionError, Subscription, SubscriptionError, SubscriptionResult, ENV_VARS,
+        async_trait, debug, error, futures03, o, q, r, serde_json, slog, warn, web3, ApiSchema,
+        BlockNumber, BlockPtr, CheapClone, DeploymentHash, DeploymentState,
+        GraphQlRunner as GraphQlRunnerTrait, Logger, Query, QueryCost, QueryExecutionError,
+        QueryResultStream, Subscription, SubscriptionError, SubscriptionResult, ENV_VARS,
     },
+    prelude::{opentelemetry::KeyValue, tokio},
+};
+use graph::{
+    data::graphql::effort::{Decision, LoadManager},
+    prelude::QueryStoreManager,
 };
-use graph::{data::graphql::effort::LoadManager, prelude::QueryStoreManager};
 use graph::{
-    data::query::{QueryResults, QueryTarget},
+    data::query::{QueryResult, QueryResults, QueryTarget, ReorgTolerance},
     prelude::QueryStore,
 };
 
 pub struct ResultSizeMetrics {
     histogram: Box<Histogram>,
+    partial_histogram: Box<Histogram>,
     max_gauge: Box<Gauge>,
+    max_window: Mutex<WindowMax>,
+    cache_hits: Box<Counter>,
+    cache_misses: Box<Counter>,
+    subscriptions_dropped: Box<Counter>,
+}
+
+/// The largest of the values observed within a sliding window of time.
+/// Only values that can still become the maximum are kept, i.e., values
+/// that are not smaller than a later value, so that the oldest value kept
+/// is the maximum. A zero window keeps the largest value ever observed
+struct WindowMax {
+    window: Duration,
+    values: VecDeque<(Instant, f64)>,
+}
+
+impl WindowMax {
+    fn new(window: Duration) -> Self {
+        WindowMax {
+            window,
+            values: VecDeque::new(),
+        }
+    }
+
+    /// Record `value` as observed at `now` and return the maximum of the
+    /// values observed within the window that ends at `now`
+    fn observe(&mut self, now: Instant, value: f64) -> f64 {
+        while matches!(self.values.back(), Some((_, last)) if *last <= value) {
+            self.values.pop_back();
+        }
+        self.values.push_back((now, value));
+        if self.window.is_zero() {
+            // Nothing ever expires, and only the maximum is needed
+            self.values.truncate(1);
+        } else {
+            while let Some((time, _)) = self.values.front() {
+                if now.saturating_duration_since(*time) <= self.window {
+                    break;
+                }
+                self.values.pop_front();
+            }
+        }
+        self.values.front().map(|(_, max)| *max).unwrap_or(value)
+    }
+}
+
+/// Parse a comma separated list of histogram bucket boundaries, which
+/// must be strictly increasing
+fn parse_buckets(spec: &str) -> Result<Vec<f64>, String> {
+    let buckets = spec
+        .split(',')
+        .map(|bucket| {
+            bucket
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("invalid bucket `{}`: {}", bucket.trim(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+        return Err("buckets must be strictly increasing".to_string());
+    }
+    Ok(buckets)
 }
 
 impl ResultSizeMetrics {
-    fn new(registry: Arc<dyn MetricsRegistry>) -> Self {
+    fn new(logger: &Logger, registry: Arc<dyn MetricsRegistry>) -> Self {
         // Divide the Histogram into exponentially sized buckets between 1k and 4G
-        let bins = (10..32).map(|n| 2u64.pow(n) as f64).collect::<Vec<_>>();
+        // unless we are told otherwise
+        let default_bins = || (10..32).map(|n| 2u64.pow(n) as f64).collect::<Vec<_>>();
+        let bins = match &ENV_VARS.graphql.query_result_size_buckets {
+            None => default_bins(),
+            Some(spec) => parse_buckets(spec).unwrap_or_else(|e| {
+                warn!(logger, "Ignoring GRAPH_QUERY_RESULT_SIZE_BUCKETS and using default buckets";
+                    "buckets" => spec,
+                    "error" => e);
+                default_bins()
+            }),
+        };
         let histogram = registry
             .new_histogram(
                 "query_result_size",
                 "the size of the result of successful GraphQL queries (in CacheWeight)",
+                bins.clone(),
+            )
+            .unwrap();
+        let partial_histogram = registry
+            .new_histogram(
+                "query_result_size_partial",
+                "the size of the result of GraphQL queries that returned data together with \
+                 errors (in CacheWeight)",
                 bins,
             )
             .unwrap();
@@ -40,39 +145,933 @@ impl ResultSizeMetrics {
         let max_gauge = registry
             .new_gauge(
                 "query_result_max",
-                "the maximum size of a query result (in CacheWeight)",
+                "the maximum size of a query result within GRAPH_QUERY_RESULT_MAX_WINDOW \
+             (in CacheWeight)",
                 HashMap::new(),
             )
             .unwrap();
 
+        let cache_hits = registry
+            .new_counter(
+                "query_result_cache_hit_count",
+                "the number of queries that were answered from the query result cache",
+            )
+            .unwrap();
+        let cache_misses = registry
+            .new_counter(
+                "query_result_cache_miss_count",
+                "the number of queries that could have been, but were not, answered from the \
+                 query result cache",
+            )
+            .unwrap();
+        let subscriptions_dropped = registry
+            .new_counter(
+                "query_subscription_dropped_count",
+                "the number of subscriptions that were ended because one of their updates \
+                 was larger than GRAPH_GRAPHQL_SUBSCRIPTION_MAX_RESULT_SIZE",
+            )
+            .unwrap();
+
         Self {
             histogram,
+            partial_histogram,
             max_gauge,
+            max_window: Mutex::new(WindowMax::new(ENV_VARS.graphql.query_result_max_window)),
+            cache_hits,
+            cache_misses,
+            subscriptions_dropped,
         }
     }
 
     // Tests need to construct one of these, but normal code doesn't
     #[cfg(debug_assertions)]
     pub fn make(registry: Arc<dyn MetricsRegistry>) -> Self {
-        Self::new(registry)
+        Self::new(&Logger::root(slog::Discard, o!()), registry)
+    }
+
+    /// Record the size of `result`. Only results without any errors are
+    /// successful; results with data and errors, for example because
+    /// their error policy allowed indexing errors, are recorded
+    /// separately, and results without data are not recorded at all
+    pub fn observe_result(&self, result: &QueryResult) {
+        let size = result.result_size as f64;
+        if !result.has_errors() {
+            self.histogram.observe(size);
+        } else if result.has_data() {
+            self.partial_histogram.observe(size);
+        } else {
+            return;
+        }
+        let max = self
+            .max_window
+            .lock()
+            .unwrap()
+            .observe(Instant::now(), size);
+        self.max_gauge.set(max);
+    }
+
+    pub fn observe_cache_hit(&self) {
+        self.cache_hits.inc();
+    }
+
+    pub fn observe_cache_miss(&self) {
+        self.cache_misses.inc();
+    }
+
+    pub fn observe_subscription_dropped(&self) {
+        self.subscriptions_dropped.inc();
+    }
+}
+
+/// The directive with which clients opt a query into the result cache
+const CACHED_DIRECTIVE: &str = "cached";
+
+/// The TTL that the query asked for with `@cached(ttl: <seconds>)` on its
+/// operation, if any. The TTL can also be passed in a variable
+fn cached_directive_ttl(query: &Query) -> Option<Duration> {
+    let directives = query
+        .document
+        .definitions
+        .iter()
+        .find_map(|defn| match defn {
+            q::Definition::Operation(q::OperationDefinition::Query(op)) => Some(&op.directives),
+            _ => None,
+        })?;
+    let ttl = directives
+        .iter()
+        .find(|dir| dir.name == CACHED_DIRECTIVE)?
+        .arguments
+        .iter()
+        .find(|(name, _)| name == "ttl")
+        .map(|(_, value)| value)?;
+    let secs = match ttl {
+        q::Value::Int(secs) => secs.as_i64(),
+        q::Value::Variable(name) => match query.variables.as_ref()?.get(name)? {
+            r::Value::Int(secs) => Some(*secs),
+            _ => None,
+        },
+        _ => None,
+    }?;
+    (secs > 0).then(|| Duration::from_secs(secs as u64))
+}
+
+/// The key under which identical queries are coalesced. Besides the
+/// `shape_hash`, the block and the variables, this needs to include the
+/// document itself since the `shape_hash` ignores the values of arguments,
+/// the limits the query is subject to, and the caller, since the
+/// `FieldAuthorizer` may let callers see different fields
+fn coalesce_key(query: &Query, state: &DeploymentState, limits: impl fmt::Debug) -> QueryHash {
+    // Sort the variables so that their order does not matter
+    let variables = query
+        .variables
+        .as_ref()
+        .map(|variables| variables.iter().collect::<BTreeMap<_, _>>());
+    let mut hasher = Sha256::new();
+    hasher.update(state.id.as_str());
+    hasher.update(state.latest_block.hash_hex());
+    hasher.update(state.latest_block.number.to_be_bytes());
+    hasher.update(query.shape_hash.to_be_bytes());
+    hasher.update(query.document.to_string());
+    hasher.update(serde_json::to_string(&variables).unwrap_or_default());
+    hasher.update(format!("{:?}", query.reorg_tolerance));
+    hasher.update([
+        query.sync_status as u8,
+        query.partial_results as u8,
+        query.force_primary as u8,
+        query.result_size as u8,
+        query.adaptive_first as u8,
+        query.localize_non_null_errors as u8,
+    ]);
+    hasher.update(format!("{:?}", query.timeout));
+    hasher.update(query.if_none_match.join(","));
+    hasher.update(format!("{:?}", query.caller));
+    hasher.update(format!("{:?}", limits));
+    hasher.finalize().into()
+}
+
+/// The ETag of the response to `query` when its block constraints are
+/// resolved to the blocks of `groups`. The selection sets contain the
+/// values of all arguments and variables, and together with the blocks
+/// and the limits the query is subject to, they determine the response
+/// completely, like they do for the results cache
+fn response_etag(
+    query: &crate::execution::Query,
+    groups: &[BlockGroup],
+    limits: impl fmt::Debug,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(query.schema.id().as_str());
+    hasher.update(query.shape_hash.to_be_bytes());
+    for group in groups {
+        hasher.update(group.block_ptr.hash_hex());
+        hasher.update(group.block_ptr.number.to_be_bytes());
+        hasher.update(format!("{:?}", group.error_policy));
+        hasher.update(format!("{:?}", group.selection_set));
+    }
+    hasher.update(format!("{:?}", limits));
+    format!("{:x}", hasher.finalize())
+}
+
+/// The timeout for a query whose client asked for `requested` when the
+/// configured timeout is `max`. Clients can shorten the timeout but not
+/// extend it
+fn query_timeout(requested: Option<Duration>, max: Option<Duration>) -> Option<Duration> {
+    match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    }
+}
+
+/// How many reorgs happened between reading `state` and `new_state`. The
+/// reorg count of a deployment never goes down, unless the store was
+/// tampered with, for example by restoring it from a backup
+fn reorgs_since(
+    state: &DeploymentState,
+    new_state: &DeploymentState,
+) -> Result<u32, QueryExecutionError> {
+    new_state
+        .reorg_count
+        .checked_sub(state.reorg_count)
+        .ok_or_else(|| {
+            QueryExecutionError::Internal(format!(
+                "the reorg count of deployment `{}` went down from {} to {} \
+                 while executing the query",
+                state.id, state.reorg_count, new_state.reorg_count
+            ))
+        })
+}
+
+/// How many blocks `reorgs` reorgs of at most `max_reorg_depth` blocks
+/// each can have reverted in total, or `None` if that number does not fit
+/// into a `BlockNumber`, in which case any block might have been reverted
+fn revert_depth(max_reorg_depth: u32, reorgs: u32) -> Option<BlockNumber> {
+    max_reorg_depth
+        .checked_mul(reorgs)
+        .and_then(|n_blocks| BlockNumber::try_from(n_blocks).ok())
+}
+
+/// Whether a query at `block` might have read data that a revert of
+/// `n_blocks` from the previous head `head` undid
+fn reorg_affects(block: u64, head: BlockNumber, n_blocks: Option<BlockNumber>) -> bool {
+    match n_blocks {
+        Some(n_blocks) => block.saturating_add(n_blocks as u64) > head as u64,
+        None => true,
+    }
+}
+
+/// Lower `max_first` and `max_skip` to `GRAPH_GRAPHQL_ABSOLUTE_MAX_FIRST`
+/// and `GRAPH_GRAPHQL_ABSOLUTE_MAX_SKIP`. The limits passed in have already
+/// been resolved from the defaults, per-deployment overrides and the limits
+/// the caller asked for, and the absolute maximums take precedence over
+/// all of them. Returns a warning for each limit that had to be lowered
+fn absolute_limits(max_first: u32, max_skip: u32) -> (u32, u32, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut clamp = |name: &str, limit: u32, absolute: u32| {
+        if limit > absolute {
+            warnings.push(format!(
+                "the limit of {} for `{}` is above the absolute maximum of {} \
+                 and was lowered to it",
+                limit, name, absolute
+            ));
+        }
+        limit.min(absolute)
+    };
+    let max_first = clamp("first", max_first, ENV_VARS.graphql.absolute_max_first);
+    let max_skip = clamp("skip", max_skip, ENV_VARS.graphql.absolute_max_skip);
+    (max_first, max_skip, warnings)
+}
+
+/// Whether a toplevel field of `selection_set` continues paginating after
+/// a cursor, which pins the query to the block of the first page
+fn paginates(selection_set: &a::SelectionSet) -> bool {
+    selection_set
+        .fields()
+        .any(|(_, mut fields)| fields.any(|field| matches!(Cursor::for_field(field), Ok(Some(_)))))
+}
+
+/// Check that a client that sent the hash of the deployment it built
+/// `query` against is still talking to that deployment. Clients that
+/// cache the schema learn this way that they need to fetch it again,
+/// rather than getting validation errors for fields that are gone
+fn check_schema_hash(query: &Query, schema: &ApiSchema) -> Result<(), QueryExecutionError> {
+    match &query.schema_hash {
+        Some(hash) if hash.as_str() != schema.id().as_str() => {
+            Err(QueryExecutionError::SchemaVersionMismatch {
+                expected: hash.clone(),
+                actual: schema.id().to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The name of the network that `query` runs against, given that the
+/// deployment indexes `actual`. A client that named the network it expects
+/// gets an error if the deployment indexes a different one
+fn query_network(query: &Query, actual: &str) -> Result<String, QueryExecutionError> {
+    match &query.network {
+        Some(expected) if expected != actual => Err(QueryExecutionError::NetworkMismatch {
+            expected: expected.clone(),
+            actual: actual.to_string(),
+        }),
+        _ => Ok(actual.to_string()),
+    }
+}
+
+/// The `QueryStore` and the state of its deployment that all queries of
+/// a batch for the same target use
+type Snapshot = (Arc<dyn QueryStore + Send + Sync>, DeploymentState);
+
+/// The block constraints of a query that read from the same block with
+/// the same error policy. Their selection sets are executed together so
+/// that they share one resolver and one pass through the store
+struct BlockGroup {
+    block_ptr: BlockPtr,
+    error_policy: ErrorPolicy,
+    selection_set: a::SelectionSet,
+    /// The index of each block constraint in the group among all the
+    /// query's block constraints, and the response keys of its fields
+    members: Vec<(usize, Vec<String>)>,
+}
+
+/// Resolve the block of each of `bcs` and group the ones that read from
+/// the same block. The groups are in the order in which their first block
+/// constraint appears in the query
+async fn group_by_block(
+    store: &dyn QueryStore,
+    state: &DeploymentState,
+    bcs: Vec<(BlockConstraint, (a::SelectionSet, ErrorPolicy))>,
+) -> Result<Vec<BlockGroup>, QueryExecutionError> {
+    let mut groups: Vec<BlockGroup> = Vec::new();
+    for (index, (bc, (selection_set, error_policy))) in bcs.into_iter().enumerate() {
+        let block_ptr = StoreResolver::locate_block(store, bc, state).await?;
+        let keys = selection_set
+            .fields()
+            .flat_map(|(_, fields)| fields.map(|field| field.response_key().to_string()))
+            .collect();
+        match groups
+            .iter_mut()
+            .find(|group| group.block_ptr == block_ptr && group.error_policy == error_policy)
+        {
+            Some(group) => {
+                for (_, fields) in selection_set.fields() {
+                    group.selection_set.push_fields(fields.collect())?;
+                }
+                group.members.push((index, keys));
+            }
+            None => groups.push(BlockGroup {
+                block_ptr,
+                error_policy,
+                selection_set,
+                members: vec![(index, keys)],
+            }),
+        }
+    }
+    Ok(groups)
+}
+
+/// How often the deployment state is checked while a query waits for a
+/// block
+const BLOCK_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Wait until the deployment has reached all the blocks that `bcs` ask for
+/// by number, or until `wait` has passed, and return the state of the
+/// deployment at that point. Constraints by hash do not make queries wait
+/// since we can't tell how far away the block with a given hash is
+async fn wait_for_block<'a>(
+    store: &dyn QueryStore,
+    mut state: DeploymentState,
+    bcs: impl Iterator<Item = &'a BlockConstraint>,
+    wait: Duration,
+) -> Result<DeploymentState, QueryExecutionError> {
+    let block = bcs
+        .filter_map(|bc| match bc {
+            BlockConstraint::Number(number) | BlockConstraint::Min(number) => Some(*number),
+            BlockConstraint::Hash(_)
+            | BlockConstraint::BlocksAgo(_)
+            | BlockConstraint::Snapshot(_)
+            | BlockConstraint::Latest => None,
+        })
+        .max();
+    let block = match block {
+        Some(block) => block,
+        None => return Ok(state),
+    };
+
+    let deadline = Instant::now() + wait;
+    while state.latest_block.number < block {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        tokio::time::sleep(BLOCK_WAIT_POLL_INTERVAL.min(deadline - now)).await;
+        state = store.deployment_state().await?;
+    }
+    Ok(state)
+}
+
+/// Describe the block `ptr` for clients as `{ hash, number }`
+fn block_value(ptr: &BlockPtr) -> r::Value {
+    // A block constraint by number does not give us the hash
+    // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+    let hash = if ptr.hash_as_h256() == web3::types::H256::zero() {
+        None
+    } else {
+        Some(ptr.hash_hex())
+    };
+    object! { hash: hash, number: ptr.number }
+}
+
+/// Operation names longer than this are reported as `other`
+const MAX_OPERATION_NAME_LEN: usize = 64;
+/// How many distinct operation names we report metrics for; names we see
+/// after that are reported as `other`
+const MAX_OPERATION_NAMES: usize = 256;
+const OTHER_OPERATION: &str = "other";
+/// How many distinct deployments we report query depths and block
+/// constraints for; queries for deployments we see after that are reported
+/// as `other`
+const MAX_LABELED_DEPLOYMENTS: usize = 256;
+const OTHER_DEPLOYMENT: &str = "other";
+/// How many API schemas the runner keeps around
+const API_SCHEMA_CACHE_SIZE: usize = 100;
+/// The `phase` labels for lookups of the deployment state: before a query
+/// is executed, to find the blocks it reads, and after, to check whether
+/// the deployment was reverted while it executed
+const BEFORE_EXECUTION: &str = "before_execution";
+const AFTER_EXECUTION: &str = "after_execution";
+
+struct GraphQlRunnerMetrics {
+    coalesced_queries: Box<Counter>,
+    rejected_queries: Box<CounterVec>,
+    rejected_queries_by_reason: Box<CounterVec>,
+    reverted_queries: Box<CounterVec>,
+    revert_depth: Box<Histogram>,
+    operation_execution_time: Box<HistogramVec>,
+    active_subscriptions: Box<GaugeVec>,
+    replica_failovers: Box<CounterVec>,
+    query_depth: Box<HistogramVec>,
+    block_constraints: Box<HistogramVec>,
+    queue_wait_time: Box<HistogramVec>,
+    api_schema_cache_hits: Box<Counter>,
+    api_schema_cache_misses: Box<Counter>,
+    prepared_query_cache_hits: Box<Counter>,
+    deployment_running_queries: Box<GaugeVec>,
+    deployment_queued_queries: Box<GaugeVec>,
+    deployment_state_time: Box<HistogramVec>,
+    /// The operation names we have used as labels so far
+    operation_names: Mutex<HashSet<String>>,
+    /// The deployments we have used as labels for `query_depth` and
+    /// `query_block_constraints` so far
+    labeled_deployments: Mutex<HashSet<DeploymentHash>>,
+}
+
+impl GraphQlRunnerMetrics {
+    fn new(registry: &dyn MetricsRegistry) -> Self {
+        let coalesced_queries = registry
+            .new_counter(
+                "query_coalesced_count",
+                "the number of queries that shared the result of an identical query in flight",
+            )
+            .expect("failed to create `query_coalesced_count` counter");
+        let rejected_queries = registry
+            .new_counter_vec(
+                "query_load_manager_rejected_count",
+                "the number of queries the load manager refused to run, by deployment and lane",
+                vec![String::from("deployment"), String::from("lane")],
+            )
+            .expect("failed to create `query_load_manager_rejected_count` counter");
+        let rejected_queries_by_reason = registry
+            .new_counter_vec(
+                "query_load_manager_rejected_by_reason_count",
+                "the number of queries the load manager refused to run, by reason and lane",
+                vec![String::from("reason"), String::from("lane")],
+            )
+            .expect("failed to create `query_load_manager_rejected_by_reason_count` counter");
+        let reverted_queries = registry
+            .new_counter_vec(
+                "query_deployment_reverted_count",
+                "the number of queries that failed because the deployment was reverted while \
+                 they were executing",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `query_deployment_reverted_count` counter");
+        let revert_depth = registry
+            .new_histogram(
+                "query_deployment_revert_depth",
+                "the number of blocks a deployment might have been reverted by while a query \
+                 was executing",
+                vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0],
+            )
+            .expect("failed to create `query_deployment_revert_depth` histogram");
+        let operation_execution_time = registry
+            .new_histogram_vec(
+                "query_operation_execution_time",
+                "Execution time of GraphQL queries by operation name",
+                vec![String::from("operation")],
+                vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 100.0],
+            )
+            .expect("failed to create `query_operation_execution_time` histogram");
+        let active_subscriptions = registry
+            .new_gauge_vec(
+                "query_active_subscriptions",
+                "the number of subscriptions that are currently active, by deployment",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `query_active_subscriptions` gauge");
+        let replica_failovers = registry
+            .new_counter_vec(
+                "query_replica_failover_count",
+                "the number of queries that were run again against the primary because their \
+                 replica was unavailable, by replica",
+                vec![String::from("replica")],
+            )
+            .expect("failed to create `query_replica_failover_count` counter");
+        let query_depth = registry
+            .new_histogram_vec(
+                "query_depth",
+                "the number of nested selection sets in the queries that are executed, by \
+                 deployment",
+                vec![String::from("deployment")],
+                vec![
+                    1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 12.0, 16.0, 24.0, 32.0, 64.0,
+                ],
+            )
+            .expect("failed to create `query_depth` histogram");
+        let block_constraints = registry
+            .new_histogram_vec(
+                "query_block_constraints",
+                "the number of different block constraints in the queries that are executed, \
+                 by deployment",
+                vec![String::from("deployment")],
+                vec![1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 16.0, 32.0],
+            )
+            .expect("failed to create `query_block_constraints` histogram");
+        let queue_wait_time = registry
+            .new_histogram_vec(
+                "query_queue_wait_time",
+                "the time in seconds that queries waited for their turn to run against their \
+                 deployment, by deployment",
+                vec![String::from("deployment")],
+                vec![0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0],
+            )
+            .expect("failed to create `query_queue_wait_time` histogram");
+        let api_schema_cache_hits = registry
+            .new_counter(
+                "query_api_schema_cache_hit_count",
+                "the number of queries whose API schema was found in the runner's schema cache",
+            )
+            .expect("failed to create `query_api_schema_cache_hit_count` counter");
+        let api_schema_cache_misses = registry
+            .new_counter(
+                "query_api_schema_cache_miss_count",
+                "the number of queries whose API schema had to be loaded from the store",
+            )
+            .expect("failed to create `query_api_schema_cache_miss_count` counter");
+        let prepared_query_cache_hits = registry
+            .new_counter(
+                "query_prepared_cache_hit_count",
+                "the number of queries that skipped validation because a query with the same \
+                 text had been validated before",
+            )
+            .expect("failed to create `query_prepared_cache_hit_count` counter");
+        let deployment_running_queries = registry
+            .new_gauge_vec(
+                "query_deployment_running",
+                "the number of queries that are currently being executed, by deployment",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `query_deployment_running` gauge");
+        let deployment_queued_queries = registry
+            .new_gauge_vec(
+                "query_deployment_queued",
+                "the number of queries that are waiting for other queries for their \
+                 deployment to finish, by deployment",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `query_deployment_queued` gauge");
+        let deployment_state_time = registry
+            .new_histogram_vec(
+                "query_deployment_state_lookup_time",
+                "the time in seconds it took to look up the state of the deployment of a query, \
+                 by whether that happened before or after the query was executed",
+                vec![String::from("phase")],
+                vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+            )
+            .expect("failed to create `query_deployment_state_lookup_time` histogram");
+
+        Self {
+            coalesced_queries,
+            rejected_queries,
+            rejected_queries_by_reason,
+            reverted_queries,
+            revert_depth,
+            operation_execution_time,
+            active_subscriptions,
+            replica_failovers,
+            query_depth,
+            block_constraints,
+            queue_wait_time,
+            api_schema_cache_hits,
+            api_schema_cache_misses,
+            prepared_query_cache_hits,
+            deployment_running_queries,
+            deployment_queued_queries,
+            deployment_state_time,
+            operation_names: Mutex::new(HashSet::new()),
+            labeled_deployments: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The label to use for the operation `name`. To keep the number of
+    /// labels bounded, anonymous operations, names that are unusually
+    /// long, and any names beyond the first `MAX_OPERATION_NAMES` we see
+    /// are all reported as `other`
+    fn operation_label<'a>(&self, name: Option<&'a str>) -> &'a str {
+        let name = match name {
+            Some(name)
+                if name.len() <= MAX_OPERATION_NAME_LEN
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                name
+            }
+            _ => return OTHER_OPERATION,
+        };
+        let mut names = self.operation_names.lock().unwrap();
+        if names.contains(name) {
+            name
+        } else if names.len() < MAX_OPERATION_NAMES {
+            names.insert(name.to_string());
+            name
+        } else {
+            OTHER_OPERATION
+        }
+    }
+
+    fn observe_rejection(
+        &self,
+        deployment: &DeploymentHash,
+        lane: Option<&str>,
+        decision: Decision,
+    ) {
+        let lane = ENV_VARS.load_lanes.label(lane);
+        self.rejected_queries
+            .with_label_values(&[deployment.as_str(), lane])
+            .inc();
+        self.rejected_queries_by_reason
+            .with_label_values(&[decision.as_str(), lane])
+            .inc();
+    }
+
+    fn observe_operation(&self, name: Option<&str>, duration: Duration) {
+        self.operation_execution_time
+            .with_label_values(&[self.operation_label(name)])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// The label to use for `deployment`. Only the first
+    /// `MAX_LABELED_DEPLOYMENTS` deployments get their own label
+    fn deployment_label<'a>(&self, deployment: &'a DeploymentHash) -> &'a str {
+        let mut deployments = self.labeled_deployments.lock().unwrap();
+        if deployments.contains(deployment) {
+            deployment.as_str()
+        } else if deployments.len() < MAX_LABELED_DEPLOYMENTS {
+            deployments.insert(deployment.clone());
+            deployment.as_str()
+        } else {
+            OTHER_DEPLOYMENT
+        }
+    }
+
+    /// Record the depth of a query for `deployment`
+    fn observe_depth(&self, deployment: &DeploymentHash, depth: u8) {
+        self.query_depth
+            .with_label_values(&[self.deployment_label(deployment)])
+            .observe(depth as f64);
+    }
+
+    /// Record how many different block constraints a query for
+    /// `deployment` has. Each of them is resolved and executed separately
+    fn observe_block_constraints(&self, deployment: &DeploymentHash, count: usize) {
+        self.block_constraints
+            .with_label_values(&[self.deployment_label(deployment)])
+            .observe(count as f64);
+    }
+
+    /// Record how long a query for `deployment` waited before it could
+    /// start executing
+    fn observe_queue_wait(&self, deployment: &DeploymentHash, wait: Duration) {
+        self.queue_wait_time
+            .with_label_values(&[self.deployment_label(deployment)])
+            .observe(wait.as_secs_f64());
+    }
+
+    /// Record how long a lookup of the deployment state took. `phase` is
+    /// one of `BEFORE_EXECUTION` and `AFTER_EXECUTION`
+    fn observe_deployment_state(&self, phase: &str, duration: Duration) {
+        self.deployment_state_time
+            .with_label_values(&[phase])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// The number of subscriptions that are currently active, per connection
+/// and per deployment
+#[derive(Default)]
+struct SubscriptionCounts {
+    by_connection: HashMap<String, usize>,
+    by_deployment: HashMap<DeploymentHash, usize>,
+}
+
+impl SubscriptionCounts {
+    /// Count a new subscription for `connection` and `deployment` unless
+    /// that would exceed the limits on the number of subscriptions
+    fn acquire(
+        &mut self,
+        connection: Option<&str>,
+        deployment: &DeploymentHash,
+    ) -> Result<(), SubscriptionError> {
+        if let (Some(connection), Some(max)) = (
+            connection,
+            ENV_VARS.graphql.max_subscriptions_per_connection,
+        ) {
+            if self.by_connection.get(connection).copied().unwrap_or(0) >= max {
+                return Err(SubscriptionError::TooManySubscriptions(format!(
+                    "a connection can have at most {} active subscriptions",
+                    max
+                )));
+            }
+        }
+        if let Some(max) = ENV_VARS.graphql.max_subscriptions_per_deployment {
+            if self.by_deployment.get(deployment).copied().unwrap_or(0) >= max {
+                return Err(SubscriptionError::TooManySubscriptions(format!(
+                    "deployment {} can have at most {} active subscriptions",
+                    deployment, max
+                )));
+            }
+        }
+
+        if let Some(connection) = connection {
+            *self
+                .by_connection
+                .entry(connection.to_string())
+                .or_default() += 1;
+        }
+        *self.by_deployment.entry(deployment.clone()).or_default() += 1;
+        Ok(())
+    }
+
+    fn release(&mut self, connection: Option<&str>, deployment: &DeploymentHash) {
+        fn decrement<
+            K: std::hash::Hash + Eq + std::borrow::Borrow<Q>,
+            Q: std::hash::Hash + Eq + ?Sized,
+        >(
+            counts: &mut HashMap<K, usize>,
+            key: &Q,
+        ) {
+            if let Some(count) = counts.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+
+        if let Some(connection) = connection {
+            decrement(&mut self.by_connection, connection);
+        }
+        decrement(&mut self.by_deployment, deployment);
+    }
+}
+
+/// Keeps a subscription counted as active until it is dropped
+struct ActiveSubscription {
+    counts: Arc<Mutex<SubscriptionCounts>>,
+    connection: Option<String>,
+    deployment: DeploymentHash,
+    gauge: Gauge,
+}
+
+impl Drop for ActiveSubscription {
+    fn drop(&mut self) {
+        self.counts
+            .lock()
+            .unwrap()
+            .release(self.connection.as_deref(), &self.deployment);
+        self.gauge.dec();
+    }
+}
+
+/// Lets a query run against its deployment while
+/// `GRAPH_GRAPHQL_MAX_CONCURRENT_QUERIES_PER_DEPLOYMENT` limits how many
+/// queries can do that at the same time. The query counts as running until
+/// this is dropped
+struct DeploymentPermit {
+    _permit: tokio::sync::OwnedSemaphorePermit,
+    gauge: Gauge,
+}
+
+impl Drop for DeploymentPermit {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Per-deployment overrides of the largest `first` and `skip` arguments
+/// that queries may use, and of their timeout. Deployments without an
+/// override use `GRAPH_GRAPHQL_MAX_FIRST`, `GRAPH_GRAPHQL_MAX_SKIP` and
+/// `GRAPH_GRAPHQL_QUERY_TIMEOUT`, and overrides can never go above
+/// `GRAPH_GRAPHQL_MAX_FIRST_CEILING`, `GRAPH_GRAPHQL_MAX_SKIP_CEILING` and
+/// `GRAPH_GRAPHQL_QUERY_TIMEOUT_CEILING`
+#[derive(Clone, Debug, Default)]
+pub struct QueryLimits {
+    overrides: HashMap<DeploymentHash, (Option<u32>, Option<u32>)>,
+    timeouts: HashMap<DeploymentHash, Duration>,
+}
+
+impl QueryLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override the limits for `deployment`; a `None` keeps the default
+    pub fn set(
+        &mut self,
+        deployment: DeploymentHash,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+    ) {
+        self.overrides.insert(deployment, (max_first, max_skip));
+    }
+
+    pub fn max_first(&self, deployment: &DeploymentHash) -> u32 {
+        self.overrides
+            .get(deployment)
+            .and_then(|(max_first, _)| *max_first)
+            .map(|max_first| max_first.min(ENV_VARS.graphql.max_first_ceiling))
+            .unwrap_or(ENV_VARS.graphql.max_first)
     }
 
-    pub fn observe(&self, size: usize) {
-        let size = size as f64;
-        self.histogram.observe(size);
-        if self.max_gauge.get() < size {
-            self.max_gauge.set(size);
+    pub fn max_skip(&self, deployment: &DeploymentHash) -> u32 {
+        self.overrides
+            .get(deployment)
+            .and_then(|(_, max_skip)| *max_skip)
+            .map(|max_skip| max_skip.min(ENV_VARS.graphql.max_skip_ceiling))
+            .unwrap_or(ENV_VARS.graphql.max_skip)
+    }
+
+    /// Override the timeout for queries against `deployment`
+    pub fn set_timeout(&mut self, deployment: DeploymentHash, timeout: Duration) {
+        self.timeouts.insert(deployment, timeout);
+    }
+
+    /// The timeout for queries against `deployment`, before clients
+    /// shorten it for individual queries
+    pub fn timeout(&self, deployment: &DeploymentHash) -> Option<Duration> {
+        match (
+            self.timeouts.get(deployment),
+            ENV_VARS.graphql.query_timeout_ceiling,
+        ) {
+            (Some(timeout), Some(ceiling)) => Some((*timeout).min(ceiling)),
+            (Some(timeout), None) => Some(*timeout),
+            (None, _) => ENV_VARS.graphql.query_timeout,
+        }
+    }
+}
+
+/// The query shapes that a runner serves when it should only answer a set
+/// of pre-approved queries. Queries are identified by their `shape_hash`
+#[derive(Clone, Debug, Default)]
+pub struct QueryAllowlist {
+    shapes: HashSet<u64>,
+    /// Whether to answer introspection queries regardless of their shape
+    introspection: bool,
+}
+
+impl QueryAllowlist {
+    pub fn new(shapes: impl IntoIterator<Item = u64>, introspection: bool) -> Self {
+        QueryAllowlist {
+            shapes: shapes.into_iter().collect(),
+            introspection,
+        }
+    }
+
+    fn check(&self, shape_hash: u64, introspection: bool) -> Result<(), QueryExecutionError> {
+        if (introspection && self.introspection) || self.shapes.contains(&shape_hash) {
+            Ok(())
+        } else {
+            Err(QueryExecutionError::QueryNotAllowed(shape_hash))
         }
     }
 }
 
+/// Decides which fields of a query a caller may see. The runner consults
+/// the authorizer with the resolved selection set of every query before
+/// executing it, and rejects the query with the errors the authorizer
+/// returns, usually `QueryExecutionError::FieldForbidden` for each field
+/// that the caller may not query
+pub trait FieldAuthorizer: Send + Sync + 'static {
+    fn authorize(
+        &self,
+        caller: Option<&str>,
+        selection_set: &a::SelectionSet,
+    ) -> Result<(), Vec<QueryExecutionError>>;
+}
+
+/// The default authorizer, which lets every caller query every field
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllowAllFields;
+
+impl FieldAuthorizer for AllowAllFields {
+    fn authorize(
+        &self,
+        _caller: Option<&str>,
+        _selection_set: &a::SelectionSet,
+    ) -> Result<(), Vec<QueryExecutionError>> {
+        Ok(())
+    }
+}
+
 /// GraphQL runner implementation for The Graph.
 pub struct GraphQlRunner<S, SM> {
     logger: Logger,
     store: Arc<S>,
     subscription_manager: Arc<SM>,
     load_manager: Arc<LoadManager>,
+    /// Chooses the replica for queries that do not need the primary
+    replica_selector: Arc<dyn ReplicaSelector>,
     result_size: Arc<ResultSizeMetrics>,
+    /// Automatic persisted queries, keyed by the hex-encoded SHA-256 hash
+    /// of their text
+    persisted_queries: Mutex<LruCache<String, q::Document>>,
+    /// Subscriptions registered with `register_subscription`, keyed by
+    /// their name
+    stored_subscriptions: Mutex<BTreeMap<String, q::Document>>,
+    /// Limit the number of queries that run for each deployment at the
+    /// same time, see `GRAPH_GRAPHQL_MAX_CONCURRENT_QUERIES_PER_DEPLOYMENT`
+    deployment_semaphores: Mutex<HashMap<DeploymentHash, Arc<tokio::sync::Semaphore>>>,
+    /// The API schemas of the deployments we recently ran queries for
+    api_schemas: Mutex<LruCache<(DeploymentHash, DeploymentId), Arc<ApiSchema>>>,
+    /// Query documents that passed validation, see
+    /// `GRAPH_GRAPHQL_PREPARED_QUERY_CACHE_SIZE`
+    prepared_queries: PreparedQueries,
+    /// Queries that are currently being executed
+    in_flight: QueryCache<Arc<Result<QueryResults, QueryResults>>>,
+    /// The results of successful queries at the latest block of their
+    /// deployment, see `GRAPH_QUERY_RESULT_CACHE_TTL`
+    result_cache: ResultCache,
+    /// The subscriptions that are currently active
+    subscriptions: Arc<Mutex<SubscriptionCounts>>,
+    /// The `first` and `skip` limits for queries that do not set their own
+    query_limits: QueryLimits,
+    /// If set, the only queries that the runner will execute
+    allowlist: Option<QueryAllowlist>,
+    /// Decides which fields callers may query
+    authorizer: Arc<dyn FieldAuthorizer>,
+    /// Whether arguments that a field or directive does not have are
+    /// ignored with a warning, see `GRAPH_GRAPHQL_IGNORE_UNKNOWN_ARGUMENTS`
+    ignore_unknown_arguments: bool,
+    metrics: GraphQlRunnerMetrics,
 }
 
 #[cfg(debug_assertions)]
@@ -92,59 +1091,467 @@ where
         store: Arc<S>,
         subscription_manager: Arc<SM>,
         load_manager: Arc<LoadManager>,
+        replica_selector: Arc<dyn ReplicaSelector>,
         registry: Arc<dyn MetricsRegistry>,
     ) -> Self {
         let logger = logger.new(o!("component" => "GraphQlRunner"));
-        let result_size = Arc::new(ResultSizeMetrics::new(registry));
+        let metrics = GraphQlRunnerMetrics::new(registry.as_ref());
+        let result_size = Arc::new(ResultSizeMetrics::new(&logger, registry));
+        let persisted_queries = Mutex::new(LruCache::with_capacity(
+            ENV_VARS.graphql.persisted_query_cache_size,
+        ));
         GraphQlRunner {
             logger,
             store,
             subscription_manager,
             load_manager,
+            replica_selector,
             result_size,
+            persisted_queries,
+            stored_subscriptions: Mutex::new(BTreeMap::new()),
+            deployment_semaphores: Mutex::new(HashMap::new()),
+            api_schemas: Mutex::new(LruCache::with_capacity(API_SCHEMA_CACHE_SIZE)),
+            prepared_queries: PreparedQueries::new(ENV_VARS.graphql.prepared_query_cache_size),
+            in_flight: QueryCache::new("query_in_flight"),
+            result_cache: ResultCache::new(ENV_VARS.graphql.query_result_cache_max_mem),
+            subscriptions: Arc::new(Mutex::new(SubscriptionCounts::default())),
+            query_limits: QueryLimits::new(),
+            allowlist: None,
+            authorizer: Arc::new(AllowAllFields),
+            ignore_unknown_arguments: ENV_VARS.graphql.ignore_unknown_arguments,
+            metrics,
+        }
+    }
+
+    /// Use `query_limits` for the `first` and `skip` limits of queries
+    pub fn with_query_limits(mut self, query_limits: QueryLimits) -> Self {
+        self.query_limits = query_limits;
+        self
+    }
+
+    /// Only execute queries whose shape is on `allowlist`; with `None`,
+    /// all queries are executed
+    pub fn with_query_allowlist(mut self, allowlist: Option<QueryAllowlist>) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Use `authorizer` to decide which fields callers may query
+    pub fn with_field_authorizer(mut self, authorizer: Arc<dyn FieldAuthorizer>) -> Self {
+        self.authorizer = authorizer;
+        self
+    }
+
+    /// Ignore arguments that a field or directive does not have with a
+    /// warning instead of failing validation
+    pub fn with_ignore_unknown_arguments(mut self, ignore_unknown_arguments: bool) -> Self {
+        self.ignore_unknown_arguments = ignore_unknown_arguments;
+        self
+    }
+
+    /// Check that the caller of `query` may query all the fields it
+    /// selects
+    fn check_authorized(
+        &self,
+        query: &crate::execution::Query,
+    ) -> Result<(), Vec<QueryExecutionError>> {
+        self.authorizer
+            .authorize(query.caller.as_deref(), &query.selection_set)
+    }
+
+    /// Check that `query` is on the allowlist, if there is one.
+    /// `introspection` indicates that `query` only asks for introspection
+    /// fields
+    fn check_allowed(
+        &self,
+        query: &crate::execution::Query,
+        introspection: bool,
+    ) -> Result<(), QueryExecutionError> {
+        match &self.allowlist {
+            Some(allowlist) => allowlist.check(query.shape_hash, introspection),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the `QueryStore` for `query`. Queries whose client asked for
+    /// the primary get it, all others go to the replica that the replica
+    /// selector chooses
+    async fn query_store_for(
+        &self,
+        query: &Query,
+        target: QueryTarget,
+    ) -> Result<Arc<dyn QueryStore + Send + Sync>, QueryExecutionError> {
+        if query.force_primary {
+            self.store.query_store(target, true).await
+        } else {
+            self.store
+                .select_query_store(
+                    target,
+                    query.shape_hash,
+                    self.replica_selector.cheap_clone(),
+                )
+                .await
+        }
+    }
+
+    /// Handle automatic persisted queries. If the client sent the query
+    /// text together with its hash, check that the hash matches and
+    /// remember the query. If the client only sent the hash, look up the
+    /// query we remembered for it
+    fn resolve_persisted_query(&self, query: Query) -> Result<Query, QueryExecutionError> {
+        let persisted = match &query.persisted_query {
+            None => return Ok(query),
+            Some(persisted) => persisted,
+        };
+        let sha256_hash = persisted.sha256_hash.to_lowercase();
+
+        match &persisted.text {
+            Some(text) => {
+                let actual = format!("{:x}", Sha256::digest(text.as_bytes()));
+                if actual != sha256_hash {
+                    return Err(QueryExecutionError::PersistedQueryHashMismatch(
+                        persisted.sha256_hash.clone(),
+                    ));
+                }
+                self.persisted_queries
+                    .lock()
+                    .unwrap()
+                    .insert(sha256_hash, query.document.clone());
+                Ok(query)
+            }
+            None => {
+                let document = self
+                    .persisted_queries
+                    .lock()
+                    .unwrap()
+                    .get(&sha256_hash)
+                    .cloned()
+                    .ok_or_else(|| {
+                        QueryExecutionError::PersistedQueryNotFound(persisted.sha256_hash.clone())
+                    })?;
+                Ok(query.with_document(document))
+            }
+        }
+    }
+
+    /// Get the API schema of the deployment that `store` reads, preferably
+    /// from our cache. Schemas are cached under the version that the store
+    /// reports, so that a redeployed subgraph or a switch to another copy
+    /// of it loads its schema afresh
+    fn api_schema(&self, store: &dyn QueryStore) -> Result<Arc<ApiSchema>, QueryExecutionError> {
+        let version = store.api_schema_version();
+        if let Some(schema) = self.api_schemas.lock().unwrap().get(&version) {
+            self.metrics.api_schema_cache_hits.inc();
+            return Ok(schema.cheap_clone());
         }
+        self.metrics.api_schema_cache_misses.inc();
+        // Load the schema without holding the lock; if several queries
+        // miss at the same time, they all store the same schema
+        let schema = store.api_schema()?;
+        self.api_schemas
+            .lock()
+            .unwrap()
+            .insert(version, schema.cheap_clone());
+        Ok(schema)
+    }
+
+    /// Get permission to run a query against `deployment` if the number of
+    /// queries that can run for one deployment at the same time is
+    /// limited. When all permits are taken, the query waits for one for up
+    /// to `GRAPH_GRAPHQL_DEPLOYMENT_QUEUE_TIMEOUT`, and is rejected if it
+    /// does not get one in time
+    async fn deployment_permit(
+        &self,
+        deployment: &DeploymentHash,
+    ) -> Result<Option<DeploymentPermit>, QueryExecutionError> {
+        let max = match ENV_VARS.graphql.max_concurrent_queries_per_deployment {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        let semaphore = self
+            .deployment_semaphores
+            .lock()
+            .unwrap()
+            .entry(deployment.clone())
+            .or_insert_with(|| Arc::new(tokio::sync::Semaphore::new(max)))
+            .cheap_clone();
+        let busy = || QueryExecutionError::DeploymentBusy(deployment.to_string());
+
+        let permit = match semaphore.cheap_clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let queue_timeout = ENV_VARS.graphql.deployment_queue_timeout;
+                if queue_timeout.is_zero() {
+                    return Err(busy());
+                }
+                let queued = self
+                    .metrics
+                    .deployment_queued_queries
+                    .with_label_values(&[deployment.as_str()]);
+                queued.inc();
+                // The query might be dropped while it waits
+                let _queued = defer::defer(|| queued.dec());
+                match tokio::time::timeout(queue_timeout, semaphore.acquire_owned()).await {
+                    Ok(Ok(permit)) => permit,
+                    Ok(Err(_)) | Err(_) => return Err(busy()),
+                }
+            }
+        };
+        let gauge = self
+            .metrics
+            .deployment_running_queries
+            .with_label_values(&[deployment.as_str()]);
+        gauge.inc();
+        Ok(Some(DeploymentPermit {
+            _permit: permit,
+            gauge,
+        }))
+    }
+
+    /// Use the document registered under the name of `subscription`, if it
+    /// has one
+    fn resolve_stored_subscription(
+        &self,
+        mut subscription: Subscription,
+    ) -> Result<Subscription, QueryExecutionError> {
+        let name = match &subscription.name {
+            None => return Ok(subscription),
+            Some(name) => name,
+        };
+        let document = self
+            .stored_subscriptions
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| QueryExecutionError::StoredSubscriptionNotFound(name.clone()))?;
+        subscription.query = subscription.query.with_document(document);
+        Ok(subscription)
+    }
+
+    /// Look up the current state of the deployment of `store` and record
+    /// how long that took for `phase`
+    async fn deployment_state(
+        &self,
+        store: &dyn QueryStore,
+        phase: &str,
+    ) -> Result<DeploymentState, QueryExecutionError> {
+        let start = Instant::now();
+        let state = store.deployment_state().await;
+        self.metrics
+            .observe_deployment_state(phase, start.elapsed());
+        state
     }
 
     /// Check if the subgraph state differs from `state` now in a way that
     /// would affect a query that looked at data as fresh as `latest_block`.
     /// If the subgraph did change, return the `Err` that should be sent back
-    /// to clients to indicate that condition
+    /// to clients to indicate that condition, unless the query tolerates
+    /// reverts, in which case we only add a warning to `result`
     async fn deployment_changed(
         &self,
         store: &dyn QueryStore,
         state: DeploymentState,
         latest_block: u64,
+        tolerance: ReorgTolerance,
+        pinned: Option<BlockNumber>,
+        result: &mut QueryResults,
     ) -> Result<(), QueryExecutionError> {
         if ENV_VARS.graphql.allow_deployment_change {
             return Ok(());
         }
-        let new_state = store.deployment_state().await?;
-        assert!(new_state.reorg_count >= state.reorg_count);
-        if new_state.reorg_count > state.reorg_count {
+        let new_state = self.deployment_state(store, AFTER_EXECUTION).await?;
+        debug_assert!(new_state.reorg_count >= state.reorg_count);
+        let reorgs = match reorgs_since(&state, &new_state) {
+            Ok(reorgs) => reorgs,
+            Err(e) => {
+                error!(self.logger, "The reorg count of a deployment went backwards";
+                       "deployment" => state.id.as_str(),
+                       "reorg_count" => state.reorg_count,
+                       "new_reorg_count" => new_state.reorg_count,
+                       "latest_block" => state.latest_block.number,
+                       "new_latest_block" => new_state.latest_block.number);
+                return Err(e);
+            }
+        };
+        if reorgs > 0 {
             // One or more reorgs happened; each reorg can't have gone back
             // farther than `max_reorg_depth`, so that querying at blocks
             // far enough away from the previous latest block is fine. Taking
             // this into consideration is important, since most of the time
             // there is only one reorg of one block, and we therefore avoid
             // flagging a lot of queries a bit behind the head
-            let n_blocks = new_state.max_reorg_depth * (new_state.reorg_count - state.reorg_count);
-            if latest_block + n_blocks as u64 > state.latest_block.number as u64 {
+            // If the number of blocks overflows, we can't rule out that
+            // any block was reverted
+            let n_blocks = revert_depth(new_state.max_reorg_depth, reorgs);
+            self.metrics
+                .revert_depth
+                .observe(n_blocks.unwrap_or(BlockNumber::MAX) as f64);
+            // Cached results for blocks that might have been reverted are
+            // stale now
+            self.result_cache.reverted(
+                &new_state,
+                n_blocks
+                    .map(|n_blocks| state.latest_block.number.saturating_sub(n_blocks))
+                    .unwrap_or(0),
+            );
+            let affected = match tolerance {
+                ReorgTolerance::Strict => true,
+                ReorgTolerance::Default | ReorgTolerance::Tolerant => {
+                    reorg_affects(latest_block, state.latest_block.number, n_blocks)
+                }
+            };
+            if affected {
+                // Pages of a collection are only consistent with each other
+                // if they are all read at the same block, and the client
+                // needs to start from the first page again
+                if let Some(block) = pinned {
+                    self.metrics
+                        .reverted_queries
+                        .with_label_values(&[state.id.as_str()])
+                        .inc();
+                    return Err(QueryExecutionError::PaginationBlockReverted(block));
+                }
+                if let (ReorgTolerance::Tolerant, Some(n_blocks)) = (tolerance, n_blocks) {
+                    result.add_warning(format!(
+                        "the chain was reorganized by up to {} blocks while executing the \
+                         query; the result might contain data from reverted blocks",
+                        n_blocks
+                    ));
+                    return Ok(());
+                }
+                self.metrics
+                    .reverted_queries
+                    .with_label_values(&[state.id.as_str()])
+                    .inc();
                 return Err(QueryExecutionError::DeploymentReverted);
             }
         }
         Ok(())
     }
 
-    async fn execute(
+    /// Describe how far the deployment has synced for clients that asked
+    /// for it with the `syncStatus` flag
+    async fn sync_status(
         &self,
-        query: Query,
-        target: QueryTarget,
-        max_complexity: Option<u64>,
-        max_depth: Option<u8>,
-        max_first: Option<u32>,
-        max_skip: Option<u32>,
-        result_size: Arc<ResultSizeMetrics>,
+        store: &dyn QueryStore,
+        state: &DeploymentState,
+    ) -> Result<r::Value, QueryExecutionError> {
+        let chain_head = store.chain_head_ptr().await?;
+        let has_indexing_errors = store.has_non_fatal_errors(None).await?;
+        Ok(object! {
+            hash: state.latest_block.hash_hex(),
+            number: state.latest_block.number,
+            chainHead: chain_head.as_ref().map(block_value),
+            hasIndexingErrors: has_indexing_errors,
+        })
+    }
+
+    /// Ask the `LoadManager` whether `query` should be run. If it should
+    /// not be run, log why, count it, and remember it in the `LoadManager`
+    fn decide(
+        &self,
+        store: &dyn QueryStore,
+        query: &crate::execution::Query,
+        span: &QuerySpan,
+    ) -> Result<(), QueryExecutionError> {
+        let decision = self.load_manager.decide(
+            &store.wait_stats().map_err(QueryExecutionError::from)?,
+            query.shape_hash,
+            query.query_text.as_ref(),
+            query.lane.as_deref(),
+        );
+        span.add_event("load_manager.decision", || {
+            let mut attributes = vec![KeyValue::new("decision", decision.as_str())];
+            if let Decision::Shed {
+                effort: Some(effort),
+                ..
+            } = decision
+            {
+                attributes.push(KeyValue::new("effort", effort));
+            }
+            attributes
+        });
+        if let Decision::Shed {
+            reason,
+            effort,
+            threshold,
+            ..
+        } = decision
+        {
+            debug!(query.logger, "Query was shed by the load manager";
+                "reason" => reason.as_str(),
+                "shape_hash" => query.shape_hash,
+                "lane" => ENV_VARS.load_lanes.label(query.lane.as_deref()),
+                "effort" => effort.map(|effort| format!("{:.4}", effort)),
+                "threshold" => threshold.map(|threshold| format!("{:.4}", threshold)),
+            );
+            self.metrics
+                .observe_rejection(query.schema.id(), query.lane.as_deref(), decision);
+            self.load_manager
+                .record_shed(query.shape_hash, query.lane.as_deref(), decision);
+        }
+        decision.to_result()
+    }
+
+    /// Execute `query` against `target`. Queries in a batch pass the
+    /// `snapshot` of the store and deployment state that they share
+    async fn execute(
+        self: &Arc<Self>,
+        mut query: Query,
+        target: QueryTarget,
+        snapshot: Option<Snapshot>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+        result_size: Arc<ResultSizeMetrics>,
     ) -> Result<QueryResults, QueryResults> {
+        // Clients can quote the id of their request when they report a
+        // problem, and it ties the response to the log lines for the
+        // query. A query that shares the result of an identical query in
+        // flight is logged under the id of that query
+        let request_id = ENV_VARS
+            .graphql
+            .request_ids
+            .then(|| Uuid::new_v4().to_string());
+        query.request_id = request_id.clone();
+
+        let mut result = self
+            .execute_with_failover(
+                query,
+                target,
+                snapshot,
+                max_complexity,
+                max_depth,
+                max_first,
+                max_skip,
+                result_size,
+            )
+            .await;
+        if let Some(request_id) = request_id {
+            let (Ok(results) | Err(results)) = &mut result;
+            results.set_extension("requestId", r::Value::String(request_id));
+        }
+        result
+    }
+
+    /// Execute `query` and, if the replica it was sent to turns out to be
+    /// unavailable, run it again against the primary. With a `snapshot`,
+    /// the query runs against its store and deployment state first
+    async fn execute_with_failover(
+        self: &Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+        snapshot: Option<Snapshot>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+        result_size: Arc<ResultSizeMetrics>,
+    ) -> Result<QueryResults, QueryResults> {
+        let query = self.resolve_persisted_query(query)?;
+
         // We need to use the same `QueryStore` for the entire query to ensure
         // we have a consistent view if the world, even when replicas, which
         // are eventually consistent, are in use. If we run different parts
@@ -153,11 +1560,299 @@ where
         // mix data from different block heights even if no reverts happen
         // while the query is running. `self.store` can not be used after this
         // point, and everything needs to go through the `store` we are
-        // setting up here
-        let store = self.store.query_store(target, false).await?;
-        let state = store.deployment_state().await?;
-        let network = Some(store.network_name().to_string());
-        let schema = store.api_schema()?;
+        // setting up here. Clients that need to see their own writes can
+        // ask for the primary, at the cost of putting more load on it
+        let (store, state) = match snapshot {
+            Some((store, state)) => (store, Some(state)),
+            None => (self.query_store_for(&query, target.clone()).await?, None),
+        };
+        let replica = store.replica_id();
+
+        // When the replica turns out to be unavailable, the query is run
+        // again from the start against the primary, which keeps the whole
+        // query on one store. We only need a copy of the query for that
+        // when it was sent to a replica
+        let failover = (ENV_VARS.graphql.replica_failover_retries > 0
+            && replica != ReplicaId::Main)
+            .then(|| query.clone());
+
+        let mut result = self
+            .execute_with_store(
+                query,
+                store,
+                state,
+                max_complexity,
+                max_depth,
+                max_first,
+                max_skip,
+                result_size.cheap_clone(),
+            )
+            .await;
+
+        if let Some(query) = failover {
+            for _ in 0..ENV_VARS.graphql.replica_failover_retries {
+                let unavailable = match &result {
+                    Ok(results) | Err(results) => results.database_unavailable(),
+                };
+                if !unavailable {
+                    break;
+                }
+                warn!(self.logger, "Database replica is unavailable, running query against the primary";
+                    "replica" => replica.to_string(),
+                    "shape_hash" => query.shape_hash);
+                self.metrics
+                    .replica_failovers
+                    .with_label_values(&[replica.to_string().as_str()])
+                    .inc();
+                let store = self.store.query_store(target.clone(), true).await?;
+                result = self
+                    .execute_with_store(
+                        query.clone(),
+                        store,
+                        None,
+                        max_complexity,
+                        max_depth,
+                        max_first,
+                        max_skip,
+                        result_size.cheap_clone(),
+                    )
+                    .await;
+            }
+        }
+        result
+    }
+
+    /// Execute `query` against `store` and nothing else. Queries that are
+    /// passed the `state` of the deployment execute at that state and are
+    /// never coalesced with other queries or answered from the cache
+    async fn execute_with_store(
+        self: &Arc<Self>,
+        query: Query,
+        store: Arc<dyn QueryStore + Send + Sync>,
+        state: Option<DeploymentState>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+        result_size: Arc<ResultSizeMetrics>,
+    ) -> Result<QueryResults, QueryResults> {
+        // Introspection queries only need the schema; answering them does
+        // not depend on the state of the deployment or on how busy the
+        // store is, so that tooling keeps working under load
+        if is_introspection_query(&query.document) {
+            return self
+                .introspect(query, store, max_complexity, max_depth)
+                .await;
+        }
+
+        // Results from the result cache or from an identical query that is
+        // executing must not hide from a client with a stale schema that
+        // it needs to fetch the schema again, or from a client that expects
+        // a different network that it is talking to the wrong deployment
+        check_schema_hash(&query, &*self.api_schema(store.as_ref())?)?;
+        query_network(&query, store.network_name())?;
+
+        let pinned = state.is_some();
+        let state = match state {
+            Some(state) => state,
+            None => {
+                let state = self
+                    .deployment_state(store.as_ref(), BEFORE_EXECUTION)
+                    .await?;
+
+                // Test only, see c435c25decbc4ad7bbbadf8e0ced0ff2
+                #[cfg(debug_assertions)]
+                let state = INITIAL_DEPLOYMENT_STATE_FOR_TESTS
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .unwrap_or(state);
+                state
+            }
+        };
+
+        // The trace of a query should reflect its own execution, and
+        // therefore traced queries are never coalesced with others. Queries
+        // that wait for blocks are neither coalesced nor cached, since the
+        // state they execute at is only known once they are done waiting
+        let waits_for_block = query.wait_for_block.map_or(false, |wait| {
+            !wait.min(ENV_VARS.graphql.max_block_wait).is_zero()
+        });
+        if pinned || query.trace || waits_for_block {
+            return self
+                .execute_at_state(
+                    query,
+                    store,
+                    state,
+                    max_complexity,
+                    max_depth,
+                    max_first,
+                    max_skip,
+                    result_size,
+                )
+                .await;
+        }
+
+        // Identical queries that arrive while this one is executing share
+        // its result. Only the first query of such a group goes through
+        // the `LoadManager`
+        let key = coalesce_key(
+            &query,
+            &state,
+            (max_complexity, max_depth, max_first, max_skip),
+        );
+
+        // Repeated queries at the same block can be answered from the
+        // result cache. Queries can set their own TTL with `@cached`,
+        // which takes precedence over `GRAPH_QUERY_RESULT_CACHE_TTL`.
+        // Queries that report the sync status are not cached since the
+        // chain head can move while the deployment stays put, and neither
+        // are queries whose page size depends on how fast they run
+        let cache_ttl = cached_directive_ttl(&query)
+            .or_else(|| {
+                ENV_VARS
+                    .graphql
+                    .query_result_cache_ttl
+                    .ttl(state.id.as_str())
+            })
+            .filter(|_| !query.sync_status && !query.adaptive_first);
+        // Queries that the cache applies to tell clients in `_meta` whether
+        // their result came from the cache, and how old it is
+        if cache_ttl.is_some() {
+            if let Some((mut result, age)) = self.result_cache.get(&state, &key) {
+                result_size.observe_cache_hit();
+                result.set_meta("cached", r::Value::Boolean(true));
+                result.set_meta("cacheAge", r::Value::Int(age.as_millis() as i64));
+                return Ok(result);
+            }
+            result_size.observe_cache_miss();
+        }
+
+        let runner = self.cheap_clone();
+        let execute = async move {
+            let cache_state = cache_ttl.map(|_| state.clone());
+            let mut result = runner
+                .execute_at_state(
+                    query,
+                    store,
+                    state,
+                    max_complexity,
+                    max_depth,
+                    max_first,
+                    max_skip,
+                    result_size,
+                )
+                .await;
+            if let (Some(ttl), Some(state), Ok(result)) = (cache_ttl, cache_state, &mut result) {
+                // Whether a response came from the cache is part of the
+                // response, and hits and misses therefore can not share
+                // an ETag
+                if !result.is_not_modified() {
+                    result.clear_etag();
+                    if !result.has_errors() {
+                        runner.result_cache.insert(&state, key, result.clone(), ttl);
+                    }
+                    result.set_meta("cached", r::Value::Boolean(false));
+                }
+            }
+            Arc::new(result)
+        };
+        let (result, coalesced) = self
+            .in_flight
+            .cached_query(key, execute, &self.logger)
+            .await;
+        if coalesced {
+            self.metrics.coalesced_queries.inc();
+        }
+        result.as_ref().clone()
+    }
+
+    /// Execute a batch of queries. Queries for the same target share one
+    /// `QueryStore` and one snapshot of the deployment state so that they
+    /// all see the same data. Queries in a batch are never coalesced with
+    /// other queries, and the result for each query is reported separately
+    async fn execute_batch(
+        self: &Arc<Self>,
+        queries: Vec<(Query, QueryTarget)>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+    ) -> Vec<QueryResults> {
+        let mut results: Vec<Option<QueryResults>> = vec![None; queries.len()];
+        let mut by_target: Vec<(QueryTarget, Vec<(usize, Query)>)> = Vec::new();
+        for (index, (query, target)) in queries.into_iter().enumerate() {
+            let query = match self.resolve_persisted_query(query) {
+                Ok(query) => query,
+                Err(e) => {
+                    results[index] = Some(QueryResults::from(e));
+                    continue;
+                }
+            };
+            match by_target.iter_mut().find(|(other, _)| other == &target) {
+                Some((_, queries)) => queries.push((index, query)),
+                None => by_target.push((target, vec![(index, query)])),
+            }
+        }
+
+        let executed =
+            futures03::future::join_all(by_target.into_iter().map(|(target, queries)| {
+                self.execute_batch_for_target(
+                    target,
+                    queries,
+                    max_complexity,
+                    max_depth,
+                    max_first,
+                    max_skip,
+                )
+            }))
+            .await;
+        for (index, result) in executed.into_iter().flatten() {
+            results[index] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every query in a batch has a result"))
+            .collect()
+    }
+
+    /// Execute the `queries` of a batch that all go to `target`. The
+    /// `usize` is the position of each query in the batch
+    async fn execute_batch_for_target(
+        self: &Arc<Self>,
+        target: QueryTarget,
+        queries: Vec<(usize, Query)>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+    ) -> Vec<(usize, QueryResults)> {
+        // If any query in the batch needs to see its own writes, all of
+        // them go to the primary, since they have to use the same store
+        let first = queries
+            .iter()
+            .map(|(_, query)| query)
+            .find(|query| query.force_primary)
+            .or_else(|| queries.first().map(|(_, query)| query))
+            .expect("batches for a target are not empty");
+        let store_and_state = async {
+            let store = self.query_store_for(first, target.clone()).await?;
+            let state = self
+                .deployment_state(store.as_ref(), BEFORE_EXECUTION)
+                .await?;
+            Ok::<_, QueryExecutionError>((store, state))
+        }
+        .await;
+        let (store, state) = match store_and_state {
+            Ok(store_and_state) => store_and_state,
+            Err(e) => {
+                let result = QueryResults::from(e);
+                return queries
+                    .into_iter()
+                    .map(|(index, _)| (index, result.clone()))
+                    .collect();
+            }
+        };
 
         // Test only, see c435c25decbc4ad7bbbadf8e0ced0ff2
         #[cfg(debug_assertions)]
@@ -167,6 +1862,40 @@ where
             .clone()
             .unwrap_or(state);
 
+        let runs = queries.into_iter().map(|(index, query)| {
+            let target = target.clone();
+            let snapshot = (store.cheap_clone(), state.clone());
+            async move {
+                let result = self
+                    .execute(
+                        query,
+                        target,
+                        Some(snapshot),
+                        max_complexity,
+                        max_depth,
+                        max_first,
+                        max_skip,
+                        self.result_size.cheap_clone(),
+                    )
+                    .await;
+                (index, result.unwrap_or_else(|e| e))
+            }
+        });
+        futures03::future::join_all(runs).await
+    }
+
+    /// Answer a query that only asks for introspection fields from the
+    /// schema of the deployment, without going through the `LoadManager`
+    async fn introspect(
+        &self,
+        query: Query,
+        store: Arc<dyn QueryStore + Send + Sync>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+    ) -> Result<QueryResults, QueryResults> {
+        let network = Some(query_network(&query, store.network_name())?);
+        let schema = self.api_schema(store.as_ref())?;
+        let timeout = query_timeout(query.timeout, ENV_VARS.graphql.query_timeout);
         let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
         let query = crate::execution::Query::new(
             &self.logger,
@@ -176,52 +1905,629 @@ where
             max_complexity,
             max_depth,
         )?;
-        self.load_manager
-            .decide(
-                &store.wait_stats().map_err(QueryExecutionError::from)?,
-                query.shape_hash,
-                query.query_text.as_ref(),
-            )
-            .to_result()?;
+        self.check_allowed(&query, true)?;
+        self.check_authorized(&query)?;
+        let result =
+            graph::spawn_blocking_allow_panic(move || execute_introspection_query(query, timeout))
+                .await
+                .map_err(|e| QueryExecutionError::Panic(e.to_string()))?;
+        Ok(QueryResults::from(result))
+    }
+
+    /// Execute `query` against `store`, where `state` is the state of the
+    /// deployment before we started executing the query
+    async fn execute_at_state(
+        &self,
+        query: Query,
+        store: Arc<dyn QueryStore + Send + Sync>,
+        state: DeploymentState,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+        result_size: Arc<ResultSizeMetrics>,
+    ) -> Result<QueryResults, QueryResults> {
+        let span = QuerySpan::sample("graphql.execute");
+        span.set_attributes(|| {
+            vec![
+                KeyValue::new("deployment", state.id.to_string()),
+                KeyValue::new("shape_hash", query.shape_hash.to_string()),
+            ]
+        });
+        let network = Some(query_network(&query, store.network_name())?);
+        let schema = self.api_schema(store.as_ref())?;
+        check_schema_hash(&query, &schema)?;
+
+        let tracer = query.trace.then(|| Arc::new(Tracer::new()));
+        if let Some(tracer) = &tracer {
+            tracer.replica(store.replica_id());
+        }
+        let reorg_tolerance = query.reorg_tolerance;
+        let sync_status = query.sync_status;
+        let partial_results = query.partial_results;
+        let report_result_size = query.result_size;
+        let if_none_match = query.if_none_match.clone();
+        let timeout = query_timeout(query.timeout, self.query_limits.timeout(&state.id));
+        let block_wait = query
+            .wait_for_block
+            .map(|wait| wait.min(ENV_VARS.graphql.max_block_wait))
+            .filter(|wait| !wait.is_zero());
+        let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
+        let query = crate::execution::Query::prepare(
+            &self.logger,
+            schema,
+            network,
+            query,
+            max_complexity,
+            max_depth,
+            Some(&self.prepared_queries),
+            self.ignore_unknown_arguments,
+        )?;
+        if query.prepared {
+            self.metrics.prepared_query_cache_hits.inc();
+        }
+        self.metrics.observe_depth(query.schema.id(), query.depth);
+        self.check_allowed(&query, false)?;
+        self.check_authorized(&query)?;
+        self.decide(store.as_ref(), &query, &span)?;
         let by_block_constraint = query.block_constraint()?;
+        self.metrics
+            .observe_block_constraints(query.schema.id(), by_block_constraint.len());
+        // Each block constraint is resolved and executed separately
+        if by_block_constraint.len() > ENV_VARS.graphql.max_block_constraints {
+            return Err(QueryExecutionError::TooManyBlockConstraints(
+                ENV_VARS.graphql.max_block_constraints,
+            )
+            .into());
+        }
+        // Queries that are willing to wait for blocks that have not been
+        // indexed yet do that here, but never beyond their timeout. If the
+        // blocks do not show up in time, resolving the block constraints
+        // fails the same way it does for queries that do not wait
+        let state = match block_wait {
+            Some(wait) => {
+                let wait = timeout.map_or(wait, |timeout| wait.min(timeout));
+                let bcs = by_block_constraint.iter().map(|(bc, _)| bc);
+                wait_for_block(store.as_ref(), state, bcs, wait).await?
+            }
+            None => state,
+        };
+        // Block constraints that resolve to the same block, e.g., no
+        // constraint and a constraint on the hash of the latest block, are
+        // executed together and their results split up again afterwards
+        let groups = group_by_block(store.as_ref(), &state, by_block_constraint).await?;
+        let (max_first, max_skip, limit_warnings) = absolute_limits(
+            max_first.unwrap_or_else(|| self.query_limits.max_first(&state.id)),
+            max_skip.unwrap_or_else(|| self.query_limits.max_skip(&state.id)),
+        );
+        // The blocks determine the response unless it reports how far the
+        // deployment has synced or how long executing the query took, its
+        // page sizes depend on how fast it runs, or it is willing to
+        // contain data from reverted blocks. Clients that already have the
+        // response do not need to execute the query again
+        let etag = (!sync_status
+            && tracer.is_none()
+            && !query.adaptive_first
+            && !matches!(reorg_tolerance, ReorgTolerance::Tolerant))
+        .then(|| {
+            response_etag(
+                &query,
+                &groups,
+                (max_first, max_skip, partial_results, report_result_size),
+            )
+        });
+        if let Some(etag) = etag.as_ref().filter(|etag| if_none_match.contains(etag)) {
+            return Ok(QueryResults::not_modified(etag.clone()));
+        }
         let mut max_block = 0;
+        let mut blocks = BTreeMap::new();
+        let mut results = BTreeMap::new();
+        let mut cursors = BTreeMap::new();
+        let mut size = 0;
+        // The block that pages after a cursor were read at
+        let mut pinned = None;
         let mut result: QueryResults = QueryResults::empty();
 
+        // Heavy queries for one deployment should not starve the queries
+        // for other deployments that share the store. The time spent
+        // waiting is recorded even if the number of queries is not
+        // limited, so that it can be compared with the limit in place
+        let wait_start = Instant::now();
+        let permit = self.deployment_permit(&state.id).await;
+        self.metrics
+            .observe_queue_wait(&state.id, wait_start.elapsed());
+        let permit = permit?;
+
         // Note: This will always iterate at least once.
-        for (bc, (selection_set, error_policy)) in by_block_constraint {
-            let resolver = StoreResolver::at_block(
+        let loop_start = Instant::now();
+        // Queries that accept partial results have one deadline for all
+        // block constraints; once it has passed, we stop executing them
+        let query_deadline = timeout.map(|t| loop_start + t);
+        let mut partial = false;
+        let mut out_of_time = false;
+        for group in groups {
+            if partial_results && query_deadline.map_or(false, |deadline| deadline < Instant::now())
+            {
+                out_of_time = true;
+                break;
+            }
+            let start = if partial_results {
+                loop_start
+            } else {
+                Instant::now()
+            };
+            let deadline = timeout.map(|t| start + t);
+            let BlockGroup {
+                block_ptr,
+                error_policy,
+                selection_set,
+                members,
+            } = group;
+            let at_block_span = span.child("graphql.at_block");
+            at_block_span
+                .set_attributes(|| vec![KeyValue::new("block_number", block_ptr.number as i64)]);
+            let resolver = StoreResolver::at_block_ptr(
                 &self.logger,
                 store.cheap_clone(),
-                &state,
                 self.subscription_manager.cheap_clone(),
-                bc,
+                block_ptr,
                 error_policy,
                 query.schema.id().clone(),
                 result_size.cheap_clone(),
             )
             .await?;
-            max_block = max_block.max(resolver.block_number());
+            at_block_span.end();
+            let block_number = resolver.block_number();
+            max_block = max_block.max(block_number);
+            if paginates(&selection_set) {
+                pinned = Some(block_number);
+            }
+            for (index, _) in &members {
+                blocks.insert(*index, resolver.block_ptr.as_ref().map(block_value));
+            }
+            query.log_field_access(&selection_set, block_number);
+            let execute_span = span.child("graphql.execute_query");
+            execute_span
+                .set_attributes(|| vec![KeyValue::new("block_number", block_number as i64)]);
+            let start = Instant::now();
             let query_res = execute_query(
                 query.clone(),
                 Some(selection_set),
                 resolver.block_ptr.clone(),
                 QueryExecutionOptions {
                     resolver,
-                    deadline: ENV_VARS.graphql.query_timeout.map(|t| Instant::now() + t),
-                    max_first: max_first.unwrap_or(ENV_VARS.graphql.max_first),
-                    max_skip: max_skip.unwrap_or(ENV_VARS.graphql.max_skip),
+                    start,
+                    deadline,
+                    max_first,
+                    max_skip,
                     load_manager: self.load_manager.clone(),
+                    tracer: tracer.clone(),
+                    partial_results,
                 },
             )
             .await;
+            execute_span.set_attributes(|| {
+                vec![KeyValue::new("result_size", query_res.result_size as i64)]
+            });
+            execute_span.end();
+            partial |= query_res.is_partial();
+            if let Some(tracer) = &tracer {
+                tracer.block_constraint(block_number, start);
+            }
+            cursors.extend(
+                query_res.cursors.iter().map(|(key, cursor)| {
+                    (Word::from(key.clone()), r::Value::String(cursor.clone()))
+                }),
+            );
+            size += query_res.result_size;
+            for warning in &query_res.warnings {
+                result.add_warning(warning.clone());
+            }
+            if members.len() == 1 {
+                results.insert(members[0].0, query_res);
+            } else {
+                let keys = members
+                    .iter()
+                    .map(|(_, keys)| keys.clone())
+                    .collect::<Vec<_>>();
+                for ((index, _), part) in
+                    members.iter().zip(QueryResult::partition(query_res, &keys))
+                {
+                    results.insert(*index, Arc::new(part));
+                }
+            }
+        }
+        drop(permit);
+        // Report the results in the order of the block constraints in the
+        // query, no matter how they were grouped
+        for query_res in results.into_values() {
             result.append(query_res);
         }
+        for warning in limit_warnings {
+            result.add_warning(warning);
+        }
+        for warning in &query.warnings {
+            result.add_warning(warning.clone());
+        }
+        if ENV_VARS.graphql.report_deprecations {
+            let deprecations = deprecated_fields(&query.selection_set);
+            if !deprecations.is_empty() {
+                result.set_extension("deprecations", r::Value::List(deprecations));
+            }
+        }
+        if out_of_time {
+            if !partial {
+                result.append(Arc::new(QueryExecutionError::DeadlineExceeded.into()));
+            }
+            partial = true;
+        }
+        let blocks = blocks.into_values().collect::<Vec<_>>();
+        self.metrics
+            .observe_operation(query.operation_name.as_deref(), loop_start.elapsed());
 
+        // Tell clients which block each block constraint was resolved to,
+        // and where the next page of each toplevel collection starts
+        let mut meta = vec![
+            ("blocks".to_string(), blocks.into_value()),
+            ("cursors".to_string(), r::Value::object(cursors)),
+        ];
+        if sync_status {
+            let block = self.sync_status(store.as_ref(), &state).await?;
+            meta.push(("block".to_string(), block));
+        }
+        // The size is the same that `ResultSizeMetrics` records, and only
+        // reported for successful results
+        if report_result_size && !result.has_errors() {
+            meta.push(("resultSize".to_string(), r::Value::Int(size as i64)));
+        }
+        result.set_extension("_meta", r::Value::Object(Object::from_iter(meta)));
+        if let Some(tracer) = tracer {
+            result.set_extension("tracing", tracer.finish());
+        }
+        if partial {
+            result.set_extension("partial", r::Value::Boolean(true));
+        }
         query.log_execution(max_block);
-        self.deployment_changed(store.as_ref(), state, max_block as u64)
-            .await
-            .map_err(QueryResults::from)
-            .map(|()| result)
+        query.log_summary(max_block, size);
+        span.set_attributes(|| {
+            vec![
+                KeyValue::new("block_number", max_block as i64),
+                KeyValue::new("result_size", size as i64),
+            ]
+        });
+        span.end();
+        self.deployment_changed(
+            store.as_ref(),
+            state,
+            max_block as u64,
+            reorg_tolerance,
+            pinned,
+            &mut result,
+        )
+        .await
+        .map_err(QueryResults::from)?;
+        // Only complete results without errors should be cached
+        if let Some(etag) = etag.filter(|_| !partial && !result.has_errors()) {
+            result.set_etag(etag);
+        }
+        Ok(result)
+    }
+
+    /// Prepare `query` the same way `execute_at_state` does and return a
+    /// stream of the pages of its toplevel collection. Streamed queries
+    /// are never coalesced with other queries or cached, and they can only
+    /// have one block constraint since all pages must come from the same
+    /// block
+    async fn execute_stream(
+        &self,
+        query: Query,
+        target: QueryTarget,
+    ) -> Result<QueryResultStream, QueryResults> {
+        let query = self.resolve_persisted_query(query)?;
+        let store = self.query_store_for(&query, target).await?;
+        let state = self
+            .deployment_state(store.as_ref(), BEFORE_EXECUTION)
+            .await?;
+        let network = Some(query_network(&query, store.network_name())?);
+        let schema = self.api_schema(store.as_ref())?;
+        check_schema_hash(&query, &schema)?;
+
+        let timeout = query_timeout(query.timeout, self.query_limits.timeout(&state.id));
+        let query = crate::execution::Query::prepare(
+            &self.logger,
+            schema,
+            network,
+            query,
+            ENV_VARS.graphql.max_complexity,
+            ENV_VARS.graphql.max_depth,
+            Some(&self.prepared_queries),
+            self.ignore_unknown_arguments,
+        )?;
+        if query.prepared {
+            self.metrics.prepared_query_cache_hits.inc();
+        }
+        self.check_allowed(&query, false)?;
+        self.check_authorized(&query)?;
+        self.decide(store.as_ref(), &query, &QuerySpan::default())?;
+        let mut by_block_constraint = query.block_constraint()?.into_iter();
+        let (bc, (selection_set, error_policy)) =
+            match (by_block_constraint.next(), by_block_constraint.next()) {
+                (Some(only), None) => only,
+                _ => {
+                    return Err(QueryExecutionError::NotSupported(
+                        "streamed queries can only use one block constraint".to_string(),
+                    )
+                    .into())
+                }
+            };
+        let resolver = StoreResolver::at_block(
+            &self.logger,
+            store,
+            &state,
+            self.subscription_manager.cheap_clone(),
+            bc,
+            error_policy,
+            query.schema.id().clone(),
+            self.result_size.cheap_clone(),
+        )
+        .await?;
+        query.log_execution(resolver.block_number());
+        query.log_field_access(&selection_set, resolver.block_number());
+        let (max_first, max_skip, _) = absolute_limits(
+            self.query_limits.max_first(&state.id),
+            self.query_limits.max_skip(&state.id),
+        );
+        let start = Instant::now();
+        Ok(execute_query_stream(
+            query,
+            Some(selection_set),
+            QueryExecutionOptions {
+                resolver,
+                start,
+                deadline: timeout.map(|t| start + t),
+                max_first,
+                max_skip,
+                load_manager: self.load_manager.clone(),
+                tracer: None,
+                partial_results: false,
+            },
+        ))
+    }
+
+    /// Prepare `query` the same way `execute` does, but instead of running
+    /// it, return a description of how it would be run. The description
+    /// lists, for each block constraint, the block we would query at and
+    /// the toplevel fields that would be fetched from the store
+    async fn explain(
+        &self,
+        query: Query,
+        target: QueryTarget,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+    ) -> Result<QueryResults, QueryResults> {
+        let mut query = self.resolve_persisted_query(query)?;
+        query.complexity_breakdown = true;
+        let store = self.query_store_for(&query, target).await?;
+        let state = self
+            .deployment_state(store.as_ref(), BEFORE_EXECUTION)
+            .await?;
+        let network = Some(query_network(&query, store.network_name())?);
+        let schema = self.api_schema(store.as_ref())?;
+
+        let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
+        let query = crate::execution::Query::new(
+            &self.logger,
+            schema,
+            network,
+            query,
+            max_complexity,
+            max_depth,
+        )?;
+        self.check_allowed(&query, false)?;
+        self.check_authorized(&query)?;
+        let root_type = sast::ObjectType::from(query.schema.query_type.cheap_clone());
+
+        let mut constraints = Vec::new();
+        for (bc, (selection_set, error_policy)) in query.block_constraint()? {
+            let resolver = StoreResolver::at_block(
+                &self.logger,
+                store.cheap_clone(),
+                &state,
+                self.subscription_manager.cheap_clone(),
+                bc.clone(),
+                error_policy,
+                query.schema.id().clone(),
+                self.result_size.cheap_clone(),
+            )
+            .await?;
+
+            // Each toplevel field turns into one query against the store
+            let mut operations = Vec::new();
+            for field in selection_set.fields_for(&root_type)? {
+                let entity_type = sast::get_field(&root_type, &field.name)
+                    .map(|field_def| field_def.field_type.get_base_type().to_string());
+                operations.push(object! {
+                    field: field.name.clone(),
+                    responseKey: field.response_key(),
+                    entityType: entity_type,
+                    arguments: r::Value::Object(Object::from_iter(field.arguments.clone())),
+                    selection: SelectedFields(&field.selection_set).to_string(),
+                });
+            }
+
+            let block = resolver.block_ptr.as_ref().map(block_value);
+            constraints.push(object! {
+                constraint: bc.into_value(),
+                block: block,
+                errorPolicy: error_policy.as_str(),
+                selection: SelectedFields(&selection_set).to_string(),
+                operations: operations,
+            });
+        }
+
+        let breakdown = query
+            .complexity_breakdown
+            .iter()
+            .map(|field| {
+                object! {
+                    path: field.path.clone(),
+                    cost: field.cost,
+                }
+            })
+            .collect::<Vec<_>>();
+        let plan = object! {
+            shapeHash: query.shape_hash,
+            complexity: query.complexity,
+            complexityBreakdown: breakdown,
+            replica: store.replica_id().to_string(),
+            blockConstraints: constraints,
+        };
+        Ok(QueryResults::from(Object::from_iter(vec![(
+            "plan".to_string(),
+            plan,
+        )])))
+    }
+
+    /// Count a new subscription as active, or fail if there are already
+    /// too many active subscriptions for its connection or deployment. The
+    /// subscription stays active until the returned value is dropped
+    fn activate_subscription(
+        &self,
+        connection: Option<String>,
+        deployment: &DeploymentHash,
+    ) -> Result<ActiveSubscription, SubscriptionError> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .acquire(connection.as_deref(), deployment)?;
+        let gauge = self
+            .metrics
+            .active_subscriptions
+            .with_label_values(&[deployment.as_str()]);
+        gauge.inc();
+        Ok(ActiveSubscription {
+            counts: self.subscriptions.cheap_clone(),
+            connection,
+            deployment: deployment.clone(),
+            gauge,
+        })
+    }
+
+    /// Turn the live query `query` into a stream of results. The query is
+    /// executed once right away, unless `skip_initial` is set, and again
+    /// whenever one of the entities it reads changes. Each execution goes
+    /// through `run_query` and is therefore subject to the same limits as
+    /// any other query
+    fn live_query(
+        self: &Arc<Self>,
+        prepared: &crate::execution::Query,
+        query: Query,
+        target: QueryTarget,
+        skip_initial: bool,
+    ) -> Result<SubscriptionResult, SubscriptionError> {
+        let root_type = sast::ObjectType::from(prepared.schema.query_type.cheap_clone());
+        let mut entities = BTreeSet::new();
+        for field in prepared.selection_set.fields_for(&root_type)? {
+            entities.extend(collect_entities_from_query_field(
+                &prepared.schema,
+                root_type.cheap_clone(),
+                field,
+            )?);
+        }
+        let source_stream = self.subscription_manager.subscribe_no_payload(entities);
+        let source_stream = if skip_initial {
+            changes_only(source_stream)
+        } else {
+            source_stream
+        };
+
+        let runner = self.cheap_clone();
+        let deployment = prepared.schema.id().clone();
+        let results = futures03::stream::iter((!skip_initial).then(|| ()))
+            .chain(source_stream)
+            .then(move |()| {
+                let runner = runner.cheap_clone();
+                let query = query.clone();
+                let target = target.clone();
+                async move { Arc::new(runner.run_query(query, target).await.into_single()) }
+            })
+            .map(recoverable_errors)
+            .boxed();
+        Ok(end_when_unavailable(
+            results,
+            self.subscription_manager.as_ref(),
+            deployment,
+        ))
+    }
+
+    /// Compute the complexity of `query` and ask the `LoadManager` whether
+    /// it would let the query run, without executing it. The limits are
+    /// resolved the same way as in `execute`, but a query that is too
+    /// complex is reported as rejected rather than as an error, so that
+    /// callers learn its complexity
+    async fn query_cost(
+        &self,
+        query: Query,
+        target: QueryTarget,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+    ) -> Result<QueryCost, QueryResults> {
+        let mut query = self.resolve_persisted_query(query)?;
+        query.complexity_breakdown = true;
+        let store = self.query_store_for(&query, target).await?;
+        let network = Some(query_network(&query, store.network_name())?);
+        let schema = self.api_schema(store.as_ref())?;
+
+        let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
+        let query =
+            crate::execution::Query::new(&self.logger, schema, network, query, None, max_depth)?;
+        let too_complex = max_complexity.map_or(false, |max| query.complexity > max);
+        let allowed =
+            self.check_allowed(&query, false).is_ok() && self.check_authorized(&query).is_ok();
+        // Estimating the cost of a query must not change what the
+        // `LoadManager` decides for queries that actually run
+        let decision = self.load_manager.would_decide(
+            &store.wait_stats().map_err(QueryExecutionError::from)?,
+            query.shape_hash,
+            query.lane.as_deref(),
+        );
+        Ok(QueryCost {
+            complexity: query.complexity,
+            shape_hash: query.shape_hash,
+            rejected: too_complex || !allowed || !matches!(decision, Decision::Proceed),
+            breakdown: query.complexity_breakdown.clone(),
+        })
+    }
+
+    /// Prepare `query` for execution and report the errors that produces.
+    /// Preparing a query validates it against the API schema, checks its
+    /// complexity and depth and coerces its variables; none of that needs
+    /// more from the store than the schema
+    async fn check_query(
+        &self,
+        query: Query,
+        target: QueryTarget,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+    ) -> Vec<QueryExecutionError> {
+        let prepare = async {
+            let query = self.resolve_persisted_query(query)?;
+            let store = self.store.query_store(target, false).await?;
+            let network = Some(query_network(&query, store.network_name())?);
+            let schema = self.api_schema(store.as_ref())?;
+
+            let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
+            crate::execution::Query::new(
+                &self.logger,
+                schema,
+                network,
+                query,
+                max_complexity,
+                max_depth,
+            )?;
+            Ok::<_, Vec<QueryExecutionError>>(())
+        };
+        prepare.await.err().unwrap_or_default()
     }
 }
 
@@ -237,8 +2543,8 @@ where
             target,
             ENV_VARS.graphql.max_complexity,
             Some(ENV_VARS.graphql.max_depth),
-            Some(ENV_VARS.graphql.max_first),
-            Some(ENV_VARS.graphql.max_skip),
+            None,
+            None,
         )
         .await
     }
@@ -255,6 +2561,7 @@ where
         self.execute(
             query,
             target,
+            None,
             max_complexity,
             max_depth,
             max_first,
@@ -265,53 +2572,331 @@ where
         .unwrap_or_else(|e| e)
     }
 
+    async fn run_query_batch(
+        self: Arc<Self>,
+        queries: Vec<(Query, QueryTarget)>,
+    ) -> Vec<QueryResults> {
+        self.run_query_batch_with_complexity(
+            queries,
+            ENV_VARS.graphql.max_complexity,
+            Some(ENV_VARS.graphql.max_depth),
+            None,
+            None,
+        )
+        .await
+    }
+
+    async fn run_query_batch_with_complexity(
+        self: Arc<Self>,
+        queries: Vec<(Query, QueryTarget)>,
+        max_complexity: Option<u64>,
+        max_depth: Option<u8>,
+        max_first: Option<u32>,
+        max_skip: Option<u32>,
+    ) -> Vec<QueryResults> {
+        self.execute_batch(queries, max_complexity, max_depth, max_first, max_skip)
+            .await
+    }
+
+    async fn run_query_multi(
+        self: Arc<Self>,
+        query: Query,
+        targets: Vec<QueryTarget>,
+    ) -> HashMap<QueryTarget, QueryResults> {
+        let results = futures03::future::join_all(targets.into_iter().map(|target| {
+            let query = query.clone();
+            let runner = self.cheap_clone();
+            async move {
+                let results = runner
+                    .execute(
+                        query,
+                        target.clone(),
+                        None,
+                        ENV_VARS.graphql.max_complexity,
+                        Some(ENV_VARS.graphql.max_depth),
+                        None,
+                        None,
+                        runner.result_size.cheap_clone(),
+                    )
+                    .await
+                    .unwrap_or_else(|e| e);
+                (target, results)
+            }
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+
+    async fn run_query_stream(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> QueryResultStream {
+        match self.execute_stream(query, target).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let result = Arc::new(e.into_single());
+                futures03::stream::once(async move { result }).boxed()
+            }
+        }
+    }
+
+    async fn run_query_explain(self: Arc<Self>, query: Query, target: QueryTarget) -> QueryResults {
+        self.explain(
+            query,
+            target,
+            ENV_VARS.graphql.max_complexity,
+            Some(ENV_VARS.graphql.max_depth),
+        )
+        .await
+        .unwrap_or_else(|e| e)
+    }
+
+    async fn estimate_cost(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Result<QueryCost, QueryResults> {
+        self.query_cost(
+            query,
+            target,
+            ENV_VARS.graphql.max_complexity,
+            Some(ENV_VARS.graphql.max_depth),
+        )
+        .await
+    }
+
+    async fn validate(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Vec<QueryExecutionError> {
+        self.check_query(
+            query,
+            target,
+            ENV_VARS.graphql.max_complexity,
+            Some(ENV_VARS.graphql.max_depth),
+        )
+        .await
+    }
+
     async fn run_subscription(
         self: Arc<Self>,
         subscription: Subscription,
         target: QueryTarget,
     ) -> Result<SubscriptionResult, SubscriptionError> {
-        let store = self.store.query_store(target, true).await?;
-        let schema = store.api_schema()?;
-        let network = store.network_name().to_string();
+        let subscription = self.resolve_stored_subscription(subscription)?;
+        let store = self.store.query_store(target.clone(), true).await?;
+        let schema = self.api_schema(store.as_ref())?;
+        let network = query_network(&subscription.query, store.network_name())?;
 
         let query = crate::execution::Query::new(
             &self.logger,
             schema,
             Some(network),
-            subscription.query,
-            ENV_VARS.graphql.max_complexity,
-            ENV_VARS.graphql.max_depth,
+            subscription.query.clone(),
+            ENV_VARS.graphql.subscription_max_complexity,
+            ENV_VARS.graphql.subscription_max_depth,
         )?;
+        self.check_allowed(&query, false)?;
+        self.check_authorized(&query)?;
 
-        if let Err(err) = self
-            .load_manager
-            .decide(
-                &store.wait_stats().map_err(QueryExecutionError::from)?,
-                query.shape_hash,
-                query.query_text.as_ref(),
-            )
-            .to_result()
-        {
+        if let Err(err) = self.decide(store.as_ref(), &query, &QuerySpan::default()) {
             return Err(SubscriptionError::GraphQLError(vec![err]));
         }
 
-        execute_prepared_subscription(
-            query,
-            SubscriptionExecutionOptions {
-                logger: self.logger.clone(),
-                store,
-                subscription_manager: self.subscription_manager.cheap_clone(),
-                timeout: ENV_VARS.graphql.query_timeout,
-                max_complexity: ENV_VARS.graphql.max_complexity,
-                max_depth: ENV_VARS.graphql.max_depth,
-                max_first: ENV_VARS.graphql.max_first,
-                max_skip: ENV_VARS.graphql.max_skip,
-                result_size: self.result_size.clone(),
-            },
-        )
+        let active = self.activate_subscription(subscription.connection_id, query.schema.id())?;
+        let (max_first, max_skip, _) = absolute_limits(
+            self.query_limits.max_first(query.schema.id()),
+            self.query_limits.max_skip(query.schema.id()),
+        );
+
+        let results = if query.is_live() {
+            self.live_query(
+                &query,
+                subscription.query,
+                target,
+                subscription.skip_initial,
+            )?
+        } else {
+            execute_prepared_subscription(
+                query,
+                SubscriptionExecutionOptions {
+                    logger: self.logger.clone(),
+                    store,
+                    subscription_manager: self.subscription_manager.cheap_clone(),
+                    timeout: ENV_VARS.graphql.query_timeout,
+                    max_complexity: ENV_VARS.graphql.subscription_max_complexity,
+                    max_depth: ENV_VARS.graphql.subscription_max_depth,
+                    max_first,
+                    max_skip,
+                    result_size: self.result_size.clone(),
+                    debounce: ENV_VARS.graphql.subscription_debounce,
+                    skip_initial: subscription.skip_initial,
+                },
+            )?
+        };
+
+        let results = limit_result_size(
+            results,
+            ENV_VARS.graphql.subscription_max_result_size,
+            self.result_size.cheap_clone(),
+        );
+
+        // The subscription stays active for as long as its stream is alive
+        Ok(results
+            .map(move |result| {
+                let _active = &active;
+                result
+            })
+            .boxed())
+    }
+
+    fn register_subscription(
+        &self,
+        name: String,
+        document: q::Document,
+    ) -> Result<(), QueryExecutionError> {
+        let is_subscription = document.definitions.iter().any(|def| {
+            matches!(
+                def,
+                q::Definition::Operation(q::OperationDefinition::Subscription(_))
+            )
+        });
+        if !is_subscription {
+            return Err(QueryExecutionError::NotSupported(
+                "only subscriptions can be registered".to_string(),
+            ));
+        }
+        self.stored_subscriptions
+            .lock()
+            .unwrap()
+            .insert(name, document);
+        Ok(())
+    }
+
+    fn stored_subscriptions(&self) -> Vec<(String, String)> {
+        self.stored_subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, document)| (name.clone(), document.to_string()))
+            .collect()
     }
 
     fn load_manager(&self) -> Arc<LoadManager> {
         self.load_manager.clone()
     }
 }
+
+#[test]
+fn result_size_buckets() {
+    assert_eq!(Ok(vec![1.0, 10.0, 100.0]), parse_buckets("1, 10,100"));
+    assert!(parse_buckets("1,10,10").is_err());
+    assert!(parse_buckets("10,1").is_err());
+    assert!(parse_buckets("1,ten").is_err());
+    assert!(parse_buckets("").is_err());
+}
+
+#[test]
+fn result_size_window_max() {
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+
+    let mut max = WindowMax::new(Duration::from_secs(60));
+    assert_eq!(10.0, max.observe(secs(0), 10.0));
+    assert_eq!(10.0, max.observe(secs(10), 5.0));
+    assert_eq!(10.0, max.observe(secs(60), 7.0));
+    // 10 has left the window, 7 is larger than 5 and came later
+    assert_eq!(7.0, max.observe(secs(61), 1.0));
+    assert_eq!(7.0, max.observe(secs(120), 2.0));
+    assert_eq!(2.0, max.observe(secs(121), 2.0));
+    assert_eq!(20.0, max.observe(secs(122), 20.0));
+
+    let mut max = WindowMax::new(Duration::ZERO);
+    assert_eq!(10.0, max.observe(secs(0), 10.0));
+    assert_eq!(10.0, max.observe(secs(100_000), 5.0));
+}
+
+#[test]
+fn subscription_counts() {
+    let deployment = DeploymentHash::new("QmSubscriptions").unwrap();
+    let mut counts = SubscriptionCounts::default();
+
+    counts.acquire(Some("c1"), &deployment).unwrap();
+    counts.acquire(Some("c2"), &deployment).unwrap();
+    counts.acquire(None, &deployment).unwrap();
+    assert_eq!(Some(&1), counts.by_connection.get("c1"));
+    assert_eq!(Some(&3), counts.by_deployment.get(&deployment));
+
+    counts.release(Some("c1"), &deployment);
+    counts.release(None, &deployment);
+    assert!(!counts.by_connection.contains_key("c1"));
+    assert_eq!(Some(&1), counts.by_deployment.get(&deployment));
+
+    counts.release(Some("c2"), &deployment);
+    assert!(counts.by_connection.is_empty());
+    assert!(counts.by_deployment.is_empty());
+}
+
+#[test]
+fn clamps_query_timeout() {
+    let secs = Duration::from_secs;
+    assert_eq!(None, query_timeout(None, None));
+    assert_eq!(Some(secs(5)), query_timeout(Some(secs(5)), None));
+    assert_eq!(Some(secs(10)), query_timeout(None, Some(secs(10))));
+    assert_eq!(Some(secs(5)), query_timeout(Some(secs(5)), Some(secs(10))));
+    assert_eq!(
+        Some(secs(10)),
+        query_timeout(Some(secs(50)), Some(secs(10)))
+    );
+}
+
+#[test]
+fn clamps_to_absolute_limits() {
+    let absolute_first = ENV_VARS.graphql.absolute_max_first;
+    assert_eq!((10, 20, vec![]), absolute_limits(10, 20));
+
+    let (max_first, max_skip, warnings) = absolute_limits(absolute_first.saturating_add(1), 20);
+    assert_eq!(absolute_first, max_first);
+    assert_eq!(20, max_skip);
+    assert_eq!(
+        u32::from(absolute_first < u32::MAX) as usize,
+        warnings.len()
+    );
+}
+
+#[test]
+fn reorg_math_does_not_overflow() {
+    assert_eq!(Some(30), revert_depth(10, 3));
+    assert_eq!(None, revert_depth(u32::MAX, 2));
+    assert_eq!(None, revert_depth(1, u32::MAX));
+    assert_eq!(
+        Some(BlockNumber::MAX),
+        revert_depth(1, BlockNumber::MAX as u32)
+    );
+
+    assert!(!reorg_affects(10, 100, Some(5)));
+    assert!(reorg_affects(98, 100, Some(5)));
+    assert!(reorg_affects(u64::MAX, 100, Some(BlockNumber::MAX)));
+    assert!(reorg_affects(0, BlockNumber::MAX, None));
+}
+
+#[test]
+fn regressed_reorg_count_is_an_error() {
+    let state = |reorg_count: u32| DeploymentState {
+        id: DeploymentHash::new("QmReorgCount").unwrap(),
+        reorg_count,
+        max_reorg_depth: 1,
+        latest_block: BlockPtr::from((web3::types::H256::repeat_byte(1), 1i32)),
+        earliest_block_number: 0,
+    };
+    assert_eq!(0, reorgs_since(&state(3), &state(3)).unwrap());
+    assert_eq!(2, reorgs_since(&state(3), &state(5)).unwrap());
+    match reorgs_since(&state(5), &state(3)) {
+        Err(QueryExecutionError::Internal(msg)) => {
+            assert!(msg.contains("went down from 5 to 3"), "{}", msg)
+        }
+        other => panic!("expected an internal error, got {:?}", other),
+    }
+}
@@ -1,15 +1,19 @@
+use std::collections::HashSet;
 use std::str::FromStr;
 
 use graphql_parser::Pos;
 use inflector::Inflector;
 use lazy_static::lazy_static;
 
+use crate::execution::ast as a;
 use crate::schema::ast;
+use crate::store::ORDER_BY_SEPARATOR;
 
 use graph::data::{
-    graphql::ext::{DirectiveExt, DocumentExt, ValueExt},
+    graphql::ext::{DirectiveExt, DirectiveFinder, DocumentExt, TypeExt, ValueExt},
     schema::{META_FIELD_NAME, META_FIELD_TYPE, SCHEMA_TYPE_NAME},
 };
+use graph::env::DefaultErrorPolicy;
 use graph::prelude::s::{Value, *};
 use graph::prelude::*;
 use thiserror::Error;
@@ -28,8 +32,9 @@ pub enum APISchemaError {
 const BLOCK_HEIGHT: &str = "Block_height";
 const CHANGE_BLOCK_FILTER_NAME: &str = "BlockChangedFilter";
 const ERROR_POLICY_TYPE: &str = "_SubgraphErrorPolicy_";
+const ERROR_POLICY_DIRECTIVE: &str = "errorPolicy";
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum ErrorPolicy {
     Allow,
     Deny,
@@ -71,6 +76,63 @@ impl TryFrom<&r::Value> for ErrorPolicy {
     }
 }
 
+impl ErrorPolicy {
+    /// The error policy of queries that do not set one with
+    /// `subgraphError`, see `GRAPH_GRAPHQL_DEFAULT_ERROR_POLICY`
+    pub fn default_policy() -> ErrorPolicy {
+        match ENV_VARS.graphql.default_error_policy {
+            DefaultErrorPolicy::Allow => ErrorPolicy::Allow,
+            DefaultErrorPolicy::Deny => ErrorPolicy::Deny,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorPolicy::Allow => "allow",
+            ErrorPolicy::Deny => "deny",
+        }
+    }
+
+    /// The error policy set for `field` with `@errorPolicy(policy: ..)`,
+    /// if the field has that directive
+    pub fn for_field(field: &a::Field) -> Result<Option<ErrorPolicy>, QueryExecutionError> {
+        let directive = match field
+            .directives
+            .iter()
+            .find(|directive| directive.name == ERROR_POLICY_DIRECTIVE)
+        {
+            Some(directive) => directive,
+            None => return Ok(None),
+        };
+        let value = directive
+            .argument_value("policy")
+            .unwrap_or(&r::Value::Null);
+        ErrorPolicy::try_from(value).map(Some).map_err(|_| {
+            QueryExecutionError::InvalidArgumentError(
+                directive.position,
+                "policy".to_string(),
+                value.clone().into(),
+            )
+        })
+    }
+
+    /// Collect the error policies that are set with `@errorPolicy` for any
+    /// of the fields in `selection_set` or their descendants, checking
+    /// that all of them are valid
+    pub fn used_in(
+        selection_set: &a::SelectionSet,
+    ) -> Result<HashSet<ErrorPolicy>, QueryExecutionError> {
+        let mut policies = HashSet::new();
+        for (_, fields) in selection_set.fields() {
+            for field in fields {
+                policies.extend(ErrorPolicy::for_field(field)?);
+                policies.extend(ErrorPolicy::used_in(&field.selection_set)?);
+            }
+        }
+        Ok(policies)
+    }
+}
+
 /// Derives a full-fledged GraphQL API schema from an input schema.
 ///
 /// The input schema should only have type/enum/interface/union definitions
@@ -159,11 +221,16 @@ fn add_order_by_type(
                 directives: vec![],
                 values: fields
                     .iter()
-                    .map(|field| &field.name)
+                    .map(|field| field.name.to_owned())
+                    .chain(nested_order_by_values(
+                        schema,
+                        fields,
+                        ENV_VARS.graphql.max_order_by_depth,
+                    ))
                     .map(|name| EnumValue {
                         position: Pos::default(),
                         description: None,
-                        name: name.to_owned(),
+                        name,
                         directives: vec![],
                     })
                     .collect(),
@@ -176,6 +243,53 @@ fn add_order_by_type(
     Ok(())
 }
 
+/// The values `<field>__<attribute>` of an `orderBy` enum that order by
+/// an `attribute` of the entity that `field` references, following at most
+/// `depth` references. Only fields that reference a single entity can be
+/// followed, and only scalar attributes can be ordered by
+fn nested_order_by_values(schema: &Document, fields: &[Field], depth: usize) -> Vec<String> {
+    if depth == 0 {
+        return vec![];
+    }
+    let mut values = vec![];
+    for field in fields {
+        if let Some(object_type) = order_by_reference(schema, field) {
+            let attributes = object_type
+                .fields
+                .iter()
+                .filter(|field| {
+                    !field.field_type.is_list()
+                        && !field.is_derived()
+                        && ValueType::is_scalar(field.field_type.get_base_type())
+                })
+                .map(|field| field.name.to_owned())
+                .chain(nested_order_by_values(
+                    schema,
+                    &object_type.fields,
+                    depth - 1,
+                ));
+            values.extend(
+                attributes
+                    .map(|attribute| format!("{}{}{}", field.name, ORDER_BY_SEPARATOR, attribute)),
+            );
+        }
+    }
+    values
+}
+
+/// The entity type that `field` references if `orderBy` can follow it
+fn order_by_reference<'a>(schema: &'a Document, field: &Field) -> Option<&'a ObjectType> {
+    if field.field_type.is_list() {
+        return None;
+    }
+    match schema.get_named_type(field.field_type.get_base_type()) {
+        Some(TypeDefinition::Object(object_type)) if object_type.name != SCHEMA_TYPE_NAME => {
+            Some(object_type)
+        }
+        _ => None,
+    }
+}
+
 /// Adds a `<type_name>_filter` enum type for the given fields to the schema.
 fn add_filter_type(
     schema: &mut Document,
@@ -578,10 +692,13 @@ fn block_argument() -> InputValue {
         description: Some(
             "The block at which the query should be executed. \
              Can either be a `{ hash: Bytes }` value containing a block hash, \
-             a `{ number: Int }` containing the block number, \
+             a `{ number: Int }` containing the block number, or, if it is negative, \
+             how many blocks before the latest block to go back, \
              or a `{ number_gte: Int }` containing the minimum block number. \
              In the case of `number_gte`, the query will be executed on the latest block only if \
              the subgraph has progressed to or past the minimum block number. \
+             A `{ snapshot: String }` executes the query at the block of a snapshot \
+             of the deployment, if the store keeps snapshots. \
              Defaults to the latest block when omitted."
                 .to_owned(),
         ),
@@ -612,7 +729,7 @@ fn subgraph_error_argument() -> InputValue {
         ),
         name: "subgraphError".to_string(),
         value_type: Type::NonNullType(Box::new(Type::NamedType(ERROR_POLICY_TYPE.to_string()))),
-        default_value: Some(Value::Enum("deny".to_string())),
+        default_value: Some(Value::Enum(ErrorPolicy::default_policy().as_str().to_string())),
         directives: vec![],
     }
 }
@@ -697,6 +814,11 @@ fn collection_arguments_for_named_type(type_name: &str) -> Vec<InputValue> {
     let args = vec![
         skip,
         first,
+        input_value(
+            &"after".to_string(),
+            "",
+            Type::NamedType("String".to_string()),
+        ),
         input_value(
             &"orderBy".to_string(),
             "",
@@ -878,6 +1000,59 @@ mod tests {
         assert_eq!(values, ["id", "name"]);
     }
 
+    #[test]
+    fn api_schema_contains_nested_order_by_values() {
+        let input_schema = parse_schema(
+            r#"
+              type User {
+                  id: ID!
+                  name: String!
+                  pets: [Pet!]!
+                  profile: Profile @derivedFrom(field: "user")
+              }
+
+              type Pet {
+                  id: ID!
+                  name: String!
+                  owner: User!
+              }
+
+              type Profile {
+                  id: ID!
+                  user: User!
+                  bio: String
+              }
+            "#,
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derived API schema");
+
+        let values = |type_name: &str| -> Vec<String> {
+            match schema.get_named_type(type_name) {
+                Some(TypeDefinition::Enum(t)) => {
+                    t.values.iter().map(|value| value.name.clone()).collect()
+                }
+                _ => panic!("{} type is missing or not an enum", type_name),
+            }
+        };
+
+        assert_eq!(
+            values("Pet_orderBy"),
+            ["id", "name", "owner", "owner__id", "owner__name"]
+        );
+        assert_eq!(
+            values("User_orderBy"),
+            [
+                "id",
+                "name",
+                "pets",
+                "profile",
+                "profile__id",
+                "profile__bio"
+            ]
+        );
+    }
+
     #[test]
     fn api_schema_contains_object_type_filter_enum() {
         let input_schema = parse_schema(
@@ -1278,6 +1453,7 @@ mod tests {
             [
                 "skip",
                 "first",
+                "after",
                 "orderBy",
                 "orderDirection",
                 "where",
@@ -1375,6 +1551,7 @@ mod tests {
             [
                 "skip",
                 "first",
+                "after",
                 "orderBy",
                 "orderDirection",
                 "where",
@@ -0,0 +1,196 @@
+//! Opaque cursors that let clients page through a collection with
+//! `first: n, after: <cursor>` instead of `skip`. A cursor remembers the
+//! key by which the last entity of a page was ordered, so that fetching
+//! the next page is a range query rather than an offset into the
+//! collection. A cursor also pins pagination to the block at which the
+//! first page was read: later pages must be read at the same block, so
+//! that the pages are consistent with each other even if the deployment
+//! advances or the chain is reorganized in between.
+
+use std::str::FromStr;
+
+use graph::data::graphql::ObjectOrInterface;
+use graph::prelude::{
+    hex, r, serde_json, web3, BlockHash, BlockNumber, BlockPtr, EntityFilter, EntityOrder,
+    QueryExecutionError, QueryStore, Value as StoreValue,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::execution::ast as a;
+use crate::schema::ast as sast;
+
+/// The key under which the prefetched data for a query carries the
+/// cursors for its toplevel collections
+pub(crate) const CURSORS_KEY: &str = "prefetch:__cursors";
+
+const ARG_AFTER: &str = "after";
+
+/// The position of an entity in a collection
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Cursor {
+    /// The block at which the entity was read
+    block: BlockNumber,
+    /// The hash of `block`, unless the query only constrained the block
+    /// by its number
+    hash: Option<String>,
+    id: String,
+    /// The value of the `orderBy` attribute of the entity
+    value: Option<serde_json::Value>,
+}
+
+impl Cursor {
+    pub fn new(block_ptr: Option<&BlockPtr>, id: String, value: Option<&r::Value>) -> Self {
+        // A block constraint by number does not give us the hash
+        // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+        let hash = block_ptr
+            .filter(|ptr| ptr.hash_as_h256() != web3::types::H256::zero())
+            .map(BlockPtr::hash_hex);
+        Cursor {
+            block: block_ptr.map(|ptr| ptr.number).unwrap_or(0),
+            hash,
+            id,
+            value: value.and_then(|value| serde_json::to_value(value).ok()),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("cursors can be serialized"))
+    }
+
+    pub fn decode(text: &str) -> Result<Self, QueryExecutionError> {
+        hex::decode(text)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| QueryExecutionError::InvalidCursor(text.to_string()))
+    }
+
+    /// The cursor passed as the `after` argument of `field`, if any
+    pub fn for_field(field: &a::Field) -> Result<Option<Self>, QueryExecutionError> {
+        match field.argument_value(ARG_AFTER) {
+            Some(r::Value::String(text)) => Cursor::decode(text).map(Some),
+            Some(r::Value::Null) | None => Ok(None),
+            Some(value) => Err(QueryExecutionError::InvalidCursor(value.to_string())),
+        }
+    }
+
+    /// Check that the page after this cursor can be read at `block`. The
+    /// cursor must come from `block`, and that block must still be on the
+    /// chain
+    pub fn check_block(
+        &self,
+        store: &(impl QueryStore + ?Sized),
+        block: BlockNumber,
+    ) -> Result<(), QueryExecutionError> {
+        if self.block != block {
+            return Err(QueryExecutionError::InvalidCursor(format!(
+                "the cursor is pinned to block {} but the query is for block {}; \
+                 read all pages at the block of the first page",
+                self.block, block
+            )));
+        }
+        if let Some(hash) = &self.hash {
+            let block_hash = BlockHash::from_str(hash)
+                .map_err(|_| QueryExecutionError::InvalidCursor(hash.clone()))?;
+            if store.block_number(&block_hash)? != Some(self.block) {
+                return Err(QueryExecutionError::PaginationBlockReverted(self.block));
+            }
+        }
+        Ok(())
+    }
+
+    /// A filter that selects the entities that come after this cursor
+    /// when `entity` is sorted by `order`. Entities with the same value
+    /// for the `orderBy` attribute are sorted by their `id` in the same
+    /// direction; `null` values sort last for ascending and first for
+    /// descending order
+    pub fn filter(
+        &self,
+        entity: ObjectOrInterface,
+        order: &EntityOrder,
+    ) -> Result<EntityFilter, QueryExecutionError> {
+        let id = store_value(entity, "id", Some(r::Value::String(self.id.clone())))?;
+        let (attr, ascending) = match order {
+            EntityOrder::Default => return Ok(EntityFilter::GreaterThan("id".to_string(), id)),
+            EntityOrder::Ascending(attr, _) => (attr, true),
+            EntityOrder::Descending(attr, _) => (attr, false),
+            EntityOrder::ChildAscending(_) | EntityOrder::ChildDescending(_) => {
+                return Err(QueryExecutionError::InvalidCursor(
+                    "cursors can not be used with collections ordered by an attribute \
+                     of a referenced entity"
+                        .to_string(),
+                ))
+            }
+            EntityOrder::Unordered => {
+                return Err(QueryExecutionError::InvalidCursor(
+                    "cursors can only be used with ordered collections".to_string(),
+                ))
+            }
+        };
+
+        let after_id = |id| {
+            if ascending {
+                EntityFilter::GreaterThan("id".to_string(), id)
+            } else {
+                EntityFilter::LessThan("id".to_string(), id)
+            }
+        };
+        if attr == "id" {
+            return Ok(after_id(id));
+        }
+
+        let value = store_value(entity, attr, self.value.clone().map(r::Value::from))?;
+        let attr = attr.to_string();
+        let same_value = |value| {
+            EntityFilter::And(vec![
+                EntityFilter::Equal(attr.clone(), value),
+                after_id(id.clone()),
+            ])
+        };
+        Ok(match (ascending, value) {
+            (true, StoreValue::Null) => same_value(StoreValue::Null),
+            (false, StoreValue::Null) => EntityFilter::Or(vec![
+                same_value(StoreValue::Null),
+                EntityFilter::Not(attr.clone(), StoreValue::Null),
+            ]),
+            (true, value) => EntityFilter::Or(vec![
+                EntityFilter::GreaterThan(attr.clone(), value.clone()),
+                same_value(value),
+                EntityFilter::Equal(attr.clone(), StoreValue::Null),
+            ]),
+            (false, value) => EntityFilter::Or(vec![
+                EntityFilter::LessThan(attr.clone(), value.clone()),
+                same_value(value),
+            ]),
+        })
+    }
+}
+
+/// Convert `value` to the store representation of attribute `attr`
+fn store_value(
+    entity: ObjectOrInterface,
+    attr: &str,
+    value: Option<r::Value>,
+) -> Result<StoreValue, QueryExecutionError> {
+    let field = sast::get_field(entity, attr).ok_or_else(|| {
+        QueryExecutionError::EntityFieldError(entity.name().to_owned(), attr.to_owned())
+    })?;
+    let value = value.ok_or_else(|| {
+        QueryExecutionError::InvalidCursor(format!(
+            "the cursor was not created for a collection ordered by `{}`",
+            attr
+        ))
+    })?;
+    StoreValue::from_query_value(&value, &field.field_type)
+}
+
+#[test]
+fn roundtrip() {
+    let cursor = Cursor {
+        block: 17,
+        hash: None,
+        id: "m1".to_string(),
+        value: Some(serde_json::json!("John")),
+    };
+    assert_eq!(cursor, Cursor::decode(&cursor.encode()).unwrap());
+    assert!(Cursor::decode("not a cursor").is_err());
+}
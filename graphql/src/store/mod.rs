@@ -1,6 +1,11 @@
+mod cursor;
 mod prefetch;
 mod query;
 mod resolver;
 
+pub(crate) use self::cursor::{Cursor, CURSORS_KEY};
+pub(crate) use self::prefetch::RESULT_SIZE_KEY;
+pub(crate) use self::query::collect_entities_from_query_field;
 pub use self::query::parse_subgraph_id;
+pub(crate) use self::query::ORDER_BY_SEPARATOR;
 pub use self::resolver::StoreResolver;
@@ -8,27 +8,30 @@ use graph::prelude::{r, CacheWeight};
 use graph::slog::warn;
 use graph::util::cache_weight;
 use lazy_static::lazy_static;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
-use std::time::Instant;
 
 use graph::{components::store::EntityType, data::graphql::*};
 use graph::{
     data::graphql::ext::DirectiveFinder,
     prelude::{
         s, ApiSchema, AttributeNames, BlockNumber, ChildMultiplicity, EntityCollection,
-        EntityFilter, EntityLink, EntityOrder, EntityWindow, Logger, ParentLink,
+        EntityFilter, EntityLink, EntityOrder, EntityRange, EntityWindow, Logger, ParentLink,
         QueryExecutionError, QueryStore, StoreError, Value as StoreValue, WindowAttribute,
         ENV_VARS,
     },
 };
 
 use crate::execution::{ast as a, ExecutionContext, Resolver};
-use crate::runner::ResultSizeMetrics;
 use crate::schema::ast as sast;
-use crate::store::query::build_query;
+use crate::store::cursor::{Cursor, CURSORS_KEY};
+use crate::store::query::{build_query, id_in_order, ORDER_BY_SEPARATOR};
 use crate::store::StoreResolver;
 
+/// The key under which the prefetched data for a query carries the
+/// `CacheWeight` of everything that was fetched
+pub(crate) const RESULT_SIZE_KEY: &str = "prefetch:__resultSize";
+
 lazy_static! {
     static ref ARG_FIRST: String = String::from("first");
     static ref ARG_SKIP: String = String::from("skip");
@@ -480,10 +483,14 @@ pub fn run(
     resolver: &StoreResolver,
     ctx: &ExecutionContext<impl Resolver>,
     selection_set: &a::SelectionSet,
-    result_size: &ResultSizeMetrics,
 ) -> Result<r::Value, Vec<QueryExecutionError>> {
     execute_root_selection_set(resolver, ctx, selection_set).map(|nodes| {
-        result_size.observe(nodes.weight());
+        let size = nodes.weight();
+        let cursors = nodes
+            .iter()
+            .map(|node| cursors(resolver, selection_set, node))
+            .flatten()
+            .collect::<Vec<_>>();
         let obj = Object::from_iter(
             nodes
                 .into_iter()
@@ -492,12 +499,56 @@ pub fn run(
                         (format!("prefetch:{}", key), node_list_as_value(nodes))
                     })
                 })
-                .flatten(),
+                .flatten()
+                .chain(std::iter::once((
+                    CURSORS_KEY.to_string(),
+                    r::Value::Object(Object::from_iter(cursors)),
+                )))
+                .chain(std::iter::once((
+                    RESULT_SIZE_KEY.to_string(),
+                    r::Value::Int(size as i64),
+                ))),
         );
         r::Value::Object(obj)
     })
 }
 
+/// Make a cursor for the last entity in each toplevel collection of
+/// `root` so clients can fetch the next page with `after`
+fn cursors<'a>(
+    resolver: &'a StoreResolver,
+    selection_set: &'a a::SelectionSet,
+    root: &'a Node,
+) -> impl Iterator<Item = (String, r::Value)> + 'a {
+    selection_set
+        .interior_fields()
+        .map(|(object_type, fields)| fields.map(move |field| (object_type, field)))
+        .flatten()
+        .filter(|(object_type, field)| {
+            object_type
+                .field(&field.name)
+                .map(sast::is_list_or_non_null_list_field)
+                .unwrap_or(false)
+                && field.argument_value("text").is_none()
+                && id_in_order(field).is_none()
+        })
+        .filter_map(move |(_, field)| {
+            let last = root
+                .children
+                .get(&Word::from(field.response_key()))
+                .and_then(|nodes| nodes.last())?;
+            let value = match field.argument_value("orderBy") {
+                Some(r::Value::Enum(attr)) => last.get(attr),
+                _ => None,
+            };
+            let cursor = Cursor::new(resolver.block_ptr.as_ref(), last.id().ok()?, value);
+            Some((
+                field.response_key().to_string(),
+                r::Value::String(cursor.encode()),
+            ))
+        })
+}
+
 /// Executes the root selection set of a query.
 fn execute_root_selection_set(
     resolver: &StoreResolver,
@@ -508,16 +559,23 @@ fn execute_root_selection_set(
     execute_selection_set(resolver, ctx, make_root_node(), selection_set)
 }
 
+/// Fail if a result of `size` is bigger than the maximum result size
+fn check_result_limit(size: usize) -> Result<(), QueryExecutionError> {
+    let limit = ENV_VARS.graphql.error_result_size;
+    if size > limit {
+        return Err(QueryExecutionError::ResultTooBig {
+            measured: size,
+            limit,
+        });
+    }
+    Ok(())
+}
+
 fn check_result_size<'a>(
     ctx: &'a ExecutionContext<impl Resolver>,
     size: usize,
 ) -> Result<(), QueryExecutionError> {
-    if size > ENV_VARS.graphql.error_result_size {
-        return Err(QueryExecutionError::ResultTooBig(
-            size,
-            ENV_VARS.graphql.error_result_size,
-        ));
-    }
+    check_result_limit(size)?;
     if size > ENV_VARS.graphql.warn_result_size {
         warn!(ctx.logger, "Large query result"; "size" => size, "query_id" => &ctx.query.query_id);
     }
@@ -535,11 +593,13 @@ fn execute_selection_set<'a>(
 
     // Process all field groups in order
     for (object_type, fields) in selection_set.interior_fields() {
-        if let Some(deadline) = ctx.deadline {
-            if deadline < Instant::now() {
-                errors.push(QueryExecutionError::Timeout);
-                break;
+        if ctx.deadline_passed() {
+            // With partial results, we return the entities we have
+            // fetched so far
+            if !ctx.partial_results {
+                errors.push(ctx.timeout());
             }
+            break;
         }
 
         // Filter out parents that do not match the type condition.
@@ -591,6 +651,14 @@ fn execute_selection_set<'a>(
                 collected_columns,
             ) {
                 Ok(children) => {
+                    ctx.memory
+                        .add(children.iter().map(|child| child.weight()).sum())?;
+                    // The children will become part of the parents; if
+                    // they already make the result too big, stop before
+                    // fetching anything for their selection set
+                    let weight = parents.iter().map(|parent| parent.weight()).sum::<usize>()
+                        + children.iter().map(|child| child.weight()).sum::<usize>();
+                    check_result_limit(weight)?;
                     match execute_selection_set(resolver, ctx, children, &field.selection_set) {
                         Ok(children) => {
                             Join::perform(&mut parents, children, field.response_key());
@@ -643,6 +711,7 @@ fn execute_field(
         resolver.block_number(),
         ctx.max_first,
         ctx.max_skip,
+        |first| ctx.page_size(field.response_key(), first),
         ctx.query.query_id.clone(),
         selected_attrs,
     )
@@ -664,9 +733,13 @@ fn fetch(
     block: BlockNumber,
     max_first: u32,
     max_skip: u32,
+    page_size: impl FnOnce(u32) -> u32,
     query_id: String,
     selected_attrs: SelectedAttributes,
 ) -> Result<Vec<Node>, QueryExecutionError> {
+    if let Some(cursor) = Cursor::for_field(field)? {
+        cursor.check_block(store, block)?;
+    }
     let mut query = build_query(
         join.child_type,
         block,
@@ -684,6 +757,9 @@ fn fetch(
         // that causes unnecessary work in the database
         query.order = EntityOrder::Unordered;
     }
+    if multiplicity == ChildMultiplicity::Many {
+        query.range.first = query.range.first.map(page_size);
+    }
 
     query.logger = Some(logger);
     if let Some(r::Value::String(id)) = field.argument_value(ARG_ID.as_str()) {
@@ -693,6 +769,12 @@ fn fetch(
         );
     }
 
+    // Toplevel collections can be returned in the order of the ids in
+    // their `id_in` filter. The store can not sort by that, and we
+    // therefore fetch all of them and only apply `skip` and `first` once
+    // they are sorted. Since that fetches as many entities as there are
+    // ids, there can be no more ids than `first` and `skip` can reach
+    let mut id_order = None;
     if !is_root_node(parents.iter().map(|p| &**p)) {
         // For anything but the root node, restrict the children we select
         // by the parent list
@@ -701,10 +783,65 @@ fn fetch(
             return Ok(vec![]);
         }
         query.collection = EntityCollection::Window(windows);
+    } else if let Some(ids) = id_in_order(field) {
+        if field.argument_value("after").is_some() {
+            return Err(QueryExecutionError::InvalidCursor(
+                "cursors can not be used with collections in the order of their `id_in` filter"
+                    .to_string(),
+            ));
+        }
+        let max_ids = max_first.saturating_add(max_skip);
+        if ids.len() > max_ids as usize {
+            return Err(QueryExecutionError::RangeArgumentsError(
+                "id_in",
+                max_ids,
+                ids.len() as i64,
+            ));
+        }
+        query.order = EntityOrder::Unordered;
+        let range = std::mem::replace(
+            &mut query.range,
+            EntityRange {
+                first: Some(ids.len() as u32),
+                skip: 0,
+            },
+        );
+        id_order = Some((ids, range));
     }
-    store
-        .find_query_values(query)
-        .map(|entities| entities.into_iter().map(|entity| entity.into()).collect())
+    let mut nodes: Vec<Node> = store
+        .find_query_values(query)?
+        .into_iter()
+        .map(|entity| entity.into())
+        .collect();
+    if let Some((ids, range)) = id_order {
+        nodes = sort_by_ids(nodes, &ids, range);
+    }
+    Ok(nodes)
+}
+
+/// Sort `nodes` into the order of their ids in `ids` and return the part
+/// of them that `range` selects
+fn sort_by_ids(mut nodes: Vec<Node>, ids: &[String], range: EntityRange) -> Vec<Node> {
+    let mut positions = HashMap::new();
+    for (pos, id) in ids.iter().enumerate() {
+        positions.entry(id.as_str()).or_insert(pos);
+    }
+    nodes.sort_by_key(|node| {
+        node.get("id")
+            .and_then(|id| id.as_str())
+            .and_then(|id| positions.get(id).copied())
+            .unwrap_or(usize::MAX)
+    });
+    nodes
+        .into_iter()
+        .skip(range.skip as usize)
+        .take(
+            range
+                .first
+                .map(|first| first as usize)
+                .unwrap_or(usize::MAX),
+        )
+        .collect()
 }
 
 #[derive(Debug, Default, Clone)]
@@ -714,6 +851,21 @@ impl SelectedAttributes {
     /// Extract the attributes we should select from `selection_set`. In
     /// particular, disregard derived fields since they are not stored
     fn for_field(field: &a::Field) -> Result<SelectedAttributes, Vec<QueryExecutionError>> {
+        // We need to also select the `orderBy` field if there is one.
+        // Because of how the API Schema is set up, `orderBy` can only have
+        // an enum value
+        let order_by = match field.argument_value("orderBy") {
+            None => None,
+            Some(r::Value::Enum(e)) => Some(e),
+            Some(v) => {
+                return Err(vec![constraint_violation!(
+                    "'orderBy' attribute must be an enum but is {:?}",
+                    v
+                )
+                .into()]);
+            }
+        };
+
         let mut map = BTreeMap::new();
         for (object_type, fields) in field.selection_set.fields() {
             let column_names = fields
@@ -732,32 +884,33 @@ impl SelectedAttributes {
                     }
                 })
                 .collect();
-            map.insert(
-                object_type.name().to_string(),
-                AttributeNames::Select(column_names),
-            );
-        }
-        // We need to also select the `orderBy` field if there is one.
-        // Because of how the API Schema is set up, `orderBy` can only have
-        // an enum value
-        match field.argument_value("orderBy") {
-            None => { /* nothing to do */ }
-            Some(r::Value::Enum(e)) => {
-                for columns in map.values_mut() {
-                    columns.add_str(e);
-                }
-            }
-            Some(v) => {
-                return Err(vec![constraint_violation!(
-                    "'orderBy' attribute must be an enum but is {:?}",
-                    v
-                )
-                .into()]);
+            let mut column_names = AttributeNames::Select(column_names);
+            if let Some(order_by) = order_by {
+                column_names.add_str(Self::order_by_attribute(object_type, order_by));
             }
+            map.insert(object_type.name().to_string(), column_names);
         }
         Ok(SelectedAttributes(map))
     }
 
+    /// The attribute of `object_type` that ordering by `order_by` needs.
+    /// When ordering by an attribute of a referenced entity, that is the
+    /// attribute holding the reference, or the `id` if the reference is a
+    /// derived field
+    fn order_by_attribute<'a>(object_type: &sast::ObjectType, order_by: &'a str) -> &'a str {
+        if sast::get_field(object_type, order_by).is_some() {
+            return order_by;
+        }
+        let name = order_by
+            .split(ORDER_BY_SEPARATOR)
+            .next()
+            .unwrap_or(order_by);
+        match sast::get_field(object_type, name) {
+            Some(field) if !field.is_derived() => name,
+            _ => "id",
+        }
+    }
+
     pub fn get(&mut self, obj_type: &s::ObjectType) -> AttributeNames {
         self.0.remove(&obj_type.name).unwrap_or(AttributeNames::All)
     }
@@ -11,14 +11,29 @@ use graph::{components::store::EntityType, data::graphql::ObjectOrInterface};
 use crate::execution::ast as a;
 use crate::schema::ast as sast;
 
+use super::cursor::Cursor;
 use super::prefetch::SelectedAttributes;
 
+/// Separates the fields in `orderBy` values that order by an attribute of
+/// a referenced entity, like `owner__createdAt`
+pub(crate) const ORDER_BY_SEPARATOR: &str = "__";
+
 #[derive(Debug)]
 enum OrderDirection {
     Ascending,
     Descending,
 }
 
+/// What to order a collection by
+#[derive(Debug)]
+enum OrderBy {
+    /// An attribute of the entities in the collection
+    Attribute(String, ValueType),
+    /// An attribute of an entity that the entities in the collection
+    /// reference
+    Child(EntityOrderByChild),
+}
+
 /// Builds a EntityQuery from GraphQL arguments.
 ///
 /// Panics if `entity` is not present in `schema`.
@@ -53,18 +68,36 @@ pub(crate) fn build_query<'a>(
         query = query.filter(filter);
     }
     let order = match (
-        build_order_by(entity, field)?,
+        build_order_by(entity, field, schema)?,
         build_order_direction(field)?,
     ) {
-        (Some((attr, value_type)), OrderDirection::Ascending) => {
+        (Some(OrderBy::Attribute(attr, value_type)), OrderDirection::Ascending) => {
             EntityOrder::Ascending(attr, value_type)
         }
-        (Some((attr, value_type)), OrderDirection::Descending) => {
+        (Some(OrderBy::Attribute(attr, value_type)), OrderDirection::Descending) => {
             EntityOrder::Descending(attr, value_type)
         }
+        (Some(OrderBy::Child(child)), OrderDirection::Ascending) => {
+            EntityOrder::ChildAscending(child)
+        }
+        (Some(OrderBy::Child(child)), OrderDirection::Descending) => {
+            EntityOrder::ChildDescending(child)
+        }
+        // Without a default order, replicas can return entities in
+        // different orders depending on how they laid out the table
+        (None, _) if ENV_VARS.graphql.disable_default_order => EntityOrder::Unordered,
         (None, _) => EntityOrder::Default,
     };
     query = query.order(order);
+    if let Some(cursor) = Cursor::for_field(field)? {
+        if field.argument_value("text").is_some() {
+            return Err(QueryExecutionError::InvalidCursor(
+                "cursors can not be used with fulltext search".to_string(),
+            ));
+        }
+        let after = cursor.filter(entity, &query.order)?;
+        query.filter = Some(after.and_maybe(query.filter));
+    }
     Ok(query)
 }
 
@@ -310,14 +343,25 @@ fn list_values(value: Value, filter_type: &str) -> Result<Vec<Value>, QueryExecu
 fn build_order_by(
     entity: ObjectOrInterface,
     field: &a::Field,
-) -> Result<Option<(String, ValueType)>, QueryExecutionError> {
+    schema: &ApiSchema,
+) -> Result<Option<OrderBy>, QueryExecutionError> {
     match field.argument_value("orderBy") {
         Some(r::Value::Enum(name)) => {
-            let field = sast::get_field(entity, name).ok_or_else(|| {
-                QueryExecutionError::EntityFieldError(entity.name().to_owned(), name.clone())
-            })?;
+            let field = match sast::get_field(entity, name) {
+                Some(field) => field,
+                None if name.contains(ORDER_BY_SEPARATOR) => {
+                    return build_child_order_by(entity, name, schema)
+                        .map(|child| Some(OrderBy::Child(child)))
+                }
+                None => {
+                    return Err(QueryExecutionError::EntityFieldError(
+                        entity.name().to_owned(),
+                        name.clone(),
+                    ))
+                }
+            };
             sast::get_field_value_type(&field.field_type)
-                .map(|value_type| Some((name.to_owned(), value_type)))
+                .map(|value_type| Some(OrderBy::Attribute(name.to_owned(), value_type)))
                 .map_err(|_| {
                     QueryExecutionError::OrderByNotSupportedError(
                         entity.name().to_owned(),
@@ -326,13 +370,94 @@ fn build_order_by(
                 })
         }
         _ => match field.argument_value("text") {
-            Some(r::Value::Object(filter)) => build_fulltext_order_by_from_object(filter),
+            Some(r::Value::Object(filter)) => build_fulltext_order_by_from_object(filter)
+                .map(|order| order.map(|(attr, value_type)| OrderBy::Attribute(attr, value_type))),
             None => Ok(None),
             _ => Err(QueryExecutionError::InvalidFilterError),
         },
     }
 }
 
+/// The ids in the `id_in` filter of `field` when the query passes an
+/// explicit `orderBy: null` to ask for the entities in the order of those
+/// ids rather than ordered by `id`
+pub(crate) fn id_in_order(field: &a::Field) -> Option<Vec<String>> {
+    if !matches!(field.argument_value("orderBy"), Some(r::Value::Null)) {
+        return None;
+    }
+    match field.argument_value("where") {
+        Some(r::Value::Object(filter)) => match filter.get("id_in") {
+            Some(r::Value::List(ids)) => Some(
+                ids.iter()
+                    .filter_map(|id| match id {
+                        r::Value::String(id) => Some(id.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Resolve an `orderBy` value `<field>__<attribute>`, where `field`
+/// references a single entity, possibly through `@derivedFrom`, and
+/// `attribute` is a scalar attribute of that entity or, in turn, is of the
+/// form `<field>__<attribute>`
+fn build_child_order_by<'a>(
+    entity: ObjectOrInterface<'a>,
+    path: &str,
+    schema: &'a ApiSchema,
+) -> Result<EntityOrderByChild, QueryExecutionError> {
+    let not_supported =
+        || QueryExecutionError::OrderByNotSupportedError(entity.name().to_owned(), path.to_owned());
+    let unknown_field = |entity: ObjectOrInterface, name: &str| {
+        QueryExecutionError::EntityFieldError(entity.name().to_owned(), name.to_owned())
+    };
+
+    let mut names: Vec<_> = path.split(ORDER_BY_SEPARATOR).collect();
+    let attribute = names.pop().ok_or_else(not_supported)?;
+
+    let mut joins = Vec::new();
+    let mut current = entity;
+    for name in names {
+        let field = sast::get_field(current, name).ok_or_else(|| unknown_field(current, name))?;
+        if field.field_type.is_list() {
+            return Err(not_supported());
+        }
+        let child = match schema.object_or_interface(field.field_type.get_base_type()) {
+            Some(ObjectOrInterface::Object(child)) => child,
+            _ => return Err(not_supported()),
+        };
+        let (join_attribute, derived) = if field.is_derived() {
+            let derived_from =
+                sast::get_derived_from_field(child, field).ok_or_else(not_supported)?;
+            (derived_from.name.clone(), true)
+        } else {
+            (field.name.clone(), false)
+        };
+        joins.push(EntityOrderByJoin {
+            entity_type: EntityType::from(child),
+            attribute: join_attribute,
+            derived,
+        });
+        current = child.into();
+    }
+
+    let field =
+        sast::get_field(current, attribute).ok_or_else(|| unknown_field(current, attribute))?;
+    if field.is_derived() {
+        return Err(not_supported());
+    }
+    let value_type = sast::get_field_value_type(&field.field_type).map_err(|_| not_supported())?;
+    Ok(EntityOrderByChild {
+        joins,
+        attribute: attribute.to_owned(),
+        value_type,
+    })
+}
+
 fn build_fulltext_order_by_from_object(
     object: &Object,
 ) -> Result<Option<(String, ValueType)>, QueryExecutionError> {
@@ -5,7 +5,7 @@ use std::sync::Arc;
 use graph::data::value::Object;
 use graph::data::{
     graphql::{object, ObjectOrInterface},
-    schema::META_FIELD_TYPE,
+    schema::{META_FIELD_NAME, META_FIELD_TYPE},
 };
 use graph::prelude::*;
 use graph::{components::store::*, data::schema::BLOCK_FIELD_TYPE};
@@ -77,7 +77,29 @@ impl StoreResolver {
     ) -> Result<Self, QueryExecutionError> {
         let store_clone = store.cheap_clone();
         let block_ptr = Self::locate_block(store_clone.as_ref(), bc, state).await?;
+        Self::at_block_ptr(
+            logger,
+            store,
+            subscription_manager,
+            block_ptr,
+            error_policy,
+            deployment,
+            result_size,
+        )
+        .await
+    }
 
+    /// Create a resolver that looks up entities at `block_ptr`, which must
+    /// have come from `locate_block`
+    pub(crate) async fn at_block_ptr(
+        logger: &Logger,
+        store: Arc<dyn QueryStore>,
+        subscription_manager: Arc<dyn SubscriptionManager>,
+        block_ptr: BlockPtr,
+        error_policy: ErrorPolicy,
+        deployment: DeploymentHash,
+        result_size: Arc<ResultSizeMetrics>,
+    ) -> Result<Self, QueryExecutionError> {
         let has_non_fatal_errors = store
             .has_non_fatal_errors(Some(block_ptr.block_number()))
             .await?;
@@ -102,7 +124,8 @@ impl StoreResolver {
             .unwrap_or(BLOCK_NUMBER_MAX)
     }
 
-    async fn locate_block(
+    /// The block that queries with block constraint `bc` read from
+    pub(crate) async fn locate_block(
         store: &dyn QueryStore,
         bc: BlockConstraint,
         state: &DeploymentState,
@@ -118,19 +141,22 @@ impl StoreResolver {
 
         match bc {
             BlockConstraint::Hash(hash) => {
-                let ptr = store
-                    .block_number(&hash)
-                    .map_err(Into::into)
-                    .and_then(|number| {
-                        number
-                            .ok_or_else(|| {
-                                QueryExecutionError::ValueParseError(
-                                    "block.hash".to_owned(),
-                                    "no block with that hash found".to_owned(),
-                                )
-                            })
-                            .map(|number| BlockPtr::new(hash, number))
-                    })?;
+                // Queries for the latest block are common enough that they
+                // should not cost a lookup
+                if hash == state.latest_block.hash {
+                    return Ok(state.latest_block.cheap_clone());
+                }
+
+                // A hash that is not in the store might have been orphaned
+                // by a reorg since the client got it
+                let ptr = match store.block_number(&hash)? {
+                    Some(number) => BlockPtr::new(hash, number),
+                    None => {
+                        return Err(QueryExecutionError::BlockNotFound {
+                            hash: hash.to_string(),
+                        })
+                    }
+                };
 
                 check_ptr(state, ptr.number)?;
                 Ok(ptr)
@@ -149,6 +175,31 @@ impl StoreResolver {
                 check_ptr(state, number)?;
                 Ok(state.latest_block.cheap_clone())
             }
+            BlockConstraint::BlocksAgo(ago) => {
+                let latest = state.latest_block.number;
+                let number = latest - ago;
+                if number < 0 {
+                    return Err(QueryExecutionError::ValueParseError(
+                        "block.number".to_owned(),
+                        format!(
+                            "the block {} blocks before the latest block {} would be \
+                             before the genesis block",
+                            ago, latest
+                        ),
+                    ));
+                }
+                check_ptr(state, number)?;
+                // Like for `Number`, we do not know the hash of the block
+                // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+                Ok(BlockPtr::from((web3::types::H256::zero(), number as u64)))
+            }
+            BlockConstraint::Snapshot(id) => {
+                let ptr = store
+                    .snapshot_block(&id)?
+                    .ok_or(QueryExecutionError::SnapshotNotFound(id))?;
+                check_ptr(state, ptr.number)?;
+                Ok(ptr)
+            }
             BlockConstraint::Latest => Ok(state.latest_block.cheap_clone()),
         }
     }
@@ -221,7 +272,7 @@ impl Resolver for StoreResolver {
         ctx: &ExecutionContext<Self>,
         selection_set: &a::SelectionSet,
     ) -> Result<Option<r::Value>, Vec<QueryExecutionError>> {
-        super::prefetch::run(self, ctx, selection_set, &self.result_size).map(Some)
+        super::prefetch::run(self, ctx, selection_set).map(Some)
     }
 
     fn resolve_objects(
@@ -293,16 +344,45 @@ impl Resolver for StoreResolver {
         Ok(self.subscription_manager.subscribe_no_payload(entities))
     }
 
-    fn post_process(&self, result: &mut QueryResult) -> Result<(), anyhow::Error> {
+    fn post_process(
+        &self,
+        result: &mut QueryResult,
+        selection_set: &a::SelectionSet,
+    ) -> Result<(), anyhow::Error> {
         // Post-processing is only necessary for queries with indexing errors, and no query errors.
         if !self.has_non_fatal_errors || result.has_errors() {
             return Ok(());
         }
 
-        // Add the "indexing_error" to the response.
+        // Add the "indexing_error" to the response. It is reported once,
+        // no matter how many fields had their data withheld because of it
         assert!(result.errors_mut().is_empty());
         *result.errors_mut() = vec![QueryError::IndexingError];
 
+        // When the query sets the error policy for individual fields, we
+        // withhold the data for exactly the fields that deny errors
+        if !ErrorPolicy::used_in(selection_set)?.is_empty() {
+            let mut data = result.take_data();
+            if let Some(data) = &mut data {
+                for (_, fields) in selection_set.fields() {
+                    for field in fields {
+                        if field.name == META_FIELD_NAME {
+                            continue;
+                        }
+                        let policy = field
+                            .argument_value("subgraphError")
+                            .and_then(|value| ErrorPolicy::try_from(value).ok())
+                            .unwrap_or_else(ErrorPolicy::default_policy);
+                        if let Some(value) = data.get_mut(field.response_key()) {
+                            apply_error_policy(value, field, policy);
+                        }
+                    }
+                }
+            }
+            result.set_data(data);
+            return Ok(());
+        }
+
         match self.error_policy {
             // If indexing errors are denied, we omit results, except for the `_meta` response.
             // Note that the meta field could have been queried under a different response key,
@@ -317,4 +397,50 @@ impl Resolver for StoreResolver {
         }
         Ok(())
     }
+
+    fn record_result(&self, result: &QueryResult) {
+        self.result_size.observe_result(result);
+    }
+}
+
+/// Withhold the data for `field` in `value` if the effective error policy
+/// of the field is `Deny`. The effective policy is the one set with
+/// `@errorPolicy` on the field itself, or `inherited` from its parent if the
+/// field does not have that directive. Fields that deny errors but have
+/// descendants that allow them keep the data of those descendants and
+/// have everything else set to `null`
+fn apply_error_policy(value: &mut r::Value, field: &a::Field, inherited: ErrorPolicy) {
+    let policy = ErrorPolicy::for_field(field)
+        .ok()
+        .flatten()
+        .unwrap_or(inherited);
+    let used = ErrorPolicy::used_in(&field.selection_set).unwrap_or_default();
+    match policy {
+        ErrorPolicy::Allow if !used.contains(&ErrorPolicy::Deny) => return,
+        ErrorPolicy::Deny if !used.contains(&ErrorPolicy::Allow) => {
+            *value = r::Value::Null;
+            return;
+        }
+        _ => {}
+    }
+    match value {
+        r::Value::List(values) => {
+            for value in values {
+                apply_error_policy_to_children(value, field, policy);
+            }
+        }
+        value => apply_error_policy_to_children(value, field, policy),
+    }
+}
+
+fn apply_error_policy_to_children(value: &mut r::Value, field: &a::Field, policy: ErrorPolicy) {
+    if let r::Value::Object(object) = value {
+        for (_, fields) in field.selection_set.fields() {
+            for child in fields {
+                if let Some(value) = object.get_mut(child.response_key()) {
+                    apply_error_policy(value, child, policy);
+                }
+            }
+        }
+    }
 }
@@ -1,7 +1,8 @@
 use std::result::Result;
 use std::time::{Duration, Instant};
 
-use graph::components::store::UnitStream;
+use graph::components::store::{EntityChange, UnitStream};
+use graph::data::store::SubscriptionFilter;
 use graph::{components::store::SubscriptionManager, prelude::*};
 
 use crate::runner::ResultSizeMetrics;
@@ -38,6 +39,14 @@ pub struct SubscriptionExecutionOptions {
     pub max_skip: u32,
 
     pub result_size: Arc<ResultSizeMetrics>,
+
+    /// How long to wait after a change before re-running the query, so
+    /// that rapid changes result in a single update.
+    pub debounce: Duration,
+
+    /// Whether to skip running the query right away and only run it once
+    /// the entities it reads change.
+    pub skip_initial: bool,
 }
 
 pub fn execute_subscription(
@@ -92,10 +101,17 @@ fn create_source_event_stream(
         logger: options.logger.cheap_clone(),
         resolver,
         query,
+        start: Instant::now(),
         deadline: None,
         max_first: options.max_first,
         max_skip: options.max_skip,
         cache_status: Default::default(),
+        tracer: None,
+        partial_results: false,
+        deadline_exceeded: Default::default(),
+        adaptive_first: None,
+        localized_errors: None,
+        memory: Default::default(),
     };
 
     let subscription_type = ctx
@@ -136,13 +152,6 @@ fn map_source_to_response_stream(
     options: SubscriptionExecutionOptions,
     source_stream: UnitStream,
 ) -> QueryResultStream {
-    // Create a stream with a single empty event. By chaining this in front
-    // of the real events, we trick the subscription into executing its query
-    // at least once. This satisfies the GraphQL over Websocket protocol
-    // requirement of "respond[ing] with at least one GQL_DATA message", see
-    // https://github.com/apollographql/subscriptions-transport-ws/blob/master/PROTOCOL.md#gql_data
-    let trigger_stream = futures03::stream::once(async {});
-
     let SubscriptionExecutionOptions {
         logger,
         store,
@@ -153,10 +162,28 @@ fn map_source_to_response_stream(
         max_first,
         max_skip,
         result_size,
+        debounce: debounce_window,
+        skip_initial,
     } = options;
 
-    trigger_stream
-        .chain(source_stream)
+    // Create a stream with a single empty event. By chaining this in front
+    // of the real events, we trick the subscription into executing its query
+    // at least once. This satisfies the GraphQL over Websocket protocol
+    // requirement of "respond[ing] with at least one GQL_DATA message", see
+    // https://github.com/apollographql/subscriptions-transport-ws/blob/master/PROTOCOL.md#gql_data
+    // Clients that asked to skip the initial result only get results for
+    // changes
+    let trigger_stream = futures03::stream::iter((!skip_initial).then(|| ()));
+    let source_stream = if skip_initial {
+        changes_only(source_stream)
+    } else {
+        source_stream
+    };
+
+    let deployment = query.schema.id().clone();
+    let unavailable_subscription_manager = subscription_manager.cheap_clone();
+    let results = trigger_stream
+        .chain(debounce(source_stream, debounce_window))
         .then(move |()| {
             execute_subscription_event(
                 logger.clone(),
@@ -170,9 +197,145 @@ fn map_source_to_response_stream(
             )
             .boxed()
         })
+        .map(recoverable_errors)
+        .boxed();
+    end_when_unavailable(
+        results,
+        unavailable_subscription_manager.as_ref(),
+        deployment,
+    )
+}
+
+/// The events of `source` without the first one. A stream from
+/// `subscribe_no_payload` starts with an event for the current state of
+/// the store before it reports any changes
+pub(crate) fn changes_only(source: UnitStream) -> UnitStream {
+    Box::new(source.skip(1))
+}
+
+/// Wait for `window` after each event from `source` and report all events
+/// that arrived in the meantime as a single event. When a popular entity
+/// changes several times in quick succession, for example right after a
+/// new block has been processed, this keeps all subscriptions watching it
+/// from re-running their query for each of these changes
+pub(crate) fn debounce(source: UnitStream, window: Duration) -> UnitStream {
+    if window.is_zero() {
+        return source;
+    }
+    Box::new(Box::pin(futures03::stream::unfold(
+        Some(source),
+        move |source| async move {
+            let mut source = source?;
+            source.next().await?;
+            tokio::time::sleep(window).await;
+            loop {
+                match source.next().now_or_never() {
+                    Some(Some(())) => continue,
+                    // Report the last changes before ending the stream
+                    Some(None) => return Some(((), None)),
+                    None => return Some(((), Some(source))),
+                }
+            }
+        },
+    )))
+}
+
+/// Report the errors from running the query of a subscription for one
+/// change as recoverable: the subscription keeps running, and the client
+/// gets the result for the next change as usual
+pub(crate) fn recoverable_errors(result: Arc<QueryResult>) -> Arc<QueryResult> {
+    if !result.has_errors() {
+        return result;
+    }
+    // Results can be shared with identical queries that ran at the same
+    // time, and we then have to copy them
+    let mut result = Arc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone());
+    for error in result.errors_mut() {
+        if let QueryError::ExecutionError(e) = error {
+            *error = QueryError::from(SubscriptionError::from(e.clone()));
+        }
+    }
+    Arc::new(result)
+}
+
+/// End the stream of `results` once `deployment` becomes unavailable,
+/// and report that as the last result
+pub(crate) fn end_when_unavailable(
+    results: QueryResultStream,
+    subscription_manager: &dyn SubscriptionManager,
+    deployment: DeploymentHash,
+) -> QueryResultStream {
+    let unavailable = deployment_unavailable(subscription_manager, deployment.clone());
+    let unavailable = futures03::stream::once(unavailable).map(|()| None);
+    futures03::stream::select(results.map(Some), unavailable)
+        .scan(false, move |done, result| {
+            let result = match (*done, result) {
+                (true, _) => None,
+                (false, Some(result)) => Some(result),
+                (false, None) => {
+                    *done = true;
+                    let error = SubscriptionError::DeploymentUnavailable(deployment.clone());
+                    Some(Arc::new(QueryResult::from(QueryError::from(error))))
+                }
+            };
+            futures03::future::ready(result)
+        })
         .boxed()
 }
 
+/// End the stream of `results` with an error as soon as one of them is
+/// larger than `max_size`, so that a single subscription can not flood
+/// its client with huge updates
+pub(crate) fn limit_result_size(
+    results: QueryResultStream,
+    max_size: usize,
+    metrics: Arc<ResultSizeMetrics>,
+) -> QueryResultStream {
+    futures03::stream::unfold(Some(results), move |results| {
+        let metrics = metrics.cheap_clone();
+        async move {
+            let mut results = results?;
+            let result = results.next().await?;
+            if result.result_size <= max_size {
+                return Some((result, Some(results)));
+            }
+            metrics.observe_subscription_dropped();
+            let error = SubscriptionError::Fatal(QueryExecutionError::ResultTooBig {
+                measured: result.result_size,
+                limit: max_size,
+            });
+            Some((Arc::new(QueryResult::from(QueryError::from(error))), None))
+        }
+    })
+    .boxed()
+}
+
+/// Resolve once `deployment` has been unassigned or reassigned. If we can
+/// not watch for assignment changes, never resolve
+fn deployment_unavailable(
+    subscription_manager: &dyn SubscriptionManager,
+    deployment: DeploymentHash,
+) -> impl futures03::Future<Output = ()> + Send + 'static {
+    let mut events = subscription_manager
+        .subscribe(FromIterator::from_iter([SubscriptionFilter::Assignment]))
+        .compat();
+    async move {
+        while let Some(Ok(event)) = events.next().await {
+            let affected = event.changes.iter().any(|change| match change {
+                EntityChange::Assignment {
+                    deployment: locator,
+                    ..
+                } => locator.hash == deployment,
+                EntityChange::Data { .. } => false,
+            });
+            if affected {
+                return;
+            }
+        }
+        futures03::future::pending().await
+    }
+}
+
 async fn execute_subscription_event(
     logger: Logger,
     store: Arc<dyn QueryStore>,
@@ -213,14 +376,22 @@ async fn execute_subscription_event(
     let block_ptr = resolver.block_ptr.clone();
 
     // Create a fresh execution context with deadline.
+    let start = Instant::now();
     let ctx = Arc::new(ExecutionContext {
         logger,
         resolver,
         query,
-        deadline: timeout.map(|t| Instant::now() + t),
+        start,
+        deadline: timeout.map(|t| start + t),
         max_first,
         max_skip,
         cache_status: Default::default(),
+        tracer: None,
+        partial_results: false,
+        deadline_exceeded: Default::default(),
+        adaptive_first: None,
+        localized_errors: None,
+        memory: Default::default(),
     });
 
     let subscription_type = match ctx.query.schema.subscription_type.as_ref() {
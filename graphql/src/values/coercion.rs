@@ -1,8 +1,10 @@
 use crate::schema;
 use graph::prelude::s::{EnumType, InputValue, ScalarType, Type, TypeDefinition};
-use graph::prelude::{q, r, QueryExecutionError};
+use graph::prelude::{q, r, BigDecimal, BigInt, QueryExecutionError};
+use graphql_parser::Pos;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::str::FromStr;
 
 /// A GraphQL value that can be coerced according to a type.
 pub trait MaybeCoercible<T> {
@@ -57,18 +59,106 @@ impl MaybeCoercible<ScalarType> for q::Value {
     }
 }
 
-/// On error, the `value` is returned as `Err(value)`.
+/// Why a value could not be coerced to a type
+#[derive(Debug, PartialEq)]
+pub(crate) enum CoercionError {
+    /// The value does not have the shape of the type; the value is
+    /// returned unchanged
+    Invalid(r::Value),
+    /// A scalar inside the value has the right shape but is not a valid
+    /// value of its type, for example because it is out of range
+    Scalar {
+        scalar_type: String,
+        value: r::Value,
+        reason: String,
+    },
+}
+
+impl CoercionError {
+    /// Turn this into a query error for the value of `argument` at `pos`,
+    /// using `invalid` to report values that do not match the type at all
+    pub(crate) fn into_error(
+        self,
+        pos: Pos,
+        argument: &str,
+        invalid: impl FnOnce(r::Value) -> QueryExecutionError,
+    ) -> QueryExecutionError {
+        match self {
+            CoercionError::Invalid(value) => invalid(value),
+            CoercionError::Scalar {
+                scalar_type,
+                value,
+                reason,
+            } => QueryExecutionError::ScalarCoercion {
+                pos,
+                argument: argument.to_owned(),
+                scalar_type,
+                value: value.to_string(),
+                reason,
+            },
+        }
+    }
+}
+
+/// Explain why `value` is not a valid value of the scalar `scalar_type`.
+/// Returns `None` if the value is valid, or if we can not say anything
+/// more precise than that it has the wrong shape
+fn scalar_error(scalar_type: &str, value: &r::Value) -> Option<String> {
+    match (scalar_type, value) {
+        ("Int", r::Value::Int(n)) if i32::try_from(*n).is_err() => {
+            Some("the value does not fit into a 32-bit signed integer".to_string())
+        }
+        ("Int", r::Value::Float(_)) => {
+            Some("`Int` values must be integers that fit into a 32-bit signed integer".to_string())
+        }
+        ("BigInt", r::Value::Float(_)) => Some(
+            "`BigInt` values that do not fit into a 32-bit signed integer must be passed \
+             as strings"
+                .to_string(),
+        ),
+        ("BigInt", r::Value::String(s)) if BigInt::from_str(s).is_err() => {
+            Some("the value is not a decimal integer".to_string())
+        }
+        ("BigDecimal", r::Value::String(s)) if BigDecimal::from_str(s).is_err() => {
+            Some("the value is not a decimal number".to_string())
+        }
+        ("Bytes", r::Value::String(s))
+            if !s
+                .strip_prefix("0x")
+                .unwrap_or(s)
+                .chars()
+                .all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Some("the value is not a hex string".to_string())
+        }
+        _ => None,
+    }
+}
+
 fn coerce_to_definition<'a>(
     value: r::Value,
     definition: &str,
     resolver: &impl Fn(&str) -> Option<&'a TypeDefinition>,
-) -> Result<r::Value, r::Value> {
-    match resolver(definition).ok_or_else(|| value.clone())? {
+) -> Result<r::Value, CoercionError> {
+    let definition = match resolver(definition) {
+        Some(definition) => definition,
+        None => return Err(CoercionError::Invalid(value)),
+    };
+    match definition {
         // Accept enum values if they match a value in the enum type
-        TypeDefinition::Enum(t) => value.coerce_enum(t),
+        TypeDefinition::Enum(t) => value.coerce_enum(t).map_err(CoercionError::Invalid),
 
         // Try to coerce Scalar values
-        TypeDefinition::Scalar(t) => value.coerce_scalar(t),
+        TypeDefinition::Scalar(t) => {
+            if let Some(reason) = scalar_error(&t.name, &value) {
+                return Err(CoercionError::Scalar {
+                    scalar_type: t.name.clone(),
+                    value,
+                    reason,
+                });
+            }
+            value.coerce_scalar(t).map_err(CoercionError::Invalid)
+        }
 
         // Try to coerce InputObject values
         TypeDefinition::InputObject(t) => match value {
@@ -76,26 +166,28 @@ fn coerce_to_definition<'a>(
                 let object_for_error = r::Value::Object(object.clone());
                 let mut coerced_object = BTreeMap::new();
                 for (name, value) in object {
-                    let def = t
-                        .fields
-                        .iter()
-                        .find(|f| f.name == &*name)
-                        .ok_or_else(|| object_for_error.clone())?;
+                    let def = match t.fields.iter().find(|f| f.name == &*name) {
+                        Some(def) => def,
+                        None => return Err(CoercionError::Invalid(object_for_error)),
+                    };
                     coerced_object.insert(
                         name.clone(),
-                        match coerce_input_value(Some(value), def, resolver) {
-                            Err(_) | Ok(None) => return Err(object_for_error),
-                            Ok(Some(v)) => v,
+                        match coerce_value(value, &def.value_type, resolver) {
+                            Err(CoercionError::Invalid(_)) => {
+                                return Err(CoercionError::Invalid(object_for_error))
+                            }
+                            Err(e) => return Err(e),
+                            Ok(v) => v,
                         },
                     );
                 }
                 Ok(r::Value::object(coerced_object))
             }
-            _ => Err(value),
+            _ => Err(CoercionError::Invalid(value)),
         },
 
         // Everything else remains unimplemented
-        _ => Err(value),
+        _ => Err(CoercionError::Invalid(value)),
     }
 }
 
@@ -139,21 +231,26 @@ pub(crate) fn coerce_input_value<'a>(
     };
 
     Ok(Some(
-        coerce_value(value, &def.value_type, resolver).map_err(|val| {
-            QueryExecutionError::InvalidArgumentError(def.position, def.name.to_owned(), val.into())
+        coerce_value(value, &def.value_type, resolver).map_err(|e| {
+            e.into_error(def.position, &def.name, |val| {
+                QueryExecutionError::InvalidArgumentError(
+                    def.position,
+                    def.name.to_owned(),
+                    val.into(),
+                )
+            })
         })?,
     ))
 }
 
-/// On error, the `value` is returned as `Err(value)`.
 pub(crate) fn coerce_value<'a>(
     value: r::Value,
     ty: &Type,
     resolver: &impl Fn(&str) -> Option<&'a TypeDefinition>,
-) -> Result<r::Value, r::Value> {
+) -> Result<r::Value, CoercionError> {
     match (ty, value) {
         // Null values cannot be coerced into non-null types.
-        (Type::NonNullType(_), r::Value::Null) => Err(r::Value::Null),
+        (Type::NonNullType(_), r::Value::Null) => Err(CoercionError::Invalid(r::Value::Null)),
 
         // Non-null values may be coercible into non-null types
         (Type::NonNullType(_), val) => {
@@ -196,7 +293,7 @@ pub(crate) fn coerce_value<'a>(
         }
 
         // Otherwise the list type is not coercible.
-        (Type::ListType(_), value) => Err(value),
+        (Type::ListType(_), value) => Err(CoercionError::Invalid(value)),
     }
 }
 
@@ -206,7 +303,7 @@ mod tests {
     use graphql_parser::schema::{EnumType, EnumValue, ScalarType, TypeDefinition};
     use graphql_parser::Pos;
 
-    use super::coerce_to_definition;
+    use super::{coerce_to_definition, CoercionError};
 
     #[test]
     fn coercion_using_enum_type_definitions_is_correct() {
@@ -393,6 +490,17 @@ mod tests {
             coerce_to_definition(Value::Int((-1234 as i32).into()), "", &resolver,),
             Ok(Value::String("-1234".to_string()))
         );
+
+        // Numbers that are too big for an `Int` arrive as floats, and
+        // strings must contain an integer
+        assert!(matches!(
+            coerce_to_definition(Value::Float(1e20), "", &resolver),
+            Err(CoercionError::Scalar { scalar_type, .. }) if scalar_type == "BigInt"
+        ));
+        assert!(matches!(
+            coerce_to_definition(Value::String("12.5".to_string()), "", &resolver),
+            Err(CoercionError::Scalar { .. })
+        ));
     }
 
     #[test]
@@ -405,6 +513,16 @@ mod tests {
             coerce_to_definition(Value::String("0x21f".to_string()), "", &resolver,),
             Ok(Value::String("0x21f".to_string()))
         );
+
+        // Malformed hex strings are rejected with a precise reason
+        assert_eq!(
+            coerce_to_definition(Value::String("0xzz".to_string()), "", &resolver,),
+            Err(CoercionError::Scalar {
+                scalar_type: "Bytes".to_string(),
+                value: Value::String("0xzz".to_string()),
+                reason: "the value is not a hex string".to_string(),
+            })
+        );
     }
 
     #[test]
@@ -420,5 +538,15 @@ mod tests {
             coerce_to_definition(Value::Int((-13289123 as i32).into()), "", &resolver,),
             Ok(Value::Int((-13289123 as i32).into()))
         );
+
+        // Values outside of the 32-bit range overflow
+        assert_eq!(
+            coerce_to_definition(Value::Int(i32::MAX as i64 + 1), "", &resolver),
+            Err(CoercionError::Scalar {
+                scalar_type: "Int".to_string(),
+                value: Value::Int(i32::MAX as i64 + 1),
+                reason: "the value does not fit into a 32-bit signed integer".to_string(),
+            })
+        );
     }
 }
@@ -2,6 +2,7 @@
 extern crate pretty_assertions;
 
 use std::sync::Arc;
+use std::time::Instant;
 
 use graph::data::graphql::{object, object_value, ObjectOrInterface};
 use graph::prelude::{
@@ -564,10 +565,13 @@ async fn introspection_query(schema: Schema, query: &str) -> QueryResult {
     let logger = Logger::root(slog::Discard, o!());
     let options = QueryExecutionOptions {
         resolver: MockResolver,
+        start: Instant::now(),
         deadline: None,
         max_first: std::u32::MAX,
         max_skip: std::u32::MAX,
         load_manager: LOAD_MANAGER.clone(),
+        tracer: None,
+        partial_results: false,
     };
 
     let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
@@ -15,8 +15,10 @@ use std::{
 };
 
 use graph::{
-    components::store::DeploymentLocator,
-    data::graphql::{object, object_value},
+    components::store::{
+        DeploymentLocator, ReplicaCandidate, ReplicaId, ReplicaSelector, RoundRobinReplicaSelector,
+    },
+    data::graphql::{effort::LoadManager, object, object_value},
     data::subgraph::schema::SubgraphError,
     data::{
         query::{QueryResults, QueryTarget},
@@ -34,8 +36,8 @@ use graph::{
 use graph_graphql::{prelude::*, subscription::execute_subscription};
 use test_store::{
     deployment_state, execute_subgraph_query_with_deadline, result_size_metrics, revert_block,
-    run_test_sequentially, transact_errors, Store, BLOCK_ONE, GENESIS_PTR, LOAD_MANAGER, LOGGER,
-    METRICS_REGISTRY, STORE, SUBSCRIPTION_MANAGER,
+    run_test_sequentially, transact_errors, Store, SubscriptionManager, BLOCK_ONE, GENESIS_PTR,
+    LOAD_MANAGER, LOGGER, METRICS_REGISTRY, STORE, SUBSCRIPTION_MANAGER,
 };
 
 const NETWORK_NAME: &str = "fake_network";
@@ -243,6 +245,22 @@ async fn insert_test_entities(
     deployment
 }
 
+/// A runner for `store` that uses `load_manager` and the defaults from
+/// the test store for everything else
+fn test_runner(
+    store: Arc<Store>,
+    load_manager: Arc<LoadManager>,
+) -> Arc<GraphQlRunner<Store, SubscriptionManager>> {
+    Arc::new(GraphQlRunner::new(
+        &*LOGGER,
+        store,
+        SUBSCRIPTION_MANAGER.clone(),
+        load_manager,
+        Arc::new(RoundRobinReplicaSelector),
+        METRICS_REGISTRY.clone(),
+    ))
+}
+
 async fn execute_query(loc: &DeploymentLocator, query: &str) -> QueryResult {
     let query = graphql_parser::parse_query(query)
         .expect("invalid test query")
@@ -255,13 +273,7 @@ async fn execute_query_document_with_variables(
     query: q::Document,
     variables: Option<QueryVariables>,
 ) -> QueryResult {
-    let runner = Arc::new(GraphQlRunner::new(
-        &*LOGGER,
-        STORE.clone(),
-        SUBSCRIPTION_MANAGER.clone(),
-        LOAD_MANAGER.clone(),
-        METRICS_REGISTRY.clone(),
-    ));
+    let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
     let target = QueryTarget::Deployment(id.clone());
     let query = Query::new(query, variables);
 
@@ -356,13 +368,7 @@ where
                     .expect("Invalid test query")
                     .into_static();
                 let variables = variables.clone();
-                let runner = Arc::new(GraphQlRunner::new(
-                    &*LOGGER,
-                    STORE.clone(),
-                    SUBSCRIPTION_MANAGER.clone(),
-                    LOAD_MANAGER.clone(),
-                    METRICS_REGISTRY.clone(),
-                ));
+                let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
                 let target = QueryTarget::Deployment(id.clone());
                 let query = Query::new(query, variables);
 
@@ -405,10 +411,21 @@ async fn run_subscription(
         max_first: std::u32::MAX,
         max_skip: std::u32::MAX,
         result_size: result_size_metrics(),
+        debounce: Duration::ZERO,
+        skip_initial: false,
     };
     let schema = STORE.subgraph_store().api_schema(&deployment.hash).unwrap();
 
-    execute_subscription(Subscription { query }, schema.clone(), options)
+    execute_subscription(
+        Subscription {
+            query,
+            name: None,
+            connection_id: None,
+            skip_initial: false,
+        },
+        schema.clone(),
+        options,
+    )
 }
 
 #[test]
@@ -653,6 +670,109 @@ fn can_query_with_child_filter_on_derived_named_type_field() {
     })
 }
 
+#[test]
+fn can_order_by_attribute_of_referenced_entity() {
+    const QUERY: &str = "
+    query {
+        musicians(first: 100, orderBy: mainBand__name) {
+            name
+        }
+        bands(first: 100, orderBy: id) {
+            members(first: 100, orderBy: mainBand__name, orderDirection: desc) {
+                name
+            }
+        }
+    }";
+
+    run_query(QUERY, |result, _| {
+        let exp = object! {
+            musicians: vec![
+                object! { name: "Tom" },
+                object! { name: "John" },
+                object! { name: "Lisa" },
+                object! { name: "Valerie" },
+            ],
+            bands: vec![
+                object! {
+                    members: vec![
+                        object! { name: "Lisa" },
+                        object! { name: "John" },
+                        object! { name: "Tom" },
+                    ]
+                },
+                object! {
+                    members: vec![
+                        object! { name: "John" },
+                        object! { name: "Tom" },
+                    ]
+                },
+            ]
+        };
+
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    })
+}
+
+#[test]
+fn can_order_by_attribute_of_derived_entity() {
+    const QUERY: &str = "
+    query {
+        songStats(first: 100, orderBy: song__title, orderDirection: desc) {
+            played
+        }
+    }";
+
+    run_query(QUERY, |result, _| {
+        let exp = object! {
+            songStats: vec![
+                object! { played: 15 },
+                object! { played: 10 },
+            ]
+        };
+
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    })
+}
+
+#[test]
+fn order_by_attribute_of_referenced_list_is_invalid() {
+    const QUERY: &str = "
+    query musicians($orderBy: Musician_orderBy) {
+      musicians(first: 100, orderBy: $orderBy) {
+        id
+      }
+    }
+";
+
+    run_query(
+        (QUERY, object! { orderBy: "mainBand__members" }),
+        |result, _| {
+            let errors: Vec<_> = result
+                .to_result()
+                .unwrap_err()
+                .into_iter()
+                .map(|e| match e {
+                    QueryError::ExecutionError(QueryExecutionError::InvalidVariable {
+                        name,
+                        reason,
+                    }) => (name, reason),
+                    e => panic!("expected an invalid variable error, got {}", e),
+                })
+                .collect();
+            assert_eq!(
+                errors,
+                vec![(
+                    "orderBy".to_string(),
+                    "`\"mainBand__members\"` is not a valid value of type `Musician_orderBy`"
+                        .to_string()
+                )]
+            );
+        },
+    );
+}
+
 #[test]
 fn root_fragments_are_expanded() {
     const QUERY: &str = r#"
@@ -864,6 +984,24 @@ fn query_complexity() {
     })
 }
 
+#[test]
+fn too_many_block_constraints() {
+    let max = graph::env::ENV_VARS.graphql.max_block_constraints;
+    let fields: Vec<_> = (0..=max)
+        .map(|nr| format!("m{nr}: musicians(block: {{ number: {nr} }}) {{ id }}"))
+        .collect();
+    let query = format!("query {{ {} }}", fields.join(" "));
+
+    run_query(query.as_str(), move |result, _| {
+        match result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::TooManyBlockConstraints(n)) => {
+                assert_eq!(max, n)
+            }
+            _ => panic!("did not catch too many block constraints"),
+        };
+    })
+}
+
 #[test]
 fn query_complexity_subscriptions() {
     run_test_sequentially(|store| async move {
@@ -939,7 +1077,7 @@ fn instant_timeout() {
         .to_result()
         .unwrap_err()[0]
         {
-            QueryError::ExecutionError(QueryExecutionError::Timeout) => (), // Expected
+            QueryError::ExecutionError(QueryExecutionError::Timeout { limit_ms: 0, .. }) => (), // Expected
             _ => panic!("did not time out"),
         };
     })
@@ -981,6 +1119,118 @@ fn variable_defaults() {
     )
 }
 
+#[test]
+fn invalid_variables() {
+    const QUERY: &str = "
+    query musicians($first: Int!, $skip: Int, $orderBy: Musician_orderBy) {
+      musicians(first: $first, skip: $skip, orderBy: $orderBy) {
+        id
+      }
+    }
+";
+
+    run_query(
+        (QUERY, object! { skip: "ten", orderBy: "age" }),
+        |result, _| {
+            let errors: Vec<_> = result
+                .to_result()
+                .unwrap_err()
+                .into_iter()
+                .map(|e| match e {
+                    QueryError::ExecutionError(QueryExecutionError::InvalidVariable {
+                        name,
+                        reason,
+                    }) => (name, reason),
+                    e => panic!("expected an invalid variable error, got {}", e),
+                })
+                .collect();
+            assert_eq!(
+                errors,
+                vec![
+                    (
+                        "first".to_string(),
+                        "a value of type `Int!` is required but none was provided".to_string()
+                    ),
+                    (
+                        "skip".to_string(),
+                        "`\"ten\"` is not a valid value of type `Int`".to_string()
+                    ),
+                    (
+                        "orderBy".to_string(),
+                        "`\"age\"` is not a valid value of type `Musician_orderBy`".to_string()
+                    ),
+                ]
+            );
+        },
+    );
+
+    // Variables are coerced like the scalars of the schema, for example
+    // an `Int` is a valid `ID`
+    const ID_QUERY: &str = "
+    query musician($id: ID!) {
+      musician(id: $id) {
+        name
+      }
+    }
+";
+    run_query((ID_QUERY, object! { id: 1 }), |result, _| {
+        let exp = object! { musician: r::Value::Null };
+        assert_eq!(extract_data!(result), Some(exp));
+    });
+}
+
+#[test]
+fn scalar_coercion_errors_are_precise() {
+    const QUERY: &str = "
+    query musicians($first: Int!) {
+      musicians(first: $first) {
+        id
+      }
+    }
+";
+
+    run_query(
+        (QUERY, object! { first: r::Value::Int(3_000_000_000) }),
+        |result, _| match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::ScalarCoercion {
+                pos,
+                argument,
+                scalar_type,
+                value,
+                ..
+            }) => {
+                assert_eq!(2, pos.line);
+                assert_eq!("first", argument);
+                assert_eq!("Int", scalar_type);
+                assert_eq!("3000000000", value);
+            }
+            e => panic!("unexpected error {}", e),
+        },
+    );
+}
+
+#[test]
+fn malformed_bytes_are_rejected() {
+    const QUERY: &str = "query { songs(where: { id: \"0xzz\" }) { id } }";
+
+    run_query(QUERY, |result, id_type| match id_type {
+        IdType::String => assert!(!result.has_errors()),
+        IdType::Bytes => match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::ScalarCoercion {
+                argument,
+                scalar_type,
+                value,
+                ..
+            }) => {
+                assert_eq!("where", argument);
+                assert_eq!("Bytes", scalar_type);
+                assert!(value.contains("0xzz"), "{}", value);
+            }
+            e => panic!("unexpected error {}", e),
+        },
+    });
+}
+
 #[test]
 fn skip_is_nullable() {
     const QUERY: &str = "
@@ -1047,16 +1297,31 @@ fn ambiguous_derived_from_result() {
     const QUERY: &str = "{ songs(first: 100, orderBy: id) { id band { id } } }";
 
     run_query(QUERY, |result, _| {
-        match &result.to_result().unwrap_err()[0] {
-            QueryError::ExecutionError(QueryExecutionError::AmbiguousDerivedFromResult(
+        let (path, error) = match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::AtPath(path, error)) => {
+                (path.clone(), error.as_ref().clone())
+            }
+            e => panic!("expected the error to have a path, got {}", e),
+        };
+        // The first song is an original song of two bands
+        assert_eq!(
+            path,
+            vec![
+                r::Value::String("songs".to_string()),
+                r::Value::Int(0),
+                r::Value::String("band".to_string())
+            ]
+        );
+        match error {
+            QueryExecutionError::AmbiguousDerivedFromResult(
                 pos,
                 derived_from_field,
                 target_type,
                 target_field,
-            )) => {
+            ) => {
                 assert_eq!(
                     pos,
-                    &Pos {
+                    Pos {
                         line: 1,
                         column: 39
                     }
@@ -1110,6 +1375,67 @@ fn can_filter_by_relationship_fields() {
     })
 }
 
+#[test]
+fn can_keep_the_order_of_id_in_filters() {
+    const QUERY: &str = "
+    query {
+        ordered: musicians(orderBy: null, where: { id_in: [\"m3\", \"m1\", \"m5\", \"m2\"] }) {
+            id
+        }
+        paged: musicians(orderBy: null, skip: 1, first: 1, where: { id_in: [\"m3\", \"m1\", \"m2\"] }) {
+            id
+        }
+        byId: musicians(where: { id_in: [\"m3\", \"m1\"] }) {
+            id
+        }
+    }
+    ";
+
+    run_query(QUERY, |result, _| {
+        // `m5` does not exist and is left out
+        let exp = object! {
+            ordered: vec![object! { id: "m3" }, object! { id: "m1" }, object! { id: "m2" }],
+            paged: vec![object! { id: "m1" }],
+            byId: vec![object! { id: "m1" }, object! { id: "m3" }],
+        };
+
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    })
+}
+
+#[test]
+fn id_in_order_is_limited_by_first_and_skip() {
+    const QUERY: &str = "query {
+        musicians(orderBy: null, first: 1, where: { id_in: [\"m3\", \"m1\", \"m2\"] }) {
+            id
+        }
+    }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+
+        // All of the ids would have to be fetched to sort them, which is
+        // more than `first` and `skip` can reach
+        let results = runner
+            .run_query_with_complexity(query, target, None, None, Some(1), Some(1))
+            .await;
+        let result = first_result(results).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::RangeArgumentsError("id_in", 2, 3)) => {
+                ()
+            }
+            e => panic!("unexpected error {}", e),
+        }
+    })
+}
+
 #[test]
 fn cannot_filter_by_derved_relationship_fields() {
     const QUERY: &str = "
@@ -1176,58 +1502,205 @@ fn subscription_gets_result_even_without_events() {
 }
 
 #[test]
-fn can_use_nested_filter() {
-    const QUERY: &str = "
-    query {
-        musicians(orderBy: id) {
-            name
-            bands(where: { originalSongs: [@S1@, @S3@, @S4@] }) { id }
-        }
-    }
-    ";
+fn live_query_gets_initial_result() {
+    run_test_sequentially(|store| async move {
+        const QUERY: &str = "query @live {
+            musicians(orderBy: id, first: 2) {
+              name
+            }
+          }";
 
-    run_query(QUERY, |result, _| {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+
+        // Live queries are executed once right away, just like
+        // subscriptions
+        let stream = runner
+            .clone()
+            .run_subscription(
+                Subscription {
+                    query,
+                    name: None,
+                    connection_id: None,
+                    skip_initial: false,
+                },
+                target.clone(),
+            )
+            .await
+            .unwrap();
+        let results: Vec<_> = stream
+            .take(1)
+            .collect()
+            .timeout(Duration::from_secs(3))
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        let result = Arc::try_unwrap(results.into_iter().next().unwrap()).unwrap();
+        let data = extract_data!(result).unwrap();
         let exp = object! {
             musicians: vec![
-                object! {
-                    name: "John",
-                    bands: vec![ object! { id: "b2" }]
-                },
-                object! {
-                    name: "Lisa",
-                    bands: Vec::<r::Value>::new(),
-                },
-                object! {
-                    name: "Tom",
-                    bands: vec![ object! { id: "b2" }]
-                },
-                object! {
-                    name: "Valerie",
-                    bands: Vec::<r::Value>::new(),
-                }
+                object! { name: "John" },
+                object! { name: "Lisa" }
             ]
         };
-
-        let data = extract_data!(result).unwrap();
         assert_eq!(data, exp);
+
+        // Clients that only care about changes do not get a result until
+        // something changes
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let mut stream = runner
+            .run_subscription(
+                Subscription {
+                    query,
+                    name: None,
+                    connection_id: None,
+                    skip_initial: true,
+                },
+                target,
+            )
+            .await
+            .unwrap();
+        assert!(stream
+            .next()
+            .timeout(Duration::from_millis(500))
+            .await
+            .is_err());
     })
 }
 
-// see: graphql-bug-compat
 #[test]
-fn ignores_invalid_field_arguments() {
-    // This query has to return all the musicians since `id` is not a
-    // valid argument for the `musicians` field and must therefore be
-    // ignored
-    const QUERY: &str = "query { musicians(id: \"m1\") { id } } ";
+fn stored_subscriptions_run_by_name() {
+    run_test_sequentially(|store| async move {
+        const SUBSCRIPTION: &str = "subscription {
+            musicians(orderBy: id, first: 2) {
+              name
+            }
+          }";
 
-    run_query(QUERY, |result, _| {
-        match &result.to_result() {
-            // Without validations
-            Ok(Some(r::Value::Object(obj))) => match obj.get("musicians").unwrap() {
-                r::Value::List(lst) => {
-                    assert_eq!(4, lst.len());
-                }
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let parse = |text| graphql_parser::parse_query(text).unwrap().into_static();
+        let by_name = |name: &str| Subscription {
+            query: Query::new(
+                q::Document {
+                    definitions: vec![],
+                },
+                None,
+            ),
+            name: Some(name.to_string()),
+            connection_id: None,
+            skip_initial: false,
+        };
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+
+        runner
+            .register_subscription("musicians".to_string(), parse(SUBSCRIPTION))
+            .unwrap();
+        match runner.register_subscription("query".to_string(), parse("{ musicians { id } }")) {
+            Err(QueryExecutionError::NotSupported(_)) => (),
+            res => panic!("expected NotSupported, got {:?}", res),
+        }
+        let stored = runner.stored_subscriptions();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, "musicians");
+
+        let stream = runner
+            .clone()
+            .run_subscription(by_name("musicians"), target.clone())
+            .await
+            .unwrap();
+        let results: Vec<_> = stream
+            .take(1)
+            .collect()
+            .timeout(Duration::from_secs(3))
+            .await
+            .unwrap();
+        // The query cache holds on to the result, so we can not take it out
+        // of its `Arc`
+        let result = results[0].duplicate();
+        let exp = object! {
+            musicians: vec![
+                object! { name: "John" },
+                object! { name: "Lisa" }
+            ]
+        };
+        assert_eq!(extract_data!(result).unwrap(), exp);
+
+        match runner.run_subscription(by_name("unknown"), target).await {
+            Err(SubscriptionError::GraphQLError(e)) => match &e[0] {
+                QueryExecutionError::StoredSubscriptionNotFound(name) => {
+                    assert_eq!(name, "unknown")
+                }
+                e => panic!("expected StoredSubscriptionNotFound, got {:?}", e),
+            },
+            Err(e) => panic!("expected a GraphQL error, got {:?}", e),
+            Ok(_) => panic!("expected an error for an unknown subscription"),
+        }
+    })
+}
+
+#[test]
+fn can_use_nested_filter() {
+    const QUERY: &str = "
+    query {
+        musicians(orderBy: id) {
+            name
+            bands(where: { originalSongs: [@S1@, @S3@, @S4@] }) { id }
+        }
+    }
+    ";
+
+    run_query(QUERY, |result, _| {
+        let exp = object! {
+            musicians: vec![
+                object! {
+                    name: "John",
+                    bands: vec![ object! { id: "b2" }]
+                },
+                object! {
+                    name: "Lisa",
+                    bands: Vec::<r::Value>::new(),
+                },
+                object! {
+                    name: "Tom",
+                    bands: vec![ object! { id: "b2" }]
+                },
+                object! {
+                    name: "Valerie",
+                    bands: Vec::<r::Value>::new(),
+                }
+            ]
+        };
+
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    })
+}
+
+// see: graphql-bug-compat
+#[test]
+fn ignores_invalid_field_arguments() {
+    // This query has to return all the musicians since `id` is not a
+    // valid argument for the `musicians` field and must therefore be
+    // ignored
+    const QUERY: &str = "query { musicians(id: \"m1\") { id } } ";
+
+    run_query(QUERY, |result, _| {
+        match &result.to_result() {
+            // Without validations
+            Ok(Some(r::Value::Object(obj))) => match obj.get("musicians").unwrap() {
+                r::Value::List(lst) => {
+                    assert_eq!(4, lst.len());
+                }
                 _ => panic!("expected a list of values"),
             },
             // With validations
@@ -1456,16 +1929,25 @@ fn query_at_block() {
          up to block number 1 and data for block number 7000 is therefore not yet available";
     const BLOCK_NOT_INDEXED2: &str = "subgraph @DEPLOYMENT@ has only indexed \
          up to block number 1 and data for block number 2 is therefore not yet available";
-    const BLOCK_HASH_NOT_FOUND: &str = "no block with that hash found";
+    const BLOCK_HASH_NOT_FOUND: &str = "is not part of the chain of this deployment";
+    const BEFORE_GENESIS: &str =
+        "the block 2 blocks before the latest block 1 would be before the genesis block";
+    const SNAPSHOT_NOT_FOUND: &str = "there is no snapshot `s1` of this deployment";
 
     musicians_at("number: 7000", Err(BLOCK_NOT_INDEXED), "n7000");
     musicians_at("number: 0", Ok(vec!["m1", "m2"]), "n0");
     musicians_at("number: 1", Ok(vec!["m1", "m2", "m3", "m4"]), "n1");
+    // Negative numbers count back from the latest block, which is block 1
+    musicians_at("number: -1", Ok(vec!["m1", "m2"]), "n-1");
+    musicians_at("number: -2", Err(BEFORE_GENESIS), "n-2");
 
     musicians_at(&hash(&*GENESIS_BLOCK), Ok(vec!["m1", "m2"]), "h0");
     musicians_at(&hash(&*BLOCK_ONE), Ok(vec!["m1", "m2", "m3", "m4"]), "h1");
     musicians_at(&hash(&*BLOCK_TWO), Err(BLOCK_NOT_INDEXED2), "h2");
     musicians_at(&hash(&*BLOCK_THREE), Err(BLOCK_HASH_NOT_FOUND), "h3");
+
+    // The Postgres store does not keep snapshots
+    musicians_at("snapshot: \"s1\"", Err(SNAPSHOT_NOT_FOUND), "s1");
 }
 
 #[test]
@@ -1502,7 +1984,7 @@ fn query_at_block_with_vars() {
          up to block number 1 and data for block number 7000 is therefore not yet available";
     const BLOCK_NOT_INDEXED2: &str = "subgraph @DEPLOYMENT@ has only indexed \
          up to block number 1 and data for block number 2 is therefore not yet available";
-    const BLOCK_HASH_NOT_FOUND: &str = "no block with that hash found";
+    const BLOCK_HASH_NOT_FOUND: &str = "is not part of the chain of this deployment";
 
     musicians_at_nr(7000, Err(BLOCK_NOT_INDEXED), "n7000");
     musicians_at_nr(0, Ok(vec!["m1", "m2"]), "n0");
@@ -1649,6 +2131,25 @@ fn can_query_meta() {
     });
 }
 
+#[test]
+fn meta_deployment_is_discoverable() {
+    // `_meta { deployment }` shows up in introspection so that clients
+    // can find out that they can check which deployment they queried
+    const QUERY: &str = "query { __type(name: \"_Meta_\") { fields { name } } }";
+    run_query(QUERY, |result, _| {
+        let exp = object! {
+            __type: object! {
+                fields: vec![
+                    object! { name: "block" },
+                    object! { name: "deployment" },
+                    object! { name: "hasIndexingErrors" },
+                ]
+            }
+        };
+        assert_eq!(extract_data!(result), Some(exp));
+    });
+}
+
 #[test]
 fn non_fatal_errors() {
     use serde_json::json;
@@ -1744,6 +2245,85 @@ fn non_fatal_errors() {
     })
 }
 
+#[test]
+fn field_error_policy() {
+    use serde_json::json;
+    use test_store::block_store::BLOCK_TWO;
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup(
+            store.as_ref(),
+            "testFieldErrorPolicy",
+            BTreeSet::from_iter(Some(SubgraphFeature::NonFatalErrors)),
+            IdType::String,
+        )
+        .await;
+
+        let err = SubgraphError {
+            subgraph_id: deployment.hash.clone(),
+            message: "cow template handler could not moo event transaction".to_string(),
+            block_ptr: Some(BLOCK_TWO.block_ptr()),
+            handler: Some("handleMoo".to_string()),
+            deterministic: true,
+        };
+
+        transact_errors(&*STORE, &deployment, BLOCK_TWO.block_ptr(), vec![err])
+            .await
+            .unwrap();
+
+        // A nested field can allow errors while the rest of the query denies them
+        let query =
+            "query { musician(id: \"m1\") { id mainBand @errorPolicy(policy: allow) { id } } }";
+        let result = execute_query(&deployment, query).await;
+        let expected = json!({
+            "data": {
+                "musician": {
+                    "id": null,
+                    "mainBand": { "id": "b1" }
+                }
+            },
+            "errors": [
+                {
+                    "message": "indexing_error"
+                }
+            ]
+        });
+        assert_eq!(expected, serde_json::to_value(&result).unwrap());
+
+        // The directive wins over `subgraphError`
+        let query = "query { musician(id: \"m1\", subgraphError: allow) { id mainBand @errorPolicy(policy: deny) { id } } }";
+        let result = execute_query(&deployment, query).await;
+        let expected = json!({
+            "data": {
+                "musician": {
+                    "id": "m1",
+                    "mainBand": null
+                }
+            },
+            "errors": [
+                {
+                    "message": "indexing_error"
+                }
+            ]
+        });
+        assert_eq!(expected, serde_json::to_value(&result).unwrap());
+
+        let query = "query { musician(id: \"m1\", subgraphError: allow) @errorPolicy(policy: deny) { id } }";
+        let result = execute_query(&deployment, query).await;
+        let expected = json!({
+            "data": {
+                "musician": null
+            },
+            "errors": [
+                {
+                    "message": "indexing_error"
+                }
+            ]
+        });
+        assert_eq!(expected, serde_json::to_value(&result).unwrap());
+    })
+}
+
 #[test]
 fn can_query_root_typename() {
     const QUERY: &str = "query { __typename }";
@@ -1754,3 +2334,1338 @@ fn can_query_root_typename() {
         assert_eq!(extract_data!(result), Some(exp));
     })
 }
+
+#[test]
+fn explain_query() {
+    use serde_json::json;
+
+    const QUERY: &str =
+        "query { musicians(first: 2, block: { number: 0 }) { id name } bands { name } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let result = first_result(runner.run_query_explain(query, target).await).await;
+
+        let data = serde_json::to_value(extract_data!(result).unwrap()).unwrap();
+        let plan = &data["plan"];
+        assert!(plan["replica"].is_string());
+        assert!(plan["shapeHash"].is_string());
+        let breakdown = plan["complexityBreakdown"].as_array().unwrap();
+        let mut paths: Vec<_> = breakdown
+            .iter()
+            .map(|field| field["path"].as_str().unwrap())
+            .collect();
+        paths.sort();
+        assert_eq!(vec!["bands", "musicians"], paths);
+
+        let bcs = plan["blockConstraints"].as_array().unwrap();
+        assert_eq!(2, bcs.len());
+
+        assert_eq!(json!({ "number": 0 }), bcs[0]["constraint"]);
+        assert_eq!(json!({ "hash": null, "number": 0 }), bcs[0]["block"]);
+        assert_eq!("deny", bcs[0]["errorPolicy"]);
+        let op = &bcs[0]["operations"][0];
+        assert_eq!("musicians", op["field"]);
+        assert_eq!("Musician", op["entityType"]);
+        assert_eq!(2, op["arguments"]["first"]);
+
+        assert_eq!(json!(null), bcs[1]["constraint"]);
+        assert_eq!(1, bcs[1]["block"]["number"]);
+        assert_eq!("bands", bcs[1]["operations"][0]["field"]);
+    })
+}
+
+#[test]
+fn estimate_query_cost() {
+    const QUERY: &str = "query { musicians(first: 10) { id bands { name } } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let shape_hash = query.shape_hash;
+        let cost = runner.estimate_cost(query, target).await.unwrap();
+        assert!(cost.complexity > 0);
+        assert!(!cost.rejected);
+        assert_eq!(shape_hash, cost.shape_hash);
+
+        // The toplevel collection accounts for all of the complexity, and
+        // the nested collection for a part of it
+        let paths: Vec<_> = cost
+            .breakdown
+            .iter()
+            .map(|field| field.path.as_str())
+            .collect();
+        assert_eq!(vec!["musicians", "musicians.bands"], paths);
+        assert_eq!(cost.complexity, cost.breakdown[0].cost);
+        assert!(cost.breakdown[1].cost < cost.breakdown[0].cost);
+
+        // Queries that the `LoadManager` would decline are reported as
+        // rejected, but estimating their cost leaves the `LoadManager` as
+        // it was
+        let load_manager = Arc::new(LoadManager::new(
+            &*LOGGER,
+            Vec::new(),
+            METRICS_REGISTRY.clone(),
+        ));
+        load_manager.jail(shape_hash, Duration::from_secs(60));
+        let runner = test_runner(STORE.clone(), load_manager.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let cost = runner.estimate_cost(query, target).await.unwrap();
+        assert!(cost.rejected);
+        assert!(load_manager.recent_sheds().is_empty());
+        let jailed: Vec<_> = load_manager
+            .jailed()
+            .iter()
+            .map(|jailed| (jailed.shape_hash, jailed.manual))
+            .collect();
+        assert_eq!(vec![(shape_hash, true)], jailed);
+        let snapshot = load_manager.effort_snapshot(10);
+        assert_eq!(1, snapshot.len());
+        assert_eq!(None, snapshot[0].query);
+    })
+}
+
+#[test]
+fn complexity_ignores_skipped_selections() {
+    const ALL: &str = "query { musicians(first: 10) { id bands(first: 10) { name } } }";
+    const SKIP_INLINE: &str = "query { musicians(first: 10) { id \
+        ... on Musician @skip(if: true) { bands(first: 10) { name } } } }";
+    const INCLUDE_SPREAD: &str =
+        "query { musicians(first: 10) { id ...bands @include(if: false) } } \
+        fragment bands on Musician { bands(first: 10) { name } }";
+    const SKIP_VARIABLE: &str = "query m($skip: Boolean!) { \
+        musicians(first: 10) { id bands(first: 10) @skip(if: $skip) { name } } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let complexity = |text: &str, skip: Option<bool>| {
+            let variables = skip.map(|skip| {
+                QueryVariables::new(HashMap::from_iter(vec![(
+                    "skip".to_string(),
+                    r::Value::Boolean(skip),
+                )]))
+            });
+            let query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                variables,
+            );
+            let target = QueryTarget::Deployment(deployment.hash.clone());
+            let runner = runner.clone();
+            async move {
+                runner
+                    .estimate_cost(query, target)
+                    .await
+                    .unwrap()
+                    .complexity
+            }
+        };
+
+        let all = complexity(ALL, None).await;
+        let skipped = complexity(SKIP_INLINE, None).await;
+        assert!(skipped < all, "{} should be less than {}", skipped, all);
+        assert_eq!(skipped, complexity(INCLUDE_SPREAD, None).await);
+        assert_eq!(skipped, complexity(SKIP_VARIABLE, Some(true)).await);
+        assert_eq!(all, complexity(SKIP_VARIABLE, Some(false)).await);
+    })
+}
+
+#[test]
+fn validate_query_without_executing() {
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let validate = |text: &str| {
+            let query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            let target = QueryTarget::Deployment(deployment.hash.clone());
+            runner.clone().validate(query, target)
+        };
+
+        let errors = validate("query { musicians(first: 10) { id name } }").await;
+        assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+
+        // Depending on whether GraphQL validations are enabled, the unknown
+        // field is caught by validation or when the query is prepared
+        let errors = validate("query { musicians { id doesNotExist } }").await;
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            errors[0],
+            QueryExecutionError::ValidationError(_, _) | QueryExecutionError::UnknownField(_, _, _)
+        ));
+
+        // The variable is required but not passed
+        let errors = validate("query m($first: Int!) { musicians(first: $first) { id } }").await;
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            errors[0],
+            QueryExecutionError::InvalidVariable { .. }
+        ));
+    })
+}
+
+#[test]
+fn automatic_persisted_queries() {
+    use graph::data::query::PersistedQuery;
+    use sha2::{Digest, Sha256};
+
+    const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let sha256_hash = format!("{:x}", Sha256::digest(QUERY.as_bytes()));
+        let with_text = |sha256_hash: &str| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(QUERY).unwrap().into_static(),
+                None,
+            );
+            query.persisted_query = Some(PersistedQuery {
+                sha256_hash: sha256_hash.to_string(),
+                text: Some(QUERY.to_string()),
+            });
+            query
+        };
+
+        // The hash is not known yet
+        let query = Query::persisted(sha256_hash.clone(), None);
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::PersistedQueryNotFound(hash)) => {
+                assert_eq!(&sha256_hash, hash)
+            }
+            e => panic!("unexpected error {}", e),
+        }
+
+        // The hash does not match the query text
+        let result = first_result(
+            runner
+                .clone()
+                .run_query(with_text("deadbeef"), target.clone())
+                .await,
+        )
+        .await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::PersistedQueryHashMismatch(_)) => (),
+            e => panic!("unexpected error {}", e),
+        }
+
+        // Sending text and hash registers the query, after which the hash
+        // alone is enough
+        let exp = object! { musicians: vec![object! { id: "m1" }] };
+        let result = first_result(
+            runner
+                .clone()
+                .run_query(with_text(&sha256_hash), target.clone())
+                .await,
+        )
+        .await;
+        assert_eq!(extract_data!(result), Some(exp.clone()));
+
+        let query = Query::persisted(sha256_hash.clone(), None);
+        let result = first_result(runner.run_query(query, target).await).await;
+        assert_eq!(extract_data!(result), Some(exp));
+    })
+}
+
+#[test]
+fn trace_query() {
+    const QUERY: &str = "query { musicians(first: 2, orderBy: id) { id name } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let mut query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        query.trace = true;
+        let results = runner.clone().run_query(query, target.clone()).await;
+
+        let tracing = serde_json::to_value(results.extension("tracing").unwrap()).unwrap();
+        assert_eq!(1, tracing["version"]);
+        assert!(tracing["startTime"].is_string());
+        assert!(tracing["endTime"].is_string());
+        assert_eq!(1, tracing["blockConstraints"][0]["block"]);
+        assert_eq!("main", tracing["replica"]);
+        assert!(tracing["peakMemory"].as_u64().unwrap() > 0);
+
+        let resolvers = tracing["execution"]["resolvers"].as_array().unwrap();
+        let paths: Vec<_> = resolvers.iter().map(|r| r["path"].to_string()).collect();
+        assert!(paths.contains(&r#"["musicians"]"#.to_string()));
+        assert!(paths.contains(&r#"["musicians",1,"name"]"#.to_string()));
+
+        // Queries that do not ask for tracing do not get it
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let results = runner.run_query(query, target).await;
+        assert!(results.extension("tracing").is_none());
+    })
+}
+
+#[test]
+fn typename_queries_do_not_read_the_store() {
+    use serde_json::json;
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let run = |text: &str| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.trace = true;
+            runner.clone().run_query(query, target.clone())
+        };
+        let store_queries = |results: &QueryResults| {
+            let tracing = serde_json::to_value(results.extension("tracing").unwrap()).unwrap();
+            tracing["execution"]["storeQueries"]
+                .as_array()
+                .unwrap()
+                .len()
+        };
+
+        let results = run("query { __typename }").await;
+        assert!(!results.has_errors());
+        assert_eq!(0, store_queries(&results));
+        let data = serde_json::to_value(&results).unwrap();
+        assert_eq!(json!({ "__typename": "Query" }), data["data"]);
+
+        let results = run("query { __typename __schema { queryType { name } } }").await;
+        assert!(!results.has_errors());
+        assert_eq!(0, store_queries(&results));
+        let data = serde_json::to_value(&results).unwrap();
+        assert_eq!(
+            json!({ "__typename": "Query", "__schema": { "queryType": { "name": "Query" } } }),
+            data["data"]
+        );
+
+        // Selecting data as well takes the normal path
+        let results = run("query { __typename musicians(first: 1, orderBy: id) { id } }").await;
+        assert!(!results.has_errors());
+        assert_eq!(1, store_queries(&results));
+        let data = serde_json::to_value(&results).unwrap();
+        assert_eq!(json!([{ "id": "m1" }]), data["data"]["musicians"]);
+    })
+}
+
+#[test]
+fn schema_hash_mismatch() {
+    const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+    const CACHED: &str = "query @cached(ttl: 60) { musicians(first: 1, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query_with_hash = |text: &str, hash: &str| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.schema_hash = Some(hash.to_string());
+            query
+        };
+        let assert_mismatch = |result: QueryResult| match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::SchemaVersionMismatch {
+                expected,
+                actual,
+            }) => {
+                assert_eq!("QmStaleSchema", expected);
+                assert_eq!(deployment.hash.as_str(), actual);
+            }
+            e => panic!("unexpected error {}", e),
+        };
+
+        // Clients that built the query against the current deployment
+        // get their data
+        let query = query_with_hash(QUERY, deployment.hash.as_str());
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert!(!result.has_errors());
+
+        // Clients with a stale schema are told to fetch it again
+        let query = query_with_hash(QUERY, "QmStaleSchema");
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert_mismatch(result);
+
+        // That also holds when the result for the query is cached
+        let query = query_with_hash(CACHED, deployment.hash.as_str());
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert!(!result.has_errors());
+        let query = query_with_hash(CACHED, "QmStaleSchema");
+        let result = first_result(runner.run_query(query, target).await).await;
+        assert_mismatch(result);
+    })
+}
+
+#[test]
+fn conditional_queries() {
+    use serde_json::json;
+
+    const QUERY: &str = "query { musicians(first: 2, orderBy: id) { id } }";
+    const OTHER: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let run = |text: &str, if_none_match: Vec<String>| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.if_none_match = if_none_match;
+            runner.clone().run_query(query, target.clone())
+        };
+
+        let results = run(QUERY, vec![]).await;
+        assert!(!results.has_errors());
+        let etag = results
+            .etag()
+            .expect("successful results have an ETag")
+            .to_string();
+
+        // The ETag depends on the values of the arguments, not just the
+        // shape of the query
+        assert_eq!(Some(etag.as_str()), run(QUERY, vec![]).await.etag());
+        assert_ne!(Some(etag.as_str()), run(OTHER, vec![]).await.etag());
+
+        // Clients that already have the response are told that it has not
+        // changed
+        let results = run(QUERY, vec!["stale".to_string(), etag.clone()]).await;
+        assert!(results.is_not_modified());
+        assert_eq!(Some(etag.as_str()), results.etag());
+
+        let results = run(QUERY, vec!["stale".to_string()]).await;
+        assert!(!results.is_not_modified());
+        assert_eq!(Some(etag.as_str()), results.etag());
+        let data = serde_json::to_value(&results).unwrap();
+        assert_eq!(
+            json!([{ "id": "m1" }, { "id": "m2" }]),
+            data["data"]["musicians"]
+        );
+    })
+}
+
+#[test]
+fn network_mismatch() {
+    const QUERY: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+    const CACHED: &str = "query @cached(ttl: 60) { musicians(first: 1, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query_for_network = |text: &str, network: &str| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.network = Some(network.to_string());
+            query
+        };
+        let assert_mismatch = |result: QueryResult| match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::NetworkMismatch {
+                expected,
+                actual,
+            }) => {
+                assert_eq!("other_network", expected);
+                assert_eq!(NETWORK_NAME, actual);
+            }
+            e => panic!("unexpected error {}", e),
+        };
+
+        // Clients that name the network of the deployment get their data
+        let query = query_for_network(QUERY, NETWORK_NAME);
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert!(!result.has_errors());
+
+        // Clients that expect a different network are told which network
+        // the deployment indexes
+        let query = query_for_network(QUERY, "other_network");
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert_mismatch(result);
+
+        // That also holds when the result for the query is cached
+        let query = query_for_network(CACHED, NETWORK_NAME);
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert!(!result.has_errors());
+        let query = query_for_network(CACHED, "other_network");
+        let result = first_result(runner.run_query(query, target).await).await;
+        assert_mismatch(result);
+    })
+}
+
+#[test]
+fn cached_directive_caches_results() {
+    const CACHED: &str = "query @cached(ttl: 60) { musicians(first: 2, orderBy: id) { id } }";
+    const UNCACHED: &str = "query { musicians(first: 2, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let run = |text: &'static str| {
+            let runner = runner.clone();
+            let target = target.clone();
+            async move {
+                let query = Query::new(
+                    graphql_parser::parse_query(text).unwrap().into_static(),
+                    None,
+                );
+                let results = runner.run_query(query, target).await;
+                let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+                (first_result(results).await, meta)
+            }
+        };
+
+        let exp = object! { musicians: vec![object! { id: "m1" }, object! { id: "m2" }] };
+        let (result, meta) = run(CACHED).await;
+        assert_eq!(extract_data!(result), Some(exp.clone()));
+        assert_eq!(false, meta["cached"]);
+
+        let (result, meta) = run(CACHED).await;
+        assert_eq!(extract_data!(result), Some(exp.clone()));
+        assert_eq!(true, meta["cached"]);
+
+        // Queries without the directive are not cached
+        let (result, meta) = run(UNCACHED).await;
+        assert_eq!(extract_data!(result), Some(exp));
+        assert!(meta.get("cached").is_none());
+    })
+}
+
+#[test]
+fn introspection_bypasses_load_manager() {
+    const INTROSPECTION: &str = "query { __schema { queryType { name } } }";
+    const MIXED: &str = "query { __schema { queryType { name } } musicians { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let parse = |text: &str| graphql_parser::parse_query(text).unwrap().into_static();
+        // The load manager rejects both queries, just like it would if they
+        // had been jailed
+        let load_manager = Arc::new(LoadManager::new(
+            &*LOGGER,
+            vec![Arc::new(parse(INTROSPECTION)), Arc::new(parse(MIXED))],
+            METRICS_REGISTRY.clone(),
+        ));
+        let runner = test_runner(STORE.clone(), load_manager);
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+
+        let query = Query::new(parse(INTROSPECTION), None);
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        let exp = object! { __schema: object! { queryType: object! { name: "Query" } } };
+        assert_eq!(extract_data!(result), Some(exp));
+
+        // Queries that also ask for entities go through the load manager
+        let query = Query::new(parse(MIXED), None);
+        let result = first_result(runner.run_query(query, target).await).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::TooExpensive) => (),
+            e => panic!("expected the query to be rejected but got {:?}", e),
+        }
+    })
+}
+
+#[test]
+fn batch_results_are_independent() {
+    const MUSICIANS: &str = "query { musicians(first: 2, orderBy: id) { id } }";
+    const BLOCKED: &str = "query { bands(orderBy: id) { id } }";
+    const INVALID: &str = "query { musicians { doesNotExist } }";
+    const BY_NAME: &str = "query { musicians(first: 2, orderBy: name) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let parse = |text: &str| graphql_parser::parse_query(text).unwrap().into_static();
+        let load_manager = Arc::new(LoadManager::new(
+            &*LOGGER,
+            vec![Arc::new(parse(BLOCKED))],
+            METRICS_REGISTRY.clone(),
+        ));
+        let runner = test_runner(STORE.clone(), load_manager);
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+
+        let queries = [MUSICIANS, BLOCKED, INVALID, MUSICIANS]
+            .iter()
+            .map(|text| (Query::new(parse(text), None), target.clone()))
+            .collect();
+        let mut results = runner.clone().run_query_batch(queries).await.into_iter();
+        assert_eq!(4, results.len());
+
+        let exp = object! { musicians: vec![object! { id: "m1" }, object! { id: "m2" }] };
+        let result = first_result(results.next().unwrap()).await;
+        assert_eq!(extract_data!(result), Some(exp.clone()));
+
+        // The load manager decides about each query on its own
+        let result = first_result(results.next().unwrap()).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::TooExpensive) => (),
+            e => panic!("expected the query to be rejected but got {:?}", e),
+        }
+
+        let result = first_result(results.next().unwrap()).await;
+        assert!(result.has_errors());
+
+        // Failing queries do not affect the rest of the batch
+        let result = first_result(results.next().unwrap()).await;
+        assert_eq!(extract_data!(result), Some(exp));
+
+        // The limits of the caller apply to every query in the batch
+        let queries = vec![(Query::new(parse(BY_NAME), None), target.clone())];
+        let mut results = runner
+            .run_query_batch_with_complexity(queries, None, None, Some(1), None)
+            .await
+            .into_iter();
+        let result = first_result(results.next().unwrap()).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::RangeArgumentsError("first", 1, 2)) => {
+                ()
+            }
+            e => panic!("expected `first` to be out of range but got {:?}", e),
+        }
+    })
+}
+
+#[test]
+fn multi_deployment_results_are_per_target() {
+    const QUERY: &str = "query { musicians(first: 2, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let missing = QueryTarget::Deployment(DeploymentHash::new("QmMissing").unwrap());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+
+        let mut results = runner
+            .run_query_multi(query, vec![target.clone(), missing.clone()])
+            .await;
+        assert_eq!(2, results.len());
+
+        let exp = object! { musicians: vec![object! { id: "m1" }, object! { id: "m2" }] };
+        let result = first_result(results.remove(&target).unwrap()).await;
+        assert_eq!(extract_data!(result), Some(exp));
+
+        // A target that fails does not affect the others
+        let result = first_result(results.remove(&missing).unwrap()).await;
+        assert!(result.has_errors());
+    })
+}
+
+#[test]
+fn replica_selector_chooses_replica() {
+    const QUERY: &str = "query { musicians(first: 2, orderBy: id) { id name } }";
+
+    /// Sends all queries to the main replica and remembers which queries
+    /// it was asked about
+    #[derive(Default)]
+    struct MainSelector {
+        shape_hashes: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl ReplicaSelector for MainSelector {
+        fn select(
+            &self,
+            shape_hash: u64,
+            candidates: &[ReplicaCandidate],
+            _: ReplicaId,
+        ) -> ReplicaId {
+            assert!(candidates.iter().any(|c| c.id == ReplicaId::Main));
+            self.shape_hashes.lock().unwrap().push(shape_hash);
+            ReplicaId::Main
+        }
+    }
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let selector = Arc::new(MainSelector::default());
+        let runner = Arc::new(GraphQlRunner::new(
+            &*LOGGER,
+            STORE.clone(),
+            SUBSCRIPTION_MANAGER.clone(),
+            LOAD_MANAGER.clone(),
+            selector.clone(),
+            METRICS_REGISTRY.clone(),
+        ));
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let shape_hash = query.shape_hash;
+        let result = first_result(runner.clone().run_query(query, target.clone()).await).await;
+        assert!(!result.has_errors());
+        assert_eq!(vec![shape_hash], *selector.shape_hashes.lock().unwrap());
+
+        // Queries that ask for the primary do not consult the selector
+        let mut query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        query.force_primary = true;
+        let result = first_result(runner.run_query(query, target).await).await;
+        assert!(!result.has_errors());
+        assert_eq!(1, selector.shape_hashes.lock().unwrap().len());
+    })
+}
+
+#[test]
+fn block_pointers_in_extensions() {
+    use serde_json::json;
+
+    const QUERY: &str = "query { musicians(block: { number: 0 }) { id } bands { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        let results = runner.run_query(query, target).await;
+
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let blocks = meta["blocks"].as_array().unwrap();
+        assert_eq!(2, blocks.len());
+        assert_eq!(json!({ "hash": null, "number": 0 }), blocks[0]);
+        assert_eq!(1, blocks[1]["number"]);
+        assert!(blocks[1]["hash"].is_string());
+    })
+}
+
+#[test]
+fn sync_status_in_extensions() {
+    const QUERY: &str = "query { musicians { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+
+        // Clients only get the sync status when they ask for it
+        let results = runner
+            .clone()
+            .run_query(query.clone(), target.clone())
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        assert!(meta.get("block").is_none());
+
+        let mut query = query;
+        query.sync_status = true;
+        let results = runner.run_query(query, target).await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let block = &meta["block"];
+        assert_eq!(1, block["number"]);
+        assert!(block["hash"].is_string());
+        assert_eq!(false, block["hasIndexingErrors"]);
+    })
+}
+
+#[test]
+fn result_size_in_extensions() {
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = |text: &str, result_size: bool| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.result_size = result_size;
+            query
+        };
+
+        // Clients only get the result size when they ask for it
+        let results = runner
+            .clone()
+            .run_query(query("query { musicians { id } }", false), target.clone())
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        assert!(meta.get("resultSize").is_none());
+
+        let results = runner
+            .clone()
+            .run_query(
+                query("query { musicians(first: 1) { id } }", true),
+                target.clone(),
+            )
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let small = meta["resultSize"].as_u64().unwrap();
+        assert!(small > 0);
+
+        let results = runner
+            .run_query(query("query { musicians { id } }", true), target)
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let large = meta["resultSize"].as_u64().unwrap();
+        assert!(large > small);
+    })
+}
+
+#[test]
+fn cursor_pagination() {
+    const QUERY: &str = "musicians(first: 2, orderBy: name, orderDirection: desc";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = |text: String| {
+            Query::new(
+                graphql_parser::parse_query(&text).unwrap().into_static(),
+                None,
+            )
+        };
+
+        let results = runner
+            .clone()
+            .run_query(
+                query(format!("query {{ {}) {{ id }} }}", QUERY)),
+                target.clone(),
+            )
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let cursor = meta["cursors"]["musicians"].as_str().unwrap().to_string();
+        let exp = object! { musicians: vec![object! { id: "m4" }, object! { id: "m3" }] };
+        assert_eq!(extract_data!(first_result(results).await), Some(exp));
+
+        let results = runner
+            .clone()
+            .run_query(
+                query(format!(
+                    "query {{ {}, after: \"{}\") {{ id }} }}",
+                    QUERY, cursor
+                )),
+                target.clone(),
+            )
+            .await;
+        let exp = object! { musicians: vec![object! { id: "m2" }, object! { id: "m1" }] };
+        assert_eq!(extract_data!(first_result(results).await), Some(exp));
+
+        let results = runner
+            .clone()
+            .run_query(
+                query(format!("query {{ {}, after: \"0xbad\") {{ id }} }}", QUERY)),
+                target.clone(),
+            )
+            .await;
+        match &first_result(results).await.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::InvalidCursor(_)) => (),
+            e => panic!("expected an invalid cursor error but got {:?}", e),
+        }
+
+        // Pagination is pinned to the block of the first page
+        let results = runner
+            .clone()
+            .run_query(
+                query(format!(
+                    "query {{ {}, block: {{ number: 0 }}) {{ id }} }}",
+                    QUERY
+                )),
+                target.clone(),
+            )
+            .await;
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let cursor = meta["cursors"]["musicians"].as_str().unwrap().to_string();
+        let results = runner
+            .run_query(
+                query(format!(
+                    "query {{ {}, after: \"{}\") {{ id }} }}",
+                    QUERY, cursor
+                )),
+                target,
+            )
+            .await;
+        match &first_result(results).await.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::InvalidCursor(msg)) => {
+                assert!(msg.contains("pinned to block 0"))
+            }
+            e => panic!("expected an invalid cursor error but got {:?}", e),
+        }
+    })
+}
+
+#[test]
+fn concurrent_identical_queries() {
+    const QUERY: &str = "query { musicians(first: 2, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+
+        // Whether or not the two queries get coalesced, they must both
+        // see the same result
+        let (res1, res2) = graph::prelude::futures03::join!(
+            runner.clone().run_query(query.clone(), target.clone()),
+            runner.clone().run_query(query, target)
+        );
+        let exp = object! { musicians: vec![object! { id: "m1" }, object! { id: "m2" }] };
+        assert_eq!(extract_data!(first_result(res1).await), Some(exp.clone()));
+        assert_eq!(extract_data!(first_result(res2).await), Some(exp));
+    })
+}
+
+#[test]
+fn streamed_query_pages() {
+    const QUERY: &str = "query { musicians(first: 3, orderBy: id) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = |text: &str| {
+            Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            )
+        };
+
+        let pages = runner
+            .clone()
+            .run_query_stream(query(QUERY), target.clone())
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        let pages = pages
+            .iter()
+            .map(|page| extract_data!(page.duplicate()))
+            .collect::<Vec<_>>();
+        let exp = vec![
+            Some(object! { musicians: vec![
+                object! { id: "m1" }, object! { id: "m2" }, object! { id: "m3" }
+            ] }),
+            Some(object! { musicians: vec![object! { id: "m4" }] }),
+        ];
+        assert_eq!(exp, pages);
+
+        // Only queries with a single toplevel collection can be streamed
+        let pages = runner
+            .run_query_stream(query("query { musicians { id } bands { id } }"), target)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(1, pages.len());
+        match &pages[0].duplicate().to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::NotSupported(_)) => (),
+            e => panic!("expected a not supported error but got {:?}", e),
+        }
+    })
+}
+
+#[test]
+fn query_allowlist() {
+    const ALLOWED: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+    const OTHER: &str = "query { bands(first: 1, orderBy: id) { id } }";
+    const INTROSPECTION: &str = "query { __schema { queryType { name } } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = |text: &str| {
+            Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            )
+        };
+        let runner = |introspection| {
+            Arc::new(
+                GraphQlRunner::new(
+                    &*LOGGER,
+                    STORE.clone(),
+                    SUBSCRIPTION_MANAGER.clone(),
+                    LOAD_MANAGER.clone(),
+                    Arc::new(RoundRobinReplicaSelector),
+                    METRICS_REGISTRY.clone(),
+                )
+                .with_query_allowlist(Some(QueryAllowlist::new(
+                    vec![query(ALLOWED).shape_hash],
+                    introspection,
+                ))),
+            )
+        };
+        let not_allowed = |result: QueryResult| match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::QueryNotAllowed(_)) => (),
+            e => panic!("expected the query to be rejected but got {:?}", e),
+        };
+
+        let strict = runner(false);
+        let result = first_result(
+            strict
+                .clone()
+                .run_query(query(ALLOWED), target.clone())
+                .await,
+        )
+        .await;
+        let exp = object! { musicians: vec![object! { id: "m1" }] };
+        assert_eq!(extract_data!(result), Some(exp));
+
+        not_allowed(
+            first_result(strict.clone().run_query(query(OTHER), target.clone()).await).await,
+        );
+        not_allowed(
+            first_result(strict.run_query(query(INTROSPECTION), target.clone()).await).await,
+        );
+
+        // Introspection is exempt from the allowlist when configured
+        let lenient = runner(true);
+        let result = first_result(
+            lenient
+                .clone()
+                .run_query(query(INTROSPECTION), target.clone())
+                .await,
+        )
+        .await;
+        assert!(!result.has_errors());
+        not_allowed(first_result(lenient.run_query(query(OTHER), target).await).await);
+    })
+}
+
+#[test]
+fn field_authorizer_forbids_fields() {
+    const NAMES: &str = "query { musicians(first: 1, orderBy: id) { id name } }";
+    const IDS: &str = "query { musicians(first: 1, orderBy: id) { id } }";
+
+    /// Keeps guests from seeing the names of musicians
+    struct NoNamesForGuests;
+
+    impl FieldAuthorizer for NoNamesForGuests {
+        fn authorize(
+            &self,
+            caller: Option<&str>,
+            selection_set: &SelectionSet,
+        ) -> Result<(), Vec<QueryExecutionError>> {
+            if caller != Some("guest") {
+                return Ok(());
+            }
+            let errors: Vec<_> = selection_set
+                .fields()
+                .flat_map(|(_, fields)| fields)
+                .filter(|field| field.name == "musicians")
+                .flat_map(|field| field.selection_set.fields())
+                .flat_map(|(_, fields)| fields)
+                .filter(|field| field.name == "name")
+                .map(|_| QueryExecutionError::FieldForbidden {
+                    path: vec!["musicians".to_string(), "name".to_string()],
+                })
+                .collect();
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        }
+    }
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let runner = Arc::new(
+            GraphQlRunner::new(
+                &*LOGGER,
+                STORE.clone(),
+                SUBSCRIPTION_MANAGER.clone(),
+                LOAD_MANAGER.clone(),
+                Arc::new(RoundRobinReplicaSelector),
+                METRICS_REGISTRY.clone(),
+            )
+            .with_field_authorizer(Arc::new(NoNamesForGuests)),
+        );
+        let run = |text: &str, caller: Option<&str>| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            query.caller = caller.map(str::to_string);
+            let runner = runner.clone();
+            let target = target.clone();
+            async move { first_result(runner.run_query(query, target).await).await }
+        };
+
+        let result = run(NAMES, Some("guest")).await;
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::FieldForbidden { path }) => {
+                assert_eq!(&vec!["musicians".to_string(), "name".to_string()], path)
+            }
+            e => panic!("expected the field to be forbidden but got {:?}", e),
+        }
+
+        let exp = object! { musicians: vec![object! { id: "m1" }] };
+        assert_eq!(extract_data!(run(IDS, Some("guest")).await), Some(exp));
+
+        let exp = object! { musicians: vec![object! { id: "m1", name: "John" }] };
+        assert_eq!(
+            extract_data!(run(NAMES, Some("member")).await),
+            Some(exp.clone())
+        );
+        assert_eq!(extract_data!(run(NAMES, None).await), Some(exp));
+    })
+}
+
+#[test]
+fn block_constraints_at_the_same_block_share_one_execution() {
+    use serde_json::json;
+
+    // `musicians` without a constraint and with `number_gte: 1` both read
+    // from the latest block, while `bands` reads from block 0
+    const QUERY: &str = "query {
+        first: musicians(first: 1, orderBy: id) { id }
+        bands(first: 1, orderBy: id, block: { number: 0 }) { id }
+        second: musicians(first: 1, skip: 1, orderBy: id, block: { number_gte: 1 }) { id }
+    }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let mut query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        query.trace = true;
+        let results = runner.run_query(query, target).await;
+        assert!(!results.has_errors());
+
+        // Three block constraints, but only two trips to the store
+        let tracing = serde_json::to_value(results.extension("tracing").unwrap()).unwrap();
+        let executions = tracing["blockConstraints"].as_array().unwrap();
+        assert_eq!(2, executions.len());
+        assert_eq!(1, executions[0]["block"]);
+        assert_eq!(0, executions[1]["block"]);
+
+        // Every block constraint still reports its own block, and the
+        // fields appear in the order of the query
+        let meta = serde_json::to_value(results.extension("_meta").unwrap()).unwrap();
+        let blocks = meta["blocks"].as_array().unwrap();
+        let numbers = blocks
+            .iter()
+            .map(|block| block["number"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!(1), json!(0), json!(1)], numbers);
+
+        let text = serde_json::to_string(&results).unwrap();
+        let first = text.find("\"first\"").unwrap();
+        let bands = text.find("\"bands\"").unwrap();
+        let second = text.find("\"second\"").unwrap();
+        assert!(first < bands && bands < second, "{}", text);
+
+        let data = serde_json::to_value(&results).unwrap();
+        assert_eq!(json!([{ "id": "m1" }]), data["data"]["first"]);
+        assert_eq!(json!([{ "id": "b1" }]), data["data"]["bands"]);
+        assert_eq!(json!([{ "id": "m2" }]), data["data"]["second"]);
+    })
+}
+
+#[test]
+fn queries_for_future_blocks_only_wait_when_allowed() {
+    const QUERY: &str = "query { musicians(block: { number: 1000 }) { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let mut query = Query::new(
+            graphql_parser::parse_query(QUERY).unwrap().into_static(),
+            None,
+        );
+        // `GRAPH_GRAPHQL_MAX_BLOCK_WAIT` is not set, and the query
+        // therefore fails right away even though it would wait
+        query.wait_for_block = Some(Duration::from_secs(30));
+
+        let start = Instant::now();
+        let result = first_result(runner.run_query(query, target).await).await;
+        assert!(start.elapsed() < Duration::from_secs(30));
+        match &result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::ValueParseError(field, message)) => {
+                assert_eq!("block.number", field);
+                assert!(message.contains("not yet available"), "{}", message);
+            }
+            e => panic!("expected the block to be unavailable but got {:?}", e),
+        }
+    })
+}
+
+#[test]
+fn prepared_queries_rebind_variables() {
+    use serde_json::json;
+
+    const QUERY: &str = "query m($id: ID!) { musician(id: $id) { id } }";
+    const LITERAL_M1: &str = "query { musician(id: \"m1\") { id } }";
+    const LITERAL_M2: &str = "query { musician(id: \"m2\") { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let musician = |text: &str, id: Option<&str>| {
+            let variables = id.map(|id| {
+                QueryVariables::new(HashMap::from_iter(vec![(
+                    "id".to_string(),
+                    r::Value::String(id.to_string()),
+                )]))
+            });
+            let query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                variables,
+            );
+            let target = QueryTarget::Deployment(deployment.hash.clone());
+            let runner = runner.clone();
+            async move {
+                let result = first_result(runner.run_query(query, target).await).await;
+                let data = serde_json::to_value(extract_data!(result).unwrap()).unwrap();
+                data["musician"]["id"].clone()
+            }
+        };
+
+        // The second time around, the query is prepared already, but its
+        // variables are still the ones it was sent with
+        assert_eq!(json!("m1"), musician(QUERY, Some("m1")).await);
+        assert_eq!(json!("m2"), musician(QUERY, Some("m2")).await);
+        // Queries that only differ in their literal values can have the
+        // same shape, but are prepared separately
+        assert_eq!(json!("m1"), musician(LITERAL_M1, None).await);
+        assert_eq!(json!("m2"), musician(LITERAL_M2, None).await);
+    })
+}
+
+#[test]
+fn prepared_queries_without_query_text() {
+    use serde_json::json;
+
+    const LITERAL_M1: &str = "query { musician(id: \"m1\") { id } }";
+    const LITERAL_M2: &str = "query { musician(id: \"m2\") { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = test_runner(STORE.clone(), LOAD_MANAGER.clone());
+        let musician = |text: &str| {
+            let mut query = Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            );
+            // This is the text that `Query::new` uses when neither gql
+            // logging nor silent validations are turned on
+            query.query_text = Arc::new("(gql logging turned off)".to_owned());
+            let target = QueryTarget::Deployment(deployment.hash.clone());
+            let runner = runner.clone();
+            async move {
+                let result = first_result(runner.run_query(query, target).await).await;
+                let data = serde_json::to_value(extract_data!(result).unwrap()).unwrap();
+                data["musician"]["id"].clone()
+            }
+        };
+
+        assert_eq!(json!("m1"), musician(LITERAL_M1).await);
+        assert_eq!(json!("m2"), musician(LITERAL_M2).await);
+    })
+}
+
+#[test]
+fn ignore_unknown_arguments() {
+    use serde_json::json;
+
+    const UNKNOWN: &str = "query { musicians(id: \"m1\", first: 2, orderBy: id) { id } }";
+    const WRONG_VALUE: &str = "query { musicians(first: \"two\") { id } }";
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup_readonly(store.as_ref()).await;
+        let runner = Arc::new(
+            GraphQlRunner::new(
+                &*LOGGER,
+                STORE.clone(),
+                SUBSCRIPTION_MANAGER.clone(),
+                LOAD_MANAGER.clone(),
+                Arc::new(RoundRobinReplicaSelector),
+                METRICS_REGISTRY.clone(),
+            )
+            .with_ignore_unknown_arguments(true),
+        );
+        let target = QueryTarget::Deployment(deployment.hash.clone());
+        let query = |text: &str| {
+            Query::new(
+                graphql_parser::parse_query(text).unwrap().into_static(),
+                None,
+            )
+        };
+
+        // Unknown arguments are not turned into warnings by default
+        let results = test_runner(STORE.clone(), LOAD_MANAGER.clone())
+            .run_query(query(UNKNOWN), target.clone())
+            .await;
+        assert!(results.extension("warnings").is_none());
+
+        // The query runs as if it did not have the unknown argument, and
+        // the warning repeats the message of the validation error
+        let results = runner
+            .clone()
+            .run_query(query(UNKNOWN), target.clone())
+            .await;
+        let warnings = serde_json::to_value(results.extension("warnings").unwrap()).unwrap();
+        assert_eq!(
+            json!([{ "message": "Unknown argument \"id\" on field \"Query.musicians\" (ignored)" }]),
+            warnings
+        );
+        let result = first_result(results).await;
+        assert_eq!(
+            extract_data!(result),
+            Some(object! { musicians: vec![object! { id: "m1" }, object! { id: "m2" }] })
+        );
+
+        // Other validation errors are still errors
+        let results = runner.run_query(query(WRONG_VALUE), target).await;
+        assert!(results.extension("warnings").is_none());
+        match first_result(results).await.to_result() {
+            Err(errors) => match errors.as_slice() {
+                // With validations
+                [QueryError::ExecutionError(QueryExecutionError::ValidationError(_, message))] => {
+                    assert_eq!("Expected value of type \"Int\", found \"two\".", message)
+                }
+                // With silent validations, the query fails when it runs
+                [QueryError::ExecutionError(QueryExecutionError::InvalidArgumentError(
+                    _,
+                    argument,
+                    _,
+                ))] => assert_eq!("first", argument),
+                errors => panic!("unexpected errors: {:?}", errors),
+            },
+            Ok(data) => panic!("expected a validation error but got {:?}", data),
+        }
+    })
+}
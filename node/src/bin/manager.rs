@@ -1,6 +1,9 @@
 use config::PoolSize;
 use git_testament::{git_testament, render_testament};
-use graph::{data::graphql::effort::LoadManager, prelude::chrono, prometheus::Registry};
+use graph::{
+    components::store::RoundRobinReplicaSelector, data::graphql::effort::LoadManager,
+    prelude::chrono, prometheus::Registry,
+};
 use graph::{
     log::logger,
     prelude::{
@@ -656,6 +659,7 @@ impl Context {
             store,
             subscription_manager,
             load_manager,
+            Arc::new(RoundRobinReplicaSelector),
             registry,
         ))
     }
@@ -8,10 +8,11 @@ use graph::{
             de::{self, value, SeqAccess, Visitor},
             Deserialize, Deserializer, Serialize,
         },
-        serde_json, Logger, NodeId, StoreError,
+        serde_json, DeploymentHash, Logger, NodeId, StoreError, ENV_VARS,
     },
 };
 use graph_chain_ethereum::{self as ethereum, NodeCapabilities};
+use graph_graphql::prelude::{QueryAllowlist, QueryLimits};
 use graph_store_postgres::{DeploymentPlacer, Shard as ShardName, PRIMARY_SHARD};
 
 use http::{HeaderMap, Uri};
@@ -20,6 +21,7 @@ use std::fs::read_to_string;
 use std::{
     collections::{BTreeMap, BTreeSet},
     fmt,
+    time::Duration,
 };
 use url::Url;
 
@@ -67,6 +69,8 @@ pub struct Config {
     #[serde(skip, default = "default_node_id")]
     pub node: NodeId,
     pub general: Option<GeneralSection>,
+    #[serde(default)]
+    pub query: QuerySection,
     #[serde(rename = "store")]
     pub stores: BTreeMap<String, Shard>,
     pub chains: ChainSection,
@@ -142,6 +146,7 @@ impl Config {
         }
 
         self.chains.validate()?;
+        self.query.validate()?;
 
         Ok(())
     }
@@ -181,6 +186,7 @@ impl Config {
         Ok(Config {
             node,
             general: None,
+            query: QuerySection::default(),
             stores,
             chains,
             deployment,
@@ -220,6 +226,118 @@ pub struct GeneralSection {
     query: Regex,
 }
 
+/// Limits for queries against individual deployments
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct QuerySection {
+    #[serde(default, rename = "deployment")]
+    pub deployments: BTreeMap<String, DeploymentQueryLimits>,
+    /// If set, only queries on the allowlist are served
+    pub allowlist: Option<Allowlist>,
+}
+
+impl QuerySection {
+    fn validate(&self) -> Result<()> {
+        for (id, limits) in &self.deployments {
+            DeploymentHash::new(id.as_str())
+                .map_err(|id| anyhow!("invalid deployment id `{}` in query limits", id))?;
+            if let Some(max_first) = limits.max_first {
+                if max_first > ENV_VARS.graphql.max_first_ceiling {
+                    bail!(
+                        "max_first for deployment {} is {}, but can be at most {} \
+                         (GRAPH_GRAPHQL_MAX_FIRST_CEILING)",
+                        id,
+                        max_first,
+                        ENV_VARS.graphql.max_first_ceiling
+                    );
+                }
+            }
+            if let (Some(timeout), Some(ceiling)) =
+                (limits.timeout, ENV_VARS.graphql.query_timeout_ceiling)
+            {
+                if timeout > ceiling.as_secs() {
+                    bail!(
+                        "timeout for deployment {} is {}s, but can be at most {}s \
+                         (GRAPH_GRAPHQL_QUERY_TIMEOUT_CEILING)",
+                        id,
+                        timeout,
+                        ceiling.as_secs()
+                    );
+                }
+            }
+            if let Some(max_skip) = limits.max_skip {
+                if max_skip > ENV_VARS.graphql.max_skip_ceiling {
+                    bail!(
+                        "max_skip for deployment {} is {}, but can be at most {} \
+                         (GRAPH_GRAPHQL_MAX_SKIP_CEILING)",
+                        id,
+                        max_skip,
+                        ENV_VARS.graphql.max_skip_ceiling
+                    );
+                }
+            }
+        }
+        if let Some(allowlist) = &self.allowlist {
+            allowlist.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The allowlist that the `GraphQlRunner` should use, if any
+    pub fn query_allowlist(&self) -> Option<QueryAllowlist> {
+        self.allowlist.as_ref().map(|allowlist| {
+            let shapes = allowlist.shapes.iter().map(|shape| {
+                shape
+                    .parse::<u64>()
+                    .expect("a validated config only has valid shape hashes")
+            });
+            QueryAllowlist::new(shapes, allowlist.introspection)
+        })
+    }
+
+    /// The limits that the `GraphQlRunner` should use for queries
+    pub fn query_limits(&self) -> QueryLimits {
+        let mut query_limits = QueryLimits::new();
+        for (id, limits) in &self.deployments {
+            let id = DeploymentHash::new(id.as_str())
+                .expect("a validated config only has valid deployment ids");
+            if let Some(timeout) = limits.timeout {
+                query_limits.set_timeout(id.clone(), Duration::from_secs(timeout));
+            }
+            query_limits.set(id, limits.max_first, limits.max_skip);
+        }
+        query_limits
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct DeploymentQueryLimits {
+    pub max_first: Option<u32>,
+    pub max_skip: Option<u32>,
+    /// The timeout for queries against the deployment, in seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Allowlist {
+    /// The shape hashes of the allowed queries, as decimal numbers. TOML
+    /// integers can not hold all of them, and they are therefore strings
+    pub shapes: Vec<String>,
+    /// Whether introspection queries are allowed, too
+    #[serde(default)]
+    pub introspection: bool,
+}
+
+impl Allowlist {
+    fn validate(&self) -> Result<()> {
+        for shape in &self.shapes {
+            shape
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid shape hash `{}` in query allowlist", shape))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Shard {
     pub connection: String,
@@ -1083,14 +1201,16 @@ where
 mod tests {
 
     use super::{
-        Chain, Config, FirehoseProvider, Provider, ProviderDetails, Transport, Web3Provider,
+        Chain, Config, FirehoseProvider, Provider, ProviderDetails, QuerySection, Transport,
+        Web3Provider,
     };
     use graph::blockchain::BlockchainKind;
-    use graph::prelude::NodeId;
+    use graph::prelude::{DeploymentHash, NodeId, ENV_VARS};
     use http::{HeaderMap, HeaderValue};
     use std::collections::BTreeSet;
     use std::fs::read_to_string;
     use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
     #[test]
     fn it_works_on_standard_config() {
@@ -1394,6 +1514,77 @@ mod tests {
         assert_eq!(usize::MAX, limit_for("default"));
     }
 
+    #[test]
+    fn it_parses_query_limits() {
+        let query = toml::from_str::<QuerySection>(
+            r#"
+            [deployment.QmExampleDeployment]
+            max_first = 5000
+            [deployment.QmOtherDeployment]
+            max_skip = 100
+            timeout = 120
+        "#,
+        )
+        .unwrap();
+        query.validate().unwrap();
+
+        let limits = query.query_limits();
+        let example = DeploymentHash::new("QmExampleDeployment").unwrap();
+        let other = DeploymentHash::new("QmOtherDeployment").unwrap();
+        let unknown = DeploymentHash::new("QmUnknownDeployment").unwrap();
+        assert_eq!(5000, limits.max_first(&example));
+        assert_eq!(ENV_VARS.graphql.max_skip, limits.max_skip(&example));
+        assert_eq!(ENV_VARS.graphql.max_first, limits.max_first(&other));
+        assert_eq!(100, limits.max_skip(&other));
+        assert_eq!(ENV_VARS.graphql.max_first, limits.max_first(&unknown));
+        assert_eq!(ENV_VARS.graphql.query_timeout, limits.timeout(&example));
+        assert_eq!(Some(Duration::from_secs(120)), limits.timeout(&other));
+
+        let too_large = toml::from_str::<QuerySection>(&format!(
+            r#"
+            [deployment.QmExampleDeployment]
+            max_first = {}
+        "#,
+            ENV_VARS.graphql.max_first_ceiling as u64 + 1
+        ));
+        assert!(too_large.is_err() || too_large.unwrap().validate().is_err());
+
+        let invalid = toml::from_str::<QuerySection>(
+            r#"
+            [deployment."not a deployment"]
+            max_first = 10
+        "#,
+        )
+        .unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn it_parses_query_allowlist() {
+        let query = toml::from_str::<QuerySection>(
+            r#"
+            [allowlist]
+            shapes = [ "17", "18446744073709551615" ]
+            introspection = true
+        "#,
+        )
+        .unwrap();
+        query.validate().unwrap();
+        assert!(query.query_allowlist().is_some());
+
+        let query = toml::from_str::<QuerySection>("").unwrap();
+        assert!(query.query_allowlist().is_none());
+
+        let query = toml::from_str::<QuerySection>(
+            r#"
+            [allowlist]
+            shapes = [ "0x11" ]
+        "#,
+        )
+        .unwrap();
+        assert!(query.validate().is_err());
+    }
+
     fn read_resource_as_string<P: AsRef<Path>>(path: P) -> String {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("resources/tests");
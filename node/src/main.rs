@@ -5,7 +5,7 @@ use ethereum::{
 use git_testament::{git_testament, render_testament};
 use graph::blockchain::firehose_block_ingestor::FirehoseBlockIngestor;
 use graph::blockchain::{Block as BlockchainBlock, Blockchain, BlockchainKind, BlockchainMap};
-use graph::components::store::BlockStore;
+use graph::components::store::{BlockStore, RoundRobinReplicaSelector};
 use graph::data::graphql::effort::LoadManager;
 use graph::env::EnvVars;
 use graph::firehose::{FirehoseEndpoints, FirehoseNetworks};
@@ -92,6 +92,20 @@ async fn main() {
         render_testament!(TESTAMENT)
     );
 
+    // Spans for the queries sampled with `GRAPH_GRAPHQL_SPAN_SAMPLE_RATE`
+    // go to the collector that the standard `OTEL_EXPORTER_OTLP_*`
+    // environment variables point to
+    if ENV_VARS.graphql.span_sample_rate > 0.0 {
+        if let Err(e) = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+            .install_batch(graph::prelude::opentelemetry::runtime::Tokio)
+        {
+            warn!(logger, "Failed to set up exporting OpenTelemetry spans, query spans will not be recorded";
+                "error" => e.to_string());
+        }
+    }
+
     if opt.unsafe_config {
         warn!(logger, "allowing unsafe configurations");
         graph::env::UNSAFE_CONFIG.store(true, atomic::Ordering::SeqCst);
@@ -324,13 +338,18 @@ async fn main() {
             expensive_queries,
             metrics_registry.clone(),
         ));
-        let graphql_runner = Arc::new(GraphQlRunner::new(
-            &logger,
-            network_store.clone(),
-            subscription_manager.clone(),
-            load_manager,
-            metrics_registry.clone(),
-        ));
+        let graphql_runner = Arc::new(
+            GraphQlRunner::new(
+                &logger,
+                network_store.clone(),
+                subscription_manager.clone(),
+                load_manager.clone(),
+                Arc::new(RoundRobinReplicaSelector),
+                metrics_registry.clone(),
+            )
+            .with_query_limits(config.query.query_limits())
+            .with_query_allowlist(config.query.query_allowlist()),
+        );
         let mut graphql_server = GraphQLQueryServer::new(
             &logger_factory,
             graphql_metrics_registry,
@@ -432,6 +451,7 @@ async fn main() {
             http_port,
             ws_port,
             subgraph_registrar.clone(),
+            load_manager,
             node_id.clone(),
             logger.clone(),
         )
@@ -2,8 +2,151 @@ use graph::prelude::serde_json;
 use hyper::body::Bytes;
 
 use graph::components::server::query::GraphQLServerError;
+use graph::data::query::{ContentEncoding, PersistedQuery, ReorgTolerance, ResponseEncoding};
 use graph::prelude::*;
 
+/// The header with which clients can ask for a shorter timeout than the
+/// one configured with `GRAPH_GRAPHQL_QUERY_TIMEOUT` or for the deployment,
+/// in milliseconds
+pub const TIMEOUT_HEADER: &str = "X-GraphQL-Timeout-Ms";
+pub const LANE_HEADER: &str = "X-GraphQL-Lane";
+/// The header with which a proxy in front of the server tells us who sent
+/// the query. The proxy must overwrite any value the client sent
+pub const CALLER_HEADER: &str = "X-GraphQL-Caller";
+/// The media type with which clients ask for the pages of a query's
+/// toplevel collection to be streamed as JSON documents, one per line
+pub const STREAM_MEDIA_TYPE: &str = "application/x-ndjson";
+
+/// Parse the timeout the client asked for with the `X-GraphQL-Timeout-Ms`
+/// header, if there is one
+pub fn parse_timeout_header(
+    headers: &http::HeaderMap,
+) -> Result<Option<Duration>, GraphQLServerError> {
+    let value = match headers.get(TIMEOUT_HEADER) {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|millis| Some(Duration::from_millis(millis)))
+        .ok_or_else(|| {
+            GraphQLServerError::ClientError(format!(
+                "The {} header must be a number of milliseconds",
+                TIMEOUT_HEADER
+            ))
+        })
+}
+
+/// Parse the priority lane that a proxy in front of the server assigned to
+/// the query with the `X-GraphQL-Lane` header, if there is one
+pub fn parse_lane_header(headers: &http::HeaderMap) -> Result<Option<String>, GraphQLServerError> {
+    let value = match headers.get(LANE_HEADER) {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    value
+        .to_str()
+        .map(|lane| Some(lane.trim().to_string()))
+        .map_err(|_| {
+            GraphQLServerError::ClientError(format!(
+                "The {} header must be a visible ASCII string",
+                LANE_HEADER
+            ))
+        })
+}
+
+/// Parse the identity of the caller that a proxy in front of the server
+/// established with the `X-GraphQL-Caller` header, if there is one
+pub fn parse_caller_header(
+    headers: &http::HeaderMap,
+) -> Result<Option<String>, GraphQLServerError> {
+    let value = match headers.get(CALLER_HEADER) {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    value
+        .to_str()
+        .map(|caller| Some(caller.trim().to_string()))
+        .map_err(|_| {
+            GraphQLServerError::ClientError(format!(
+                "The {} header must be a visible ASCII string",
+                CALLER_HEADER
+            ))
+        })
+}
+
+/// Parse the encoding the client wants for the response from the `Accept`
+/// header. Headers that we can not decode fall back to JSON, just like
+/// media types that we do not support
+pub fn parse_accept_header(headers: &http::HeaderMap) -> ResponseEncoding {
+    ResponseEncoding::from_accept(
+        headers
+            .get(http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
+/// Parse the compression the client accepts for the response from the
+/// `Accept-Encoding` header. Headers that we can not decode mean that the
+/// response is not compressed
+pub fn parse_accept_encoding_header(headers: &http::HeaderMap) -> ContentEncoding {
+    ContentEncoding::from_accept_encoding(
+        headers
+            .get(http::header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
+/// Parse the ETags of the responses that the client or a cache in front
+/// of the server already has from the `If-None-Match` header. Weak ETags
+/// are compared like strong ones, as the header allows, and `*` and
+/// anything else that is not a quoted ETag is ignored
+pub fn parse_if_none_match_header(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .map(|tag| tag.strip_prefix("W/").unwrap_or(tag))
+        .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether the `Accept` header asks for the response to be streamed
+pub fn parse_stream_accept(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |accept| {
+            accept.split(',').any(|media_range| {
+                let mut parts = media_range.split(';').map(str::trim);
+                let media_type = parts.next().unwrap_or("");
+                media_type.eq_ignore_ascii_case(STREAM_MEDIA_TYPE)
+                    && !parts.any(|param| param == "q=0")
+            })
+        })
+}
+
+/// The value of the boolean extension `name`, where a missing extension
+/// or `null` means `false`
+fn bool_extension(
+    extensions: Option<&serde_json::Value>,
+    name: &str,
+) -> Result<bool, GraphQLServerError> {
+    match extensions.and_then(|extensions| extensions.get(name)) {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(flag)) => Ok(*flag),
+        Some(_) => Err(GraphQLServerError::ClientError(format!(
+            "The \"{}\" extension must be a boolean",
+            name
+        ))),
+    }
+}
+
 pub fn parse_graphql_request(body: &Bytes) -> Result<Query, GraphQLServerError> {
     // Parse request body as JSON
     let json: serde_json::Value = serde_json::from_slice(body)
@@ -14,23 +157,6 @@ pub fn parse_graphql_request(body: &Bytes) -> Result<Query, GraphQLServerError>
         GraphQLServerError::ClientError(String::from("Request data is not an object"))
     })?;
 
-    // Ensure the JSON data has a "query" field
-    let query_value = obj.get("query").ok_or_else(|| {
-        GraphQLServerError::ClientError(String::from(
-            "The \"query\" field is missing in request data",
-        ))
-    })?;
-
-    // Ensure the "query" field is a string
-    let query_string = query_value.as_str().ok_or_else(|| {
-        GraphQLServerError::ClientError(String::from("The \"query\" field is not a string"))
-    })?;
-
-    // Parse the "query" field of the JSON body
-    let document = graphql_parser::parse_query(query_string)
-        .map_err(|e| GraphQLServerError::from(QueryError::ParseError(Arc::new(e.into()))))?
-        .into_static();
-
     // Parse the "variables" field of the JSON body, if present
     let variables = match obj.get("variables") {
         None | Some(serde_json::Value::Null) => Ok(None),
@@ -42,7 +168,144 @@ pub fn parse_graphql_request(body: &Bytes) -> Result<Query, GraphQLServerError>
         )),
     }?;
 
-    Ok(Query::new(document, variables))
+    let extensions = obj.get("extensions");
+
+    // Clients can ask for timing information with `extensions.tracing`
+    let trace = bool_extension(extensions, "tracing")?;
+
+    // Clients can ask how far the deployment has synced with
+    // `extensions.syncStatus`
+    let sync_status = bool_extension(extensions, "syncStatus")?;
+
+    // Clients can ask for the data fetched so far when a query runs out of
+    // time with `extensions.partialResults`
+    let partial_results = bool_extension(extensions, "partialResults")?;
+
+    // Clients can ask how big the result is, as counted against the result
+    // size limits, with `extensions.resultSize`
+    let result_size = bool_extension(extensions, "resultSize")?;
+
+    // Clients that prefer a smaller page over running out of time can ask
+    // for the page size to adapt to the deadline with
+    // `extensions.adaptiveFirst`
+    let adaptive_first = bool_extension(extensions, "adaptiveFirst")?;
+
+    // Clients that would rather get an error at the field with a non-null
+    // violation than lose its parent can ask for that with
+    // `extensions.localizeNonNullErrors`
+    let localize_non_null_errors = bool_extension(extensions, "localizeNonNullErrors")?;
+
+    // Clients that need to see their own writes can send the query to the
+    // primary instead of a replica with `extensions.forcePrimary`
+    let force_primary = bool_extension(extensions, "forcePrimary")?;
+
+    // Clients that query blocks the deployment has not reached yet can ask
+    // to wait for them with `extensions.waitForBlock`, in milliseconds
+    let wait_for_block = match extensions.and_then(|extensions| extensions.get("waitForBlock")) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(wait) => Some(wait.as_u64().map(Duration::from_millis).ok_or_else(|| {
+            GraphQLServerError::ClientError(String::from(
+                "The \"waitForBlock\" extension must be a number of milliseconds",
+            ))
+        })?),
+    };
+
+    // Clients can loosen or tighten how queries react to reorgs with
+    // `extensions.reorgTolerance`
+    let reorg_tolerance = match extensions.and_then(|extensions| extensions.get("reorgTolerance")) {
+        None | Some(serde_json::Value::Null) => ReorgTolerance::default(),
+        Some(serde_json::Value::String(tolerance)) => {
+            tolerance.parse().map_err(GraphQLServerError::ClientError)?
+        }
+        Some(_) => {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "The \"reorgTolerance\" extension must be a string",
+            )))
+        }
+    };
+
+    // Clients that cache the schema can send the hash of the deployment
+    // they built the query against with `extensions.schemaHash`
+    let schema_hash = match extensions.and_then(|extensions| extensions.get("schemaHash")) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(hash)) => Some(hash.clone()),
+        Some(_) => {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "The \"schemaHash\" extension must be a string",
+            )))
+        }
+    };
+
+    // Clients that address networks by name can check that the deployment
+    // indexes the network they expect with `extensions.network`
+    let network = match extensions.and_then(|extensions| extensions.get("network")) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(network)) => Some(network.clone()),
+        Some(_) => {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "The \"network\" extension must be a string",
+            )))
+        }
+    };
+
+    // Look for an automatic persisted query hash in
+    // `extensions.persistedQuery.sha256Hash`
+    let sha256_hash = match extensions.and_then(|extensions| extensions.get("persistedQuery")) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(persisted) => Some(
+            persisted
+                .get("sha256Hash")
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    GraphQLServerError::ClientError(String::from(
+                        "The \"persistedQuery\" extension must have a \"sha256Hash\" string",
+                    ))
+                })?,
+        ),
+    };
+
+    // Ensure the JSON data has a "query" field, unless the client only
+    // identified the query by its hash
+    let mut query = match (obj.get("query"), sha256_hash) {
+        (Some(query_value), sha256_hash) => {
+            // Ensure the "query" field is a string
+            let query_string = query_value.as_str().ok_or_else(|| {
+                GraphQLServerError::ClientError(String::from("The \"query\" field is not a string"))
+            })?;
+
+            // Parse the "query" field of the JSON body
+            let document = graphql_parser::parse_query(query_string)
+                .map_err(|e| GraphQLServerError::from(QueryError::ParseError(Arc::new(e.into()))))?
+                .into_static();
+
+            let mut query = Query::new(document, variables);
+            query.persisted_query = sha256_hash.map(|sha256_hash| PersistedQuery {
+                sha256_hash,
+                text: Some(query_string.to_string()),
+            });
+            query
+        }
+        (None, Some(sha256_hash)) => Query::persisted(sha256_hash, variables),
+        (None, None) => {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "The \"query\" field is missing in request data",
+            )))
+        }
+    };
+
+    query.trace = trace;
+    query.reorg_tolerance = reorg_tolerance;
+    query.sync_status = sync_status;
+    query.partial_results = partial_results;
+    query.force_primary = force_primary;
+    query.result_size = result_size;
+    query.adaptive_first = adaptive_first;
+    query.localize_non_null_errors = localize_non_null_errors;
+    query.wait_for_block = wait_for_block;
+    query.schema_hash = schema_hash;
+    query.network = network;
+    Ok(query)
 }
 
 #[cfg(test)]
@@ -52,11 +315,18 @@ mod tests {
     use std::collections::HashMap;
 
     use graph::{
-        data::{query::QueryTarget, value::Object},
+        data::{
+            query::{ContentEncoding, QueryTarget, ReorgTolerance, ResponseEncoding},
+            value::Object,
+        },
         prelude::*,
     };
 
-    use super::parse_graphql_request;
+    use super::{
+        parse_accept_encoding_header, parse_accept_header, parse_caller_header,
+        parse_graphql_request, parse_if_none_match_header, parse_lane_header, parse_stream_accept,
+        parse_timeout_header, CALLER_HEADER, LANE_HEADER, STREAM_MEDIA_TYPE, TIMEOUT_HEADER,
+    };
 
     lazy_static! {
         static ref TARGET: QueryTarget =
@@ -164,4 +434,335 @@ mod tests {
         assert_eq!(query.document, expected_query);
         assert_eq!(query.variables, Some(expected_variables));
     }
+
+    #[test]
+    fn accepts_persisted_query_hash_without_query() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"extensions\": { \
+                 \"persistedQuery\": { \"version\": 1, \"sha256Hash\": \"abc\" } \
+                 } \
+                 }",
+        ));
+        let query = request.expect("Should accept a persisted query hash");
+        let persisted = query.persisted_query.expect("query is persisted");
+        assert_eq!(persisted.sha256_hash, "abc");
+        assert_eq!(persisted.text, None);
+    }
+
+    #[test]
+    fn keeps_text_of_persisted_query() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \
+                 \"persistedQuery\": { \"version\": 1, \"sha256Hash\": \"abc\" } \
+                 } \
+                 }",
+        ));
+        let query = request.expect("Should accept a persisted query with text");
+        let persisted = query.persisted_query.expect("query is persisted");
+        assert_eq!(persisted.sha256_hash, "abc");
+        assert_eq!(persisted.text.as_deref(), Some("{ user { name } }"));
+    }
+
+    #[test]
+    fn parses_flags() {
+        let flags: [(&str, fn(&Query) -> bool); 7] = [
+            ("tracing", |query| query.trace),
+            ("syncStatus", |query| query.sync_status),
+            ("partialResults", |query| query.partial_results),
+            ("forcePrimary", |query| query.force_primary),
+            ("resultSize", |query| query.result_size),
+            ("adaptiveFirst", |query| query.adaptive_first),
+            ("localizeNonNullErrors", |query| {
+                query.localize_non_null_errors
+            }),
+        ];
+        let parse = |extensions: &str| {
+            parse_graphql_request(&hyper::body::Bytes::from(format!(
+                "{{\"query\": \"{{ user {{ name }} }}\", \"extensions\": {}}}",
+                extensions
+            )))
+        };
+
+        for (name, flag) in flags {
+            for (extensions, exp) in [
+                ("{}".to_string(), false),
+                (format!("{{ \"{}\": null }}", name), false),
+                (format!("{{ \"{}\": false }}", name), false),
+                (format!("{{ \"{}\": true }}", name), true),
+            ] {
+                let query = parse(&extensions).expect("Should accept the flag");
+                assert_eq!(exp, flag(&query), "{}", extensions);
+            }
+            parse(&format!("{{ \"{}\": \"yes\" }}", name))
+                .expect_err("Should reject flags that are not booleans");
+        }
+    }
+
+    #[test]
+    fn parses_reorg_tolerance() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "{\"query\": \"{ user { name } }\"}",
+        ));
+        assert_eq!(
+            request
+                .expect("Should accept valid queries")
+                .reorg_tolerance,
+            ReorgTolerance::Default
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"reorgTolerance\": \"tolerant\" } \
+                 }",
+        ));
+        assert_eq!(
+            request
+                .expect("Should accept the reorg tolerance")
+                .reorg_tolerance,
+            ReorgTolerance::Tolerant
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"reorgTolerance\": \"sometimes\" } \
+                 }",
+        ));
+        request.expect_err("Should reject unknown reorg tolerances");
+    }
+
+    #[test]
+    fn parses_wait_for_block() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "{\"query\": \"{ user { name } }\"}",
+        ));
+        assert_eq!(
+            None,
+            request.expect("Should accept valid queries").wait_for_block
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"waitForBlock\": 1500 } \
+                 }",
+        ));
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            request
+                .expect("Should accept waiting for blocks")
+                .wait_for_block
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"waitForBlock\": \"forever\" } \
+                 }",
+        ));
+        request.expect_err("Should reject invalid waits");
+    }
+
+    #[test]
+    fn parses_schema_hash() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "{\"query\": \"{ user { name } }\"}",
+        ));
+        assert_eq!(
+            None,
+            request.expect("Should accept valid queries").schema_hash
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"schemaHash\": \"QmSchema\" } \
+                 }",
+        ));
+        assert_eq!(
+            Some("QmSchema"),
+            request
+                .expect("Should accept the schema hash")
+                .schema_hash
+                .as_deref()
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"schemaHash\": 5 } \
+                 }",
+        ));
+        request.expect_err("Should reject schema hashes that are not strings");
+    }
+
+    #[test]
+    fn parses_network() {
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "{\"query\": \"{ user { name } }\"}",
+        ));
+        assert_eq!(None, request.expect("Should accept valid queries").network);
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"network\": \"mainnet\" } \
+                 }",
+        ));
+        assert_eq!(
+            Some("mainnet"),
+            request
+                .expect("Should accept the network")
+                .network
+                .as_deref()
+        );
+
+        let request = parse_graphql_request(&hyper::body::Bytes::from(
+            "\
+                 {\
+                 \"query\": \"{ user { name } }\", \
+                 \"extensions\": { \"network\": 1 } \
+                 }",
+        ));
+        request.expect_err("Should reject networks that are not strings");
+    }
+
+    #[test]
+    fn parses_timeout_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(None, parse_timeout_header(&headers).unwrap());
+
+        headers.insert(TIMEOUT_HEADER, http::HeaderValue::from_static("250"));
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            parse_timeout_header(&headers).unwrap()
+        );
+
+        headers.insert(TIMEOUT_HEADER, http::HeaderValue::from_static("soon"));
+        parse_timeout_header(&headers).expect_err("Should reject invalid timeouts");
+    }
+
+    #[test]
+    fn parses_if_none_match_header() {
+        let mut headers = http::HeaderMap::new();
+        assert!(parse_if_none_match_header(&headers).is_empty());
+
+        headers.insert(
+            http::header::IF_NONE_MATCH,
+            http::HeaderValue::from_static("\"abc\", W/\"def\", *, ghi"),
+        );
+        assert_eq!(
+            vec!["abc".to_string(), "def".to_string()],
+            parse_if_none_match_header(&headers)
+        );
+    }
+
+    #[test]
+    fn parses_lane_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(None, parse_lane_header(&headers).unwrap());
+
+        headers.insert(LANE_HEADER, http::HeaderValue::from_static(" paid "));
+        assert_eq!(
+            Some("paid".to_string()),
+            parse_lane_header(&headers).unwrap()
+        );
+
+        headers.insert(
+            LANE_HEADER,
+            http::HeaderValue::from_bytes(b"p\xe4id").unwrap(),
+        );
+        parse_lane_header(&headers).expect_err("Should reject invalid lanes");
+    }
+
+    #[test]
+    fn parses_caller_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(None, parse_caller_header(&headers).unwrap());
+
+        headers.insert(CALLER_HEADER, http::HeaderValue::from_static(" guest "));
+        assert_eq!(
+            Some("guest".to_string()),
+            parse_caller_header(&headers).unwrap()
+        );
+
+        headers.insert(
+            CALLER_HEADER,
+            http::HeaderValue::from_bytes(b"g\xe4st").unwrap(),
+        );
+        parse_caller_header(&headers).expect_err("Should reject invalid callers");
+    }
+
+    #[test]
+    fn parses_accept_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(ResponseEncoding::Json, parse_accept_header(&headers));
+
+        headers.insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("application/cbor"),
+        );
+        assert_eq!(ResponseEncoding::Cbor, parse_accept_header(&headers));
+
+        headers.insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("application/xml"),
+        );
+        assert_eq!(ResponseEncoding::Json, parse_accept_header(&headers));
+    }
+
+    #[test]
+    fn parses_accept_encoding_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(
+            ContentEncoding::Identity,
+            parse_accept_encoding_header(&headers)
+        );
+
+        headers.insert(
+            http::header::ACCEPT_ENCODING,
+            http::HeaderValue::from_static("gzip, deflate, br"),
+        );
+        assert_eq!(
+            ContentEncoding::Gzip,
+            parse_accept_encoding_header(&headers)
+        );
+    }
+
+    #[test]
+    fn parses_stream_accept() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!parse_stream_accept(&headers));
+
+        headers.insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("application/json, application/x-ndjson"),
+        );
+        assert!(parse_stream_accept(&headers));
+
+        headers.insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("application/x-ndjson;q=0"),
+        );
+        assert!(!parse_stream_accept(&headers));
+
+        headers.insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static(STREAM_MEDIA_TYPE),
+        );
+        assert!(parse_stream_accept(&headers));
+    }
 }
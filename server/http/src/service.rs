@@ -5,18 +5,24 @@ use std::task::Context;
 use std::task::Poll;
 use std::time::Instant;
 
-use graph::data::query::QueryResults;
+use graph::data::query::{
+    etag_suffix, ContentEncoding, QueryResults, ResponseEncoding, VARY_HEADERS,
+};
 use graph::prelude::*;
 use graph::{components::server::query::GraphQLServerError, data::query::QueryTarget};
 use http::header;
 use http::header::{
     ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_TYPE, LOCATION,
+    CONTENT_TYPE, ETAG, LOCATION, VARY,
 };
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 
-use crate::request::parse_graphql_request;
+use crate::request::{
+    parse_accept_encoding_header, parse_accept_header, parse_caller_header, parse_graphql_request,
+    parse_if_none_match_header, parse_lane_header, parse_stream_accept, parse_timeout_header,
+    CALLER_HEADER, LANE_HEADER, STREAM_MEDIA_TYPE, TIMEOUT_HEADER,
+};
 
 pub struct GraphQLServiceMetrics {
     query_execution_time: Box<HistogramVec>,
@@ -157,7 +163,7 @@ where
             GraphQLServerError::ClientError(format!("Invalid subgraph name {:?}", subgraph_name))
         })?;
 
-        self.handle_graphql_query(subgraph_name.into(), request.into_body())
+        self.handle_graphql_query(subgraph_name.into(), request)
             .await
     }
 
@@ -170,25 +176,54 @@ where
             .map_err(|id| GraphQLServerError::ClientError(format!("Invalid subgraph id `{}`", id)));
         match res {
             Err(_) => self.handle_not_found(),
-            Ok(id) => self
-                .handle_graphql_query(id.into(), request.into_body())
-                .boxed(),
+            Ok(id) => self.handle_graphql_query(id.into(), request).boxed(),
         }
     }
 
     async fn handle_graphql_query(
         self,
         target: QueryTarget,
-        request_body: Body,
+        request: Request<Body>,
     ) -> GraphQLServiceResult {
         let service = self.clone();
         let service_metrics = self.metrics.clone();
 
         let start = Instant::now();
-        let body = hyper::body::to_bytes(request_body)
+        let timeout = parse_timeout_header(request.headers())?;
+        let lane = parse_lane_header(request.headers())?;
+        let caller = parse_caller_header(request.headers())?;
+        let encoding = parse_accept_header(request.headers());
+        let compression = parse_accept_encoding_header(request.headers());
+        let stream = parse_stream_accept(request.headers());
+        // The client has the ETags of the representation it asks for; the
+        // runner only knows the ETags of the results
+        let suffix = etag_suffix(encoding, compression);
+        let if_none_match = parse_if_none_match_header(request.headers())
+            .into_iter()
+            .filter_map(|etag| etag.strip_suffix(suffix.as_str()).map(str::to_string))
+            .collect();
+        let body = hyper::body::to_bytes(request.into_body())
             .map_err(|_| GraphQLServerError::InternalError("Failed to read request body".into()))
             .await?;
-        let query = parse_graphql_request(&body);
+        let query = parse_graphql_request(&body).map(|mut query| {
+            query.timeout = timeout;
+            query.lane = lane;
+            query.caller = caller;
+            query.if_none_match = if_none_match;
+            query
+        });
+
+        if stream {
+            let pages = match query {
+                Ok(query) => service.graphql_runner.run_query_stream(query, target).await,
+                Err(GraphQLServerError::QueryError(e)) => {
+                    let result = Arc::new(QueryResult::from(e));
+                    futures03::stream::once(async move { result }).boxed()
+                }
+                Err(e) => return Err(e),
+            };
+            return Ok(Self::stream_response(pages, service_metrics, start));
+        }
 
         let result = match query {
             Ok(query) => service.graphql_runner.run_query(query, target).await,
@@ -198,7 +233,79 @@ where
 
         service_metrics.observe_query(start.elapsed(), &result);
 
-        Ok(result.as_http_response())
+        if result.is_not_modified() {
+            return Ok(Self::not_modified_response(&result, encoding, compression));
+        }
+        Ok(result.as_encoded_http_response(encoding, compression))
+    }
+
+    /// Tell a client that already has the response with the ETag of
+    /// `results` in `encoding` and `compression` that it has not changed
+    fn not_modified_response(
+        results: &QueryResults,
+        encoding: ResponseEncoding,
+        compression: ContentEncoding,
+    ) -> Response<Body> {
+        let builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(VARY, VARY_HEADERS);
+        let builder = match results.representation_etag(encoding, compression) {
+            Some(etag) => builder.header(ETAG, format!("\"{}\"", etag)),
+            None => builder,
+        };
+        builder.body(Body::empty()).unwrap()
+    }
+
+    /// Send the items of the toplevel collection of each page of `pages`
+    /// as soon as the page has been produced, as one JSON document per
+    /// line. The errors of all pages follow on a last line of their own
+    /// with an `errors` key. We stop producing pages when the client goes
+    /// away
+    fn stream_response(
+        mut pages: QueryResultStream,
+        metrics: Arc<GraphQLServiceMetrics>,
+        start: Instant,
+    ) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+        graph::spawn(async move {
+            let mut last = None;
+            let mut errors = Vec::new();
+            while let Some(page) = pages.next().await {
+                errors.extend(page.errors().iter().cloned());
+                let items = match page.data().and_then(|data| data.iter().next()) {
+                    Some((_, r::Value::List(items))) => items.as_slice(),
+                    _ => &[],
+                };
+                let mut chunk = Vec::new();
+                for item in items {
+                    serde_json::to_writer(&mut chunk, item).expect("Failed to serialize item");
+                    chunk.push(b'\n');
+                }
+                let sent = sender.send_data(chunk.into()).await;
+                last = Some(page);
+                if sent.is_err() {
+                    break;
+                }
+            }
+            if !errors.is_empty() {
+                let mut chunk = serde_json::to_vec(&serde_json::json!({ "errors": errors }))
+                    .expect("Failed to serialize query errors");
+                chunk.push(b'\n');
+                sender.send_data(chunk.into()).await.ok();
+            }
+            if let Some(last) = last {
+                metrics.observe_query(start.elapsed(), &QueryResults::from(last));
+            }
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
+            .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
+            .header(CONTENT_TYPE, STREAM_MEDIA_TYPE)
+            .body(body)
+            .unwrap()
     }
 
     // Handles OPTIONS requests
@@ -207,7 +314,13 @@ where
             Ok(Response::builder()
                 .status(200)
                 .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
+                .header(
+                    ACCESS_CONTROL_ALLOW_HEADERS,
+                    format!(
+                        "Content-Type, User-Agent, If-None-Match, {}, {}, {}",
+                        TIMEOUT_HEADER, LANE_HEADER, CALLER_HEADER
+                    ),
+                )
                 .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
                 .header(CONTENT_TYPE, "text/html")
                 .body(Body::from(""))
@@ -233,6 +346,33 @@ where
             })
     }
 
+    /// Report whether the node is ready for more queries so that load
+    /// balancers can stop sending queries to a node that sheds most of
+    /// the queries it gets. Responds with `503 Service Unavailable` when
+    /// the load manager considers the node degraded
+    fn handle_ready(&self) -> GraphQLServiceResponse {
+        let health = self.graphql_runner.load_manager().health();
+        let (status, text) = if health.degraded {
+            (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+        } else {
+            (StatusCode::OK, "ok")
+        };
+        let body = serde_json::json!({
+            "status": text,
+            "shedRate": health.shed_rate,
+            "waitAvgMs": health.wait_avg.map(|wait| wait.as_millis() as u64),
+        });
+        async move {
+            Ok(Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }
+        .boxed()
+    }
+
     /// Handles 404s.
     fn handle_not_found(&self) -> GraphQLServiceResponse {
         async {
@@ -261,6 +401,7 @@ where
 
         match (method, path_segments.as_slice()) {
             (Method::GET, [""]) => self.index().boxed(),
+            (Method::GET, ["ready"]) => self.handle_ready(),
             (Method::GET, &["subgraphs", "id", _, "graphql"])
             | (Method::GET, &["subgraphs", "name", _, "graphql"])
             | (Method::GET, &["subgraphs", "name", _, _, "graphql"])
@@ -407,6 +548,64 @@ mod tests {
             ))
         }
 
+        async fn run_query_batch(
+            self: Arc<Self>,
+            _queries: Vec<(Query, QueryTarget)>,
+        ) -> Vec<QueryResults> {
+            unimplemented!();
+        }
+
+        async fn run_query_batch_with_complexity(
+            self: Arc<Self>,
+            _queries: Vec<(Query, QueryTarget)>,
+            _max_complexity: Option<u64>,
+            _max_depth: Option<u8>,
+            _max_first: Option<u32>,
+            _max_skip: Option<u32>,
+        ) -> Vec<QueryResults> {
+            unimplemented!();
+        }
+
+        async fn run_query_multi(
+            self: Arc<Self>,
+            _query: Query,
+            _targets: Vec<QueryTarget>,
+        ) -> std::collections::HashMap<QueryTarget, QueryResults> {
+            unimplemented!();
+        }
+
+        async fn run_query_stream(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+        ) -> QueryResultStream {
+            unimplemented!();
+        }
+
+        async fn run_query_explain(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+        ) -> QueryResults {
+            unimplemented!();
+        }
+
+        async fn estimate_cost(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+        ) -> Result<QueryCost, QueryResults> {
+            unimplemented!();
+        }
+
+        async fn validate(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+        ) -> Vec<QueryExecutionError> {
+            unimplemented!();
+        }
+
         async fn run_subscription(
             self: Arc<Self>,
             _subscription: Subscription,
@@ -415,6 +614,18 @@ mod tests {
             unreachable!();
         }
 
+        fn register_subscription(
+            &self,
+            _name: String,
+            _document: q::Document,
+        ) -> Result<(), QueryExecutionError> {
+            unimplemented!()
+        }
+
+        fn stored_subscriptions(&self) -> Vec<(String, String)> {
+            unimplemented!()
+        }
+
         fn load_manager(&self) -> Arc<LoadManager> {
             unimplemented!()
         }
@@ -14,6 +14,9 @@ use graph_server_http::GraphQLServer as HyperGraphQLServer;
 
 use tokio::time::sleep;
 
+/// The ETag of all successful responses from `TestGraphQlRunner`
+const TEST_ETAG: &str = "0123abcd";
+
 /// A simple stupid query runner for testing.
 pub struct TestGraphQlRunner;
 
@@ -32,7 +35,10 @@ impl GraphQlRunner for TestGraphQlRunner {
     }
 
     async fn run_query(self: Arc<Self>, query: Query, _target: QueryTarget) -> QueryResults {
-        if query.variables.is_some()
+        if query.if_none_match.iter().any(|etag| etag == TEST_ETAG) {
+            return QueryResults::not_modified(TEST_ETAG.to_string());
+        }
+        let mut results: QueryResults = if query.variables.is_some()
             && query
                 .variables
                 .as_ref()
@@ -58,7 +64,67 @@ impl GraphQlRunner for TestGraphQlRunner {
                 .into_iter(),
             )
         }
-        .into()
+        .into();
+        results.set_etag(TEST_ETAG.to_string());
+        results
+    }
+
+    async fn run_query_batch(
+        self: Arc<Self>,
+        _queries: Vec<(Query, QueryTarget)>,
+    ) -> Vec<QueryResults> {
+        unimplemented!();
+    }
+
+    async fn run_query_batch_with_complexity(
+        self: Arc<Self>,
+        _queries: Vec<(Query, QueryTarget)>,
+        _max_complexity: Option<u64>,
+        _max_depth: Option<u8>,
+        _max_first: Option<u32>,
+        _max_skip: Option<u32>,
+    ) -> Vec<QueryResults> {
+        unimplemented!();
+    }
+
+    async fn run_query_multi(
+        self: Arc<Self>,
+        _query: Query,
+        _targets: Vec<QueryTarget>,
+    ) -> std::collections::HashMap<QueryTarget, QueryResults> {
+        unimplemented!();
+    }
+
+    async fn run_query_stream(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+    ) -> QueryResultStream {
+        unimplemented!();
+    }
+
+    async fn run_query_explain(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+    ) -> QueryResults {
+        unimplemented!();
+    }
+
+    async fn estimate_cost(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+    ) -> Result<QueryCost, QueryResults> {
+        unimplemented!();
+    }
+
+    async fn validate(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+    ) -> Vec<QueryExecutionError> {
+        unimplemented!();
     }
 
     async fn run_subscription(
@@ -69,6 +135,18 @@ impl GraphQlRunner for TestGraphQlRunner {
         unreachable!();
     }
 
+    fn register_subscription(
+        &self,
+        _name: String,
+        _document: q::Document,
+    ) -> Result<(), QueryExecutionError> {
+        unimplemented!()
+    }
+
+    fn stored_subscriptions(&self) -> Vec<(String, String)> {
+        unimplemented!()
+    }
+
     fn load_manager(&self) -> Arc<LoadManager> {
         unimplemented!()
     }
@@ -314,4 +392,81 @@ mod test {
                 .unwrap()
         });
     }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let logger = Logger::root(slog::Discard, o!());
+            let logger_factory = LoggerFactory::new(logger, None);
+            let metrics_registry = Arc::new(MockMetricsRegistry::new());
+            let id = USERS.clone();
+            let query_runner = Arc::new(TestGraphQlRunner);
+            let node_id = NodeId::new("test").unwrap();
+            let mut server =
+                HyperGraphQLServer::new(&logger_factory, metrics_registry, query_runner, node_id);
+            let http_server = server
+                .serve(8009, 8010)
+                .expect("Failed to start GraphQL server");
+
+            // Launch the server to handle the requests
+            tokio::spawn(http_server.fuse().compat());
+            // Give some time for the server to start.
+            sleep(Duration::from_secs(2)).await;
+
+            let client = Client::new();
+            let request = |etag: &str| {
+                Request::post(format!("http://localhost:8009/subgraphs/id/{}", id))
+                    .header(http::header::IF_NONE_MATCH, etag)
+                    .body(Body::from("{\"query\": \"{ name }\"}"))
+                    .unwrap()
+            };
+            let expected_etag = format!("\"{}\"", TEST_ETAG);
+
+            // Clients with a different ETag get the full response, and
+            // learn its ETag
+            let response = client.request(request("\"stale\"")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(http::header::ETAG).unwrap(),
+                expected_etag.as_str()
+            );
+
+            // Clients that already have the response get an empty one
+            let response = client
+                .request(request(&format!("\"stale\", W/{}", expected_etag)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(
+                response.headers().get(http::header::ETAG).unwrap(),
+                expected_etag.as_str()
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(body.is_empty());
+
+            // Each encoding of the response has its own ETag
+            let cbor_request = |etag: &str| {
+                let mut request = request(etag);
+                request.headers_mut().insert(
+                    http::header::ACCEPT,
+                    http::HeaderValue::from_static("application/cbor"),
+                );
+                request
+            };
+            let cbor_etag = format!("\"{}-cbor\"", TEST_ETAG);
+            let response = client.request(cbor_request(&expected_etag)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(http::header::ETAG).unwrap(),
+                cbor_etag.as_str()
+            );
+            let response = client.request(cbor_request(&cbor_etag)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(
+                response.headers().get(http::header::ETAG).unwrap(),
+                cbor_etag.as_str()
+            );
+        });
+    }
 }
@@ -9,6 +9,7 @@ use hyper::{Body, Method, Request, Response, StatusCode};
 use serde_json;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Instant;
 
 use graph::components::{server::query::GraphQLServerError, store::Store};
 use graph::data::query::QueryResults;
@@ -140,10 +141,13 @@ where
             );
             let options = QueryExecutionOptions {
                 resolver,
+                start: Instant::now(),
                 deadline: None,
                 max_first: std::u32::MAX,
                 max_skip: std::u32::MAX,
                 load_manager,
+                tracer: None,
+                partial_results: false,
             };
             let result = execute_query(query_clone.cheap_clone(), None, None, options).await;
             query_clone.log_execution(0);
@@ -3,7 +3,8 @@ extern crate jsonrpc_http_server;
 extern crate lazy_static;
 extern crate serde;
 
-use graph::prelude::serde_json;
+use graph::data::graphql::effort::{LoadManager, LoadThresholds};
+use graph::prelude::{chrono, serde_json};
 use graph::prelude::{JsonRpcServer as JsonRpcServerTrait, *};
 use jsonrpc_http_server::{
     jsonrpc_core::{self, Compatibility, IoHandler, Params, Value},
@@ -19,6 +20,9 @@ const JSON_RPC_REMOVE_ERROR: i64 = 1;
 const JSON_RPC_CREATE_ERROR: i64 = 2;
 const JSON_RPC_REASSIGN_ERROR: i64 = 3;
 
+/// The number of query shapes that `load_effort` reports
+const EFFORT_SNAPSHOT_SIZE: usize = 100;
+
 #[derive(Debug, Deserialize)]
 struct SubgraphCreateParams {
     name: SubgraphName,
@@ -43,8 +47,22 @@ struct SubgraphReassignParams {
     node_id: NodeId,
 }
 
+#[derive(Debug, Deserialize)]
+struct LoadThresholdsParams {
+    load_threshold_ms: u64,
+    jail_threshold: Option<f64>,
+}
+
+fn thresholds_value(thresholds: LoadThresholds) -> Value {
+    serde_json::json!({
+        "loadThresholdMs": thresholds.load_threshold.as_millis() as u64,
+        "jailThreshold": thresholds.jail_threshold,
+    })
+}
+
 pub struct JsonRpcServer<R> {
     registrar: Arc<R>,
+    load_manager: Arc<LoadManager>,
     http_port: u16,
     ws_port: u16,
     node_id: NodeId,
@@ -149,6 +167,67 @@ impl<R: SubgraphRegistrar> JsonRpcServer<R> {
             )),
         }
     }
+
+    /// Handler for the `load_sheds` endpoint.
+    fn sheds_handler(&self) -> Result<Value, jsonrpc_core::Error> {
+        let sheds = self
+            .load_manager
+            .recent_sheds()
+            .into_iter()
+            .map(|shed| {
+                serde_json::json!({
+                    "time": chrono::DateTime::<chrono::Utc>::from(shed.time).to_rfc3339(),
+                    "shapeHash": shed.shape_hash.to_string(),
+                    "lane": shed.lane,
+                    "reason": shed.reason.as_str(),
+                    "effort": shed.effort,
+                    "threshold": shed.threshold,
+                })
+            })
+            .collect();
+        Ok(Value::Array(sheds))
+    }
+
+    /// Handler for the `load_thresholds` endpoint.
+    fn thresholds_handler(&self) -> Result<Value, jsonrpc_core::Error> {
+        Ok(thresholds_value(self.load_manager.thresholds()))
+    }
+
+    /// Handler for the `load_set_thresholds` endpoint.
+    fn set_thresholds_handler(
+        &self,
+        params: LoadThresholdsParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received load_set_thresholds request"; "params" => format!("{:?}", params));
+
+        let thresholds = LoadThresholds {
+            load_threshold: Duration::from_millis(params.load_threshold_ms),
+            jail_threshold: params.jail_threshold,
+        };
+        self.load_manager
+            .set_thresholds(thresholds)
+            .map_err(jsonrpc_core::Error::invalid_params)?;
+        Ok(thresholds_value(thresholds))
+    }
+
+    /// Handler for the `load_effort` endpoint.
+    fn effort_handler(&self) -> Result<Value, jsonrpc_core::Error> {
+        let efforts = self
+            .load_manager
+            .effort_snapshot(EFFORT_SNAPSHOT_SIZE)
+            .into_iter()
+            .map(|effort| {
+                serde_json::json!({
+                    "shapeHash": effort.shape_hash.to_string(),
+                    "effortMs": effort.effort.map(|effort| effort.as_millis() as u64),
+                    "totalEffortMs": effort.total_effort.as_millis() as u64,
+                    "jailed": effort.jailed,
+                    "query": effort.query,
+                })
+            })
+            .collect();
+        Ok(Value::Array(efforts))
+    }
 }
 
 impl<R> JsonRpcServerTrait<R> for JsonRpcServer<R>
@@ -162,6 +241,7 @@ where
         http_port: u16,
         ws_port: u16,
         registrar: Arc<R>,
+        load_manager: Arc<LoadManager>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error> {
@@ -178,6 +258,7 @@ where
 
         let arc_self = Arc::new(JsonRpcServer {
             registrar,
+            load_manager,
             http_port,
             ws_port,
             node_id,
@@ -211,7 +292,7 @@ where
             }
         });
 
-        let me = arc_self;
+        let me = arc_self.clone();
         handler.add_method("subgraph_reassign", move |params: Params| {
             let me = me.clone();
             async move {
@@ -220,6 +301,21 @@ where
             }
         });
 
+        let me = arc_self.clone();
+        handler.add_sync_method("load_sheds", move |_: Params| me.sheds_handler());
+
+        let me = arc_self.clone();
+        handler.add_sync_method("load_effort", move |_: Params| me.effort_handler());
+
+        let me = arc_self.clone();
+        handler.add_sync_method("load_thresholds", move |_: Params| me.thresholds_handler());
+
+        let me = arc_self;
+        handler.add_sync_method("load_set_thresholds", move |params: Params| {
+            let params = params.parse()?;
+            me.set_thresholds_handler(params)
+        });
+
         ServerBuilder::new(handler)
             // Enable REST API:
             // POST /<method>/<param1>/<param2>
@@ -3,6 +3,7 @@ use futures03::stream::SplitStream;
 use graphql_parser::parse_query;
 use http::StatusCode;
 use std::collections::HashMap;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
 use tokio_tungstenite::WebSocketStream;
@@ -13,9 +14,14 @@ use graph::{data::query::QueryTarget, prelude::*};
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct StartPayload {
-    query: String,
+    query: Option<String>,
+    /// The name of a stored subscription to run instead of `query`
+    name: Option<String>,
     variables: Option<serde_json::Value>,
     operation_name: Option<String>,
+    /// Only send results once entities change, not the current result
+    #[serde(default)]
+    skip_initial: bool,
 }
 
 /// GraphQL/WebSocket message received from a client.
@@ -64,6 +70,10 @@ enum OutgoingMessage {
     Complete {
         id: String,
     },
+    /// Sent when a subscription has not had a result for a while so that
+    /// idle connections are not dropped
+    #[serde(rename = "ka")]
+    ConnectionKeepAlive,
 }
 
 impl OutgoingMessage {
@@ -85,6 +95,25 @@ impl From<OutgoingMessage> for WsMessage {
     }
 }
 
+/// Interleave `messages` with keepalive messages whenever `interval` passes
+/// without a message. A zero `interval` turns keepalives off
+fn with_keepalive(
+    messages: impl futures03::Stream<Item = OutgoingMessage> + Unpin + Send + 'static,
+    interval: Duration,
+) -> futures03::stream::BoxStream<'static, OutgoingMessage> {
+    if interval.is_zero() {
+        return messages.boxed();
+    }
+    futures03::stream::unfold(messages, move |mut messages| async move {
+        match tokio::time::timeout(interval, messages.next()).await {
+            Ok(Some(msg)) => Some((msg, messages)),
+            Ok(None) => None,
+            Err(_) => Some((OutgoingMessage::ConnectionKeepAlive, messages)),
+        }
+    })
+    .boxed()
+}
+
 /// Helper function to send outgoing messages.
 fn send_message(
     sink: &mpsc::UnboundedSender<WsMessage>,
@@ -259,15 +288,29 @@ where
                         );
                     }
 
-                    // Parse the GraphQL query document; respond with a GQL_ERROR if
+                    // Parse the GraphQL query document unless the client asked
+                    // for a stored subscription; respond with a GQL_ERROR if
                     // the query is invalid
-                    let query = match parse_query(&payload.query) {
-                        Ok(query) => query.into_static(),
-                        Err(e) => {
+                    let query = match (&payload.name, &payload.query) {
+                        (Some(_), _) => q::Document {
+                            definitions: vec![],
+                        },
+                        (None, Some(text)) => match parse_query(text) {
+                            Ok(query) => query.into_static(),
+                            Err(e) => {
+                                return send_error_string(
+                                    &msg_sink,
+                                    id,
+                                    format!("Invalid query: {}: {}", text, e),
+                                );
+                            }
+                        },
+                        (None, None) => {
                             return send_error_string(
                                 &msg_sink,
                                 id,
-                                format!("Invalid query: {}: {}", payload.query, e),
+                                "Either a query or the name of a stored subscription is required"
+                                    .to_string(),
                             );
                         }
                     };
@@ -302,6 +345,9 @@ where
                         // Subscriptions currently do not benefit from the generational cache
                         // anyways, so don't bother passing a network.
                         query: Query::new(query, variables),
+                        name: payload.name,
+                        connection_id: Some(connection_id.clone()),
+                        skip_initial: payload.skip_initial,
                     };
 
                     debug!(logger, "Start operation";
@@ -312,6 +358,8 @@ where
                     let error_sink = msg_sink.clone();
                     let result_sink = msg_sink.clone();
                     let result_id = id.clone();
+                    let complete_sink = msg_sink.clone();
+                    let complete_id = id.clone();
                     let err_id = id.clone();
                     let err_connection_id = connection_id.clone();
                     let err_logger = logger.clone();
@@ -345,19 +393,32 @@ where
                                         let _ = error_sink.unbounded_send(msg.into());
                                     }
                                 }
+                                e => {
+                                    let msg = OutgoingMessage::from_error_string(
+                                        err_id.clone(),
+                                        e.to_string(),
+                                    );
+                                    let _ = error_sink.unbounded_send(msg.into());
+                                }
                             };
                         })
                         .and_then(move |result_stream| {
-                            // Send results back to the client as GQL_DATA
-                            result_stream
-                                .map(move |result| {
-                                    OutgoingMessage::from_query_result(result_id.clone(), result)
-                                })
+                            // Send results back to the client as GQL_DATA, and
+                            // keep the connection alive while there are none
+                            let results = result_stream.map(move |result| {
+                                OutgoingMessage::from_query_result(result_id.clone(), result)
+                            });
+                            with_keepalive(results, ENV_VARS.graphql.ws_keepalive_interval)
                                 .map(WsMessage::from)
                                 .map(Ok)
                                 .compat()
                                 .forward(result_sink.sink_map_err(|_| ()))
-                                .map(|_| ())
+                                .map(move |_| {
+                                    // The subscription ended on its own, for example
+                                    // because its deployment became unavailable
+                                    let msg = OutgoingMessage::Complete { id: complete_id };
+                                    let _ = complete_sink.unbounded_send(msg.into());
+                                })
                         });
 
                     // Setup cancelation.
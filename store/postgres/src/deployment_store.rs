@@ -4,7 +4,10 @@ use diesel::connection::SimpleConnection;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use graph::components::store::{EntityType, StoredDynamicDataSource};
+pub use graph::components::store::ReplicaId;
+use graph::components::store::{
+    EntityType, ReplicaCandidate, ReplicaSelector, StoredDynamicDataSource,
+};
 use graph::data::subgraph::{status, SPEC_VERSION_0_0_6};
 use graph::prelude::{
     tokio, CancelHandle, CancelToken, CancelableError, EntityOperation, PoolWaitStats,
@@ -46,16 +49,6 @@ use crate::relational_queries::FromEntityData;
 use crate::{connection_pool::ConnectionPool, detail};
 use crate::{dynds, primary::Site};
 
-/// When connected to read replicas, this allows choosing which DB server to use for an operation.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum ReplicaId {
-    /// The main server has write and read access.
-    Main,
-
-    /// A read replica identified by its index.
-    ReadOnly(usize),
-}
-
 /// Commonly needed information about a subgraph that we cache in
 /// `Store.subgraph_cache`. Only immutable subgraph data can be cached this
 /// way as the cache lives for the lifetime of the `Store` object
@@ -1174,17 +1167,8 @@ impl DeploymentStore {
         &self,
         for_subscription: bool,
     ) -> Result<ReplicaId, StoreError> {
-        use std::sync::atomic::Ordering;
-
         let replica_id = match for_subscription {
-            // Pick a weighted ReplicaId. `replica_order` contains a list of
-            // replicas with repetitions according to their weight
-            false => {
-                let weights_count = self.replica_order.len();
-                let index =
-                    self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst) % weights_count;
-                *self.replica_order.get(index).unwrap()
-            }
+            false => self.next_replica(),
             // Subscriptions always go to the main replica.
             true => ReplicaId::Main,
         };
@@ -1192,6 +1176,49 @@ impl DeploymentStore {
         Ok(replica_id)
     }
 
+    /// Pick a weighted ReplicaId. `replica_order` contains a list of
+    /// replicas with repetitions according to their weight
+    fn next_replica(&self) -> ReplicaId {
+        use std::sync::atomic::Ordering;
+
+        let weights_count = self.replica_order.len();
+        let index = self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst) % weights_count;
+        *self.replica_order.get(index).unwrap()
+    }
+
+    /// Let `selector` choose the replica for a query with `shape_hash`
+    /// among all the replicas of this store
+    pub(crate) fn select_replica_for_query(
+        &self,
+        shape_hash: u64,
+        selector: &dyn ReplicaSelector,
+    ) -> Result<ReplicaId, StoreError> {
+        let round_robin = self.next_replica();
+        let replicas = std::iter::once(ReplicaId::Main)
+            .chain((0..self.read_only_pools.len()).map(ReplicaId::ReadOnly));
+        let candidates = replicas
+            .map(|id| {
+                Ok(ReplicaCandidate {
+                    id,
+                    weight: self.replica_order.iter().filter(|r| **r == id).count(),
+                    wait_stats: self.wait_stats(id)?,
+                })
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+
+        let replica_id = selector.select(shape_hash, &candidates, round_robin);
+        if !candidates
+            .iter()
+            .any(|candidate| candidate.id == replica_id)
+        {
+            return Err(constraint_violation!(
+                "the replica selector chose replica {} which does not exist",
+                replica_id
+            ));
+        }
+        Ok(replica_id)
+    }
+
     pub(crate) async fn load_dynamic_data_sources(
         &self,
         site: Arc<Site>,
@@ -3,7 +3,7 @@ use std::collections::BTreeMap;
 use graph::data::value::Word;
 
 use crate::deployment_store::{DeploymentStore, ReplicaId};
-use graph::components::store::QueryStore as QueryStoreTrait;
+use graph::components::store::{DeploymentId, QueryStore as QueryStoreTrait};
 use graph::prelude::*;
 
 use crate::primary::Site;
@@ -58,6 +58,10 @@ impl QueryStoreTrait for QueryStore {
         self.store.block_ptr(self.site.cheap_clone()).await
     }
 
+    async fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, StoreError> {
+        Ok(self.chain_store.cheap_clone().chain_head_ptr().await?)
+    }
+
     fn block_number(&self, block_hash: &BlockHash) -> Result<Option<BlockNumber>, StoreError> {
         // We should also really check that the block with the given hash is
         // on the chain starting at the subgraph's current head. That check is
@@ -106,10 +110,18 @@ impl QueryStoreTrait for QueryStore {
         Ok(info.api)
     }
 
+    fn api_schema_version(&self) -> (DeploymentHash, DeploymentId) {
+        (self.site.deployment.clone(), self.site.id.into())
+    }
+
     fn network_name(&self) -> &str {
         &self.site.network
     }
 
+    fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
     async fn query_permit(&self) -> Result<tokio::sync::OwnedSemaphorePermit, StoreError> {
         self.store.query_permit(self.replica_id).await
     }
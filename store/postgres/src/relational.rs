@@ -665,6 +665,7 @@ impl Layout {
             range,
             block,
             query_id,
+            self,
         )?;
         let query_clone = query.clone();
 
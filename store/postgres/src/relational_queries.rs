@@ -15,8 +15,8 @@ use diesel::Connection;
 use graph::data::value::Word;
 use graph::prelude::{
     anyhow, r, serde_json, Attribute, BlockNumber, ChildMultiplicity, Entity, EntityCollection,
-    EntityFilter, EntityKey, EntityLink, EntityOrder, EntityRange, EntityWindow, ParentLink,
-    QueryExecutionError, StoreError, Value, ENV_VARS,
+    EntityFilter, EntityKey, EntityLink, EntityOrder, EntityOrderByChild, EntityRange,
+    EntityWindow, ParentLink, QueryExecutionError, StoreError, Value, ENV_VARS,
 };
 use graph::{
     components::store::{AttributeNames, EntityType},
@@ -1946,7 +1946,7 @@ impl<'a> ParentLimit<'a> {
     fn restrict(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
         if let ParentLimit::Ranked(sort_key, range) = self {
             out.push_sql(" ");
-            sort_key.order_by(out)?;
+            sort_key.order_by(false, out)?;
             range.walk_ast(out.reborrow())?;
         }
         Ok(())
@@ -2515,9 +2515,14 @@ impl<'a> FilterCollection<'a> {
     }
 }
 
+/// The name under which queries that combine the rows of several tables
+/// select a `SortKey::ChildKey`, since ordering the combined rows can only
+/// refer to the names of selected columns
+const SORT_KEY_COLUMN: &str = "sort_key$";
+
 /// Convenience to pass the name of the column to order by around. If `name`
 /// is `None`, the sort key should be ignored
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum SortKey<'a> {
     None,
     /// Order by `id asc`
@@ -2530,6 +2535,27 @@ pub enum SortKey<'a> {
         value: Option<&'a str>,
         direction: &'static str,
     },
+    /// Order by a column of a table that is reached from the table we
+    /// take entities from by following the references in `joins`
+    ChildKey {
+        joins: Vec<SortJoin<'a>>,
+        column: &'a Column,
+        direction: &'static str,
+        block: BlockNumber,
+    },
+}
+
+/// A reference that needs to be followed for a `SortKey::ChildKey`
+#[derive(Debug, Clone)]
+pub struct SortJoin<'a> {
+    /// The table that the reference leads to
+    table: &'a Table,
+    /// The column holding the reference. If `derived` is `true`, it is a
+    /// column of `table` that holds the `id` of the referencing entity;
+    /// otherwise it is a column of the referencing table that holds the
+    /// `id` of the entity in `table`
+    column: &'a Column,
+    derived: bool,
 }
 
 /// String representation that is useful for debugging when `walk_ast` fails
@@ -2555,6 +2581,20 @@ impl<'a> fmt::Display for SortKey<'a> {
                 PRIMARY_KEY_COLUMN,
                 direction
             ),
+            ChildKey {
+                joins,
+                column,
+                direction,
+                block: _,
+            } => write!(
+                f,
+                "{}.{} {}, {} {}",
+                joins.iter().map(|join| join.column.name.as_str()).join("."),
+                column.name.as_str(),
+                direction,
+                PRIMARY_KEY_COLUMN,
+                direction
+            ),
         }
     }
 }
@@ -2565,6 +2605,7 @@ impl<'a> SortKey<'a> {
         collection: &'a FilterCollection,
         filter: Option<&'a EntityFilter>,
         block: BlockNumber,
+        layout: &'a Layout,
     ) -> Result<Self, QueryExecutionError> {
         const ASC: &str = "asc";
         const DESC: &str = "desc";
@@ -2605,6 +2646,47 @@ impl<'a> SortKey<'a> {
             }
         }
 
+        fn with_child_key<'a>(
+            layout: &'a Layout,
+            table: &'a Table,
+            child: EntityOrderByChild,
+            direction: &'static str,
+            block: BlockNumber,
+        ) -> Result<SortKey<'a>, QueryExecutionError> {
+            let mut parent = table;
+            let mut joins = Vec::with_capacity(child.joins.len());
+            for join in child.joins {
+                let table = layout
+                    .table_for_entity(&join.entity_type)
+                    .map(|rc| rc.as_ref())?;
+                let column = if join.derived {
+                    table.column_for_field(&join.attribute)?
+                } else {
+                    parent.column_for_field(&join.attribute)?
+                };
+                joins.push(SortJoin {
+                    table,
+                    column,
+                    derived: join.derived,
+                });
+                parent = table;
+            }
+            if joins.is_empty() {
+                return Err(graph::constraint_violation!(
+                    "ordering by `{}` of a referenced entity needs at least one reference",
+                    child.attribute
+                )
+                .into());
+            }
+            let column = parent.column_for_field(&child.attribute)?;
+            Ok(SortKey::ChildKey {
+                joins,
+                column,
+                direction,
+                block,
+            })
+        }
+
         // If there is more than one table, we are querying an interface,
         // and the order is on an attribute in that interface so that all
         // tables have a column for that. It is therefore enough to just
@@ -2622,6 +2704,10 @@ impl<'a> SortKey<'a> {
         match order {
             EntityOrder::Ascending(attr, _) => with_key(table, attr, filter, ASC, br_column),
             EntityOrder::Descending(attr, _) => with_key(table, attr, filter, DESC, br_column),
+            EntityOrder::ChildAscending(child) => with_child_key(layout, table, child, ASC, block),
+            EntityOrder::ChildDescending(child) => {
+                with_child_key(layout, table, child, DESC, block)
+            }
             EntityOrder::Default => Ok(SortKey::IdAsc(br_column)),
             EntityOrder::Unordered => Ok(SortKey::None),
         }
@@ -2650,12 +2736,27 @@ impl<'a> SortKey<'a> {
                 out.push_identifier(column.name.as_str())?;
                 Ok(())
             }
+            SortKey::ChildKey {
+                joins,
+                column,
+                direction: _,
+                block,
+            } => {
+                out.push_sql(", ");
+                SortKey::child_expr(joins, column, *block, 0, out)?;
+                out.push_sql(" as ");
+                out.push_identifier(SORT_KEY_COLUMN)
+            }
         }
     }
 
     /// Generate
     ///   order by [name direction], id
-    fn order_by(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+    ///
+    /// Queries that combine the rows from several tables must have
+    /// selected the sort key with `select` and set `use_sort_key_alias`,
+    /// since they can only order by the names of selected columns
+    fn order_by(&self, use_sort_key_alias: bool, out: &mut AstPass<Pg>) -> QueryResult<()> {
         match self {
             SortKey::None => Ok(()),
             SortKey::IdAsc(br_column) => {
@@ -2686,12 +2787,18 @@ impl<'a> SortKey<'a> {
                 out.push_sql("order by ");
                 SortKey::sort_expr(column, value, direction, out)
             }
+            SortKey::ChildKey { .. } => {
+                out.push_sql("order by ");
+                self.child_sort_expr(use_sort_key_alias, out)
+            }
         }
     }
 
     /// Generate
     ///   order by g$parent_id, [name direction], id
-    fn order_by_parent(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+    ///
+    /// See `order_by` for when to set `use_sort_key_alias`
+    fn order_by_parent(&self, use_sort_key_alias: bool, out: &mut AstPass<Pg>) -> QueryResult<()> {
         match self {
             SortKey::None => Ok(()),
             SortKey::IdAsc(_) => {
@@ -2712,6 +2819,10 @@ impl<'a> SortKey<'a> {
                 out.push_sql("order by g$parent_id, ");
                 SortKey::sort_expr(column, value, direction, out)
             }
+            SortKey::ChildKey { .. } => {
+                out.push_sql("order by g$parent_id, ");
+                self.child_sort_expr(use_sort_key_alias, out)
+            }
         }
     }
 
@@ -2749,6 +2860,12 @@ impl<'a> SortKey<'a> {
                 out.push_identifier(name)?;
             }
         }
+        SortKey::sort_direction(direction, out)
+    }
+
+    /// Generate
+    ///   [direction], id [direction]
+    fn sort_direction(direction: &str, out: &mut AstPass<Pg>) -> QueryResult<()> {
         if ENV_VARS.store.reversible_order_by_off {
             // Old behavior
             out.push_sql(" ");
@@ -2766,6 +2883,106 @@ impl<'a> SortKey<'a> {
         }
         Ok(())
     }
+
+    /// Generate
+    ///   {child_expr} direction, id direction
+    /// for a `SortKey::ChildKey`, using `SORT_KEY_COLUMN` instead of the
+    /// expression if `use_sort_key_alias` is set
+    fn child_sort_expr(&self, use_sort_key_alias: bool, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        match self {
+            SortKey::ChildKey {
+                joins,
+                column,
+                direction,
+                block,
+            } => {
+                if use_sort_key_alias {
+                    out.push_identifier(SORT_KEY_COLUMN)?;
+                } else {
+                    SortKey::child_expr(joins, column, *block, 0, out)?;
+                }
+                SortKey::sort_direction(direction, out)
+            }
+            _ => Err(constraint_violation!(
+                "child_sort_expr called with sort key {}",
+                self
+            )),
+        }
+    }
+
+    /// Generate a subquery that looks up `column` by following `joins`,
+    /// starting with the references of the entity `c`, or of the entity
+    /// `j{depth}` for nested lookups
+    ///   (select j1.{column}
+    ///      from {joins[0].table} j1
+    ///     where j1.id = c.{joins[0].column}
+    ///       and j1.block_range @> $block
+    ///     limit 1)
+    /// For derived references, the condition is `j1.{joins[0].column} = c.id`
+    /// instead. If there is more than one join, the subquery selects the
+    /// lookup for the remaining joins instead of `j1.{column}`
+    fn child_expr(
+        joins: &[SortJoin],
+        column: &Column,
+        block: BlockNumber,
+        depth: usize,
+        out: &mut AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let (join, rest) = joins
+            .split_first()
+            .ok_or_else(|| constraint_violation!("a child sort key needs at least one join"))?;
+        let parent = if depth == 0 {
+            "c".to_string()
+        } else {
+            format!("j{}", depth)
+        };
+        let alias = format!("j{}", depth + 1);
+        let prefix = format!("{}.", alias);
+
+        out.push_sql("(select ");
+        if rest.is_empty() {
+            out.push_sql(&prefix);
+            out.push_identifier(column.name.as_str())?;
+        } else {
+            SortKey::child_expr(rest, column, block, depth + 1, out)?;
+        }
+        out.push_sql(" from ");
+        out.push_sql(join.table.qualified_name.as_str());
+        out.push_sql(" ");
+        out.push_sql(&alias);
+        out.push_sql(" where ");
+        match (join.derived, join.column.is_list()) {
+            (true, true) => {
+                out.push_sql(&parent);
+                out.push_sql(".");
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                out.push_sql(" = any(");
+                out.push_sql(&prefix);
+                out.push_identifier(join.column.name.as_str())?;
+                out.push_sql(")");
+            }
+            (true, false) => {
+                out.push_sql(&prefix);
+                out.push_identifier(join.column.name.as_str())?;
+                out.push_sql(" = ");
+                out.push_sql(&parent);
+                out.push_sql(".");
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            }
+            (false, _) => {
+                out.push_sql(&prefix);
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                out.push_sql(" = ");
+                out.push_sql(&parent);
+                out.push_sql(".");
+                out.push_identifier(join.column.name.as_str())?;
+            }
+        }
+        out.push_sql(" and ");
+        BlockRangeColumn::new(join.table, &prefix, block).contains(out)?;
+        out.push_sql(" limit 1)");
+        Ok(())
+    }
 }
 
 /// Generate `[limit {first}] [offset {skip}]
@@ -2839,8 +3056,9 @@ impl<'a> FilterQuery<'a> {
         range: EntityRange,
         block: BlockNumber,
         query_id: Option<String>,
+        layout: &'a Layout,
     ) -> Result<Self, QueryExecutionError> {
-        let sort_key = SortKey::new(order, collection, filter, block)?;
+        let sort_key = SortKey::new(order, collection, filter, block, layout)?;
 
         Ok(FilterQuery {
             collection,
@@ -2907,7 +3125,7 @@ impl<'a> FilterQuery<'a> {
         write_column_names(column_names, table, &mut out)?;
         self.filtered_rows(table, filter, out.reborrow())?;
         out.push_sql("\n ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by(false, &mut out)?;
         self.range.walk_ast(out.reborrow())?;
         out.push_sql(") c");
         Ok(())
@@ -2935,7 +3153,7 @@ impl<'a> FilterQuery<'a> {
         )?;
         out.push_sql(") c");
         out.push_sql("\n ");
-        self.sort_key.order_by_parent(&mut out)
+        self.sort_key.order_by_parent(false, &mut out)
     }
 
     /// No windowing, but multiple entity types
@@ -2986,7 +3204,7 @@ impl<'a> FilterQuery<'a> {
             self.filtered_rows(table, filter, out.reborrow())?;
         }
         out.push_sql("\n ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by(true, &mut out)?;
         self.range.walk_ast(out.reborrow())?;
 
         out.push_sql(")\n");
@@ -3008,7 +3226,7 @@ impl<'a> FilterQuery<'a> {
             out.push_bind_param::<Text, _>(&table.object.as_str())?;
         }
         out.push_sql("\n ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by(true, &mut out)?;
         Ok(())
     }
 
@@ -3059,7 +3277,7 @@ impl<'a> FilterQuery<'a> {
             window.children_uniform(&self.sort_key, self.block, out.reborrow())?;
         }
         out.push_sql("\n");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by(true, &mut out)?;
         self.range.walk_ast(out.reborrow())?;
         out.push_sql(") c)\n");
 
@@ -3097,7 +3315,7 @@ impl<'a> FilterQuery<'a> {
             out.push_sql("'");
         }
         out.push_sql("\n ");
-        self.sort_key.order_by_parent(&mut out)
+        self.sort_key.order_by_parent(true, &mut out)
     }
 }
 
@@ -5,7 +5,8 @@ use graph::{
     components::{
         server::index_node::VersionInfo,
         store::{
-            BlockStore as BlockStoreTrait, QueryStoreManager, StatusStore, Store as StoreTrait,
+            BlockStore as BlockStoreTrait, QueryStoreManager, ReplicaSelector, StatusStore,
+            Store as StoreTrait,
         },
     },
     constraint_violation,
@@ -16,7 +17,13 @@ use graph::{
     },
 };
 
-use crate::{block_store::BlockStore, query_store::QueryStore, SubgraphStore};
+use crate::{
+    block_store::BlockStore,
+    deployment_store::{DeploymentStore, ReplicaId},
+    primary::Site,
+    query_store::QueryStore,
+    SubgraphStore,
+};
 
 /// The overall store of the system, consisting of a [`SubgraphStore`] and a
 /// [`BlockStore`], each of which multiplex across multiple database shards.
@@ -64,6 +71,25 @@ impl StoreTrait for Store {
     }
 }
 
+impl Store {
+    fn make_query_store(
+        &self,
+        store: Arc<DeploymentStore>,
+        site: Arc<Site>,
+        replica: ReplicaId,
+    ) -> Result<Arc<dyn graph::prelude::QueryStore + Send + Sync>, QueryExecutionError> {
+        let chain_store = self.block_store.chain_store(&site.network).ok_or_else(|| {
+            constraint_violation!(
+                "Subgraphs index a known network, but {} indexes `{}` which we do not know about. This is most likely a configuration error.",
+                site.deployment,
+                site.network
+            )
+        })?;
+
+        Ok(Arc::new(QueryStore::new(store, chain_store, site, replica)))
+    }
+}
+
 #[async_trait]
 impl QueryStoreManager for Store {
     async fn query_store(
@@ -84,15 +110,29 @@ impl QueryStoreManager for Store {
         .map_err(|e| QueryExecutionError::Panic(e.to_string()))
         .and_then(|x| x)?;
 
-        let chain_store = self.block_store.chain_store(&site.network).ok_or_else(|| {
-            constraint_violation!(
-                "Subgraphs index a known network, but {} indexes `{}` which we do not know about. This is most likely a configuration error.",
-                site.deployment,
-                site.network
-            )
-        })?;
+        self.make_query_store(store, site, replica)
+    }
 
-        Ok(Arc::new(QueryStore::new(store, chain_store, site, replica)))
+    async fn select_query_store(
+        &self,
+        target: graph::data::query::QueryTarget,
+        shape_hash: u64,
+        selector: Arc<dyn ReplicaSelector>,
+    ) -> Result<
+        Arc<dyn graph::prelude::QueryStore + Send + Sync>,
+        graph::prelude::QueryExecutionError,
+    > {
+        let store = self.subgraph_store.cheap_clone();
+        let (store, site, replica) = graph::spawn_blocking_allow_panic(move || {
+            store
+                .select_replica_for_query(target, shape_hash, selector.as_ref())
+                .map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| QueryExecutionError::Panic(e.to_string()))
+        .and_then(|x| x)?;
+
+        self.make_query_store(store, site, replica)
     }
 }
 
@@ -684,10 +684,7 @@ impl SubgraphStoreInner {
         target: QueryTarget,
         for_subscription: bool,
     ) -> Result<(Arc<DeploymentStore>, Arc<Site>, ReplicaId), StoreError> {
-        let id = match target {
-            QueryTarget::Name(name) => self.mirror.current_deployment_for_subgraph(&name)?,
-            QueryTarget::Deployment(id) => id,
-        };
+        let id = self.target_deployment(target)?;
 
         let (store, site) = self.store(&id)?;
         let replica = store.replica_for_query(for_subscription)?;
@@ -695,6 +692,29 @@ impl SubgraphStoreInner {
         Ok((store.clone(), site, replica))
     }
 
+    /// Like `replica_for_query` for a query that is not part of a
+    /// subscription, but let `selector` choose the replica
+    pub(crate) fn select_replica_for_query(
+        &self,
+        target: QueryTarget,
+        shape_hash: u64,
+        selector: &dyn store::ReplicaSelector,
+    ) -> Result<(Arc<DeploymentStore>, Arc<Site>, ReplicaId), StoreError> {
+        let id = self.target_deployment(target)?;
+
+        let (store, site) = self.store(&id)?;
+        let replica = store.select_replica_for_query(shape_hash, selector)?;
+
+        Ok((store.clone(), site, replica))
+    }
+
+    fn target_deployment(&self, target: QueryTarget) -> Result<DeploymentHash, StoreError> {
+        match target {
+            QueryTarget::Name(name) => self.mirror.current_deployment_for_subgraph(&name),
+            QueryTarget::Deployment(id) => Ok(id),
+        }
+    }
+
     /// Delete all entities. This function exists solely for integration tests
     /// and should never be called from any other code. Unfortunately, Rust makes
     /// it very hard to export items just for testing
@@ -19,7 +19,7 @@ use graph_mock::MockMetricsRegistry;
 use graph_node::config::{Config, Opt};
 use graph_node::store_builder::StoreBuilder;
 use graph_store_postgres::layout_for_tests::FAKE_NETWORK_SHARED;
-use graph_store_postgres::{connection_pool::ConnectionPool, Shard, SubscriptionManager};
+use graph_store_postgres::{connection_pool::ConnectionPool, Shard};
 use graph_store_postgres::{
     BlockStore as DieselBlockStore, DeploymentPlacer, SubgraphStore as DieselSubgraphStore,
     PRIMARY_SHARD,
@@ -36,7 +36,7 @@ use web3::types::H256;
 pub const NETWORK_NAME: &str = "fake_network";
 pub const NETWORK_VERSION: &str = "graph test suite";
 
-pub use graph_store_postgres::Store;
+pub use graph_store_postgres::{Store, SubscriptionManager};
 
 const CONN_POOL_SIZE: u32 = 20;
 
@@ -469,10 +469,13 @@ async fn execute_subgraph_query_internal(
                 None,
                 QueryExecutionOptions {
                     resolver,
+                    start: Instant::now(),
                     deadline,
                     load_manager: LOAD_MANAGER.clone(),
                     max_first: std::u32::MAX,
                     max_skip: std::u32::MAX,
+                    tracer: None,
+                    partial_results: false,
                 },
             )
             .await,